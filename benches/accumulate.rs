@@ -0,0 +1,29 @@
+use andrea::optimizer;
+use andrea::value::Value;
+use andrea::vm::VM;
+use andrea::workloads;
+use std::time::{Duration, Instant};
+
+const TARGET: Duration = Duration::from_millis(500);
+
+// The array sum loop as built and after the peephole pass has combined its
+// accumulator updates into locals in place.
+fn main() {
+    let chunk = workloads::array_sum(10_000);
+    let combined = optimizer::peephole(&chunk).unwrap();
+    let expected = Value::Integer(10_000 * 9_999 / 2);
+    let mut times = Vec::new();
+    for (name, chunk) in [("before", &chunk), ("after", &combined)] {
+        let mut runs = 0;
+        let start = Instant::now();
+        while start.elapsed() < TARGET {
+            let outcome = VM::new(chunk.clone()).execute_all().unwrap();
+            assert_eq!(outcome.value, Some(expected));
+            runs += 1;
+        }
+        let per_run = start.elapsed() / runs;
+        println!("array_sum  {name:<10}: {runs:>6} runs, {per_run:>10.2?}/run");
+        times.push(per_run.as_secs_f64());
+    }
+    println!("speedup: {:.2}x", times[0] / times[1]);
+}
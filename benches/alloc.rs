@@ -0,0 +1,34 @@
+use andrea::heap::{HeapMode, Object};
+use andrea::value::Value;
+use andrea::vm::VM;
+use std::time::Instant;
+
+const OBJECTS: i64 = 1_000_000;
+
+fn churn(label: &str, mut vm: VM) {
+    let start = Instant::now();
+    for i in 0..OBJECTS {
+        vm.alloc(Object::new(1, vec![Value::Integer(i)]));
+    }
+    let elapsed = start.elapsed();
+
+    let stats = vm.heap().stats();
+    println!(
+        "{label:<22}: {elapsed:>10.2?} ({:.1} ns/object, {} fresh, {} recycled)",
+        elapsed.as_nanos() as f64 / OBJECTS as f64,
+        stats.fresh,
+        stats.recycled,
+    );
+}
+
+fn main() {
+    for cap in [0, 1024] {
+        let mut vm = VM::new(vec![]);
+        vm.set_free_list_cap(cap).unwrap();
+        churn(&format!("free list cap {cap}"), vm);
+    }
+    churn(
+        "arena",
+        VM::with_heap_mode(vec![], HeapMode::Arena).unwrap(),
+    );
+}
@@ -0,0 +1,34 @@
+use andrea::optimizer;
+use andrea::value::Value;
+use andrea::vm::VM;
+use andrea::workloads;
+use std::time::{Duration, Instant};
+
+const TARGET: Duration = Duration::from_millis(500);
+const N: i64 = 10_000;
+
+// The array sum loop after `optimize`, with and without its reads turned
+// into unchecked ones. The fill loop is bounded by a literal and left as it
+// is, so the difference per element is all in the sum loop.
+fn main() {
+    let chunk = optimizer::optimize(&workloads::array_sum(N)).unwrap();
+    let unchecked = optimizer::eliminate_range_checks(&chunk).unwrap();
+    let expected = Value::Integer(N * (N - 1) / 2);
+    // alternating runs, keeping the fastest of each, to see past a noisy
+    // machine
+    let mut best = [Duration::MAX; 2];
+    let start = Instant::now();
+    while start.elapsed() < TARGET * 2 {
+        for (best, chunk) in best.iter_mut().zip([&chunk, &unchecked]) {
+            let run = Instant::now();
+            let outcome = VM::new(chunk.clone()).execute_all().unwrap();
+            *best = (*best).min(run.elapsed());
+            assert_eq!(outcome.value, Some(expected));
+        }
+    }
+    let per_element = best.map(|time| time.as_nanos() as f64 / N as f64);
+    for (name, time) in ["checked", "unchecked"].iter().zip(per_element) {
+        println!("array_sum  {name:<10}: {time:>6.2}ns/element");
+    }
+    println!("saved: {:.2}ns/element", per_element[0] - per_element[1]);
+}
@@ -0,0 +1,32 @@
+use andrea::verifier::VerifiedChunk;
+use andrea::vm::VM;
+use andrea::workloads;
+use std::time::{Duration, Instant};
+
+const TARGET: Duration = Duration::from_millis(500);
+
+// The countdown loop run with every check and on the verified fast path.
+fn main() {
+    let chunk = workloads::countdown(100_000);
+    let verified = VerifiedChunk::new(chunk.clone()).unwrap();
+    let mut rates = Vec::new();
+    for fast in [false, true] {
+        let mut count = 0;
+        let mut runs = 0;
+        let start = Instant::now();
+        while start.elapsed() < TARGET {
+            let mut vm = match fast {
+                false => VM::new(chunk.clone()),
+                true => VM::from_verified(verified.clone()).unwrap(),
+            };
+            let outcome = vm.execute_all().unwrap();
+            count = outcome.instructions;
+            runs += 1;
+        }
+        let rate = (count * runs) as f64 / start.elapsed().as_secs_f64() / 1e6;
+        let name = if fast { "verified" } else { "checked" };
+        println!("countdown  {name:<10}: {runs:>6} runs, {rate:>7.1} M instructions/s");
+        rates.push(rate);
+    }
+    println!("speedup: {:.2}x", rates[1] / rates[0]);
+}
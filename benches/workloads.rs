@@ -0,0 +1,34 @@
+use andrea::config::VmBuilder;
+use andrea::vm::ExecutionMode;
+use andrea::workloads;
+use std::time::{Duration, Instant};
+
+const TARGET: Duration = Duration::from_millis(500);
+
+fn main() {
+    for workload in workloads::standard() {
+        for mode in [ExecutionMode::Bytecode, ExecutionMode::Predecoded] {
+            let mut count = 0;
+            let mut runs = 0;
+            let start = Instant::now();
+            while start.elapsed() < TARGET {
+                let mut vm = VmBuilder::new()
+                    .execution_mode(mode)
+                    .build(workload.chunk.clone())
+                    .unwrap();
+                let outcome = vm.execute_all().unwrap();
+                assert_eq!(outcome.value, Some(workload.expected));
+                count = outcome.instructions;
+                runs += 1;
+            }
+            let elapsed = start.elapsed();
+
+            println!(
+                "{:<10} {:<10}: {runs:>6} runs, {count:>9} instructions/run, {:>7.1} M instructions/s",
+                workload.name,
+                format!("{mode:?}"),
+                (count * runs) as f64 / elapsed.as_secs_f64() / 1e6,
+            );
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use andrea::error::FrontendError;
+use andrea::frontend;
+use std::process::ExitCode;
+
+// Evaluates an expression with variables bound on the command line:
+//   cargo run --example calc -- "x < y ? y / x : x / y" x=3 y=12
+fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1);
+    let Some(source) = args.next() else {
+        eprintln!("usage: calc EXPRESSION [NAME=VALUE]...");
+        return ExitCode::FAILURE;
+    };
+    let mut env = Vec::new();
+    for binding in args {
+        let parsed = binding
+            .split_once('=')
+            .and_then(|(name, val)| Some((name.to_string(), val.parse::<i64>().ok()?)));
+        match parsed {
+            Some(binding) => env.push(binding),
+            None => {
+                eprintln!("expected NAME=VALUE, found {binding:?}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    let env: Vec<_> = env
+        .iter()
+        .map(|(name, val)| (name.as_str(), *val))
+        .collect();
+
+    match frontend::compile(&source).and_then(|program| program.run(&env)) {
+        Ok(val) => {
+            println!("{val}");
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("{source}");
+            // point at the place in the source where it went wrong
+            let at = match err {
+                FrontendError::Syntax { at, .. } | FrontendError::TypeMismatch { at, .. } => {
+                    Some(at)
+                }
+                FrontendError::Trap { at, .. } => at,
+                FrontendError::Unbound(_) => None,
+            };
+            if let Some(at) = at {
+                eprintln!("{:>1$}", "^", at + 1);
+            }
+            eprintln!("error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,39 @@
+use andrea::chunk::Chunk;
+use andrea::{usage, workloads};
+use std::process::ExitCode;
+
+// Reports static opcode usage over serialized chunks, or over the standard
+// workloads when no files are given:
+//   cargo run --example usage -- a.chunk b.chunk
+fn main() -> ExitCode {
+    let mut corpus = Vec::new();
+    for path in std::env::args().skip(1) {
+        let chunk = std::fs::read(&path)
+            .map_err(|err| err.to_string())
+            .and_then(|bytes| Chunk::deserialize(&bytes).map_err(|err| err.to_string()));
+        match chunk {
+            Ok(chunk) => corpus.push(chunk),
+            Err(err) => {
+                eprintln!("{path}: {err}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    if corpus.is_empty() {
+        corpus = workloads::standard()
+            .into_iter()
+            .map(|workload| workload.chunk)
+            .collect();
+    }
+
+    match usage::analyze(&corpus) {
+        Ok(usage) => {
+            print!("{usage}");
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use crate::chunk::Chunk;
+use crate::heap::Heap;
+use crate::instruction::Instruction;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+// Where objects were allocated, recorded while allocation profiling is on:
+// the ip of the instruction that was running, which for an object a native
+// allocated is its `CallNative`. Sites are kept by object id in a side
+// table, pruned after each collection, so the heap itself carries nothing.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AllocationProfile {
+    sites: HashMap<u64, usize>,
+    totals: BTreeMap<usize, u64>,
+}
+
+impl AllocationProfile {
+    pub(crate) fn record(&mut self, id: u64, ip: usize) {
+        self.sites.insert(id, ip);
+        *self.totals.entry(ip).or_insert(0) += 1;
+    }
+
+    // Forgets the objects the heap no longer has.
+    pub(crate) fn retain(&mut self, heap: &Heap) {
+        let live: HashMap<_, _> = heap
+            .iter()
+            .filter_map(|ptr| Some((ptr.id(), *self.sites.get(&ptr.id())?)))
+            .collect();
+        self.sites = live;
+    }
+
+    // Every site that allocated anything, most allocations first, with its
+    // instruction from `chunk` and its line from `lines`: pairs of an offset
+    // and the line that the code from there up to the next pair came from,
+    // in order of offset. Live counts are of objects `heap` still holds,
+    // garbage not yet collected included.
+    pub fn report(&self, chunk: &Chunk, heap: &Heap, lines: &[(usize, usize)]) -> AllocationReport {
+        let mut live = HashMap::<usize, usize>::new();
+        for ptr in heap.iter() {
+            if let Some(&ip) = self.sites.get(&ptr.id()) {
+                *live.entry(ip).or_insert(0) += 1;
+            }
+        }
+        let mut rows: Vec<_> = self
+            .totals
+            .iter()
+            .map(|(&ip, &allocated)| SiteRow {
+                ip,
+                instruction: Instruction::decode(chunk.code(), ip).ok(),
+                line: match lines.partition_point(|&(offset, _)| offset <= ip) {
+                    0 => None,
+                    i => Some(lines[i - 1].1),
+                },
+                allocated,
+                live: live.get(&ip).copied().unwrap_or(0),
+            })
+            .collect();
+        rows.sort_by_key(|row| (std::cmp::Reverse(row.allocated), row.ip));
+        AllocationReport(rows)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SiteRow {
+    pub ip: usize,
+    pub instruction: Option<Instruction>,
+    pub line: Option<usize>,
+    pub allocated: u64,
+    pub live: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AllocationReport(pub Vec<SiteRow>);
+
+impl fmt::Display for AllocationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:>10} {:>8} {:>6} {:>6}  instruction",
+            "allocated", "live", "ip", "line"
+        )?;
+        for row in &self.0 {
+            let line = row.line.map_or("-".to_string(), |line| line.to_string());
+            write!(
+                f,
+                "\n{:>10} {:>8} {:>6} {:>6}  ",
+                row.allocated, row.live, row.ip, line
+            )?;
+            match row.instruction {
+                Some(instruction) => write!(f, "{instruction:?}")?,
+                None => write!(f, "?")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::builder::ChunkBuilder;
+    use crate::instruction::Instruction;
+    use crate::opcode::OpCode::*;
+    use crate::vm::VM;
+
+    // Two loops, allocating 10 arrays kept in local 0 and then 100 strings
+    // of which only the last is kept in local 1.
+    #[test]
+    fn test_sites() {
+        let mut b = ChunkBuilder::new();
+        let mut lines = Vec::new();
+        let (first, second, done) = (b.label(), b.label(), b.label());
+        let end = b.label();
+        lines.push((b.len(), 1));
+        b.op(Imm0).store(0).imm_i(10).store(2);
+        b.bind(first).load(2).op(Imm0).op(CmpLeI).goto_if(second);
+        lines.push((b.len(), 2));
+        b.imm_i(1);
+        let arrays = b.len();
+        b.op(ArrayNew).store(3);
+        b.load(3).op(Imm0).load(0).op(ArraySet).load(3).store(0);
+        lines.push((b.len(), 3));
+        b.load(2).op(Imm1).op(SubI).store(2).goto(first);
+        b.bind(second).imm_i(100).store(2);
+        b.bind(done).load(2).op(Imm0).op(CmpLeI).goto_if(end);
+        lines.push((b.len(), 4));
+        let strings = b.len();
+        b.string("s").store(1);
+        b.load(2).op(Imm1).op(SubI).store(2).goto(done);
+        b.bind(end);
+        let chunk = b.build().unwrap();
+
+        let mut vm = VM::new(chunk);
+        assert_eq!(vm.allocation_profile(&lines), None);
+        vm.set_allocation_profiling(true).unwrap();
+        vm.execute_all().unwrap();
+        vm.collect_garbage();
+        let report = vm.allocation_profile(&lines).unwrap();
+        let rows: Vec<_> = report
+            .0
+            .iter()
+            .map(|row| (row.ip, row.line, row.allocated, row.live))
+            .collect();
+        assert_eq!(
+            rows,
+            [(strings, Some(4), 100, 1), (arrays, Some(2), 10, 10)]
+        );
+        assert_eq!(report.0[1].instruction, Some(Instruction::ArrayNew));
+        assert!(matches!(
+            report.0[0].instruction,
+            Some(Instruction::LoadConst(_))
+        ));
+        let text = report.to_string();
+        assert_eq!(
+            text.lines().nth(1),
+            Some(&*format!(
+                "       100        1 {strings:>6}      4  LoadConst(0)"
+            ))
+        );
+
+        // without a line table
+        let report = vm.allocation_profile(&[]).unwrap();
+        assert!(report.0.iter().all(|row| row.line.is_none()));
+        vm.set_allocation_profiling(false).unwrap();
+        assert_eq!(vm.allocation_profile(&lines), None);
+    }
+}
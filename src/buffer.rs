@@ -0,0 +1,82 @@
+use crate::error::VmError;
+use crate::heap::{tag, Object};
+use std::ops::Range;
+
+// Buffers keep their bytes out of the fields, which stay empty, so the
+// collector and the object utilities never look at them. Integers of 1, 2,
+// 4 or 8 bytes are read and written big-endian at any offset.
+impl Object {
+    pub fn buffer(bytes: Vec<u8>) -> Self {
+        Self {
+            buffer: Some(bytes.into_boxed_slice()),
+            ..Self::new(tag::BUFFER, Vec::new())
+        }
+    }
+
+    pub fn as_buffer(&self) -> Option<&[u8]> {
+        self.buffer.as_deref()
+    }
+
+    pub fn as_buffer_mut(&mut self) -> Option<&mut [u8]> {
+        self.buffer.as_deref_mut()
+    }
+
+    // Zero-extends the `width` bytes at `offset`.
+    pub fn buffer_load(&self, offset: i64, width: usize) -> Result<u64, VmError> {
+        let bytes = self.as_buffer().ok_or(NOT_A_BUFFER)?;
+        let mut word = [0; 8];
+        word[8 - width..].copy_from_slice(&bytes[range(offset, width, bytes.len())?]);
+        Ok(u64::from_be_bytes(word))
+    }
+
+    // Stores the low `width` bytes of `val` at `offset`.
+    pub fn buffer_store(&mut self, offset: i64, width: usize, val: u64) -> Result<(), VmError> {
+        let bytes = self.as_buffer_mut().ok_or(NOT_A_BUFFER)?;
+        let range = range(offset, width, bytes.len())?;
+        bytes[range].copy_from_slice(&val.to_be_bytes()[8 - width..]);
+        Ok(())
+    }
+}
+
+const NOT_A_BUFFER: VmError = VmError::TypeMismatch {
+    expected: "buffer",
+    found: "object",
+};
+
+fn range(offset: i64, width: usize, len: usize) -> Result<Range<usize>, VmError> {
+    usize::try_from(offset)
+        .ok()
+        .and_then(|start| Some(start..start.checked_add(width)?))
+        .filter(|range| range.end <= len)
+        .ok_or(VmError::BufferOutOfBounds { offset, width, len })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_store() {
+        let mut buffer = Object::buffer(vec![0; 10]);
+        buffer.buffer_store(1, 4, 0x1122_3344_5566).unwrap();
+        buffer.buffer_store(5, 2, u64::MAX).unwrap();
+        buffer.buffer_store(9, 1, 0x1ab).unwrap();
+        assert_eq!(
+            buffer.as_buffer(),
+            Some(&[0, 0x33, 0x44, 0x55, 0x66, 0xff, 0xff, 0, 0, 0xab][..])
+        );
+        assert_eq!(buffer.buffer_load(1, 8), Ok(0x3344_5566_ffff_0000));
+        assert_eq!(buffer.buffer_load(5, 2), Ok(0xffff));
+        assert_eq!(buffer.buffer_load(9, 1), Ok(0xab));
+
+        let out_of_bounds = |offset, width| VmError::BufferOutOfBounds {
+            offset,
+            width,
+            len: 10,
+        };
+        assert_eq!(buffer.buffer_load(3, 8), Err(out_of_bounds(3, 8)));
+        assert_eq!(buffer.buffer_load(10, 1), Err(out_of_bounds(10, 1)));
+        assert_eq!(buffer.buffer_store(-1, 2, 0), Err(out_of_bounds(-1, 2)));
+        assert_eq!(Object::array(1).buffer_load(0, 1), Err(NOT_A_BUFFER));
+    }
+}
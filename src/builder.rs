@@ -0,0 +1,496 @@
+use crate::chunk::{feature, Chunk, Constant, Function};
+use crate::encode;
+use crate::error::BuildError;
+use crate::instruction::Instruction;
+use crate::opcode::{effect, OpCode};
+use std::collections::BTreeSet;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Label(usize);
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ChunkBuilder {
+    code: Vec<u8>,
+    labels: Vec<Option<usize>>,
+    fixups: Vec<(usize, Label)>,
+    addresses: Vec<(usize, Label)>,
+    max_locals: Option<u16>,
+    constants: Vec<Constant>,
+    // Entries are filled in from the labels by `build`.
+    functions: Vec<(Label, Function)>,
+    exports: Vec<(String, u16)>,
+    imports: Vec<String>,
+    // Accumulated as instructions are emitted.
+    features: u32,
+    // The first immediate that failed its width check, returned by `build`.
+    out_of_range: Option<BuildError>,
+}
+
+impl ChunkBuilder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.code.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
+
+    pub fn max_locals(&mut self, max_locals: u16) -> &mut Self {
+        self.max_locals = Some(max_locals);
+        self
+    }
+
+    pub fn op(&mut self, op: OpCode) -> &mut Self {
+        self.code.push(op as u8);
+        self.features |= op.features();
+        self
+    }
+
+    fn op_u8(&mut self, op: OpCode, operand: u8) -> &mut Self {
+        self.op(op);
+        self.code.push(operand);
+        self
+    }
+
+    fn op_u16(&mut self, op: OpCode, operand: u16) -> &mut Self {
+        self.op(op);
+        encode::put_u16(&mut self.code, operand);
+        self
+    }
+
+    fn op_u64(&mut self, op: OpCode, operand: u64) -> &mut Self {
+        self.op(op);
+        encode::put_u64(&mut self.code, operand);
+        self
+    }
+
+    pub fn imm_i(&mut self, i: i64) -> &mut Self {
+        match i {
+            0 => self.op(OpCode::Imm0),
+            1 => self.op(OpCode::Imm1),
+            -1 => self.op(OpCode::ImmNeg1),
+            _ if i8::try_from(i).is_ok() => self.op_u8(OpCode::ImmI8, i as u8),
+            _ if i16::try_from(i).is_ok() => self.op_u16(OpCode::ImmI16, i as u16),
+            _ => self.op_u64(OpCode::ImmI, i as u64),
+        }
+    }
+
+    // For front ends with narrower integers: the value still gets the
+    // smallest encoding, but one outside the width fails the build.
+    pub fn imm_i8(&mut self, i: i64) -> &mut Self {
+        self.imm_checked(i, 8, i8::try_from(i).is_ok())
+    }
+
+    pub fn imm_i16(&mut self, i: i64) -> &mut Self {
+        self.imm_checked(i, 16, i16::try_from(i).is_ok())
+    }
+
+    pub fn imm_i32(&mut self, i: i64) -> &mut Self {
+        self.imm_checked(i, 32, i32::try_from(i).is_ok())
+    }
+
+    fn imm_checked(&mut self, value: i64, bits: u8, fits: bool) -> &mut Self {
+        if !fits && self.out_of_range.is_none() {
+            self.out_of_range = Some(BuildError::ImmediateOutOfRange { value, bits });
+        }
+        self.imm_i(value)
+    }
+
+    pub fn imm_w(&mut self, w: u64) -> &mut Self {
+        match w {
+            _ if u8::try_from(w).is_ok() => self.op_u8(OpCode::ImmW8, w as u8),
+            _ if u16::try_from(w).is_ok() => self.op_u16(OpCode::ImmW16, w as u16),
+            _ => self.op_u64(OpCode::ImmW, w),
+        }
+    }
+
+    pub fn imm_f(&mut self, f: f64) -> &mut Self {
+        self.op_u64(OpCode::ImmF, f.to_bits())
+    }
+
+    // Equal constants share one pool entry.
+    fn constant(&mut self, constant: Constant) -> u16 {
+        let index = match self.constants.iter().position(|c| *c == constant) {
+            Some(index) => index,
+            None => {
+                self.constants.push(constant);
+                self.constants.len() - 1
+            }
+        };
+        index as u16
+    }
+
+    pub fn load_const(&mut self, constant: Constant) -> &mut Self {
+        if matches!(constant, Constant::Str(_)) {
+            self.features |= feature::OBJECTS;
+        }
+        let index = self.constant(constant);
+        self.op_u16(OpCode::LoadConst, index)
+    }
+
+    pub fn call_native(&mut self, name: &str) -> &mut Self {
+        let index = self.constant(Constant::Str(name.to_string()));
+        self.op_u16(OpCode::CallNative, index)
+    }
+
+    pub fn string(&mut self, s: &str) -> &mut Self {
+        self.load_const(Constant::Str(s.to_string()))
+    }
+
+    // Interned literals with the same contents evaluate to the same object.
+    pub fn interned(&mut self, s: &str) -> &mut Self {
+        self.string(s).op(OpCode::Intern)
+    }
+
+    pub fn load(&mut self, index: u16) -> &mut Self {
+        self.op_u16(OpCode::Load, index)
+    }
+
+    pub fn store(&mut self, index: u16) -> &mut Self {
+        self.op_u16(OpCode::Store, index)
+    }
+
+    pub fn store_if(&mut self, index: u16) -> &mut Self {
+        self.op_u16(OpCode::StoreIf, index)
+    }
+
+    pub fn load_or_default(&mut self, index: u16) -> &mut Self {
+        self.op_u16(OpCode::LoadOrDefault, index)
+    }
+
+    pub fn add_to_local(&mut self, index: u16) -> &mut Self {
+        self.op_u16(OpCode::AddToLocal, index)
+    }
+
+    pub fn mul_to_local(&mut self, index: u16) -> &mut Self {
+        self.op_u16(OpCode::MulToLocal, index)
+    }
+
+    pub fn min_to_local(&mut self, index: u16) -> &mut Self {
+        self.op_u16(OpCode::MinToLocal, index)
+    }
+
+    pub fn max_to_local(&mut self, index: u16) -> &mut Self {
+        self.op_u16(OpCode::MaxToLocal, index)
+    }
+
+    pub fn load_arg(&mut self, index: u8) -> &mut Self {
+        self.op_u8(OpCode::LoadArg, index)
+    }
+
+    pub fn store_arg(&mut self, index: u8) -> &mut Self {
+        self.op_u8(OpCode::StoreArg, index)
+    }
+
+    pub fn get_field(&mut self, index: u16) -> &mut Self {
+        self.op_u16(OpCode::GetField, index)
+    }
+
+    pub fn set_field(&mut self, index: u16) -> &mut Self {
+        self.op_u16(OpCode::SetField, index)
+    }
+
+    pub fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    pub fn bind(&mut self, label: Label) -> &mut Self {
+        self.labels[label.0] = Some(self.code.len());
+        self
+    }
+
+    fn jump(&mut self, op: OpCode, label: Label) -> &mut Self {
+        self.op(op);
+        self.fixups.push((self.code.len(), label));
+        self.code.extend([0, 0]);
+        self
+    }
+
+    // Pushes the label's offset as a word, for use with `GotoDyn`.
+    pub fn imm_label(&mut self, label: Label) -> &mut Self {
+        self.op(OpCode::ImmW);
+        self.addresses.push((self.code.len(), label));
+        self.code.extend([0; 8]);
+        self
+    }
+
+    // Adds a function starting at `entry` to the function table, returning
+    // its index for `call`. The label may be bound later, so functions can
+    // call themselves.
+    pub fn function(&mut self, entry: Label, arity: u8) -> u16 {
+        self.functions.push((entry, Function::new(0, arity)));
+        (self.functions.len() - 1) as u16
+    }
+
+    pub fn returns(&mut self, function: u16, count: u8) -> &mut Self {
+        self.functions[function as usize].1.returns = Some(count);
+        self
+    }
+
+    pub fn function_name(&mut self, function: u16, name: &str) -> &mut Self {
+        self.functions[function as usize].1.name = Some(name.to_string());
+        self
+    }
+
+    // Names one of the function's local slots, for debuggers.
+    pub fn local_name(&mut self, function: u16, slot: u16, name: &str) -> &mut Self {
+        self.functions[function as usize]
+            .1
+            .locals
+            .push((slot, name.to_string()));
+        self
+    }
+
+    pub fn export(&mut self, function: u16, name: &str) -> &mut Self {
+        self.exports.push((name.to_string(), function));
+        self
+    }
+
+    // Returns an index for `call` that the linker resolves to the function
+    // another chunk exports under `name`.
+    pub fn import(&mut self, name: &str) -> u16 {
+        let import = match self.imports.iter().position(|import| import == name) {
+            Some(import) => import,
+            None => {
+                self.imports.push(name.to_string());
+                self.imports.len() - 1
+            }
+        };
+        Chunk::import_index(import)
+    }
+
+    pub fn call(&mut self, function: u16) -> &mut Self {
+        self.op_u16(OpCode::Call, function)
+    }
+
+    pub fn ret_n(&mut self, count: u8) -> &mut Self {
+        self.op_u8(OpCode::ReturnN, count)
+    }
+
+    pub fn goto(&mut self, label: Label) -> &mut Self {
+        self.jump(OpCode::Goto, label)
+    }
+
+    pub fn goto_if(&mut self, label: Label) -> &mut Self {
+        self.jump(OpCode::GotoIf, label)
+    }
+
+    // Immediates outside the i32 range that are operands of the 32-bit
+    // integer opcodes, which would truncate them, by offset. Values are only
+    // followed through straight-line code on the stack, not through locals.
+    pub fn wide_i32_immediates(&self) -> Vec<(usize, i64)> {
+        let targets: BTreeSet<_> = self.labels.iter().flatten().copied().collect();
+        let chunk = Chunk::new(self.code.clone());
+        let mut stack: Vec<Option<(usize, i64)>> = Vec::new();
+        let mut found = Vec::new();
+        for (offset, instruction) in chunk.instructions().map_while(Result::ok) {
+            if targets.contains(&offset) {
+                stack.clear();
+            }
+            let op = instruction.opcode();
+            let Some((pops, pushes)) = op.stack_effect() else {
+                stack.clear();
+                continue;
+            };
+            let popped = stack.split_off(stack.len().saturating_sub(pops));
+            if matches!(
+                op,
+                OpCode::AddI32 | OpCode::SubI32 | OpCode::MulI32 | OpCode::DivI32
+            ) {
+                found.extend(
+                    popped
+                        .into_iter()
+                        .flatten()
+                        .filter(|&(_, i)| i32::try_from(i).is_err()),
+                );
+            }
+            if op.effects() & effect::CONTROL_FLOW != 0 {
+                stack.clear();
+                continue;
+            }
+            let pushed = match instruction {
+                Instruction::ImmI(i) => Some((offset, i)),
+                _ => None,
+            };
+            stack.extend(std::iter::repeat_n(pushed, pushes));
+        }
+        found
+    }
+
+    pub fn build(&self) -> Result<Chunk, BuildError> {
+        if let Some(err) = self.out_of_range {
+            return Err(err);
+        }
+        let mut code = self.code.clone();
+        for &(at, label) in &self.fixups {
+            let target = self.labels[label.0].ok_or(BuildError::UnboundLabel)?;
+            let target = u16::try_from(target).map_err(|_| BuildError::JumpOutOfRange(target))?;
+            encode::patch_u16(&mut code, at, target);
+        }
+        for &(at, label) in &self.addresses {
+            let target = self.labels[label.0].ok_or(BuildError::UnboundLabel)?;
+            encode::patch_u64(&mut code, at, target as u64);
+        }
+
+        let functions = self
+            .functions
+            .iter()
+            .map(|(label, function)| {
+                let entry = self.labels[label.0].ok_or(BuildError::UnboundLabel)?;
+                Ok(Function {
+                    entry,
+                    ..function.clone()
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        let chunk = Chunk::new(code)
+            .with_constants(self.constants.clone())
+            .with_functions(functions)
+            .with_exports(self.exports.clone())
+            .with_imports(self.imports.clone())
+            .with_features(self.features);
+        Ok(match self.max_locals {
+            Some(max) => chunk.with_max_locals(max),
+            None => chunk,
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::value::Value;
+    use crate::vm::{self, VM};
+    use OpCode::*;
+
+    pub(crate) fn factorial(n: i64) -> ChunkBuilder {
+        let mut b = ChunkBuilder::new();
+        let (head, end) = (b.label(), b.label());
+        b.imm_i(n).store(0);
+        b.imm_i(1).store(1);
+        b.bind(head).imm_i(1).load(0).op(CmpGtI).goto_if(end);
+        b.load(1).load(0).op(MulI).store(1);
+        b.load(0).imm_i(1).op(SubI).store(0);
+        b.goto(head);
+        b.bind(end).load(1).op(Return);
+        b
+    }
+
+    #[test]
+    fn test_factorial_small_immediates() {
+        let chunk = factorial(5).build().unwrap();
+        let raw = vm::tests::factorial();
+        // 5 takes two bytes rather than nine, each 1 a single byte
+        assert_eq!(chunk.len(), raw.len() - 7 - 3 * 8);
+
+        let mut vm = VM::new(chunk);
+        vm.execute_all().unwrap();
+        assert_eq!(vm.stack(), [Value::Integer(120)]);
+    }
+
+    #[test]
+    fn test_imm_selection() {
+        let mut b = ChunkBuilder::new();
+        let values = [
+            0, 1, -1, 2, -2, 127, -128, 128, -129, 32767, -32768, 32768, -32769,
+        ];
+        for &i in &values {
+            b.imm_i(i);
+        }
+        let chunk = b.build().unwrap();
+        let ops: Vec<_> = chunk
+            .instructions()
+            .map(|r| r.unwrap().1.opcode())
+            .collect();
+        assert_eq!(
+            ops,
+            [
+                Imm0, Imm1, ImmNeg1, ImmI8, ImmI8, ImmI8, ImmI8, ImmI16, ImmI16, ImmI16, ImmI16,
+                ImmI, ImmI
+            ]
+        );
+        assert_eq!(chunk.code()[5..7], [ImmI8 as u8, 0xfe]);
+
+        let mut vm = VM::new(chunk);
+        vm.execute_all().unwrap();
+        assert_eq!(vm.stack(), values.map(Value::Integer));
+
+        let mut b = ChunkBuilder::new();
+        let values = [0, 0xff, 0x100, 0xffff, 0x1_0000, u64::MAX];
+        for &w in &values {
+            b.imm_w(w);
+        }
+        let chunk = b.build().unwrap();
+        let ops: Vec<_> = chunk
+            .instructions()
+            .map(|r| r.unwrap().1.opcode())
+            .collect();
+        assert_eq!(ops, [ImmW8, ImmW8, ImmW16, ImmW16, ImmW, ImmW]);
+
+        let mut vm = VM::new(chunk);
+        vm.execute_all().unwrap();
+        assert_eq!(vm.stack(), values.map(Value::Word));
+    }
+
+    #[test]
+    fn test_unbound_label() {
+        let mut b = ChunkBuilder::new();
+        let label = b.label();
+        b.goto(label);
+        assert_eq!(b.build(), Err(BuildError::UnboundLabel));
+    }
+
+    #[test]
+    fn test_checked_immediates() {
+        let mut b = ChunkBuilder::new();
+        b.imm_i8(-128).imm_i16(300).imm_i32(-70000).imm_i32(1);
+        let chunk = b.build().unwrap();
+        let ops: Vec<_> = chunk
+            .instructions()
+            .map(|r| r.unwrap().1.opcode())
+            .collect();
+        assert_eq!(ops, [ImmI8, ImmI16, ImmI, Imm1]);
+        assert_eq!(chunk.code()[..2], [ImmI8 as u8, 0x80]);
+
+        let mut b = ChunkBuilder::new();
+        b.imm_i8(127).imm_i8(128).imm_i16(-40000);
+        let err = b.build().unwrap_err();
+        assert_eq!(
+            err,
+            BuildError::ImmediateOutOfRange {
+                value: 128,
+                bits: 8
+            }
+        );
+        assert_eq!(err.to_string(), "immediate 128 does not fit in an i8");
+        let err = ChunkBuilder::new().imm_i32(1 << 31).build().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "immediate 2147483648 does not fit in an i32"
+        );
+    }
+
+    #[test]
+    fn test_wide_i32_immediates() {
+        let mut b = ChunkBuilder::new();
+        let head = b.label();
+        b.imm_i(1 << 40).imm_i(2).op(AddI32);
+        let wide = b.len();
+        b.imm_i(i64::MIN).store(0);
+        b.imm_i(-(1 << 33)).op(SubI32);
+        // through a local or across a label the value isn't followed
+        b.load(0).imm_i(3).op(MulI32);
+        b.imm_i(1 << 35).bind(head).imm_i(4).op(DivI32);
+        b.imm_i(i32::MAX as i64).imm_i(i32::MIN as i64).op(AddI32);
+        assert_eq!(
+            b.wide_i32_immediates(),
+            [(0, 1 << 40), (wide + 12, -(1 << 33))]
+        );
+    }
+}
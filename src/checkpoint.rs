@@ -0,0 +1,764 @@
+use crate::chunk::Chunk;
+use crate::error::CheckpointError;
+use crate::heap::{Heap, Object, ObjectPtr};
+use crate::instruction::Instruction;
+use crate::map::{MapIndex, MapKey};
+use crate::value::Value;
+use std::collections::{HashMap, HashSet};
+
+const MAGIC: &[u8; 4] = b"ANDC";
+const VERSION: u8 = 1;
+
+mod value_tag {
+    pub const INTEGER: u8 = 0;
+    pub const WORD: u8 = 1;
+    pub const FLOAT: u8 = 2;
+    pub const CHAR: u8 = 3;
+    pub const NULL: u8 = 4;
+    pub const OBJECT: u8 = 5;
+    // Only map keys are strings by contents.
+    pub const STR: u8 = 6;
+}
+
+// A value with its object, if it is one, replaced by the object's index in
+// the checkpoint's object table.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Saved {
+    // Never an object pointer.
+    Plain(Value),
+    Object(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct SavedObject {
+    tag: u8,
+    fields: Vec<Saved>,
+    interned: bool,
+    // The keys in entry order and the version.
+    map: Option<(Vec<MapKey>, u64)>,
+    // Set only while the target is in the table.
+    weak: Option<usize>,
+    buffer: Option<Box<[u8]>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct SavedFrame {
+    pub(crate) return_ip: usize,
+    pub(crate) locals: Vec<Option<Saved>>,
+    pub(crate) function: Option<u16>,
+    pub(crate) base: usize,
+}
+
+// A paused run, as `VM::checkpoint` takes it for `VM::restore` to pick up,
+// possibly in another process: the ip, the stack, every frame's locals, the
+// rng and the objects they lead to, along with the content hash of the
+// chunk it ran. Natives, hooks and the rest of the host's configuration
+// aren't part of it, and neither are objects only the host has rooted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    pub(crate) chunk_hash: [u8; 32],
+    pub(crate) ip: usize,
+    pub(crate) instruction_ip: usize,
+    pub(crate) function: Option<u16>,
+    pub(crate) rng: u64,
+    pub(crate) stack: Vec<Saved>,
+    pub(crate) locals: Vec<Option<Saved>>,
+    pub(crate) frames: Vec<SavedFrame>,
+    pub(crate) objects: Vec<SavedObject>,
+}
+
+impl Checkpoint {
+    pub fn chunk_hash(&self) -> [u8; 32] {
+        self.chunk_hash
+    }
+
+    pub fn objects(&self) -> usize {
+        self.objects.len()
+    }
+
+    // Layout, all integers big-endian:
+    //   magic, version: u8
+    //   chunk hash: 32 bytes
+    //   ip, instruction ip: u64 each
+    //   function: u8 flag, then a u16 when set
+    //   rng: u64
+    //   objects: u64 count, each a tag byte, a u8 interned flag, a u64
+    //     count of fields, a u8 flag followed by a u64 version and a u64
+    //     count of keys for a map, a u8 flag followed by a u64 index for a
+    //     weak reference whose target is in the table, and a u8 flag
+    //     followed by a u64 length and the bytes for a buffer
+    //   stack: u64 count of values
+    //   locals: u64 count, each a u8 flag followed by a value when set
+    //   frames: u64 count, each a u64 return ip, the function as above, a
+    //     u64 base and the locals as above
+    // Values are a tag byte and their payload, an object's being its u64
+    // index in the table. Map keys are tagged the same way, strings with a
+    // u64 length and UTF-8 bytes.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        out.extend(self.chunk_hash);
+        put_len(&mut out, self.ip);
+        put_len(&mut out, self.instruction_ip);
+        put_function(&mut out, self.function);
+        out.extend(self.rng.to_be_bytes());
+
+        put_len(&mut out, self.objects.len());
+        for obj in &self.objects {
+            out.extend([obj.tag, obj.interned as u8]);
+            put_values(&mut out, &obj.fields);
+            match &obj.map {
+                Some((keys, version)) => {
+                    out.push(1);
+                    out.extend(version.to_be_bytes());
+                    put_len(&mut out, keys.len());
+                    keys.iter().for_each(|key| put_key(&mut out, key));
+                }
+                None => out.push(0),
+            }
+            match obj.weak {
+                Some(target) => {
+                    out.push(1);
+                    put_len(&mut out, target);
+                }
+                None => out.push(0),
+            }
+            match &obj.buffer {
+                Some(bytes) => {
+                    out.push(1);
+                    put_len(&mut out, bytes.len());
+                    out.extend(bytes.iter());
+                }
+                None => out.push(0),
+            }
+        }
+
+        put_values(&mut out, &self.stack);
+        put_locals(&mut out, &self.locals);
+        put_len(&mut out, self.frames.len());
+        for frame in &self.frames {
+            put_len(&mut out, frame.return_ip);
+            put_function(&mut out, frame.function);
+            put_len(&mut out, frame.base);
+            put_locals(&mut out, &frame.locals);
+        }
+        out
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, CheckpointError> {
+        let mut r = Reader(bytes);
+        if r.take(MAGIC.len())? != MAGIC {
+            return Err(CheckpointError::BadMagic);
+        }
+        let version = r.u8()?;
+        if version != VERSION {
+            return Err(CheckpointError::UnsupportedVersion(version));
+        }
+        let chunk_hash = r.array()?;
+        let ip = r.len()?;
+        let instruction_ip = r.len()?;
+        let function = r.function()?;
+        let rng = r.u64()?;
+
+        let count = r.len()?;
+        let mut objects = Vec::with_capacity(count.min(r.0.len()));
+        for _ in 0..count {
+            let tag = r.u8()?;
+            let interned = r.u8()? != 0;
+            let fields = r.values()?;
+            let map = match r.u8()? {
+                0 => None,
+                _ => {
+                    let version = r.u64()?;
+                    let count = r.len()?;
+                    let mut keys = Vec::with_capacity(count.min(r.0.len()));
+                    for _ in 0..count {
+                        keys.push(r.key()?);
+                    }
+                    Some((keys, version))
+                }
+            };
+            let weak = match r.u8()? {
+                0 => None,
+                _ => Some(r.len()?),
+            };
+            let buffer = match r.u8()? {
+                0 => None,
+                _ => {
+                    let len = r.len()?;
+                    Some(r.take(len)?.into())
+                }
+            };
+            objects.push(SavedObject {
+                tag,
+                fields,
+                interned,
+                map,
+                weak,
+                buffer,
+            });
+        }
+
+        let stack = r.values()?;
+        let locals = r.locals()?;
+        let count = r.len()?;
+        let mut frames = Vec::with_capacity(count.min(r.0.len()));
+        for _ in 0..count {
+            frames.push(SavedFrame {
+                return_ip: r.len()?,
+                function: r.function()?,
+                base: r.len()?,
+                locals: r.locals()?,
+            });
+        }
+
+        if !r.0.is_empty() {
+            return Err(CheckpointError::TrailingBytes(r.0.len()));
+        }
+        let checkpoint = Self {
+            chunk_hash,
+            ip,
+            instruction_ip,
+            function,
+            rng,
+            stack,
+            locals,
+            frames,
+            objects,
+        };
+        checkpoint.check()?;
+        Ok(checkpoint)
+    }
+
+    // Every index has to name an object in the table, and every map's keys
+    // its entries, one key each.
+    fn check(&self) -> Result<(), CheckpointError> {
+        let count = self.objects.len();
+        let check_index = |index: usize| match index < count {
+            true => Ok(()),
+            false => Err(CheckpointError::InvalidObject(index as u64)),
+        };
+        let frames = self.frames.iter().flat_map(|frame| &frame.locals);
+        let locals = self.locals.iter().chain(frames).flatten();
+        let fields = self.objects.iter().flat_map(|obj| &obj.fields);
+        for saved in self.stack.iter().chain(locals).chain(fields) {
+            if let Saved::Object(index) = *saved {
+                check_index(index)?;
+            }
+        }
+
+        for (index, obj) in self.objects.iter().enumerate() {
+            if let Some(target) = obj.weak {
+                check_index(target)?;
+            }
+            if let Some((keys, _)) = &obj.map {
+                let unique: HashSet<_> = keys.iter().collect();
+                if unique.len() != keys.len() || 2 * keys.len() != obj.fields.len() {
+                    return Err(CheckpointError::InvalidMap(index as u64));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // What only the chunk being restored into can tell: that every saved
+    // function is one of its functions, that every frame returns to just
+    // after a `Call`, and that the frames' bases climb no higher than the
+    // stack.
+    pub(crate) fn check_frames(&self, chunk: &Chunk) -> Result<(), CheckpointError> {
+        let functions = chunk.functions().len();
+        let saved = self.frames.iter().map(|frame| frame.function);
+        for function in saved.chain([self.function]).flatten() {
+            if function as usize >= functions {
+                return Err(CheckpointError::UnknownFunction(function));
+            }
+        }
+
+        let call_len = Instruction::Call(0).encoded_len();
+        let mut base = 0;
+        for (index, frame) in self.frames.iter().enumerate() {
+            let call = frame
+                .return_ip
+                .checked_sub(call_len)
+                .map(|ip| Instruction::decode(chunk.code(), ip));
+            let returns_to_call = matches!(call, Some(Ok(Instruction::Call(_))));
+            if !returns_to_call || frame.base < base || frame.base > self.stack.len() {
+                return Err(CheckpointError::InvalidFrame(index as u64));
+            }
+            base = frame.base;
+        }
+        Ok(())
+    }
+
+    // Allocates the object table into `heap`, which mustn't collect in the
+    // meantime, and returns the pointers it got by index. Every object is
+    // allocated before any fields are set, so cycles come back as they were.
+    pub(crate) fn restore_objects(&self, heap: &mut Heap) -> Vec<ObjectPtr> {
+        let ptrs: Vec<_> = self
+            .objects
+            .iter()
+            .map(|saved| {
+                let mut obj = Object::new(saved.tag, Vec::new());
+                obj.map_index = saved
+                    .map
+                    .clone()
+                    .map(|(keys, version)| Box::new(MapIndex::from_keys(keys, version)));
+                obj.buffer = saved.buffer.clone();
+                heap.new_object(obj)
+            })
+            .collect();
+
+        for (saved, &ptr) in self.objects.iter().zip(&ptrs) {
+            heap.get_mut(ptr).fields = restored_values(&ptrs, &saved.fields);
+            heap.recharge(ptr);
+            if let Some(target) = saved.weak {
+                heap.set_weak(ptr, ptrs[target]);
+            }
+            if saved.interned {
+                heap.intern(ptr);
+            }
+        }
+        ptrs
+    }
+}
+
+// Numbers the objects reachable from the values it is built from, oldest
+// first. Weak references aren't followed, so a target nothing else leads
+// to is left out, as the next collection would clear it anyway.
+pub(crate) struct ObjectTable {
+    ptrs: Vec<ObjectPtr>,
+    index: HashMap<ObjectPtr, usize>,
+}
+
+impl ObjectTable {
+    pub(crate) fn new<'a>(heap: &Heap, values: impl Iterator<Item = &'a Value>) -> Self {
+        let mut pending: Vec<_> = values.filter_map(Value::get_object_ptr).collect();
+        let mut seen = HashSet::new();
+        let mut ptrs = Vec::new();
+        while let Some(ptr) = pending.pop() {
+            if seen.insert(ptr) {
+                ptrs.push(ptr);
+                pending.extend(
+                    heap.object(ptr)
+                        .fields
+                        .iter()
+                        .filter_map(Value::get_object_ptr),
+                );
+            }
+        }
+        ptrs.sort_by_key(|ptr| ptr.id());
+        let index = ptrs.iter().copied().zip(0..).collect();
+        Self { ptrs, index }
+    }
+
+    pub(crate) fn save(&self, val: Value) -> Saved {
+        match val {
+            Value::ObjectPtr(ptr) => Saved::Object(self.index[&ptr]),
+            val => Saved::Plain(val),
+        }
+    }
+
+    pub(crate) fn save_values(&self, values: &[Value]) -> Vec<Saved> {
+        values.iter().map(|&val| self.save(val)).collect()
+    }
+
+    pub(crate) fn save_locals(&self, locals: &[Option<Value>]) -> Vec<Option<Saved>> {
+        locals
+            .iter()
+            .map(|local| local.map(|val| self.save(val)))
+            .collect()
+    }
+
+    pub(crate) fn objects(&self, heap: &Heap) -> Vec<SavedObject> {
+        let saved = |&ptr: &ObjectPtr| {
+            let obj = heap.object(ptr);
+            let map = obj.map_index.as_deref();
+            SavedObject {
+                tag: obj.tag,
+                fields: self.save_values(&obj.fields),
+                interned: ptr.node().is_interned(),
+                map: map.map(|index| (index.keys().to_vec(), index.version())),
+                weak: obj.weak.and_then(|target| self.index.get(&target).copied()),
+                buffer: obj.buffer.clone(),
+            }
+        };
+        self.ptrs.iter().map(saved).collect()
+    }
+}
+
+pub(crate) fn restored(ptrs: &[ObjectPtr], saved: Saved) -> Value {
+    match saved {
+        Saved::Plain(val) => val,
+        Saved::Object(index) => Value::ObjectPtr(ptrs[index]),
+    }
+}
+
+pub(crate) fn restored_values(ptrs: &[ObjectPtr], values: &[Saved]) -> Vec<Value> {
+    values.iter().map(|&saved| restored(ptrs, saved)).collect()
+}
+
+pub(crate) fn restored_locals(ptrs: &[ObjectPtr], locals: &[Option<Saved>]) -> Vec<Option<Value>> {
+    let restore = |local: &Option<Saved>| local.map(|saved| restored(ptrs, saved));
+    locals.iter().map(restore).collect()
+}
+
+fn put_len(out: &mut Vec<u8>, len: usize) {
+    out.extend((len as u64).to_be_bytes());
+}
+
+fn put_function(out: &mut Vec<u8>, function: Option<u16>) {
+    match function {
+        Some(index) => {
+            out.push(1);
+            out.extend(index.to_be_bytes());
+        }
+        None => out.push(0),
+    }
+}
+
+fn put_value(out: &mut Vec<u8>, saved: &Saved) {
+    let val = match saved {
+        Saved::Plain(val) => val,
+        Saved::Object(index) => {
+            out.push(value_tag::OBJECT);
+            put_len(out, *index);
+            return;
+        }
+    };
+    match val {
+        Value::Integer(i) => {
+            out.push(value_tag::INTEGER);
+            out.extend(i.to_be_bytes());
+        }
+        Value::Word(w) => {
+            out.push(value_tag::WORD);
+            out.extend(w.to_be_bytes());
+        }
+        Value::Float(f) => {
+            out.push(value_tag::FLOAT);
+            out.extend(f.to_bits().to_be_bytes());
+        }
+        Value::Char(c) => {
+            out.push(value_tag::CHAR);
+            out.extend((*c as u32).to_be_bytes());
+        }
+        Value::Null => out.push(value_tag::NULL),
+        Value::ObjectPtr(_) => unreachable!("objects are saved by index"),
+    }
+}
+
+fn put_values(out: &mut Vec<u8>, values: &[Saved]) {
+    put_len(out, values.len());
+    values.iter().for_each(|saved| put_value(out, saved));
+}
+
+fn put_locals(out: &mut Vec<u8>, locals: &[Option<Saved>]) {
+    put_len(out, locals.len());
+    for local in locals {
+        match local {
+            Some(saved) => {
+                out.push(1);
+                put_value(out, saved);
+            }
+            None => out.push(0),
+        }
+    }
+}
+
+fn put_key(out: &mut Vec<u8>, key: &MapKey) {
+    match key {
+        MapKey::Char(c) => put_value(out, &Saved::Plain(Value::Char(*c))),
+        MapKey::Integer(i) => put_value(out, &Saved::Plain(Value::Integer(*i))),
+        MapKey::Word(w) => put_value(out, &Saved::Plain(Value::Word(*w))),
+        MapKey::Str(s) => {
+            out.push(value_tag::STR);
+            put_len(out, s.len());
+            out.extend(s.as_bytes());
+        }
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], CheckpointError> {
+        if n > self.0.len() {
+            return Err(CheckpointError::Truncated);
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], CheckpointError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, CheckpointError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u64(&mut self) -> Result<u64, CheckpointError> {
+        Ok(u64::from_be_bytes(self.array()?))
+    }
+
+    // A length too large for a `usize` can't fit in what's left either.
+    fn len(&mut self) -> Result<usize, CheckpointError> {
+        usize::try_from(self.u64()?).map_err(|_| CheckpointError::Truncated)
+    }
+
+    fn function(&mut self) -> Result<Option<u16>, CheckpointError> {
+        Ok(match self.u8()? {
+            0 => None,
+            _ => Some(u16::from_be_bytes(self.array()?)),
+        })
+    }
+
+    fn char(&mut self) -> Result<char, CheckpointError> {
+        let c = u32::from_be_bytes(self.array()?);
+        char::from_u32(c).ok_or(CheckpointError::InvalidChar(c))
+    }
+
+    fn value(&mut self) -> Result<Saved, CheckpointError> {
+        Ok(Saved::Plain(match self.u8()? {
+            value_tag::INTEGER => Value::Integer(self.u64()? as i64),
+            value_tag::WORD => Value::Word(self.u64()?),
+            value_tag::FLOAT => Value::Float(f64::from_bits(self.u64()?)),
+            value_tag::CHAR => Value::Char(self.char()?),
+            value_tag::NULL => Value::Null,
+            value_tag::OBJECT => return Ok(Saved::Object(self.len()?)),
+            tag => return Err(CheckpointError::InvalidValue(tag)),
+        }))
+    }
+
+    fn values(&mut self) -> Result<Vec<Saved>, CheckpointError> {
+        let count = self.len()?;
+        let mut values = Vec::with_capacity(count.min(self.0.len()));
+        for _ in 0..count {
+            values.push(self.value()?);
+        }
+        Ok(values)
+    }
+
+    fn locals(&mut self) -> Result<Vec<Option<Saved>>, CheckpointError> {
+        let count = self.len()?;
+        let mut locals = Vec::with_capacity(count.min(self.0.len()));
+        for _ in 0..count {
+            locals.push(match self.u8()? {
+                0 => None,
+                _ => Some(self.value()?),
+            });
+        }
+        Ok(locals)
+    }
+
+    fn key(&mut self) -> Result<MapKey, CheckpointError> {
+        Ok(match self.u8()? {
+            value_tag::INTEGER => MapKey::Integer(self.u64()? as i64),
+            value_tag::WORD => MapKey::Word(self.u64()?),
+            value_tag::CHAR => MapKey::Char(self.char()?),
+            value_tag::STR => {
+                let len = self.len()?;
+                let s = std::str::from_utf8(self.take(len)?)
+                    .map_err(|_| CheckpointError::InvalidUtf8)?;
+                MapKey::Str(s.to_string())
+            }
+            tag => return Err(CheckpointError::InvalidValue(tag)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::{self, ChunkBuilder};
+    use crate::error::VmError;
+    use crate::heap::tag;
+    use crate::native::Native;
+    use crate::opcode::OpCode;
+    use crate::vm::VM;
+    use std::rc::Rc;
+
+    // Through the bytes, as a checkpoint kept on disk would be.
+    fn round_trip(vm: &VM) -> Checkpoint {
+        let checkpoint = vm.checkpoint().unwrap();
+        let bytes = checkpoint.serialize();
+        assert_eq!(Checkpoint::deserialize(&bytes).as_ref(), Ok(&checkpoint));
+        checkpoint
+    }
+
+    #[test]
+    fn test_resume_factorial() {
+        let chunk = builder::tests::factorial(5).build().unwrap();
+        let mut vm = VM::new(chunk.clone());
+        vm.set_fuel(Some(20)).unwrap();
+        assert_eq!(vm.execute_all(), Err(VmError::FuelExhausted));
+        // partway through the loop
+        assert!(matches!(vm.local(0), Some(Value::Integer(2..=4))));
+        let checkpoint = round_trip(&vm);
+        assert_eq!(checkpoint.chunk_hash(), chunk.content_hash());
+
+        let mut resumed = VM::new(chunk);
+        resumed.restore(&checkpoint).unwrap();
+        assert_eq!(resumed.ip(), vm.ip());
+        assert_eq!(resumed.locals(), vm.locals());
+        resumed.execute_all().unwrap();
+        assert_eq!(resumed.stack(), [Value::Integer(120)]);
+    }
+
+    #[test]
+    fn test_wrong_chunk() {
+        let mut vm = VM::new(builder::tests::factorial(5).build().unwrap());
+        vm.set_fuel(Some(20)).unwrap();
+        assert!(vm.execute_all().is_err());
+        let checkpoint = round_trip(&vm);
+
+        let mut other = VM::new(builder::tests::factorial(4).build().unwrap());
+        assert_eq!(other.restore(&checkpoint), Err(VmError::HashMismatch));
+        assert_eq!(other.ip(), 0);
+        assert!(other.locals().iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn test_heap_graph() {
+        let mut vm = VM::default();
+        let a = vm.alloc_object(1, vec![Value::Null]);
+        let b = vm.alloc_object(2, vec![Value::ObjectPtr(a), Value::Integer(7)]);
+        vm.set_object_field(a, 0, Value::ObjectPtr(b)).unwrap();
+        let map = vm.alloc_map();
+        let key = vm.alloc_string("key");
+        vm.map_set(map, Value::ObjectPtr(key), Value::ObjectPtr(b))
+            .unwrap();
+        vm.map_set(map, Value::Word(3), Value::Float(1.5)).unwrap();
+        let buffer = vm.alloc_buffer(vec![1, 2, 3]);
+        let weak = vm.alloc(Object::weak(a));
+        let unreachable = vm.alloc_object(3, Vec::new());
+        let dangling = vm.alloc(Object::weak(unreachable));
+        let interned = vm.intern_string("λ");
+        for ptr in [a, map, buffer, weak, dangling, interned] {
+            vm.push(Value::ObjectPtr(ptr));
+        }
+        let checkpoint = round_trip(&vm);
+        // all but the weak reference's lone target
+        assert_eq!(checkpoint.objects(), 8);
+
+        let mut restored = VM::default();
+        restored.restore(&checkpoint).unwrap();
+        let stack = restored.stack().to_vec();
+        assert_eq!(stack.len(), 6);
+        for (original, restored_val) in vm.stack().iter().zip(&stack) {
+            assert!(original.deep_eq_across(vm.heap(), restored_val, restored.heap()));
+        }
+        let heap = restored.heap();
+        let ptrs: Vec<_> = stack
+            .iter()
+            .map(|val| val.get_object_ptr().unwrap())
+            .collect();
+        let b = heap.get(ptrs[0]).fields[0];
+        assert_eq!(heap.get(b.get_object_ptr().unwrap()).fields[0], stack[0]);
+        assert_eq!(heap.get(ptrs[3]).weak_target(), Some(Some(ptrs[0])));
+        assert_eq!(heap.get(ptrs[4]).weak_target(), Some(None));
+        assert_eq!(heap.get(ptrs[4]).tag, tag::WEAK);
+        assert_eq!(heap.lookup_interned("λ"), Some(ptrs[5]));
+
+        let key = restored.alloc_string("key");
+        let map = ptrs[1];
+        let found = restored.map_get(map, Value::ObjectPtr(key)).unwrap();
+        assert_eq!(found, Some(b));
+        assert_eq!(
+            restored.map_get(map, Value::Word(3)).unwrap(),
+            Some(Value::Float(1.5))
+        );
+        restored.unroot(key);
+        restored.collect_garbage();
+        assert_eq!(restored.heap().len(), 8);
+        assert_eq!(
+            restored.heap().get(ptrs[3]).weak_target(),
+            Some(Some(ptrs[0]))
+        );
+    }
+
+    #[test]
+    fn test_natives_cannot_checkpoint() {
+        let mut vm = VM::new(ChunkBuilder::new().call_native("save").build().unwrap());
+        let save: Native = Rc::new(|vm| vm.checkpoint().map(drop));
+        vm.register_native("save", save).unwrap();
+        assert_eq!(vm.execute_all(), Err(VmError::ReconfiguredWhileRunning));
+    }
+
+    #[test]
+    fn test_malformed() {
+        let bytes = VM::default().checkpoint().unwrap().serialize();
+        assert_eq!(
+            Checkpoint::deserialize(b"ANDR"),
+            Err(CheckpointError::BadMagic)
+        );
+        assert_eq!(
+            Checkpoint::deserialize(&bytes[..bytes.len() - 1]),
+            Err(CheckpointError::Truncated)
+        );
+        assert_eq!(
+            Checkpoint::deserialize(&[&bytes[..], &[0]].concat()),
+            Err(CheckpointError::TrailingBytes(1))
+        );
+
+        let mut checkpoint = Checkpoint::deserialize(&bytes).unwrap();
+        checkpoint.stack.push(Saved::Object(3));
+        assert_eq!(
+            Checkpoint::deserialize(&checkpoint.serialize()),
+            Err(CheckpointError::InvalidObject(3))
+        );
+
+        // paused inside a call, for what only restoring can catch
+        let mut b = ChunkBuilder::new();
+        let entry = b.label();
+        let f = b.function(entry, 0);
+        b.imm_i(1).call(f).op(OpCode::Return);
+        b.bind(entry)
+            .op(OpCode::Nop)
+            .op(OpCode::Nop)
+            .op(OpCode::Return);
+        let chunk = b.build().unwrap();
+        let mut vm = VM::new(chunk.clone());
+        vm.set_fuel(Some(3)).unwrap();
+        assert_eq!(vm.execute_all(), Err(VmError::FuelExhausted));
+        let checkpoint = round_trip(&vm);
+        assert_eq!(checkpoint.frames.len(), 1);
+
+        let invalid = |err| Err(VmError::InvalidCheckpoint(err));
+        let mut vm = VM::new(chunk);
+        let mut bad = checkpoint.clone();
+        bad.function = Some(0xFFFF);
+        assert_eq!(
+            vm.restore(&bad),
+            invalid(CheckpointError::UnknownFunction(0xFFFF))
+        );
+        let mut bad = checkpoint.clone();
+        bad.frames[0].function = Some(1);
+        assert_eq!(
+            vm.restore(&bad),
+            invalid(CheckpointError::UnknownFunction(1))
+        );
+        let mut bad = checkpoint.clone();
+        bad.frames[0].return_ip = 0;
+        assert_eq!(vm.restore(&bad), invalid(CheckpointError::InvalidFrame(0)));
+        let mut bad = checkpoint.clone();
+        bad.frames[0].return_ip -= 1;
+        assert_eq!(vm.restore(&bad), invalid(CheckpointError::InvalidFrame(0)));
+        let mut bad = checkpoint.clone();
+        bad.frames[0].base = bad.stack.len() + 1;
+        assert_eq!(vm.restore(&bad), invalid(CheckpointError::InvalidFrame(0)));
+        let mut bad = checkpoint.clone();
+        let mut lower = bad.frames[0].clone();
+        lower.base -= 1;
+        bad.frames.push(lower);
+        assert_eq!(vm.restore(&bad), invalid(CheckpointError::InvalidFrame(1)));
+
+        // and none of them touched the vm
+        assert_eq!(vm.ip(), 0);
+        vm.restore(&checkpoint).unwrap();
+        vm.set_function_profiling(true).unwrap();
+        assert_eq!(vm.backtrace().0.len(), 2);
+        vm.execute_all().unwrap();
+    }
+}
@@ -0,0 +1,337 @@
+use crate::error::PatchError;
+use crate::instruction::Instruction;
+use crate::opcode::OpCode;
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Chunk {
+    code: Code,
+    boundaries: Vec<bool>,
+    max_locals: Option<u16>,
+    constants: Vec<Constant>,
+    functions: Vec<Function>,
+    // Functions other chunks may call by name once linked with this one.
+    exports: Vec<(String, u16)>,
+    // Functions this chunk calls by name, resolved by `link::link`.
+    imports: Vec<String>,
+    features: u32,
+}
+
+// Lengths are serialized as u32s, so the VM refuses to run a longer chunk.
+// A policy may set a lower limit.
+pub const MAX_LEN: usize = u32::MAX as usize;
+
+// How far a frame may grow when the chunk doesn't declare its size, so a
+// store to a high local can't allocate every slot below it.
+pub const MAX_UNDECLARED_LOCALS: u16 = 1024;
+
+// Bytes that outlive any chunk borrowing from them, such as a mapped file.
+pub type Image = Arc<dyn AsRef<[u8]> + Send + Sync>;
+
+// The code section, either owned or borrowed from the image it was
+// deserialized from. Patching borrowed code copies it first.
+#[derive(Clone)]
+enum Code {
+    Owned(Vec<u8>),
+    Static(&'static [u8]),
+    Shared(Image, Range<usize>),
+}
+
+impl Code {
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            Self::Owned(code) => code,
+            Self::Static(code) => code,
+            Self::Shared(image, range) => &(**image).as_ref()[range.clone()],
+        }
+    }
+
+    fn to_mut(&mut self) -> &mut Vec<u8> {
+        if !matches!(self, Self::Owned(_)) {
+            *self = Self::Owned(self.as_slice().to_vec());
+        }
+        match self {
+            Self::Owned(code) => code,
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Default for Code {
+    fn default() -> Self {
+        Self::Owned(Vec::new())
+    }
+}
+
+impl PartialEq for Code {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl fmt::Debug for Code {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.as_slice().fmt(f)
+    }
+}
+
+// Bits of the features mask a chunk declares, so that a VM can refuse a
+// chunk it can't run before executing any of it.
+pub mod feature {
+    pub const OBJECTS: u32 = 1 << 0;
+    pub const NATIVES: u32 = 1 << 1;
+    pub const DYNAMIC_JUMPS: u32 = 1 << 2;
+    // Jumps with targets past 16 bits, which this VM doesn't implement yet.
+    pub const WIDE_JUMPS: u32 = 1 << 3;
+
+    pub const SUPPORTED: u32 = OBJECTS | NATIVES | DYNAMIC_JUMPS;
+}
+
+// Literals referenced by `LoadConst`. Strings are allocated afresh each time
+// they are loaded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Constant {
+    Integer(i64),
+    Word(u64),
+    Float(f64),
+    Char(char),
+    Str(String),
+}
+
+// Entry of the function table targeted by `Call`. The arguments are popped
+// into the callee's first `arity` locals. When `returns` is declared, the
+// callee must return exactly that many values, which is checked on return
+// and by `verifier::verify_stack`. The name and `locals`, which names some
+// of the callee's local slots, are only for debugging.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Function {
+    pub entry: usize,
+    pub arity: u8,
+    pub returns: Option<u8>,
+    pub name: Option<String>,
+    pub locals: Vec<(u16, String)>,
+}
+
+impl Function {
+    pub fn new(entry: usize, arity: u8) -> Self {
+        Self {
+            entry,
+            arity,
+            returns: None,
+            name: None,
+            locals: Vec::new(),
+        }
+    }
+
+    pub fn local_name(&self, slot: u16) -> Option<&str> {
+        self.locals
+            .iter()
+            .find(|(named, _)| *named == slot)
+            .map(|(_, name)| name.as_str())
+    }
+}
+
+impl Chunk {
+    pub fn new(code: Vec<u8>) -> Self {
+        Self::from_code(Code::Owned(code))
+    }
+
+    // Executes straight from `code`, which is never copied unless patched.
+    pub fn from_static(code: &'static [u8]) -> Self {
+        Self::from_code(Code::Static(code))
+    }
+
+    // As `from_static`, for the `range` of an image.
+    pub fn from_image(image: Image, range: Range<usize>) -> Self {
+        Self::from_code(Code::Shared(image, range))
+    }
+
+    fn from_code(code: Code) -> Self {
+        Self {
+            boundaries: boundaries(code.as_slice()),
+            code,
+            max_locals: None,
+            constants: Vec::new(),
+            functions: Vec::new(),
+            exports: Vec::new(),
+            imports: Vec::new(),
+            features: 0,
+        }
+    }
+
+    // Declares the size of the locals frame. Without a declaration the
+    // frame grows on demand as locals are stored, up to
+    // `MAX_UNDECLARED_LOCALS`.
+    pub fn with_max_locals(mut self, max_locals: u16) -> Self {
+        self.max_locals = Some(max_locals);
+        self
+    }
+
+    pub fn with_constants(mut self, constants: Vec<Constant>) -> Self {
+        self.constants = constants;
+        self
+    }
+
+    pub fn with_functions(mut self, functions: Vec<Function>) -> Self {
+        self.functions = functions;
+        self
+    }
+
+    // Each export names an index into the function table.
+    pub fn with_exports(mut self, exports: Vec<(String, u16)>) -> Self {
+        self.exports = exports;
+        self
+    }
+
+    pub fn with_imports(mut self, imports: Vec<String>) -> Self {
+        self.imports = imports;
+        self
+    }
+
+    // Declares the features the chunk needs, a mask of `feature` bits.
+    pub fn with_features(mut self, features: u32) -> Self {
+        self.features = features;
+        self
+    }
+
+    // Bits of `features` which this VM doesn't support.
+    pub const fn unsupported_features(&self) -> u32 {
+        self.features & !feature::SUPPORTED
+    }
+
+    pub fn code(&self) -> &[u8] {
+        self.code.as_slice()
+    }
+
+    // The code as it's serialized and hashed, with the public opcode each
+    // internal one stands in for.
+    pub(crate) fn public_code(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut code = std::borrow::Cow::Borrowed(self.code());
+        for (ip, &boundary) in self.boundaries.iter().enumerate() {
+            match OpCode::try_from(code[ip]) {
+                Ok(op) if boundary && op.is_internal() => code.to_mut()[ip] = op.public() as u8,
+                _ => {}
+            }
+        }
+        code
+    }
+
+    // Whether `ip` is the start of an instruction or the end of the chunk,
+    // the only places a jump may land.
+    pub fn is_boundary(&self, ip: usize) -> bool {
+        ip == self.len() || self.boundaries.get(ip).copied().unwrap_or(false)
+    }
+
+    // Overwrites the instruction at `offset` in place. Shorter replacements
+    // are padded with `Nop`s so that every later offset, and so every jump
+    // target, stays where it was.
+    pub fn patch(&mut self, offset: usize, instruction: Instruction) -> Result<(), PatchError> {
+        if offset >= self.len() || !self.is_boundary(offset) {
+            return Err(PatchError::NotABoundary(offset));
+        }
+        let available = Instruction::decode(self.code(), offset)
+            .map_err(|_| PatchError::NotABoundary(offset))?
+            .encoded_len();
+        let len = instruction.encoded_len();
+        if len > available {
+            return Err(PatchError::TooLong {
+                offset,
+                len,
+                available,
+            });
+        }
+
+        let mut bytes = Vec::with_capacity(available);
+        instruction.encode_into(&mut bytes);
+        bytes.resize(available, OpCode::Nop as u8);
+        self.code.to_mut()[offset..offset + available].copy_from_slice(&bytes);
+        for (i, boundary) in self.boundaries[offset..offset + available]
+            .iter_mut()
+            .enumerate()
+        {
+            *boundary = i == 0 || i >= len;
+        }
+        Ok(())
+    }
+
+    // Adds `code` past the end, leaving the code before it as it was. Jumps
+    // to the old end now land at the start of `code`.
+    pub fn append(&mut self, code: &[u8]) {
+        self.code.to_mut().extend_from_slice(code);
+        self.boundaries.extend(boundaries(code));
+    }
+
+    pub fn len(&self) -> usize {
+        self.code().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.code().is_empty()
+    }
+
+    pub const fn max_locals(&self) -> Option<u16> {
+        self.max_locals
+    }
+
+    pub fn constants(&self) -> &[Constant] {
+        &self.constants
+    }
+
+    pub fn functions(&self) -> &[Function] {
+        &self.functions
+    }
+
+    pub fn exports(&self) -> &[(String, u16)] {
+        &self.exports
+    }
+
+    pub fn imports(&self) -> &[String] {
+        &self.imports
+    }
+
+    // Until linked, `Call` refers to import `i` by the index `u16::MAX - i`,
+    // outside any function table, which the VM refuses to call.
+    pub const fn import_index(import: usize) -> u16 {
+        u16::MAX - import as u16
+    }
+
+    // The name of the import a `Call` index refers to, if any.
+    pub fn import(&self, index: u16) -> Option<&str> {
+        let import = (u16::MAX - index) as usize;
+        let name = self.imports.get(import)?;
+        (index as usize >= self.functions.len()).then_some(name.as_str())
+    }
+
+    pub const fn features(&self) -> u32 {
+        self.features
+    }
+}
+
+impl From<Vec<u8>> for Chunk {
+    fn from(code: Vec<u8>) -> Self {
+        Self::new(code)
+    }
+}
+
+// Decoding stops at the first invalid or truncated instruction; executing
+// it fails anyway.
+fn boundaries(code: &[u8]) -> Vec<bool> {
+    let mut boundaries = vec![false; code.len()];
+    for (ip, _) in opcodes(code) {
+        boundaries[ip] = true;
+    }
+    boundaries
+}
+
+// The opcode at each boundary, up to the first byte that isn't one.
+pub(crate) fn opcodes(code: &[u8]) -> impl Iterator<Item = (usize, OpCode)> + '_ {
+    let mut ip = 0;
+    std::iter::from_fn(move || {
+        let op = OpCode::try_from(*code.get(ip)?).ok()?;
+        let start = ip;
+        ip += 1 + op.operand_len();
+        Some((start, op))
+    })
+}
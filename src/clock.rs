@@ -0,0 +1,44 @@
+use std::fmt;
+use std::time::Instant;
+
+// Monotonic nanosecond readings for the `Clock` instruction. Successive
+// readings must never decrease; what they count from is up to the clock.
+pub trait Clock {
+    fn now(&mut self) -> u64;
+}
+
+// Counts from when it was created.
+#[derive(Debug, Clone, Copy)]
+pub struct MonotonicClock(Instant);
+
+impl MonotonicClock {
+    pub fn new() -> Self {
+        Self(Instant::now())
+    }
+}
+
+impl Default for MonotonicClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn now(&mut self) -> u64 {
+        self.0.elapsed().as_nanos() as u64
+    }
+}
+
+pub(crate) struct VmClock(pub(crate) Box<dyn Clock>);
+
+impl Default for VmClock {
+    fn default() -> Self {
+        Self(Box::new(MonotonicClock::new()))
+    }
+}
+
+impl fmt::Debug for VmClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("VmClock")
+    }
+}
@@ -0,0 +1,363 @@
+use crate::chunk::Chunk;
+use crate::clock::Clock;
+use crate::error::VmError;
+use crate::heap::{Finalizer, GcObserver, HeapMode};
+use crate::hook::{Hook, TrapHandler};
+use crate::native::Native;
+use crate::policy::ExecutionPolicy;
+#[cfg(feature = "profiler")]
+use crate::profile::Profiler;
+use crate::replay::Log;
+use crate::vm::{ExecutionMode, IncrementalGc, VM};
+use std::time::Duration;
+
+// Everything a VM is configured with before it runs. Anything left unset
+// keeps the default of `VM::new`.
+#[derive(Default)]
+pub struct VmBuilder {
+    execution_mode: ExecutionMode,
+    heap_mode: HeapMode,
+    free_list_cap: Option<usize>,
+    gc_stress: bool,
+    incremental_gc: Option<IncrementalGc>,
+    gc_observer: Option<GcObserver>,
+    shadow_checking: bool,
+    canonical_nans: bool,
+    function_profiling: bool,
+    policy: Option<ExecutionPolicy>,
+    max_stack: Option<usize>,
+    fuel: Option<u64>,
+    hooks: Vec<Box<dyn Hook>>,
+    trap_handler: Option<TrapHandler>,
+    natives: Vec<(String, Native)>,
+    finalizers: Vec<(u8, Finalizer)>,
+    clock: Option<Box<dyn Clock>>,
+    rng_seed: Option<u64>,
+    timeout: Option<Duration>,
+    record: bool,
+    replay: Option<Log>,
+    #[cfg(feature = "profiler")]
+    profiler: Option<Profiler>,
+}
+
+impl VmBuilder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn execution_mode(mut self, mode: ExecutionMode) -> Self {
+        self.execution_mode = mode;
+        self
+    }
+
+    pub fn heap_mode(mut self, mode: HeapMode) -> Self {
+        self.heap_mode = mode;
+        self
+    }
+
+    pub fn free_list_cap(mut self, cap: usize) -> Self {
+        self.free_list_cap = Some(cap);
+        self
+    }
+
+    pub fn gc_stress(mut self, stress: bool) -> Self {
+        self.gc_stress = stress;
+        self
+    }
+
+    pub fn incremental_gc(mut self, config: IncrementalGc) -> Self {
+        self.incremental_gc = Some(config);
+        self
+    }
+
+    pub fn gc_observer(mut self, observer: GcObserver) -> Self {
+        self.gc_observer = Some(observer);
+        self
+    }
+
+    pub fn shadow_checking(mut self, enabled: bool) -> Self {
+        self.shadow_checking = enabled;
+        self
+    }
+
+    pub fn canonical_nans(mut self, enabled: bool) -> Self {
+        self.canonical_nans = enabled;
+        self
+    }
+
+    pub fn function_profiling(mut self, enabled: bool) -> Self {
+        self.function_profiling = enabled;
+        self
+    }
+
+    pub fn policy(mut self, policy: ExecutionPolicy) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    // Overrides the policy's stack limit, starting from a permissive policy
+    // if none is set.
+    pub fn max_stack(mut self, max: usize) -> Self {
+        self.max_stack = Some(max);
+        self
+    }
+
+    // Capped by the policy's fuel limit, as with `VM::set_fuel`.
+    pub fn fuel(mut self, fuel: u64) -> Self {
+        self.fuel = Some(fuel);
+        self
+    }
+
+    // Hooks run in the order they were added.
+    pub fn hook(mut self, hook: Box<dyn Hook>) -> Self {
+        self.hooks.push(hook);
+        self
+    }
+
+    pub fn trap_handler(mut self, handler: TrapHandler) -> Self {
+        self.trap_handler = Some(handler);
+        self
+    }
+
+    pub fn native(mut self, name: &str, native: Native) -> Self {
+        self.natives.push((name.to_string(), native));
+        self
+    }
+
+    pub fn finalizer(mut self, tag: u8, finalizer: Finalizer) -> Self {
+        self.finalizers.push((tag, finalizer));
+        self
+    }
+
+    pub fn clock(mut self, clock: Box<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    pub fn rng_seed(mut self, seed: u64) -> Self {
+        self.rng_seed = Some(seed);
+        self
+    }
+
+    // Measured by the configured clock, from when the VM is built.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn record(mut self) -> Self {
+        self.record = true;
+        self
+    }
+
+    // Takes precedence over `record`; the replay is recorded either way.
+    pub fn replay(mut self, log: Log) -> Self {
+        self.replay = Some(log);
+        self
+    }
+
+    #[cfg(feature = "profiler")]
+    pub fn profiler(mut self, profiler: Profiler) -> Self {
+        self.profiler = Some(profiler);
+        self
+    }
+
+    // Fails if the chunk requires features this VM doesn't support.
+    pub fn build(self, chunk: impl Into<Chunk>) -> Result<VM, VmError> {
+        let mut vm = VM::try_new(chunk)?;
+        vm.set_execution_mode(self.execution_mode);
+        vm.set_heap_mode(self.heap_mode);
+        if let Some(cap) = self.free_list_cap {
+            vm.set_free_list_cap(cap)?;
+        }
+        vm.set_gc_stress(self.gc_stress)?;
+        if self.incremental_gc.is_some() {
+            vm.set_incremental_gc(self.incremental_gc)?;
+        }
+        if self.gc_observer.is_some() {
+            vm.set_gc_observer(self.gc_observer)?;
+        }
+        vm.set_shadow_checking(self.shadow_checking)?;
+        vm.set_canonical_nans(self.canonical_nans)?;
+        vm.set_function_profiling(self.function_profiling)?;
+
+        let mut policy = self.policy;
+        if let Some(max) = self.max_stack {
+            policy
+                .get_or_insert_with(ExecutionPolicy::permissive)
+                .max_stack = Some(max);
+        }
+        if policy.is_some() {
+            vm.set_policy(policy)?;
+        }
+        if self.fuel.is_some() {
+            vm.set_fuel(self.fuel)?;
+        }
+
+        for hook in self.hooks {
+            vm.add_hook(hook)?;
+        }
+        if let Some(handler) = self.trap_handler {
+            vm.set_trap_handler(handler)?;
+        }
+        for (name, native) in self.natives {
+            vm.register_native(&name, native)?;
+        }
+        for (tag, finalizer) in self.finalizers {
+            vm.register_finalizer(tag, finalizer)?;
+        }
+        if let Some(clock) = self.clock {
+            vm.set_clock(clock)?;
+        }
+        if let Some(seed) = self.rng_seed {
+            vm.seed_rng(seed)?;
+        }
+        if self.timeout.is_some() {
+            vm.set_timeout(self.timeout)?;
+        }
+        #[cfg(feature = "profiler")]
+        vm.set_profiler(self.profiler)?;
+        match self.replay {
+            Some(log) => vm.replay(log)?,
+            None if self.record => vm.record()?,
+            None => {}
+        }
+        Ok(vm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::ChunkBuilder;
+    use crate::heap::tag;
+    use crate::hook::{HookAction, ResumePoint, TrapDecision, VmView};
+    use crate::opcode::OpCode::{self, *};
+    use crate::value::Value;
+    use crate::workloads;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    struct Tracer(Rc<Cell<u64>>);
+
+    impl Hook for Tracer {
+        fn after_instruction(&mut self, _vm: &VmView, _ip: usize, _op: OpCode) -> HookAction {
+            self.0.set(self.0.get() + 1);
+            HookAction::Continue
+        }
+    }
+
+    struct FixedClock(u64);
+
+    impl crate::clock::Clock for FixedClock {
+        fn now(&mut self) -> u64 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_defaults_match_new() {
+        for workload in workloads::standard() {
+            let mut built = VmBuilder::new().build(workload.chunk.clone()).unwrap();
+            let mut vm = VM::new(workload.chunk);
+            assert_eq!(built.fuel(), vm.fuel());
+            assert_eq!(built.policy(), vm.policy());
+            assert_eq!(built.rng_state(), vm.rng_state());
+            assert_eq!(built.canonical_nans(), vm.canonical_nans());
+            assert_eq!(built.execute_all(), vm.execute_all(), "{}", workload.name);
+        }
+    }
+
+    #[test]
+    fn test_fully_configured() {
+        let traced = Rc::new(Cell::new(0));
+        let configured = || {
+            VmBuilder::new()
+                .policy(ExecutionPolicy {
+                    max_fuel: Some(1000),
+                    ..ExecutionPolicy::permissive()
+                })
+                .max_stack(8)
+                .fuel(40)
+                .hook(Box::new(Tracer(traced.clone())))
+                .trap_handler(Box::new(|_, _| TrapDecision::Recover {
+                    push: Some(Value::Integer(-1)),
+                    resume_at: ResumePoint::NextInstruction,
+                }))
+                .native(
+                    "answer",
+                    Rc::new(|vm: &mut VM| {
+                        vm.push(Value::Integer(42));
+                        Ok(())
+                    }),
+                )
+                .clock(Box::new(FixedClock(7)))
+                .rng_seed(99)
+        };
+
+        // fuel runs out after exactly the configured count
+        let mut vm = configured().build(workloads::countdown(100)).unwrap();
+        assert_eq!(vm.execute_all(), Err(VmError::FuelExhausted));
+        assert_eq!(traced.get(), 40);
+        assert_eq!(vm.policy().and_then(|policy| policy.max_stack), Some(8));
+
+        let mut b = ChunkBuilder::new();
+        for _ in 0..10 {
+            b.op(Imm0);
+        }
+        let mut vm = configured().build(b.build().unwrap()).unwrap();
+        assert_eq!(vm.execute_all(), Err(VmError::StackOverflow));
+
+        // the division traps and is recovered from
+        let mut b = ChunkBuilder::new();
+        b.op(OpCode::Clock).op(Rand).call_native("answer");
+        b.imm_i(1).imm_i(0).op(DivI);
+        let mut vm = configured().build(b.build().unwrap()).unwrap();
+        vm.execute_all().unwrap();
+        let mut seeded = VM::new(ChunkBuilder::new().op(Rand).build().unwrap());
+        seeded.seed_rng(99).unwrap();
+        seeded.execute_all().unwrap();
+        assert_eq!(
+            vm.stack(),
+            [
+                Value::Word(7),
+                seeded.stack()[0],
+                Value::Integer(42),
+                Value::Integer(-1)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_heap_configuration() {
+        // the first map is garbage by the time the third is allocated
+        let mut b = ChunkBuilder::new();
+        for _ in 0..3 {
+            b.op(MapNew).store(0);
+        }
+        let chunk = b.build().unwrap();
+        let finalized = Rc::new(Cell::new(0));
+        let counted = || {
+            let finalized = finalized.clone();
+            Box::new(move |_: &_| finalized.set(finalized.get() + 1))
+        };
+
+        let mut vm = VmBuilder::new()
+            .gc_stress(true)
+            .finalizer(tag::MAP, counted())
+            .build(chunk.clone())
+            .unwrap();
+        vm.execute_all().unwrap();
+        assert_eq!(finalized.get(), 1);
+
+        // arenas are never collected
+        let mut vm = VmBuilder::new()
+            .heap_mode(HeapMode::Arena)
+            .gc_stress(true)
+            .build(chunk)
+            .unwrap();
+        vm.execute_all().unwrap();
+        vm.collect_garbage();
+        assert_eq!(vm.heap().len(), 3);
+    }
+}
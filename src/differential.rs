@@ -0,0 +1,424 @@
+use crate::chunk::Chunk;
+use crate::error::VmError;
+use crate::value::Value;
+use crate::vm::VM;
+use std::fmt;
+
+// Runs that agree for this many instructions are taken to agree, so chunks
+// that never finish still give an answer.
+const MAX_STEPS: usize = 1 << 24;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct State {
+    pub ip: usize,
+    pub stack: Vec<Value>,
+    pub locals: Vec<Option<Value>>,
+    pub error: Option<VmError>,
+}
+
+impl State {
+    fn capture(vm: &VM, result: Result<(), VmError>) -> Self {
+        Self {
+            ip: vm.ip(),
+            stack: vm.stack().to_vec(),
+            locals: vm.locals().to_vec(),
+            error: result.err(),
+        }
+    }
+}
+
+// States of both runs right after the instruction at which they first
+// disagreed, counting from zero.
+// `diff` is the first difference in the stack or locals, if the runs
+// disagreed on those rather than only on the ip or the result.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    pub step: usize,
+    pub a: State,
+    pub b: State,
+    pub diff: Option<StateDiff>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "runs diverged at step {}", self.step)?;
+        if let Some(diff) = &self.diff {
+            writeln!(f, "  {diff}")?;
+        }
+        writeln!(f, "  a: {:?}", self.a)?;
+        write!(f, "  b: {:?}", self.b)
+    }
+}
+
+impl std::error::Error for Divergence {}
+
+// The first place two VMs' stacks or locals differ, with the values as
+// each VM shows them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateDiff {
+    StackLen { a: usize, b: usize },
+    Stack { index: usize, a: String, b: String },
+    LocalsLen { a: usize, b: usize },
+    Local { index: usize, a: String, b: String },
+}
+
+impl fmt::Display for StateDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::StackLen { a, b } => write!(f, "stack depths differ: {a} and {b}"),
+            Self::Stack { index, a, b } => write!(f, "stack slot {index} differs: {a} and {b}"),
+            Self::LocalsLen { a, b } => write!(f, "local counts differ: {a} and {b}"),
+            Self::Local { index, a, b } => write!(f, "local {index} differs: {a} and {b}"),
+        }
+    }
+}
+
+impl std::error::Error for StateDiff {}
+
+impl VM {
+    // Compares the stack and locals of two VMs, objects structurally since
+    // their addresses mean nothing across heaps. The ip, frames and
+    // counters such as fuel are left out.
+    pub fn state_eq(&self, other: &VM) -> Result<(), StateDiff> {
+        let same = |x: &Value, y: &Value| x.deep_eq_across(self.heap(), y, other.heap());
+        let show = |vm: &VM, val: &Value| format!("{:#}", val.display(vm.heap()));
+        let show_local = |vm: &VM, local: &Option<Value>| match local {
+            Some(val) => show(vm, val),
+            None => "uninitialized".to_string(),
+        };
+
+        let (a, b) = (self.stack(), other.stack());
+        if a.len() != b.len() {
+            return Err(StateDiff::StackLen {
+                a: a.len(),
+                b: b.len(),
+            });
+        }
+        if let Some(index) = (0..a.len()).find(|&i| !same(&a[i], &b[i])) {
+            return Err(StateDiff::Stack {
+                index,
+                a: show(self, &a[index]),
+                b: show(other, &b[index]),
+            });
+        }
+
+        let (a, b) = (self.locals(), other.locals());
+        if a.len() != b.len() {
+            return Err(StateDiff::LocalsLen {
+                a: a.len(),
+                b: b.len(),
+            });
+        }
+        let agree = |x: &Option<Value>, y: &Option<Value>| match (x, y) {
+            (Some(x), Some(y)) => same(x, y),
+            (x, y) => x.is_none() && y.is_none(),
+        };
+        match (0..a.len()).find(|&i| !agree(&a[i], &b[i])) {
+            Some(index) => Err(StateDiff::Local {
+                index,
+                a: show_local(self, &a[index]),
+                b: show_local(other, &b[index]),
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+// Executes the chunk on two VMs, each prepared by its own configuration, one
+// instruction at a time, comparing the ip, stack and locals after every
+// step. Objects compare structurally, since the two heaps never share
+// pointers. Hooks and fuel are bypassed while stepping. A chunk the VM
+// refuses to load is refused by both, which counts as agreeing.
+pub fn run_differential(
+    chunk: &Chunk,
+    config_a: impl FnOnce(&mut VM),
+    config_b: impl FnOnce(&mut VM),
+) -> Result<(), Box<Divergence>> {
+    let (Ok(mut a), Ok(mut b)) = (VM::try_new(chunk.clone()), VM::try_new(chunk.clone())) else {
+        return Ok(());
+    };
+    config_a(&mut a);
+    config_b(&mut b);
+
+    for step in 0..MAX_STEPS {
+        if a.eof() && b.eof() {
+            break;
+        }
+        let (result_a, result_b) = (a.execute(), b.execute());
+        let diff = a.state_eq(&b).err();
+        if diff.is_some() || a.ip() != b.ip() || result_a != result_b {
+            return Err(Box::new(Divergence {
+                step,
+                a: State::capture(&a, result_a),
+                b: State::capture(&b, result_b),
+                diff,
+            }));
+        }
+        if result_a.is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::ChunkBuilder;
+    use crate::config::VmBuilder;
+    use crate::heap::HeapMode;
+    use crate::opcode::OpCode::*;
+    use crate::testing::{self, Rng};
+    use crate::vm::{self, ExecutionMode, IncrementalGc};
+    use crate::workloads;
+    use std::rc::Rc;
+
+    fn plain(_: &mut VM) {}
+
+    // Locals are compared structurally at every step, so the arrays are kept
+    // small.
+    fn small_workloads() -> [Chunk; 5] {
+        [
+            workloads::countdown(100),
+            workloads::factorial(10),
+            workloads::fibonacci(10),
+            workloads::object_churn(100),
+            workloads::array_sum(100),
+        ]
+    }
+
+    fn predecoded(chunk: &Chunk) -> impl FnOnce(&mut VM) + '_ {
+        |vm| {
+            *vm = VmBuilder::new()
+                .execution_mode(ExecutionMode::Predecoded)
+                .build(chunk.clone())
+                .unwrap()
+        }
+    }
+
+    #[test]
+    fn test_refused_chunks_agree() {
+        let chunk = workloads::countdown(3).with_features(1 << 31);
+        assert!(VM::try_new(chunk.clone()).is_err());
+        assert_eq!(run_differential(&chunk, plain, plain), Ok(()));
+    }
+
+    #[test]
+    fn test_workloads_agree_across_heap_configurations() {
+        for chunk in &small_workloads() {
+            let config =
+                |vm: &mut VM| *vm = VM::with_heap_mode(chunk.clone(), HeapMode::Arena).unwrap();
+            assert_eq!(run_differential(chunk, plain, config), Ok(()));
+            assert_eq!(
+                run_differential(chunk, plain, |vm| vm.set_gc_stress(true).unwrap()),
+                Ok(())
+            );
+            let incremental = IncrementalGc {
+                steps: 2,
+                interval: 3,
+            };
+            let config = |vm: &mut VM| vm.set_incremental_gc(Some(incremental)).unwrap();
+            assert_eq!(run_differential(chunk, plain, config), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_generated_programs_agree_under_gc_stress() {
+        for seed in 0..200 {
+            let chunk = testing::program(&mut Rng::new(seed));
+            let result = run_differential(&chunk, plain, |vm| vm.set_gc_stress(true).unwrap());
+            assert_eq!(result, Ok(()), "seed {seed}");
+        }
+    }
+
+    #[test]
+    fn test_predecoded_engine_agrees() {
+        for chunk in &small_workloads() {
+            assert_eq!(run_differential(chunk, plain, predecoded(chunk)), Ok(()));
+        }
+        for seed in 0..200 {
+            let chunk = testing::program(&mut Rng::new(seed));
+            let result = run_differential(&chunk, plain, predecoded(&chunk));
+            assert_eq!(result, Ok(()), "seed {seed}");
+        }
+
+        // offsets past a bad opcode aren't in the table
+        let chunk = Chunk::new(vec![Imm0 as u8, 0xff, Imm1 as u8]);
+        assert_eq!(run_differential(&chunk, plain, predecoded(&chunk)), Ok(()));
+    }
+
+    #[cfg(feature = "verified-fast")]
+    #[test]
+    fn test_verified_fast_path_agrees() {
+        use crate::clock::Clock;
+        use crate::verifier::VerifiedChunk;
+        use crate::vm::ExecutionOutcome;
+        use std::mem;
+        let verified = |chunk: &Chunk| {
+            let chunk = VerifiedChunk::new(chunk.clone()).unwrap();
+            move |vm: &mut VM| *vm = VM::from_verified(chunk).unwrap()
+        };
+        for chunk in &small_workloads() {
+            assert_eq!(run_differential(chunk, plain, verified(chunk)), Ok(()));
+        }
+        for seed in 0..200 {
+            let chunk = testing::program(&mut Rng::new(seed));
+            let result = run_differential(&chunk, plain, verified(&chunk));
+            assert_eq!(result, Ok(()), "seed {seed}");
+        }
+
+        // these may never finish, so both runs get the same fuel, and a
+        // clock that stands still
+        struct Stopped;
+        impl Clock for Stopped {
+            fn now(&mut self) -> u64 {
+                0
+            }
+        }
+        for seed in 0..200 {
+            let chunk = testing::instructions(&mut Rng::new(seed), 48);
+            let mut checked = VM::new(chunk.clone());
+            let mut fast = VM::new(chunk.clone());
+            verified(&chunk)(&mut fast);
+            for vm in [&mut checked, &mut fast] {
+                vm.set_fuel(Some(256)).unwrap();
+                vm.set_clock(Box::new(Stopped)).unwrap();
+            }
+            // errors may hold pointers, which differ between the heaps
+            let summary = |result: Result<ExecutionOutcome, VmError>| {
+                result
+                    .map(|o| o.instructions)
+                    .map_err(|err| mem::discriminant(&err))
+            };
+            let result = summary(checked.execute_all());
+            assert_eq!(summary(fast.execute_all()), result, "seed {seed}");
+            assert_eq!(fast.ip(), checked.ip(), "seed {seed}");
+            assert_eq!(fast.state_eq(&checked), Ok(()), "seed {seed}");
+            assert!(fast.is_verified(), "seed {seed}");
+        }
+    }
+
+    #[test]
+    fn test_buggy_handler_is_caught() {
+        // x = 0; repeat 5 times { x = double(x + 1) }
+        let mut b = ChunkBuilder::new();
+        let (head, end) = (b.label(), b.label());
+        b.imm_i(0).store(0).imm_i(5).store(1);
+        b.bind(head).imm_i(0).load(1).op(CmpGeI).goto_if(end);
+        b.imm_i(1).load(0).op(AddI).call_native("double").store(0);
+        b.load(1).imm_i(1).op(SubI).store(1);
+        b.goto(head);
+        b.bind(end).load(0);
+        let chunk = b.build().unwrap();
+
+        // the buggy version overflows into an extra bit past 16
+        let double = |buggy: bool| {
+            move |vm: &mut VM| {
+                vm.register_native(
+                    "double",
+                    Rc::new(move |vm: &mut VM| {
+                        let x = vm.get_integer()?;
+                        let bug = if buggy && x > 16 { 1 } else { 0 };
+                        vm.push(Value::Integer(2 * x + bug));
+                        Ok(())
+                    }),
+                )
+                .unwrap();
+            }
+        };
+        assert_eq!(
+            run_differential(&chunk, double(false), double(false)),
+            Ok(())
+        );
+
+        let divergence = *run_differential(&chunk, double(false), double(true)).unwrap_err();
+        assert_eq!(chunk.code()[divergence.a.ip - 3], CallNative as u8);
+        assert_eq!(divergence.a.stack, [Value::Integer(2 * 31)]);
+        assert_eq!(divergence.b.stack, [Value::Integer(2 * 31 + 1)]);
+        assert_eq!(divergence.a.locals, divergence.b.locals);
+        assert_eq!(divergence.a.error, None);
+        assert_eq!(
+            divergence.diff,
+            Some(StateDiff::Stack {
+                index: 0,
+                a: "62".to_string(),
+                b: "63".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_state_eq() {
+        let run = |chunk: Chunk| {
+            let mut vm = VM::new(chunk);
+            vm.execute_all().unwrap();
+            vm
+        };
+        // the hand-spelled factorial uses the long immediates throughout
+        let factorial = |n| {
+            let mut b = ChunkBuilder::new();
+            let (head, end) = (b.label(), b.label());
+            b.imm_i(n).store(0).imm_i(1).store(1);
+            b.bind(head).imm_i(1).load(0).op(CmpGtI).goto_if(end);
+            b.load(1).load(0).op(MulI).store(1);
+            b.load(0).imm_i(1).op(SubI).store(0).goto(head);
+            b.bind(end).load(1).op(Return);
+            b.build().unwrap()
+        };
+        let spelled = run(vm::tests::factorial());
+        let built = run(factorial(5));
+        assert_ne!(spelled.ip(), built.ip());
+        assert_eq!(spelled.state_eq(&built), Ok(()));
+
+        let diff = spelled.state_eq(&run(factorial(6))).unwrap_err();
+        assert_eq!(
+            diff,
+            StateDiff::Stack {
+                index: 0,
+                a: "120".to_string(),
+                b: "720".to_string()
+            }
+        );
+        assert_eq!(diff.to_string(), "stack slot 0 differs: 120 and 720");
+
+        // objects compare by contents, wherever they are
+        let array = |x| {
+            let mut b = ChunkBuilder::new();
+            b.op(Imm1).op(ArrayNew).store(0);
+            b.load(0).op(Imm0).imm_i(x).op(ArraySet).load(0);
+            run(b.build().unwrap())
+        };
+        assert_eq!(array(1).state_eq(&array(1)), Ok(()));
+        assert_eq!(
+            array(1).state_eq(&array(2)),
+            Err(StateDiff::Stack {
+                index: 0,
+                a: "{tag 252: [1]}".to_string(),
+                b: "{tag 252: [2]}".to_string()
+            })
+        );
+
+        // 120 on the stack, and 1 in local `slot`
+        let local = |slot| {
+            let mut b = ChunkBuilder::new();
+            b.op(Imm1).store(slot).imm_i(120);
+            run(b.build().unwrap())
+        };
+        assert_eq!(
+            spelled.state_eq(&local(1)),
+            Err(StateDiff::Local {
+                index: 0,
+                a: "0".to_string(),
+                b: "uninitialized".to_string()
+            })
+        );
+        assert_eq!(
+            local(1).state_eq(&local(2)),
+            Err(StateDiff::LocalsLen { a: 2, b: 3 })
+        );
+        assert_eq!(
+            built.state_eq(&run(Chunk::new(vec![]))),
+            Err(StateDiff::StackLen { a: 1, b: 0 })
+        );
+    }
+}
@@ -0,0 +1,159 @@
+// The one encoding of operands and immediates, big-endian whatever the
+// host. The VM reads code with these, the builder writes it, and code
+// assembled by hand should use them too rather than spelling out bytes.
+
+pub fn put_u16(out: &mut Vec<u8>, v: u16) {
+    out.extend(v.to_be_bytes());
+}
+
+pub fn put_u32(out: &mut Vec<u8>, v: u32) {
+    out.extend(v.to_be_bytes());
+}
+
+pub fn put_u64(out: &mut Vec<u8>, v: u64) {
+    out.extend(v.to_be_bytes());
+}
+
+pub fn put_i64(out: &mut Vec<u8>, v: i64) {
+    put_u64(out, v as u64);
+}
+
+pub fn put_f64_bits(out: &mut Vec<u8>, v: f64) {
+    put_u64(out, v.to_bits());
+}
+
+// Overwrites the operand at `at`, as when fixing up a jump.
+pub fn patch_u16(code: &mut [u8], at: usize, v: u16) {
+    code[at..at + 2].copy_from_slice(&v.to_be_bytes());
+}
+
+pub fn patch_u64(code: &mut [u8], at: usize, v: u64) {
+    code[at..at + 8].copy_from_slice(&v.to_be_bytes());
+}
+
+// The readers decode the front of `bytes`, or return `None` if it's too
+// short.
+fn read<const N: usize>(bytes: &[u8]) -> Option<[u8; N]> {
+    bytes.get(..N).map(|bytes| bytes.try_into().unwrap())
+}
+
+pub fn read_u16(bytes: &[u8]) -> Option<u16> {
+    read(bytes).map(u16::from_be_bytes)
+}
+
+pub fn read_u32(bytes: &[u8]) -> Option<u32> {
+    read(bytes).map(u32::from_be_bytes)
+}
+
+pub fn read_u64(bytes: &[u8]) -> Option<u64> {
+    read(bytes).map(u64::from_be_bytes)
+}
+
+pub fn read_i64(bytes: &[u8]) -> Option<i64> {
+    read_u64(bytes).map(|v| v as i64)
+}
+
+pub fn read_f64_bits(bytes: &[u8]) -> Option<f64> {
+    read_u64(bytes).map(f64::from_bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::Chunk;
+    use crate::opcode::OpCode::{self, *};
+    use crate::value::Value;
+    use crate::vm::{self, VM};
+
+    #[test]
+    fn test_known_bytes() {
+        let mut out = Vec::new();
+        put_u16(&mut out, 0x1234);
+        put_u32(&mut out, 0xdead_beef);
+        put_u64(&mut out, 0x0102_0304_0506_0708);
+        put_i64(&mut out, -2);
+        put_f64_bits(&mut out, 1.5);
+        #[rustfmt::skip]
+        assert_eq!(out, [
+            0x12, 0x34,
+            0xde, 0xad, 0xbe, 0xef,
+            1, 2, 3, 4, 5, 6, 7, 8,
+            0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe,
+            0x3f, 0xf8, 0, 0, 0, 0, 0, 0,
+        ]);
+
+        assert_eq!(read_u16(&out), Some(0x1234));
+        assert_eq!(read_u32(&out[2..]), Some(0xdead_beef));
+        assert_eq!(read_u64(&out[6..]), Some(0x0102_0304_0506_0708));
+        assert_eq!(read_i64(&out[14..]), Some(-2));
+        assert_eq!(read_f64_bits(&out[22..]), Some(1.5));
+        assert_eq!(read_u16(&out[29..]), None);
+        assert_eq!(read_u64(&out[22..29]), None);
+
+        patch_u16(&mut out, 0, 0xabcd);
+        patch_u64(&mut out, 22, u64::MAX);
+        assert_eq!(out[..2], [0xab, 0xcd]);
+        assert_eq!(read_u64(&out[22..]), Some(u64::MAX));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for v in [0, 1, 0x7fff, 0x8000, u16::MAX] {
+            let mut out = Vec::new();
+            put_u16(&mut out, v);
+            assert_eq!(read_u16(&out), Some(v));
+        }
+        for v in [0, -1, i64::MIN, i64::MAX, 0x1234_5678] {
+            let mut out = Vec::new();
+            put_i64(&mut out, v);
+            assert_eq!(read_i64(&out), Some(v));
+        }
+        for v in [0.0, -0.0, f64::MAX, f64::MIN_POSITIVE, f64::INFINITY] {
+            let mut out = Vec::new();
+            put_f64_bits(&mut out, v);
+            assert_eq!(read_f64_bits(&out).map(f64::to_bits), Some(v.to_bits()));
+        }
+    }
+
+    #[test]
+    fn test_assembles_factorial() {
+        // the hand-spelled factorial, assembled with the helpers instead
+        let mut code = Vec::new();
+        let op_u16 = |code: &mut Vec<u8>, op: OpCode, index| {
+            code.push(op as u8);
+            put_u16(code, index);
+        };
+        let imm = |code: &mut Vec<u8>, i| {
+            code.push(ImmI as u8);
+            put_i64(code, i);
+        };
+        imm(&mut code, 5);
+        op_u16(&mut code, Store, 0);
+        imm(&mut code, 1);
+        op_u16(&mut code, Store, 1);
+        imm(&mut code, 1);
+        op_u16(&mut code, Load, 0);
+        code.push(CmpGtI as u8);
+        op_u16(&mut code, GotoIf, 69);
+        op_u16(&mut code, Load, 1);
+        op_u16(&mut code, Load, 0);
+        code.push(MulI as u8);
+        op_u16(&mut code, Store, 1);
+        op_u16(&mut code, Load, 0);
+        imm(&mut code, 1);
+        code.push(SubI as u8);
+        op_u16(&mut code, Store, 0);
+        op_u16(&mut code, Goto, 24);
+        op_u16(&mut code, Load, 1);
+        code.push(Return as u8);
+
+        let factorial = vm::tests::factorial();
+        assert_eq!(code, factorial.code());
+        let mut vm = VM::new(Chunk::new(code));
+        assert_eq!(
+            vm.execute_all().unwrap().value,
+            VM::new(factorial).execute_all().unwrap().value
+        );
+        assert_eq!(vm.stack().last(), Some(&Value::Integer(120)));
+    }
+}
@@ -0,0 +1,976 @@
+use crate::opcode::OpCode;
+use crate::shadow::Site;
+use crate::value::Value;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VmError {
+    StackUnderflow,
+    UnexpectedEof,
+    InvalidOpcode(u8),
+    TypeMismatch {
+        expected: &'static str,
+        found: &'static str,
+    },
+    // An operand of the wrong type, popped by `op` at `ip`.
+    OperandMismatch {
+        expected: &'static str,
+        found: Operand,
+        op: Option<OpCode>,
+        ip: usize,
+    },
+    // Found by shadow checking, which knows which instruction pushed the
+    // value as well as which popped it.
+    ShadowMismatch {
+        expected: &'static str,
+        found: &'static str,
+        producer: Option<Site>,
+        consumer: Option<Site>,
+    },
+    UninitializedLocal(usize),
+    FieldOutOfBounds {
+        index: usize,
+        len: usize,
+    },
+    DivisionByZero,
+    LocalOutOfRange {
+        index: usize,
+        max: u16,
+    },
+    ArgOutOfRange {
+        index: u8,
+        arity: u8,
+    },
+    StackOverflow,
+    FuelExhausted,
+    DeadlineExceeded,
+    InvalidNumber,
+    InvalidKey(&'static str),
+    KeyNotFound,
+    IteratorInvalidated,
+    ConstantOutOfRange(usize),
+    InvalidJump(usize),
+    UnknownNative(u16),
+    ForbiddenOpcode(u8),
+    ForbiddenNative(u16),
+    HeapExhausted,
+    UnknownFunction(u16),
+    IndexOutOfBounds {
+        index: i64,
+        len: usize,
+    },
+    BufferOutOfBounds {
+        offset: i64,
+        width: usize,
+        len: usize,
+    },
+    InvalidLength(i64),
+    InvalidPrecision(i64),
+    UnsupportedFeature(u32),
+    InvalidChunk(VerifyError),
+    ChunkTooLarge {
+        len: usize,
+        max: usize,
+    },
+    ReconfiguredWhileRunning,
+    ReturnCountMismatch {
+        expected: u8,
+        found: usize,
+    },
+    UnreachableExecuted(usize),
+    // Raised by `TrapMsg`, with the message copied out of the heap.
+    GuestPanic(String),
+    // Stack depths at a `Return` from `function`: `expected` is its frame's
+    // base plus its declared return count, or for a function that declares
+    // none just the base, which is then the least the depth may be.
+    StackImbalance {
+        expected: usize,
+        actual: usize,
+        function: u16,
+    },
+    ReplayDiverged {
+        step: u64,
+        expected: Option<u8>,
+        found: u8,
+    },
+    ReplayEventMissing {
+        step: u64,
+    },
+    ReplayedNativeFailure,
+    UnrecordableValue,
+    HashMismatch,
+    InvalidCheckpoint(CheckpointError),
+}
+
+// An operand as an error shows it. Objects are kept by their heap id, taken
+// while the heap was alive, since the error can outlive it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    Char(char),
+    Integer(i64),
+    Word(u64),
+    Float(f64),
+    Object(u64),
+    Null,
+}
+
+impl Operand {
+    pub(crate) fn of(val: Value) -> Self {
+        match val {
+            Value::Char(c) => Self::Char(c),
+            Value::Integer(i) => Self::Integer(i),
+            Value::Word(w) => Self::Word(w),
+            Value::Float(x) => Self::Float(x),
+            Value::ObjectPtr(ptr) => Self::Object(ptr.id()),
+            Value::Null => Self::Null,
+        }
+    }
+
+    pub const fn type_name(&self) -> &'static str {
+        match self {
+            Self::Char(_) => "char",
+            Self::Integer(_) => "integer",
+            Self::Word(_) => "word",
+            Self::Float(_) => "float",
+            Self::Object(_) => "object",
+            Self::Null => "null",
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Char(c) => write!(f, "{c:?}"),
+            Self::Integer(i) => write!(f, "{i}"),
+            Self::Word(w) => write!(f, "{w:#x}"),
+            Self::Float(x) => write!(f, "{x:?}"),
+            Self::Object(id) => write!(f, "object #{id}"),
+            Self::Null => write!(f, "null"),
+        }
+    }
+}
+
+// What `VmError::kind` gives for each variant, and so the kind field of the
+// error objects that guest code gets from a trap it recovers from. These
+// numbers are part of the guest interface and never reused.
+pub mod err_kind {
+    pub const STACK_UNDERFLOW: i64 = 1;
+    pub const UNEXPECTED_EOF: i64 = 2;
+    pub const INVALID_OPCODE: i64 = 3;
+    pub const TYPE_MISMATCH: i64 = 4;
+    pub const OPERAND_MISMATCH: i64 = 5;
+    pub const SHADOW_MISMATCH: i64 = 6;
+    pub const UNINITIALIZED_LOCAL: i64 = 7;
+    pub const FIELD_OUT_OF_BOUNDS: i64 = 8;
+    pub const DIVISION_BY_ZERO: i64 = 9;
+    pub const LOCAL_OUT_OF_RANGE: i64 = 10;
+    pub const ARG_OUT_OF_RANGE: i64 = 11;
+    pub const STACK_OVERFLOW: i64 = 12;
+    pub const FUEL_EXHAUSTED: i64 = 13;
+    pub const DEADLINE_EXCEEDED: i64 = 14;
+    pub const INVALID_NUMBER: i64 = 15;
+    pub const INVALID_KEY: i64 = 16;
+    pub const KEY_NOT_FOUND: i64 = 17;
+    pub const ITERATOR_INVALIDATED: i64 = 18;
+    pub const CONSTANT_OUT_OF_RANGE: i64 = 19;
+    pub const INVALID_JUMP: i64 = 20;
+    pub const UNKNOWN_NATIVE: i64 = 21;
+    pub const FORBIDDEN_OPCODE: i64 = 22;
+    pub const FORBIDDEN_NATIVE: i64 = 23;
+    pub const HEAP_EXHAUSTED: i64 = 24;
+    pub const UNKNOWN_FUNCTION: i64 = 25;
+    pub const INDEX_OUT_OF_BOUNDS: i64 = 26;
+    pub const BUFFER_OUT_OF_BOUNDS: i64 = 27;
+    pub const INVALID_LENGTH: i64 = 28;
+    pub const INVALID_PRECISION: i64 = 29;
+    pub const UNSUPPORTED_FEATURE: i64 = 30;
+    pub const INVALID_CHUNK: i64 = 31;
+    pub const CHUNK_TOO_LARGE: i64 = 32;
+    pub const RECONFIGURED_WHILE_RUNNING: i64 = 33;
+    pub const RETURN_COUNT_MISMATCH: i64 = 34;
+    pub const UNREACHABLE_EXECUTED: i64 = 35;
+    pub const GUEST_PANIC: i64 = 36;
+    pub const STACK_IMBALANCE: i64 = 37;
+    pub const REPLAY_DIVERGED: i64 = 38;
+    pub const REPLAY_EVENT_MISSING: i64 = 39;
+    pub const REPLAYED_NATIVE_FAILURE: i64 = 40;
+    pub const UNRECORDABLE_VALUE: i64 = 41;
+    pub const HASH_MISMATCH: i64 = 42;
+    pub const INVALID_CHECKPOINT: i64 = 43;
+}
+
+impl VmError {
+    pub const fn kind(&self) -> i64 {
+        use err_kind::*;
+        match self {
+            Self::StackUnderflow => STACK_UNDERFLOW,
+            Self::UnexpectedEof => UNEXPECTED_EOF,
+            Self::InvalidOpcode(..) => INVALID_OPCODE,
+            Self::TypeMismatch { .. } => TYPE_MISMATCH,
+            Self::OperandMismatch { .. } => OPERAND_MISMATCH,
+            Self::ShadowMismatch { .. } => SHADOW_MISMATCH,
+            Self::UninitializedLocal(..) => UNINITIALIZED_LOCAL,
+            Self::FieldOutOfBounds { .. } => FIELD_OUT_OF_BOUNDS,
+            Self::DivisionByZero => DIVISION_BY_ZERO,
+            Self::LocalOutOfRange { .. } => LOCAL_OUT_OF_RANGE,
+            Self::ArgOutOfRange { .. } => ARG_OUT_OF_RANGE,
+            Self::StackOverflow => STACK_OVERFLOW,
+            Self::FuelExhausted => FUEL_EXHAUSTED,
+            Self::DeadlineExceeded => DEADLINE_EXCEEDED,
+            Self::InvalidNumber => INVALID_NUMBER,
+            Self::InvalidKey(..) => INVALID_KEY,
+            Self::KeyNotFound => KEY_NOT_FOUND,
+            Self::IteratorInvalidated => ITERATOR_INVALIDATED,
+            Self::ConstantOutOfRange(..) => CONSTANT_OUT_OF_RANGE,
+            Self::InvalidJump(..) => INVALID_JUMP,
+            Self::UnknownNative(..) => UNKNOWN_NATIVE,
+            Self::ForbiddenOpcode(..) => FORBIDDEN_OPCODE,
+            Self::ForbiddenNative(..) => FORBIDDEN_NATIVE,
+            Self::HeapExhausted => HEAP_EXHAUSTED,
+            Self::UnknownFunction(..) => UNKNOWN_FUNCTION,
+            Self::IndexOutOfBounds { .. } => INDEX_OUT_OF_BOUNDS,
+            Self::BufferOutOfBounds { .. } => BUFFER_OUT_OF_BOUNDS,
+            Self::InvalidLength(..) => INVALID_LENGTH,
+            Self::InvalidPrecision(..) => INVALID_PRECISION,
+            Self::UnsupportedFeature(..) => UNSUPPORTED_FEATURE,
+            Self::InvalidChunk(..) => INVALID_CHUNK,
+            Self::ChunkTooLarge { .. } => CHUNK_TOO_LARGE,
+            Self::ReconfiguredWhileRunning => RECONFIGURED_WHILE_RUNNING,
+            Self::ReturnCountMismatch { .. } => RETURN_COUNT_MISMATCH,
+            Self::UnreachableExecuted(..) => UNREACHABLE_EXECUTED,
+            Self::GuestPanic(..) => GUEST_PANIC,
+            Self::StackImbalance { .. } => STACK_IMBALANCE,
+            Self::ReplayDiverged { .. } => REPLAY_DIVERGED,
+            Self::ReplayEventMissing { .. } => REPLAY_EVENT_MISSING,
+            Self::ReplayedNativeFailure => REPLAYED_NATIVE_FAILURE,
+            Self::UnrecordableValue => UNRECORDABLE_VALUE,
+            Self::HashMismatch => HASH_MISMATCH,
+            Self::InvalidCheckpoint(..) => INVALID_CHECKPOINT,
+        }
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::StackUnderflow => write!(f, "stack underflow"),
+            Self::UnexpectedEof => write!(f, "unexpected end of chunk"),
+            Self::InvalidOpcode(b) => write!(f, "invalid opcode {b:#04x}"),
+            Self::TypeMismatch { expected, found } => {
+                write!(f, "type mismatch: expected {expected}, found {found}")
+            }
+            Self::OperandMismatch {
+                expected,
+                found,
+                op,
+                ip,
+            } => {
+                let kind = found.type_name();
+                write!(f, "type mismatch: expected {expected}, found {kind} {found}")?;
+                match op {
+                    Some(op) => write!(f, " for {op:?} at {ip}"),
+                    None => write!(f, " at {ip}"),
+                }
+            }
+            Self::ShadowMismatch {
+                expected,
+                found,
+                producer,
+                consumer,
+            } => {
+                write!(f, "shadow check: expected {expected}, found {found}")?;
+                match producer {
+                    Some(Site { ip, op }) => write!(f, " pushed by {op:?} at {ip}")?,
+                    None => write!(f, " pushed by the host")?,
+                }
+                match consumer {
+                    Some(Site { ip, op }) => write!(f, " and popped by {op:?} at {ip}"),
+                    None => Ok(()),
+                }
+            }
+            Self::UninitializedLocal(index) => write!(f, "local {index} is uninitialized"),
+            Self::FieldOutOfBounds { index, len } => {
+                write!(
+                    f,
+                    "field {index} out of bounds for object with {len} fields"
+                )
+            }
+            Self::DivisionByZero => write!(f, "division by zero"),
+            Self::LocalOutOfRange { index, max } => {
+                write!(f, "local {index} out of range for a frame of {max} locals")
+            }
+            Self::ArgOutOfRange { index, arity } => {
+                write!(f, "argument {index} out of range for a function of arity {arity}")
+            }
+            Self::StackOverflow => write!(f, "stack overflow"),
+            Self::FuelExhausted => write!(f, "fuel exhausted"),
+            Self::DeadlineExceeded => write!(f, "deadline exceeded"),
+            Self::InvalidNumber => write!(f, "string is not a valid number"),
+            Self::InvalidKey(found) => write!(f, "{found} can't be used as a map key"),
+            Self::KeyNotFound => write!(f, "key not found in map"),
+            Self::IteratorInvalidated => write!(f, "map changed while iterating over it"),
+            Self::ConstantOutOfRange(index) => write!(f, "constant {index} does not exist"),
+            Self::InvalidJump(target) => write!(f, "jump target {target} is not an instruction"),
+            Self::UnknownNative(index) => {
+                write!(f, "constant {index} doesn't name a registered native")
+            }
+            Self::ForbiddenOpcode(b) => write!(f, "opcode {b:#04x} is forbidden by the policy"),
+            Self::ForbiddenNative(index) => {
+                write!(
+                    f,
+                    "native named by constant {index} is forbidden by the policy"
+                )
+            }
+            Self::HeapExhausted => write!(f, "heap limit exceeded"),
+            Self::UnknownFunction(index) => write!(f, "function {index} does not exist"),
+            Self::IndexOutOfBounds { index, len } => {
+                write!(f, "index {index} out of bounds for array of length {len}")
+            }
+            Self::BufferOutOfBounds { offset, width, len } => write!(
+                f,
+                "{width}-byte access at offset {offset} out of bounds for buffer of length {len}"
+            ),
+            Self::InvalidLength(len) => write!(f, "{len} is not a valid array length"),
+            Self::InvalidPrecision(precision) => {
+                write!(f, "{precision} is not a valid number of decimal places")
+            }
+            Self::UnsupportedFeature(bits) => {
+                write!(f, "chunk requires unsupported features {bits:#x}")
+            }
+            Self::InvalidChunk(err) => write!(f, "chunk failed verification: {err}"),
+            Self::ChunkTooLarge { len, max } => {
+                write!(f, "chunk of {len} bytes exceeds the limit of {max}")
+            }
+            Self::ReconfiguredWhileRunning => {
+                write!(f, "the VM can't be reconfigured while it is running")
+            }
+            Self::ReturnCountMismatch { expected, found } => {
+                write!(f, "function returned {found} values, expected {expected}")
+            }
+            Self::UnreachableExecuted(ip) => write!(f, "reached code marked unreachable at {ip}"),
+            Self::GuestPanic(message) => write!(f, "guest panicked: {message}"),
+            Self::StackImbalance {
+                expected,
+                actual,
+                function,
+            } => write!(
+                f,
+                "function {function} returned with the stack at depth {actual}, expected {expected}"
+            ),
+            Self::ReplayDiverged {
+                step,
+                expected: Some(expected),
+                found,
+            } => write!(
+                f,
+                "replay diverged at step {step}: expected opcode {expected:#04x}, found {found:#04x}"
+            ),
+            Self::ReplayDiverged {
+                step,
+                expected: None,
+                found,
+            } => write!(
+                f,
+                "replay ran past the recording at step {step}, with opcode {found:#04x}"
+            ),
+            Self::ReplayEventMissing { step } => {
+                write!(f, "recording has no input for step {step}")
+            }
+            Self::ReplayedNativeFailure => write!(f, "native failed when it was recorded"),
+            Self::UnrecordableValue => write!(f, "objects can't be recorded as inputs"),
+            Self::HashMismatch => write!(
+                f,
+                "the recording or checkpoint was made against a different chunk"
+            ),
+            Self::InvalidCheckpoint(err) => write!(f, "checkpoint can't be restored: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+// A trap together with a dump of the VM it happened in, from
+// `VmError::with_state`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorWithState {
+    pub error: VmError,
+    pub state: String,
+}
+
+impl fmt::Display for ErrorWithState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.error)?;
+        write!(f, "{}", self.state)
+    }
+}
+
+impl std::error::Error for ErrorWithState {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+// One frame of a backtrace. `ip` is where the frame's function trapped, for
+// the innermost frame, and otherwise the call it's waiting on. Frames
+// outside any function, running the chunk's top level, have no entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BacktraceFrame {
+    pub function: Option<u16>,
+    pub name: Option<String>,
+    pub entry: Option<usize>,
+    pub ip: usize,
+}
+
+impl fmt::Display for BacktraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.name, self.entry) {
+            (Some(name), _) => write!(f, "{name}")?,
+            (None, Some(entry)) => write!(f, "<function at {entry}>")?,
+            (None, None) => write!(f, "<top level>")?,
+        }
+        write!(f, " at {}", self.ip)
+    }
+}
+
+// Innermost frame first.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Backtrace(pub Vec<BacktraceFrame>);
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (depth, frame) in self.0.iter().enumerate() {
+            if depth > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "  #{depth} {frame}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorWithBacktrace {
+    pub error: VmError,
+    pub backtrace: Backtrace,
+}
+
+impl fmt::Display for ErrorWithBacktrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.error)?;
+        write!(f, "{}", self.backtrace)
+    }
+}
+
+impl std::error::Error for ErrorWithBacktrace {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildError {
+    UnboundLabel,
+    JumpOutOfRange(usize),
+    ImmediateOutOfRange { value: i64, bits: u8 },
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnboundLabel => write!(f, "jump to a label that was never bound"),
+            Self::JumpOutOfRange(target) => {
+                write!(f, "jump target {target} does not fit in a u16")
+            }
+            Self::ImmediateOutOfRange { value, bits } => {
+                write!(f, "immediate {value} does not fit in an i{bits}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    InvalidOpcode { offset: usize, byte: u8 },
+    Truncated { offset: usize },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        VerifyError::from(*self).fmt(f)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyError {
+    InvalidOpcode {
+        offset: usize,
+        byte: u8,
+    },
+    Truncated {
+        offset: usize,
+    },
+    InvalidJump {
+        offset: usize,
+        target: usize,
+    },
+    LocalOutOfRange {
+        offset: usize,
+        index: u16,
+        max: u16,
+    },
+    // An argument index past the arity of a function the access is reachable
+    // from, with `None` for the top level.
+    ArgOutOfRange {
+        offset: usize,
+        index: u8,
+        function: Option<u16>,
+        arity: u8,
+    },
+    ConstantOutOfRange {
+        offset: usize,
+        index: u16,
+    },
+    InvalidNativeName {
+        offset: usize,
+        index: u16,
+    },
+    ForbiddenOpcode {
+        offset: usize,
+        byte: u8,
+    },
+    ForbiddenNative {
+        offset: usize,
+        index: u16,
+    },
+    FunctionOutOfRange {
+        offset: usize,
+        index: u16,
+    },
+    InvalidEntry {
+        index: u16,
+        entry: usize,
+    },
+    StackUnderflow {
+        offset: usize,
+    },
+    InconsistentStack {
+        offset: usize,
+        expected: usize,
+        found: usize,
+    },
+    ReturnCountMismatch {
+        offset: usize,
+        expected: u8,
+        found: usize,
+    },
+    UnknownStackEffect {
+        offset: usize,
+    },
+    ChunkTooLarge {
+        len: usize,
+        max: usize,
+    },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidOpcode { offset, byte } => {
+                write!(f, "invalid opcode {byte:#04x} at {offset}")
+            }
+            Self::Truncated { offset } => {
+                write!(f, "instruction at {offset} runs past the end of the chunk")
+            }
+            Self::InvalidJump { offset, target } => {
+                write!(
+                    f,
+                    "jump at {offset} targets {target}, which is not an instruction"
+                )
+            }
+            Self::LocalOutOfRange { offset, index, max } => write!(
+                f,
+                "local {index} accessed at {offset} is out of range for a frame of {max} locals"
+            ),
+            Self::ArgOutOfRange {
+                offset,
+                index,
+                function,
+                arity,
+            } => {
+                write!(
+                    f,
+                    "argument {index} accessed at {offset} is out of range for "
+                )?;
+                match function {
+                    Some(function) => write!(f, "function {function} of arity {arity}"),
+                    None => write!(f, "the top level, which has no arguments"),
+                }
+            }
+            Self::ConstantOutOfRange { offset, index } => {
+                write!(f, "constant {index} loaded at {offset} does not exist")
+            }
+            Self::InvalidNativeName { offset, index } => {
+                write!(
+                    f,
+                    "native called at {offset} is named by non-string constant {index}"
+                )
+            }
+            Self::ForbiddenOpcode { offset, byte } => {
+                write!(
+                    f,
+                    "opcode {byte:#04x} at {offset} is forbidden by the policy"
+                )
+            }
+            Self::ForbiddenNative { offset, index } => write!(
+                f,
+                "native called at {offset} (constant {index}) is forbidden by the policy"
+            ),
+            Self::FunctionOutOfRange { offset, index } => {
+                write!(f, "function {index} called at {offset} does not exist")
+            }
+            Self::InvalidEntry { index, entry } => {
+                write!(
+                    f,
+                    "function {index} starts at {entry}, which is not an instruction"
+                )
+            }
+            Self::StackUnderflow { offset } => {
+                write!(f, "instruction at {offset} pops more values than there are")
+            }
+            Self::InconsistentStack {
+                offset,
+                expected,
+                found,
+            } => write!(
+                f,
+                "stack holds {found} values at {offset} on one path and {expected} on another"
+            ),
+            Self::ReturnCountMismatch {
+                offset,
+                expected,
+                found,
+            } => write!(
+                f,
+                "return at {offset} returns {found} values, expected {expected}"
+            ),
+            Self::UnknownStackEffect { offset } => {
+                write!(f, "stack effect of the instruction at {offset} is unknown")
+            }
+            Self::ChunkTooLarge { len, max } => {
+                write!(f, "chunk of {len} bytes exceeds the limit of {max}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+impl From<DecodeError> for VerifyError {
+    fn from(err: DecodeError) -> Self {
+        match err {
+            DecodeError::InvalidOpcode { offset, byte } => Self::InvalidOpcode { offset, byte },
+            DecodeError::Truncated { offset } => Self::Truncated { offset },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchError {
+    NotABoundary(usize),
+    TooLong {
+        offset: usize,
+        len: usize,
+        available: usize,
+    },
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotABoundary(offset) => write!(f, "{offset} is not the start of an instruction"),
+            Self::TooLong {
+                offset,
+                len,
+                available,
+            } => write!(
+                f,
+                "replacement of {len} bytes doesn't fit the {available} bytes at {offset}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    Truncated,
+    TrailingBytes(usize),
+    InvalidConstant(u8),
+    InvalidChar(u32),
+    InvalidUtf8,
+    UnsupportedFeature(u32),
+    HashMismatch,
+    // An opcode only the optimizer emits, at this offset of the code.
+    InternalOpcode(usize),
+}
+
+impl fmt::Display for ChunkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadMagic => write!(f, "not a serialized chunk"),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "unsupported chunk format version {version}")
+            }
+            Self::Truncated => write!(f, "serialized chunk is truncated"),
+            Self::TrailingBytes(n) => write!(f, "{n} unexpected bytes after the chunk"),
+            Self::InvalidConstant(tag) => write!(f, "invalid constant tag {tag:#04x}"),
+            Self::InvalidChar(c) => write!(f, "{c:#x} is not a valid char constant"),
+            Self::InvalidUtf8 => write!(f, "string constant is not valid UTF-8"),
+            Self::UnsupportedFeature(bits) => {
+                write!(f, "chunk requires unsupported features {bits:#x}")
+            }
+            Self::HashMismatch => write!(f, "chunk doesn't match its content hash"),
+            Self::InternalOpcode(offset) => write!(f, "internal opcode at {offset}"),
+        }
+    }
+}
+
+impl std::error::Error for ChunkError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    Truncated,
+    TrailingBytes(usize),
+    InvalidEvent(u8),
+    InvalidValue(u8),
+    InvalidChar(u32),
+    // Only from serializing: a count that doesn't fit in its u32.
+    TooLarge(u64),
+}
+
+impl fmt::Display for LogError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadMagic => write!(f, "not a serialized recording"),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "unsupported recording version {version}")
+            }
+            Self::Truncated => write!(f, "recording is truncated"),
+            Self::TrailingBytes(n) => write!(f, "{n} unexpected bytes after the recording"),
+            Self::InvalidEvent(tag) => write!(f, "invalid event tag {tag:#04x}"),
+            Self::InvalidValue(tag) => write!(f, "invalid value tag {tag:#04x}"),
+            Self::InvalidChar(c) => write!(f, "{c:#x} is not a valid char"),
+            Self::TooLarge(len) => write!(f, "{len} is too large to serialize"),
+        }
+    }
+}
+
+impl std::error::Error for LogError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckpointError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    Truncated,
+    TrailingBytes(usize),
+    InvalidValue(u8),
+    InvalidChar(u32),
+    InvalidUtf8,
+    // An object index past the end of the object table.
+    InvalidObject(u64),
+    // A map whose keys don't match its entries.
+    InvalidMap(u64),
+    // Found only on restoring: a function the chunk doesn't have, or a
+    // frame that doesn't return just after a `Call` or whose base is out of
+    // order.
+    UnknownFunction(u16),
+    InvalidFrame(u64),
+}
+
+impl fmt::Display for CheckpointError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadMagic => write!(f, "not a serialized checkpoint"),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "unsupported checkpoint version {version}")
+            }
+            Self::Truncated => write!(f, "checkpoint is truncated"),
+            Self::TrailingBytes(n) => write!(f, "{n} unexpected bytes after the checkpoint"),
+            Self::InvalidValue(tag) => write!(f, "invalid value tag {tag:#04x}"),
+            Self::InvalidChar(c) => write!(f, "{c:#x} is not a valid char"),
+            Self::InvalidUtf8 => write!(f, "map key is not valid UTF-8"),
+            Self::InvalidObject(index) => write!(f, "no object {index} in the checkpoint"),
+            Self::InvalidMap(index) => write!(f, "object {index} is a malformed map"),
+            Self::UnknownFunction(index) => write!(f, "chunk has no function {index}"),
+            Self::InvalidFrame(index) => write!(f, "frame {index} doesn't fit the chunk"),
+        }
+    }
+}
+
+impl std::error::Error for CheckpointError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MarshalError {
+    TypeMismatch {
+        expected: &'static str,
+        found: &'static str,
+    },
+    WrongTag {
+        expected: u8,
+        found: u8,
+    },
+    WrongLength {
+        expected: usize,
+        found: usize,
+    },
+    InField {
+        index: usize,
+        err: Box<MarshalError>,
+    },
+}
+
+impl fmt::Display for MarshalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TypeMismatch { expected, found } => {
+                write!(f, "expected {expected}, found {found}")
+            }
+            Self::WrongTag { expected, found } => {
+                write!(
+                    f,
+                    "expected an object tagged {expected:#04x}, found {found:#04x}"
+                )
+            }
+            Self::WrongLength { expected, found } => {
+                write!(f, "expected {expected} fields, found {found}")
+            }
+            Self::InField { index, err } => write!(f, "field {index}: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for MarshalError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkError {
+    Invalid { chunk: usize, err: VerifyError },
+    DynamicJumps { chunk: usize },
+    UnresolvedSymbol(String),
+    DuplicateSymbol(String),
+    InvalidExport { name: String, function: u16 },
+    JumpOutOfRange(usize),
+    TooManyConstants(usize),
+    TooManyFunctions(usize),
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Invalid { chunk, err } => write!(f, "chunk {chunk}: {err}"),
+            Self::DynamicJumps { chunk } => {
+                write!(
+                    f,
+                    "chunk {chunk} has computed jumps, which can't be relocated"
+                )
+            }
+            Self::UnresolvedSymbol(name) => write!(f, "no chunk exports {name:?}"),
+            Self::DuplicateSymbol(name) => write!(f, "{name:?} is exported more than once"),
+            Self::InvalidExport { name, function } => {
+                write!(
+                    f,
+                    "{name:?} exports function {function}, which doesn't exist"
+                )
+            }
+            Self::JumpOutOfRange(target) => {
+                write!(f, "relocated jump target {target} does not fit in a u16")
+            }
+            Self::TooManyConstants(n) => write!(f, "{n} constants don't fit in one chunk"),
+            Self::TooManyFunctions(n) => write!(f, "{n} functions don't fit in one chunk"),
+        }
+    }
+}
+
+impl std::error::Error for LinkError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WatchError {
+    // What was expected at byte `offset` of the expression.
+    Syntax {
+        offset: usize,
+        expected: &'static str,
+    },
+    UnknownLocal(String),
+    // What running the same operations would have trapped with.
+    Eval(VmError),
+}
+
+impl From<VmError> for WatchError {
+    fn from(err: VmError) -> Self {
+        Self::Eval(err)
+    }
+}
+
+impl fmt::Display for WatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Syntax { offset, expected } => {
+                write!(f, "expected {expected} at offset {offset}")
+            }
+            Self::UnknownLocal(name) => write!(f, "no local is named {name:?}"),
+            Self::Eval(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for WatchError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FrontendError {
+    // What was expected at byte `at` of the source.
+    Syntax {
+        at: usize,
+        expected: &'static str,
+    },
+    TypeMismatch {
+        expected: &'static str,
+        found: &'static str,
+        at: usize,
+    },
+    Unbound(String),
+    // `at` is the operator that trapped, when it's known.
+    Trap {
+        error: VmError,
+        at: Option<usize>,
+    },
+}
+
+impl fmt::Display for FrontendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Syntax { at, expected } => write!(f, "expected {expected} at {at}"),
+            Self::TypeMismatch {
+                expected,
+                found,
+                at,
+            } => write!(f, "expected {expected}, found {found} at {at}"),
+            Self::Unbound(name) => write!(f, "{name} has no value"),
+            Self::Trap {
+                error,
+                at: Some(at),
+            } => write!(f, "{error} at {at}"),
+            Self::Trap { error, at: None } => write!(f, "{error}"),
+        }
+    }
+}
+
+impl std::error::Error for FrontendError {}
@@ -0,0 +1,71 @@
+use crate::chunk::Chunk;
+use crate::config::VmBuilder;
+use crate::error::{Backtrace, ErrorWithBacktrace, VmError};
+use crate::instruction::{self, Instruction};
+use crate::value::Value;
+use crate::verifier;
+
+// Limits for `eval`, which runs code nobody has thought about bounding, so
+// that a runaway program fails instead of hanging the caller.
+pub const FUEL: u64 = 1_000_000;
+pub const MAX_STACK: usize = 1 << 16;
+
+// Encodes, verifies and runs `instructions` on a default VM in one go,
+// returning the value left on top of the stack. The arguments are stored
+// in the first locals, as a function's are; objects belong to the VM that
+// allocated them, so they can't be passed in.
+pub fn eval(instructions: &[Instruction], args: &[Value]) -> Result<Option<Value>, VmError> {
+    eval_chunk(instruction::encode(instructions), args).map_err(|err| err.error)
+}
+
+// As `eval`, for a chunk from anywhere, such as a builder. A trap comes
+// with the backtrace of where it happened; one before anything ran has an
+// empty backtrace.
+pub fn eval_chunk(chunk: Chunk, args: &[Value]) -> Result<Option<Value>, ErrorWithBacktrace> {
+    let early = |error| ErrorWithBacktrace {
+        error,
+        backtrace: Backtrace::default(),
+    };
+    verifier::verify(&chunk).map_err(|err| early(VmError::InvalidChunk(err)))?;
+    let mut vm = VmBuilder::new()
+        .fuel(FUEL)
+        .max_stack(MAX_STACK)
+        .build(chunk)
+        .map_err(early)?;
+    for (index, &arg) in args.iter().enumerate() {
+        vm.set_local(index, arg).map_err(early)?;
+    }
+    match vm.execute_all() {
+        Ok(outcome) => Ok(outcome.value),
+        Err(err) => Err(err.with_backtrace(&vm)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::VerifyError;
+    use crate::instruction::Instruction::*;
+
+    #[test]
+    fn test_eval() {
+        let add = [Load(0), Load(1), AddI];
+        let args = [Value::Integer(40), Value::Integer(2)];
+        assert_eq!(eval(&add, &args), Ok(Some(Value::Integer(42))));
+        assert_eq!(eval(&[], &[]), Ok(None));
+        assert_eq!(eval(&[Imm1, Imm0, DivI], &[]), Err(VmError::DivisionByZero));
+        assert_eq!(
+            eval(&[Goto(7)], &[]),
+            Err(VmError::InvalidChunk(VerifyError::InvalidJump {
+                offset: 0,
+                target: 7
+            }))
+        );
+    }
+
+    #[test]
+    fn test_eval_limits() {
+        assert_eq!(eval(&[Goto(0)], &[]), Err(VmError::FuelExhausted));
+        assert_eq!(eval(&[Imm0, Goto(0)], &[]), Err(VmError::StackOverflow));
+    }
+}
@@ -0,0 +1,281 @@
+use crate::builder::ChunkBuilder;
+use crate::chunk::Chunk;
+use crate::error::FrontendError;
+use crate::eval;
+use crate::opcode::OpCode::{self, *};
+use crate::value::Value;
+
+// A calculator language compiled to a chunk, as an example of a front end
+// and a test of the builder, verifier and VM together:
+//   expr    := compare ("?" expr ":" expr)?
+//   compare := sum (("==" | "!=" | "<=" | ">=" | "<" | ">") sum)?
+//   sum     := product (("+" | "-") product)*
+//   product := unary (("*" | "/") unary)*
+//   unary   := "-" unary | number | name | "(" expr ")"
+// Numbers are integers and comparisons give booleans, which only the
+// condition of a `?` takes; both branches have to be of the same type.
+// Variables get a local each in the order they first appear and are bound
+// when the program runs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    chunk: Chunk,
+    variables: Vec<String>,
+    // The source offset of the operator each division was compiled from,
+    // by the offset of its `DivI`.
+    lines: Vec<(usize, usize)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Type {
+    Integer,
+    Boolean,
+}
+
+impl Type {
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Integer => "integer",
+            Self::Boolean => "boolean",
+        }
+    }
+}
+
+pub fn compile(source: &str) -> Result<Program, FrontendError> {
+    let mut compiler = Compiler {
+        source,
+        offset: 0,
+        b: ChunkBuilder::new(),
+        variables: Vec::new(),
+        lines: Vec::new(),
+    };
+    compiler.expr()?;
+    compiler.skip_space();
+    if compiler.offset < source.len() {
+        return Err(compiler.expected("an operator"));
+    }
+    let chunk = compiler
+        .b
+        .max_locals(compiler.variables.len() as u16)
+        .build()
+        .unwrap();
+    Ok(Program {
+        chunk,
+        variables: compiler.variables,
+        lines: compiler.lines,
+    })
+}
+
+impl Program {
+    pub fn chunk(&self) -> &Chunk {
+        &self.chunk
+    }
+
+    // In the order of their locals.
+    pub fn variables(&self) -> &[String] {
+        &self.variables
+    }
+
+    // Runs the program with `env` binding its variables, giving an
+    // integer, or a word of 1 or 0 for a boolean.
+    pub fn run(&self, env: &[(&str, i64)]) -> Result<Value, FrontendError> {
+        let args = self
+            .variables
+            .iter()
+            .map(|name| match env.iter().find(|(bound, _)| bound == name) {
+                Some(&(_, val)) => Ok(Value::Integer(val)),
+                None => Err(FrontendError::Unbound(name.clone())),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        match eval::eval_chunk(self.chunk.clone(), &args) {
+            Ok(val) => Ok(val.unwrap()),
+            Err(err) => {
+                let ip = err.backtrace.0.first().map(|frame| frame.ip);
+                let at = self
+                    .lines
+                    .iter()
+                    .find(|&&(offset, _)| Some(offset) == ip)
+                    .map(|&(_, at)| at);
+                Err(FrontendError::Trap {
+                    error: err.error,
+                    at,
+                })
+            }
+        }
+    }
+}
+
+struct Compiler<'a> {
+    source: &'a str,
+    offset: usize,
+    b: ChunkBuilder,
+    variables: Vec<String>,
+    lines: Vec<(usize, usize)>,
+}
+
+impl<'a> Compiler<'a> {
+    fn expr(&mut self) -> Result<Type, FrontendError> {
+        let at = self.here();
+        let condition = self.compare()?;
+        if !self.eat("?") {
+            return Ok(condition);
+        }
+        self.check(condition, Type::Boolean, at)?;
+        let (then, otherwise, end) = (self.b.label(), self.b.label(), self.b.label());
+        self.b.goto_if(then).goto(otherwise).bind(then);
+        let ty = self.expr()?;
+        self.expect(":")?;
+        self.b.goto(end).bind(otherwise);
+        let at = self.here();
+        let other = self.expr()?;
+        self.b.bind(end);
+        self.check(other, ty, at)?;
+        Ok(ty)
+    }
+
+    fn compare(&mut self) -> Result<Type, FrontendError> {
+        let at = self.here();
+        let x = self.sum()?;
+        let comparisons = [
+            ("==", CmpEqI),
+            ("!=", CmpEqI),
+            ("<=", CmpLeI),
+            (">=", CmpGeI),
+            ("<", CmpLtI),
+            (">", CmpGtI),
+        ];
+        let Some((token, op)) = comparisons.into_iter().find(|(token, _)| self.eat(token)) else {
+            return Ok(x);
+        };
+        self.check(x, Type::Integer, at)?;
+        let at = self.here();
+        let y = self.sum()?;
+        self.check(y, Type::Integer, at)?;
+        self.b.op(op);
+        if token == "!=" {
+            self.b.imm_w(0).op(CmpEqW);
+        }
+        Ok(Type::Boolean)
+    }
+
+    fn sum(&mut self) -> Result<Type, FrontendError> {
+        self.operators(Self::product, &[("+", AddI), ("-", SubI)])
+    }
+
+    fn product(&mut self) -> Result<Type, FrontendError> {
+        self.operators(Self::unary, &[("*", MulI), ("/", DivI)])
+    }
+
+    // Left-associative integer operators over what `operand` compiles.
+    fn operators(
+        &mut self,
+        operand: fn(&mut Self) -> Result<Type, FrontendError>,
+        ops: &[(&str, OpCode)],
+    ) -> Result<Type, FrontendError> {
+        let mut at = self.here();
+        let mut ty = operand(self)?;
+        loop {
+            let op_at = self.here();
+            let Some(&(_, op)) = ops.iter().find(|(token, _)| self.eat(token)) else {
+                return Ok(ty);
+            };
+            self.check(ty, Type::Integer, at)?;
+            at = self.here();
+            ty = operand(self)?;
+            self.check(ty, Type::Integer, at)?;
+            if op == DivI {
+                self.lines.push((self.b.len(), op_at));
+            }
+            self.b.op(op);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Type, FrontendError> {
+        if self.eat("-") {
+            self.b.op(Imm0);
+            let at = self.here();
+            let ty = self.unary()?;
+            self.check(ty, Type::Integer, at)?;
+            self.b.op(SubI);
+            return Ok(ty);
+        }
+        if self.eat("(") {
+            let ty = self.expr()?;
+            self.expect(")")?;
+            return Ok(ty);
+        }
+        let start = self.here();
+        let name = self.take_while(|c| c.is_alphanumeric() || c == '_');
+        if name.starts_with(|c: char| c.is_ascii_digit()) {
+            let Ok(n) = name.parse() else {
+                self.offset = start;
+                return Err(self.expected("an integer"));
+            };
+            self.b.imm_i(n);
+        } else if name.is_empty() {
+            return Err(self.expected("an expression"));
+        } else {
+            let slot = match self.variables.iter().position(|known| known == name) {
+                Some(slot) => slot,
+                None => {
+                    self.variables.push(name.to_string());
+                    self.variables.len() - 1
+                }
+            };
+            self.b.load(slot as u16);
+        }
+        Ok(Type::Integer)
+    }
+
+    fn check(&self, found: Type, expected: Type, at: usize) -> Result<(), FrontendError> {
+        match found == expected {
+            true => Ok(()),
+            false => Err(FrontendError::TypeMismatch {
+                expected: expected.name(),
+                found: found.name(),
+                at,
+            }),
+        }
+    }
+
+    // Where the next token starts.
+    fn here(&mut self) -> usize {
+        self.skip_space();
+        self.offset
+    }
+
+    fn skip_space(&mut self) {
+        let rest = &self.source[self.offset..];
+        self.offset += rest.len() - rest.trim_start().len();
+    }
+
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> &'a str {
+        self.skip_space();
+        let rest = &self.source[self.offset..];
+        let len = rest.find(|c| !f(c)).unwrap_or(rest.len());
+        self.offset += len;
+        &rest[..len]
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_space();
+        let found = self.source[self.offset..].starts_with(token);
+        if found {
+            self.offset += token.len();
+        }
+        found
+    }
+
+    fn expect(&mut self, token: &'static str) -> Result<(), FrontendError> {
+        match self.eat(token) {
+            true => Ok(()),
+            false => Err(self.expected(token)),
+        }
+    }
+
+    fn expected(&self, expected: &'static str) -> FrontendError {
+        FrontendError::Syntax {
+            at: self.offset,
+            expected,
+        }
+    }
+}
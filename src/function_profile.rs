@@ -0,0 +1,247 @@
+use crate::chunk::Chunk;
+use std::fmt;
+
+// Instructions executed per function, counted while profiling is on. An
+// instruction counts towards the self count of the function whose frame
+// it ran in, and towards the inclusive count of that function and all its
+// callers; a function that recurses has each instruction counted once.
+// Slot 0 is the top level and slot `i + 1` function `i`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FunctionProfile {
+    counts: Vec<Counts>,
+    // The calls in progress, outermost first, with the total when each
+    // was made.
+    open: Vec<(usize, u64)>,
+    current: usize,
+    total: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Counts {
+    calls: u64,
+    instructions: u64,
+    inclusive: u64,
+    // How many of the calls in progress are to this function.
+    active: u32,
+}
+
+impl FunctionProfile {
+    // `active` is the functions of the calls already in progress,
+    // outermost first.
+    pub(crate) fn new(functions: usize, active: impl IntoIterator<Item = u16>) -> Self {
+        let mut profile = Self {
+            counts: vec![Counts::default(); functions + 1],
+            ..Self::default()
+        };
+        for function in active {
+            let slot = function as usize + 1;
+            if slot >= profile.counts.len() {
+                profile.counts.resize(slot + 1, Counts::default());
+            }
+            profile.counts[slot].active += 1;
+            profile.open.push((slot, 0));
+            profile.current = slot;
+        }
+        profile
+    }
+
+    pub(crate) fn step(&mut self) {
+        self.counts[self.current].instructions += 1;
+        self.total += 1;
+    }
+
+    pub(crate) fn enter(&mut self, function: u16) {
+        let slot = function as usize + 1;
+        if slot >= self.counts.len() {
+            self.counts.resize(slot + 1, Counts::default());
+        }
+        self.counts[slot].calls += 1;
+        self.counts[slot].active += 1;
+        self.open.push((slot, self.total));
+        self.current = slot;
+    }
+
+    pub(crate) fn leave(&mut self) {
+        if let Some((slot, start)) = self.open.pop() {
+            let counts = &mut self.counts[slot];
+            counts.active -= 1;
+            if counts.active == 0 {
+                counts.inclusive += self.total - start;
+            }
+        }
+        self.current = self.open.last().map_or(0, |&(slot, _)| slot);
+    }
+
+    // Abandons the calls in progress, as when the frames are unwound. What
+    // they ran so far is left out of the inclusive counts.
+    pub(crate) fn unwind(&mut self) {
+        for (slot, _) in self.open.drain(..) {
+            self.counts[slot].active = 0;
+        }
+        self.current = 0;
+    }
+
+    // Every function that was called or ran anything, and the top level,
+    // most self instructions first, named from `chunk`'s function table.
+    pub fn report(&self, chunk: &Chunk) -> FunctionReport {
+        let mut rows: Vec<_> = self
+            .counts
+            .iter()
+            .enumerate()
+            .filter(|&(slot, counts)| slot == 0 || counts.calls > 0 || counts.instructions > 0)
+            .map(|(slot, counts)| {
+                let function = slot.checked_sub(1).map(|index| index as u16);
+                let name = match function {
+                    None => "<top level>".to_string(),
+                    Some(index) => match chunk.functions().get(index as usize) {
+                        Some(f) => f
+                            .name
+                            .clone()
+                            .unwrap_or_else(|| format!("<function at {}>", f.entry)),
+                        None => format!("<function {index}>"),
+                    },
+                };
+                FunctionRow {
+                    function,
+                    name,
+                    calls: counts.calls,
+                    instructions: counts.instructions,
+                    inclusive: match function {
+                        None => self.total,
+                        Some(_) => counts.inclusive,
+                    },
+                }
+            })
+            .collect();
+        rows.sort_by_key(|row| (std::cmp::Reverse(row.instructions), row.function));
+        FunctionReport(rows)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionRow {
+    // `None` for the top level.
+    pub function: Option<u16>,
+    pub name: String,
+    pub calls: u64,
+    pub instructions: u64,
+    pub inclusive: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FunctionReport(pub Vec<FunctionRow>);
+
+impl fmt::Display for FunctionReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:>10} {:>10} {:>8}  function",
+            "self", "inclusive", "calls"
+        )?;
+        for row in &self.0 {
+            write!(
+                f,
+                "\n{:>10} {:>10} {:>8}  {}",
+                row.instructions, row.inclusive, row.calls, row.name
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::builder::ChunkBuilder;
+    use crate::config::VmBuilder;
+    use crate::opcode::OpCode::*;
+    use crate::vm::VM;
+
+    #[test]
+    fn test_report() {
+        let mut b = ChunkBuilder::new();
+        let (cheap_entry, expensive_entry, head, done) =
+            (b.label(), b.label(), b.label(), b.label());
+        let cheap = b.function(cheap_entry, 0);
+        let expensive = b.function(expensive_entry, 0);
+        b.function_name(cheap, "cheap");
+        b.function_name(expensive, "expensive");
+        for _ in 0..10 {
+            b.call(cheap);
+        }
+        b.call(expensive).op(Return);
+        b.bind(cheap_entry).op(Nop).op(Return);
+        b.bind(expensive_entry).imm_i(100).store(0);
+        b.bind(head).load(0).op(Imm0).op(CmpLeI).goto_if(done);
+        b.load(0).op(Imm1).op(SubI).store(0).goto(head);
+        b.bind(done).op(Return);
+
+        let mut vm = VM::new(b.build().unwrap());
+        assert_eq!(vm.function_profile(), None);
+        vm.set_function_profiling(true).unwrap();
+        let outcome = vm.execute_all().unwrap();
+        let report = vm.function_profile().unwrap();
+
+        let rows: Vec<_> = report
+            .0
+            .iter()
+            .map(|row| {
+                (
+                    row.name.as_str(),
+                    row.calls,
+                    row.instructions,
+                    row.inclusive,
+                )
+            })
+            .collect();
+        let total = outcome.instructions;
+        assert_eq!(
+            rows,
+            [
+                ("expensive", 1, total - 32, total - 32),
+                ("cheap", 10, 20, 20),
+                ("<top level>", 0, 12, total),
+            ]
+        );
+        assert!(total > 900);
+        assert!(report
+            .to_string()
+            .ends_with(&format!("{:>10} {total:>10} {:>8}  <top level>", 12, 0)));
+
+        vm.set_function_profiling(false).unwrap();
+        assert_eq!(vm.function_profile(), None);
+    }
+
+    #[test]
+    fn test_recursion() {
+        // a function that calls itself until its argument reaches zero
+        let mut b = ChunkBuilder::new();
+        let (entry, base) = (b.label(), b.label());
+        let countdown = b.function(entry, 1);
+        b.imm_i(5).call(countdown).op(Return);
+        b.bind(entry).load(0).op(Imm0).op(CmpLeI).goto_if(base);
+        b.load(0).op(Imm1).op(SubI).call(countdown);
+        b.bind(base).op(Return);
+
+        let mut vm = VmBuilder::new()
+            .function_profiling(true)
+            .build(b.build().unwrap())
+            .unwrap();
+        let total = vm.execute_all().unwrap().instructions;
+        let report = vm.function_profile().unwrap();
+        let row = &report.0[0];
+        assert_eq!((row.function, row.calls), (Some(countdown), 6));
+        assert_eq!(row.inclusive, total - 3);
+        assert_eq!(row.instructions, row.inclusive);
+        assert_eq!(report.0[1].name, "<top level>");
+    }
+
+    #[test]
+    fn test_active_past_the_chunk() {
+        // grows for a call in progress the way `enter` does for a new one
+        let mut profile = super::FunctionProfile::new(0, [0xFFFF]);
+        profile.step();
+        let report = profile.report(&ChunkBuilder::new().build().unwrap());
+        assert_eq!(report.0[0].function, Some(0xFFFF));
+        assert_eq!(report.0[0].name, "<function 65535>");
+    }
+}
@@ -0,0 +1,59 @@
+use crate::chunk::Chunk;
+use crate::config::VmBuilder;
+use crate::error::VmError;
+use crate::policy::ExecutionPolicy;
+use crate::vm::ExecutionOutcome;
+
+// Entry points for fuzzers. Whatever the input, running it has to end in
+// `Ok` or a `VmError`: never a panic or an abort, and never more time or
+// memory than the limits below allow.
+pub const FUEL: u64 = 100_000;
+pub const MAX_STACK: usize = 1 << 12;
+pub const MAX_HEAP_BYTES: usize = 1 << 22;
+
+pub fn policy() -> ExecutionPolicy {
+    ExecutionPolicy {
+        max_fuel: Some(FUEL),
+        max_stack: Some(MAX_STACK),
+        max_heap_bytes: Some(MAX_HEAP_BYTES),
+        ..ExecutionPolicy::permissive()
+    }
+}
+
+pub fn run_chunk(chunk: Chunk) -> Result<ExecutionOutcome, VmError> {
+    let mut vm = VmBuilder::new().policy(policy()).build(chunk)?;
+    vm.execute_all()
+}
+
+// The input is raw, unverified code.
+pub fn run_code(code: &[u8]) -> Result<ExecutionOutcome, VmError> {
+    run_chunk(Chunk::new(code.to_vec()))
+}
+
+// The input is a serialized chunk, which is also unverified once decoded.
+pub fn run_serialized(bytes: &[u8]) {
+    if let Ok(chunk) = Chunk::deserialize(bytes) {
+        let _ = run_chunk(chunk);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Rng;
+
+    #[test]
+    fn test_random_bytes() {
+        for seed in 0..2000 {
+            let mut rng = Rng::new(seed);
+            let len = rng.below(64);
+            let code: Vec<u8> = (0..len).map(|_| rng.next() as u8).collect();
+            let _ = run_code(&code);
+
+            let mut bytes = Chunk::new(code).serialize();
+            let at = rng.below(bytes.len());
+            bytes[at] ^= rng.next() as u8;
+            run_serialized(&bytes);
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use crate::chunk::{Chunk, Constant, Function};
+use crate::serialize;
+
+// A chunk's identity for caches and for recordings made against it: SHA-256
+// over what decides how it runs, so names, exports and imports are left out.
+// The bytes hashed are frozen, all integers big-endian:
+//   features: u32
+//   max_locals: u8 flag, then a u16 when the flag is set
+//   code: u32 length, bytes
+//   constants: u32 count, each serialized as in a chunk
+//   functions: u32 count, each a u32 entry, a u8 arity and a u8 flag
+//     followed by a u8 return count when set
+impl Chunk {
+    pub fn content_hash(&self) -> [u8; 32] {
+        content_hash(
+            self.features(),
+            self.max_locals(),
+            &self.public_code(),
+            self.constants(),
+            self.functions(),
+        )
+    }
+}
+
+pub(crate) fn content_hash(
+    features: u32,
+    max_locals: Option<u16>,
+    code: &[u8],
+    constants: &[Constant],
+    functions: &[Function],
+) -> [u8; 32] {
+    let mut out = features.to_be_bytes().to_vec();
+    match max_locals {
+        Some(max) => {
+            out.push(1);
+            out.extend(max.to_be_bytes());
+        }
+        None => out.push(0),
+    }
+    out.extend((code.len() as u32).to_be_bytes());
+    out.extend(code);
+    out.extend((constants.len() as u32).to_be_bytes());
+    for constant in constants {
+        serialize::put_constant(&mut out, constant);
+    }
+    out.extend((functions.len() as u32).to_be_bytes());
+    for function in functions {
+        out.extend((function.entry as u32).to_be_bytes());
+        out.push(function.arity);
+        match function.returns {
+            Some(count) => out.extend([1, count]),
+            None => out.push(0),
+        }
+    }
+    sha256(&out)
+}
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+// FIPS 180-4.
+pub fn sha256(bytes: &[u8]) -> [u8; 32] {
+    let mut state: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+    let mut padded = bytes.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56 {
+        padded.push(0);
+    }
+    padded.extend((bytes.len() as u64 * 8).to_be_bytes());
+
+    for block in padded.chunks_exact(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (word, add) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *word = word.wrapping_add(add);
+        }
+    }
+
+    let mut digest = [0; 32];
+    for (out, word) in digest.chunks_exact_mut(4).zip(state) {
+        out.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::ChunkBuilder;
+    use crate::instruction::{self, Instruction};
+    use crate::opcode::OpCode::*;
+
+    fn hex(digest: [u8; 32]) -> String {
+        digest.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    #[test]
+    fn test_sha256() {
+        assert_eq!(
+            hex(sha256(b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            hex(sha256(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        // two blocks, since the length no longer fits after the padding
+        assert_eq!(
+            hex(sha256(&[b'a'; 56])),
+            "b35439a4ac6f0948b6d6f9e3c6af0f5f590ce20f1bde7090ef7970686ec6738a"
+        );
+    }
+
+    #[test]
+    fn test_content_hash() {
+        let built = ChunkBuilder::new()
+            .imm_i(1000)
+            .imm_i(2)
+            .op(AddI)
+            .build()
+            .unwrap();
+        let encoded = instruction::encode(&[
+            Instruction::ImmI16(1000),
+            Instruction::ImmI8(2),
+            Instruction::AddI,
+        ]);
+        assert_eq!(built.content_hash(), encoded.content_hash());
+        // only what decides how the chunk runs is hashed
+        let exported = encoded.with_exports(vec![("f".to_string(), 0)]);
+        assert_eq!(exported.content_hash(), built.content_hash());
+
+        let flipped = instruction::encode(&[
+            Instruction::ImmI16(1001),
+            Instruction::ImmI8(2),
+            Instruction::AddI,
+        ]);
+        assert_ne!(flipped.content_hash(), built.content_hash());
+        assert_ne!(
+            built.clone().with_max_locals(0).content_hash(),
+            built.content_hash()
+        );
+    }
+}
@@ -1,38 +1,188 @@
+use crate::map::MapIndex;
+use crate::snapshot::HeapSnapshot;
 use crate::value::Value;
 use std::{
     cell::Cell,
-    ops::{Deref, DerefMut},
+    collections::{HashMap, HashSet},
+    fmt,
+    hash::{BuildHasherDefault, Hasher},
+    mem,
     ptr::{self, NonNull},
 };
 
 const HEAP_THRESHOLD: usize = 1024;
-#[derive(Debug, Clone, PartialEq)]
+const FREE_LIST_CAP: usize = 1024;
+const BLOCK_LEN: usize = 1024;
+
+// Tags from 0xf0 up are reserved for objects with a meaning to the VM itself.
+pub mod tag {
+    pub const STRING: u8 = 0xff;
+    pub const MAP: u8 = 0xfe;
+    pub const WEAK: u8 = 0xfd;
+    pub const ARRAY: u8 = 0xfc;
+    pub const ITERATOR: u8 = 0xfb;
+    pub const BUFFER: u8 = 0xfa;
+    pub const ERROR: u8 = 0xf9;
+}
+
+#[derive(Debug)]
 pub struct Heap {
+    mode: HeapMode,
     head: *mut HeapObject,
     size: usize,
+    bytes: usize,
     threshold: usize,
+    stress: bool,
+    marking: bool,
+    gray: Vec<ObjectPtr>,
+    free: Vec<*mut HeapObject>,
+    free_cap: usize,
+    interned: HashMap<String, ObjectPtr>,
+    finalizers: Finalizers,
+    weak_refs: Vec<ObjectPtr>,
+    arena: Option<NonNull<[HeapObject]>>,
+    blocks: Vec<Vec<HeapObject>>,
+    stats: HeapStats,
+    next_id: u64,
+    // Every node holding a live object, for checking the pointers the host
+    // hands in; see `ObjectPtr`.
+    live: HashSet<ObjectPtr, BuildHasherDefault<NodeHasher>>,
+}
+
+// Nodes are at least 8-byte aligned, so the low bits of their addresses
+// carry nothing and the rest is spread by a multiply.
+#[derive(Default)]
+struct NodeHasher(u64);
+
+impl Hasher for NodeHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 << 8 | byte as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        }
+    }
+
+    fn write_usize(&mut self, n: usize) {
+        self.0 = (n as u64 >> 3).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+// In arena mode objects are bump-allocated in blocks and never collected;
+// everything is released at once when the heap is cleared or dropped.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum HeapMode {
+    #[default]
+    Collected,
+    Arena,
+}
+
+pub type Finalizer = Box<dyn FnMut(&Object)>;
+
+// Finalizers only see the object's contents, so they can neither resurrect
+// it nor allocate while the heap is being swept.
+#[derive(Default)]
+struct Finalizers(HashMap<u8, Finalizer>);
+
+impl Finalizers {
+    fn run(&mut self, obj: &Object) {
+        if let Some(finalizer) = self.0.get_mut(&obj.tag) {
+            finalizer(obj);
+        }
+    }
+}
+
+impl fmt::Debug for Finalizers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    pub live: usize,
+    pub fresh: u64,
+    pub recycled: u64,
+    pub freed: u64,
+    pub collections: u64,
+    pub freed_bytes: u64,
+    // Summed over the collections an observer saw; the clock isn't read
+    // without one.
+    pub pause_nanos: u64,
+    pub last_collection: Option<GcReport>,
+}
+
+// Why a collection ran. `Threshold` includes collections forced by the
+// policy's heap limit; `Explicit` is the host or the `Gc` instruction.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum GcTrigger {
+    Threshold,
+    #[default]
+    Explicit,
+    Stress,
+}
+
+// One collection's pause: for an incremental cycle, only the final trace
+// and sweep.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GcReport {
+    pub trigger: GcTrigger,
+    pub nanos: u64,
+    pub objects_before: usize,
+    pub objects_after: usize,
+    pub bytes_before: usize,
+    pub bytes_after: usize,
+}
+
+pub type GcObserver = Box<dyn FnMut(&GcReport)>;
+
+#[derive(Default)]
+pub(crate) struct Observer(pub(crate) Option<GcObserver>);
+
+impl fmt::Debug for Observer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(if self.0.is_some() { "Some(..)" } else { "None" })
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct HeapObject {
-    pub next: *mut Self,
-    pub color: Cell<Color>,
-    pub data: Object,
+    next: *mut Self,
+    id: u64,
+    bytes: usize,
+    pub(crate) color: Cell<Color>,
+    pub(crate) interned: Cell<bool>,
+    pub(crate) data: Object,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Object {
     pub tag: u8,
     pub fields: Vec<Value>,
+    pub(crate) map_index: Option<Box<MapIndex>>,
+    pub(crate) weak: Option<ObjectPtr>,
+    pub(crate) buffer: Option<Box<[u8]>>,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub struct ObjectPtr(pub NonNull<HeapObject>);
+// Pointers are only handed out by the heap and stay valid for as long as the
+// object is reachable from the VM's roots. Objects allocated on behalf of the
+// host are rooted until they are explicitly unrooted. The host can still keep
+// a copy past that, so every public way to read through a pointer or hand one
+// to the VM checks that it is live in that heap first, and panics if not;
+// only the crate itself follows pointers unchecked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObjectPtr(NonNull<HeapObject>);
 
+// Unmarked objects are white and Reachable objects black. Gray objects are
+// known to be live, but their fields haven't been traced yet; they only
+// exist while an incremental marking cycle is in progress.
 #[derive(Debug, Default, Clone, Copy, PartialEq)]
 pub enum Color {
     #[default]
     Unmarked,
+    Gray,
     Reachable,
 }
 
@@ -41,94 +191,871 @@ impl Heap {
         Default::default()
     }
 
+    pub fn with_mode(mode: HeapMode) -> Self {
+        let mut heap = Self::new();
+        heap.mode = mode;
+        heap
+    }
+
+    pub const fn mode(&self) -> HeapMode {
+        self.mode
+    }
+
     pub const fn is_full(&self) -> bool {
         self.size >= self.threshold
     }
 
-    pub fn new_object(&mut self, obj: Object) -> ObjectPtr {
-        if self.is_full() {
-            self.sweep();
+    pub const fn len(&self) -> usize {
+        self.size
+    }
+
+    // How many objects there may be before the next collection.
+    pub const fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    // Approximate memory used by live objects.
+    pub const fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    // In stress mode every allocation is preceded by a full collection,
+    // which flushes out values that aren't rooted while a handler runs.
+    pub fn set_stress(&mut self, stress: bool) {
+        self.stress = stress;
+    }
+
+    pub const fn is_stress(&self) -> bool {
+        self.stress
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.mode == HeapMode::Collected && (self.stress || self.is_full())
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            live: self.size,
+            ..self.stats
+        }
+    }
+
+    pub fn free_list_len(&self) -> usize {
+        self.free.len()
+    }
+
+    // Freed nodes are kept for reuse by later allocations, up to `cap` of
+    // them; the rest go back to the system allocator.
+    pub fn set_free_list_cap(&mut self, cap: usize) {
+        self.free_cap = cap;
+        for node in self.free.drain(cap.min(self.free.len())..) {
+            if !owns(self.arena, node) {
+                drop(unsafe { Box::from_raw(node) });
+            }
         }
+    }
+
+    // Runs `finalizer` on every object with this tag when it is freed,
+    // including by dropping the heap. Replaces any earlier finalizer for the
+    // same tag.
+    pub fn register_finalizer(&mut self, tag: u8, finalizer: Finalizer) {
+        self.finalizers.0.insert(tag, finalizer);
+    }
+
+    pub fn contains(&self, ptr: ObjectPtr) -> bool {
+        self.live.contains(&ptr)
+    }
+
+    pub fn get(&self, ptr: ObjectPtr) -> &Object {
+        self.check(ptr);
+        self.object(ptr)
+    }
+
+    // The heap id of a live object, see `HeapObject::id`.
+    pub fn id(&self, ptr: ObjectPtr) -> u64 {
+        self.check(ptr);
+        ptr.id()
+    }
+
+    pub(crate) fn check(&self, ptr: ObjectPtr) {
+        assert!(self.contains(ptr), "object pointer isn't live in this heap");
+    }
+
+    pub(crate) fn check_value(&self, val: Value) {
+        if let Value::ObjectPtr(ptr) = val {
+            self.check(ptr);
+        }
+    }
+
+    // `get` for pointers the VM itself holds, which are live by construction.
+    pub(crate) fn object(&self, ptr: ObjectPtr) -> &Object {
+        unsafe { &ptr.0.as_ref().data }
+    }
 
-        let obj = HeapObject::new(self.head, obj);
+    // Taking the heap mutably keeps two objects from being borrowed mutably
+    // at once through copies of the same pointer.
+    pub(crate) fn get_mut(&mut self, ptr: ObjectPtr) -> &mut Object {
+        unsafe { &mut (*ptr.0.as_ptr()).data }
+    }
+
+    // Charges `ptr` for its fields as they are now, as when an array grows
+    // or shrinks, so that its size counts towards the heap limit.
+    pub(crate) fn recharge(&mut self, ptr: ObjectPtr) {
+        let node = unsafe { &mut *ptr.0.as_ptr() };
+        let bytes = HeapObject::size(&node.data);
+        self.bytes = self.bytes - node.bytes + bytes;
+        node.bytes = bytes;
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = ObjectPtr> + '_ {
+        let mut ptr = self.head;
+        std::iter::from_fn(move || {
+            let obj = NonNull::new(ptr)?;
+            ptr = unsafe { obj.as_ref().next };
+            Some(ObjectPtr(obj))
+        })
+    }
+
+    // Callers are responsible for marking roots and sweeping beforehand,
+    // see `VM::alloc`.
+    pub fn new_object(&mut self, obj: Object) -> ObjectPtr {
+        obj.fields.iter().for_each(|&field| self.check_value(field));
+        if let Some(target) = obj.weak {
+            self.check(target);
+        }
+        let obj = HeapObject::new(self.head, self.next_id, obj);
+        self.next_id += 1;
+        self.bytes += obj.bytes;
 
-        let ptr = Box::into_raw(Box::new(obj));
+        let ptr = match self.free.pop() {
+            _ if self.mode == HeapMode::Arena => {
+                self.stats.fresh += 1;
+                self.bump(obj)
+            }
+            Some(node) => {
+                unsafe { *node = obj };
+                self.stats.recycled += 1;
+                node
+            }
+            None => {
+                self.stats.fresh += 1;
+                Box::into_raw(Box::new(obj))
+            }
+        };
         self.head = ptr;
         self.size += 1;
+        let ptr = ObjectPtr(NonNull::new(ptr).unwrap());
+        self.live.insert(ptr);
+
+        // Objects allocated mid-cycle may hold pointers to white objects, so
+        // they start out gray rather than being skipped by the marker.
+        if ptr.node().data.weak.is_some() {
+            self.weak_refs.push(ptr);
+        }
+        if self.marking {
+            ptr.node().color.set(Color::Gray);
+            self.gray.push(ptr);
+        }
+        ptr
+    }
 
-        ObjectPtr(NonNull::new(ptr).unwrap())
+    fn bump(&mut self, obj: HeapObject) -> *mut HeapObject {
+        let block = match self.blocks.last_mut() {
+            Some(block) if block.len() < block.capacity() => block,
+            _ => {
+                self.blocks.push(Vec::with_capacity(BLOCK_LEN));
+                self.blocks.last_mut().unwrap()
+            }
+        };
+        block.push(obj);
+        unsafe { block.as_mut_ptr().add(block.len() - 1) }
     }
 
-    pub fn sweep(&mut self) {
-        let mut ptr = &mut self.head;
+    // Returns the canonical string with the same contents as `ptr`, making
+    // `ptr` canonical if there is none yet, or `None` if it isn't a string.
+    // The table holds its strings weakly: an interned string that is
+    // otherwise unreachable is collected and evicted.
+    pub fn intern(&mut self, ptr: ObjectPtr) -> Option<ObjectPtr> {
+        self.check(ptr);
+        if ptr.node().interned.get() {
+            return Some(ptr);
+        }
+        let s = self.get(ptr).as_string()?;
+        let canonical = self.interned.entry(s).or_insert_with(|| {
+            ptr.node().interned.set(true);
+            ptr
+        });
+        Some(*canonical)
+    }
+
+    pub fn lookup_interned(&self, s: &str) -> Option<ObjectPtr> {
+        self.interned.get(s).copied()
+    }
+
+    // Points a weak reference allocated without a target at `target`, for
+    // restoring a checkpoint once every object in it exists.
+    pub(crate) fn set_weak(&mut self, weak: ObjectPtr, target: ObjectPtr) {
+        self.get_mut(weak).weak = Some(target);
+        self.weak_refs.push(weak);
+    }
+
+    pub fn interned_len(&self) -> usize {
+        self.interned.len()
+    }
+
+    pub const fn is_marking(&self) -> bool {
+        self.marking
+    }
+
+    pub(crate) fn start_marking(&mut self) {
+        self.marking = true;
+    }
+
+    pub(crate) fn shade(&mut self, ptr: ObjectPtr) {
+        if ptr.node().color.get() == Color::Unmarked {
+            ptr.node().color.set(Color::Gray);
+            self.gray.push(ptr);
+        }
+    }
+
+    // Traces up to `budget` gray objects, returning whether any are left.
+    pub(crate) fn mark_step(&mut self, budget: usize) -> bool {
+        for _ in 0..budget {
+            let Some(ptr) = self.gray.pop() else {
+                break;
+            };
+            ptr.node().color.set(Color::Reachable);
+            for field in &ptr.node().data.fields {
+                if let Some(child) = field.get_object_ptr() {
+                    self.shade(child);
+                }
+            }
+        }
+        !self.gray.is_empty()
+    }
+
+    // Must be called whenever an object's fields are mutated. A black object
+    // gaining a pointer to a white one would hide it from the marker, so the
+    // object is turned gray again and re-traced.
+    pub(crate) fn write_barrier(&mut self, ptr: ObjectPtr) {
+        if self.marking && ptr.node().reachable() {
+            ptr.node().color.set(Color::Gray);
+            self.gray.push(ptr);
+        }
+    }
+
+    pub(crate) fn sweep(&mut self) {
+        self.marking = false;
+        self.gray.clear();
+        if self.mode == HeapMode::Arena {
+            self.iter().for_each(|ptr| ptr.node().unmark());
+            return;
+        }
+
+        self.clear_weak_refs();
+
+        let mut ptr = self.head;
+        let mut prev: Option<&mut HeapObject> = None;
         while let Some(obj) = unsafe { ptr.as_mut() } {
-            if obj.reachable() {
+            let node = ptr;
+            ptr = obj.next;
+            if obj.color.get() != Color::Unmarked {
                 obj.unmark();
-                ptr = &mut obj.next;
+                prev = Some(obj);
             } else {
-                *ptr = unsafe { Box::from_raw(*ptr) }.next;
+                self.discard(obj);
+                self.live.remove(&ObjectPtr(NonNull::new(node).unwrap()));
+                match &mut prev {
+                    Some(prev) => prev.next = ptr,
+                    None => self.head = ptr,
+                }
                 self.size -= 1;
+                release(&mut self.free, self.free_cap, self.arena, node);
+            }
+        }
+        self.finish_collection();
+    }
+
+    // Like `sweep`, but the survivors are moved into a fresh contiguous
+    // arena and every node they used to occupy is freed. Pointers between
+    // heap objects are rewritten; the returned map tells the caller where
+    // the pointers it holds have moved to.
+    pub(crate) fn compact(&mut self) -> HashMap<ObjectPtr, ObjectPtr> {
+        if self.mode == HeapMode::Arena {
+            self.sweep();
+            return self.iter().map(|ptr| (ptr, ptr)).collect();
+        }
+        self.marking = false;
+        self.gray.clear();
+        self.clear_weak_refs();
+
+        let mut old = Vec::new();
+        let mut live = Vec::new();
+        let mut moved = Vec::new();
+        let mut ptr = self.head;
+        while let Some(node) = NonNull::new(ptr) {
+            let obj = unsafe { &mut *node.as_ptr() };
+            ptr = obj.next;
+            old.push(node.as_ptr());
+            if obj.color.get() == Color::Unmarked {
+                self.discard(obj);
+                continue;
+            }
+            let data = mem::replace(&mut obj.data, Object::new(0, Vec::new()));
+            let mut copy = HeapObject::new(ptr::null_mut(), obj.id, data);
+            copy.bytes = obj.bytes;
+            copy.interned.set(obj.interned.get());
+            moved.push(copy);
+            live.push(ObjectPtr(node));
+        }
+
+        let arena = NonNull::from(Box::leak(moved.into_boxed_slice()));
+        let base = arena.as_ptr() as *mut HeapObject;
+        let forward: HashMap<_, _> = (live.into_iter().enumerate())
+            .map(|(i, ptr)| {
+                (
+                    ptr,
+                    ObjectPtr(unsafe { NonNull::new_unchecked(base.add(i)) }),
+                )
+            })
+            .collect();
+
+        let len = arena.len();
+        for i in 0..len {
+            let obj = unsafe { &mut *base.add(i) };
+            obj.next = if i + 1 < len {
+                unsafe { base.add(i + 1) }
+            } else {
+                ptr::null_mut()
+            };
+            for field in &mut obj.data.fields {
+                if let Value::ObjectPtr(ptr) = field {
+                    *ptr = forward[ptr];
+                }
+            }
+            if let Some(target) = &mut obj.data.weak {
+                *target = forward[target];
+            }
+        }
+        self.head = if len > 0 { base } else { ptr::null_mut() };
+        self.size = len;
+        self.live = forward.values().copied().collect();
+        self.weak_refs = self.weak_refs.iter().map(|weak| forward[weak]).collect();
+        for ptr in self.interned.values_mut() {
+            *ptr = forward[ptr];
+        }
+
+        for node in self.free.drain(..).chain(old) {
+            if !owns(self.arena, node) {
+                drop(unsafe { Box::from_raw(node) });
+            }
+        }
+        if let Some(old) = self.arena.replace(arena) {
+            drop(unsafe { Box::from_raw(old.as_ptr()) });
+        }
+        self.finish_collection();
+        forward
+    }
+
+    // Number of slots in the arena built by the last compaction, live or not.
+    pub fn arena_len(&self) -> usize {
+        self.arena.map_or(0, |arena| arena.len())
+    }
+
+    // Weak targets are cleared once marking is over but before anything is
+    // freed, so no weak reference can observe a dead object.
+    fn clear_weak_refs(&mut self) {
+        self.weak_refs
+            .retain(|weak| weak.node().color.get() != Color::Unmarked);
+        for &weak in &self.weak_refs {
+            let obj = unsafe { &mut (*weak.0.as_ptr()).data };
+            if obj
+                .weak
+                .is_some_and(|target| target.node().color.get() == Color::Unmarked)
+            {
+                obj.weak = None;
+            }
+        }
+    }
+
+    fn discard(&mut self, obj: &HeapObject) {
+        if obj.interned.get() {
+            if let Some(s) = obj.data.as_string() {
+                self.interned.remove(&s);
             }
         }
+        self.finalizers.run(&obj.data);
+        self.stats.freed += 1;
+        self.bytes -= obj.bytes;
+    }
+
+    pub(crate) fn record_collection(&mut self, report: GcReport) {
+        let freed = report.bytes_before.saturating_sub(report.bytes_after);
+        self.stats.freed_bytes += freed as u64;
+        self.stats.pause_nanos += report.nanos;
+        self.stats.last_collection = Some(report);
+    }
+
+    fn finish_collection(&mut self) {
+        self.stats.collections += 1;
+        self.threshold = (self.size * 2).max(HEAP_THRESHOLD);
+    }
+}
+
+fn owns(arena: Option<NonNull<[HeapObject]>>, node: *mut HeapObject) -> bool {
+    arena.is_some_and(|arena| {
+        let start = arena.as_ptr() as *mut HeapObject;
+        (start..start.wrapping_add(arena.len())).contains(&node)
+    })
+}
+
+// Recycled nodes have their contents dropped right away, so nothing
+// reachable from a dead object outlives the sweep that freed it.
+// Nodes in the arena can't be freed on their own, so those that don't fit in
+// the free list are left empty until the arena itself is dropped.
+fn release(
+    free: &mut Vec<*mut HeapObject>,
+    cap: usize,
+    arena: Option<NonNull<[HeapObject]>>,
+    node: *mut HeapObject,
+) {
+    if free.len() < cap || owns(arena, node) {
+        unsafe { *node = HeapObject::new(ptr::null_mut(), 0, Object::new(0, Vec::new())) };
+        if free.len() < cap {
+            free.push(node);
+        }
+    } else {
+        drop(unsafe { Box::from_raw(node) });
+    }
+}
+
+impl Heap {
+    // Frees every object, running finalizers, but keeps the heap's settings.
+    pub(crate) fn clear(&mut self) {
+        let mut ptr = self.head;
+        while let Some(obj) = unsafe { ptr.as_ref() } {
+            self.finalizers.run(&obj.data);
+            let node = ptr;
+            ptr = obj.next;
+            if self.mode == HeapMode::Collected {
+                release(&mut self.free, self.free_cap, self.arena, node);
+            }
+        }
+        self.blocks.clear();
+
+        self.head = ptr::null_mut();
+        self.size = 0;
+        self.bytes = 0;
+        self.threshold = HEAP_THRESHOLD;
+        self.marking = false;
+        self.gray.clear();
+        self.interned.clear();
+        self.weak_refs.clear();
+        self.live.clear();
+        self.next_id = 0;
+    }
+}
+
+// Read-only access to a heap for analysis on other threads, borrowed from a
+// paused VM so that it can neither run nor allocate while a view is alive.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapView<'a> {
+    heap: &'a Heap,
+}
+
+// Objects are only written, their colours and interned flags included,
+// through `&mut Heap` or by a collection, which borrows the VM mutably. Shared
+// borrows only ever read them, so threads holding the same view can't race.
+unsafe impl Sync for HeapView<'_> {}
+unsafe impl Send for HeapView<'_> {}
+
+impl<'a> HeapView<'a> {
+    pub(crate) fn new(heap: &'a Heap) -> Self {
+        Self { heap }
+    }
+
+    pub fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.heap.is_empty()
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.heap.bytes()
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        self.heap.stats()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = ObjectPtr> + 'a {
+        self.heap.iter()
+    }
+
+    pub fn get(&self, ptr: ObjectPtr) -> &'a Object {
+        self.heap.get(ptr)
+    }
+
+    pub fn lookup_interned(&self, s: &str) -> Option<ObjectPtr> {
+        self.heap.lookup_interned(s)
+    }
+
+    pub fn format_object(&self, ptr: ObjectPtr, max_depth: usize, max_fields: usize) -> String {
+        self.heap.format_object(ptr, max_depth, max_fields)
+    }
 
-        self.threshold = self.size * 2;
+    // Without collecting first, garbage not swept yet is counted too.
+    pub fn snapshot(&self) -> HeapSnapshot {
+        HeapSnapshot::new(self.heap)
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        self.clear();
+
+        for node in self.free.drain(..) {
+            if !owns(self.arena, node) {
+                drop(unsafe { Box::from_raw(node) });
+            }
+        }
+        if let Some(arena) = self.arena {
+            drop(unsafe { Box::from_raw(arena.as_ptr()) });
+        }
     }
 }
 
 impl Default for Heap {
     fn default() -> Self {
         Self {
+            mode: HeapMode::default(),
             head: ptr::null_mut(),
             size: 0,
+            bytes: 0,
             threshold: HEAP_THRESHOLD,
+            stress: false,
+            marking: false,
+            gray: Vec::new(),
+            free: Vec::new(),
+            free_cap: FREE_LIST_CAP,
+            interned: HashMap::new(),
+            finalizers: Finalizers::default(),
+            weak_refs: Vec::new(),
+            arena: None,
+            blocks: Vec::new(),
+            stats: HeapStats::default(),
+            next_id: 0,
+            live: HashSet::default(),
+        }
+    }
+}
+
+impl Object {
+    pub fn new(tag: u8, fields: Vec<Value>) -> Self {
+        Self {
+            tag,
+            fields,
+            map_index: None,
+            weak: None,
+            buffer: None,
+        }
+    }
+
+    // Weak references don't keep their target alive. The target is kept out
+    // of the fields so that the marker never sees it.
+    pub fn weak(target: ObjectPtr) -> Self {
+        Self {
+            weak: Some(target),
+            ..Self::new(tag::WEAK, Vec::new())
         }
     }
+
+    // `None` if this isn't a weak reference, `Some(None)` once the target
+    // has been collected.
+    pub fn weak_target(&self) -> Option<Option<ObjectPtr>> {
+        (self.tag == tag::WEAK).then_some(self.weak)
+    }
+
+    // Arrays start out with every element null.
+    pub fn array(len: usize) -> Self {
+        Self::new(tag::ARRAY, vec![Value::Null; len])
+    }
+
+    // Strings are objects whose fields are the characters, in order.
+    pub fn string(s: &str) -> Self {
+        Self::new(tag::STRING, s.chars().map(Value::Char).collect())
+    }
+
+    // Errors a trap handler hands to the guest: the `VmError::kind` as an
+    // integer, the ip of the instruction that trapped as a word, and the
+    // message as a string, or null.
+    pub fn error(kind: i64, ip: usize, message: Value) -> Self {
+        let fields = vec![Value::Integer(kind), Value::Word(ip as u64), message];
+        Self::new(tag::ERROR, fields)
+    }
+
+    pub fn as_string(&self) -> Option<String> {
+        if self.tag != tag::STRING {
+            return None;
+        }
+        self.fields
+            .iter()
+            .map(|field| match field {
+                Value::Char(c) => Some(*c),
+                _ => None,
+            })
+            .collect()
+    }
 }
 
 impl HeapObject {
-    pub fn new(next: *mut Self, data: Object) -> Self {
+    // Objects are charged for their fields and bytes when they are
+    // allocated; fields added later, such as new map entries, aren't
+    // counted unless the object is recharged.
+    fn new(next: *mut Self, id: u64, data: Object) -> Self {
         Self {
             next,
+            id,
+            bytes: Self::size(&data),
             color: Cell::new(Color::default()),
+            interned: Cell::new(false),
             data,
         }
     }
 
+    pub(crate) fn size(data: &Object) -> usize {
+        let buffer = data.as_buffer().map_or(0, <[u8]>::len);
+        mem::size_of::<Self>() + data.fields.len() * mem::size_of::<Value>() + buffer
+    }
+
+    // Numbered from 0 in the order the heap allocated objects since it was
+    // created or last cleared, so the same run numbers them the same way
+    // wherever they end up in memory. Collection and compaction keep it.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn data(&self) -> &Object {
+        &self.data
+    }
+
+    pub fn color(&self) -> Color {
+        self.color.get()
+    }
+
+    pub fn is_interned(&self) -> bool {
+        self.interned.get()
+    }
+
     pub fn reachable(&self) -> bool {
         self.color.get() == Color::Reachable
     }
 
-    pub fn mark(&self) {
+    // Traced with an explicit worklist rather than by recursion, so long
+    // chains of objects can't overflow the native stack.
+    pub(crate) fn mark(&self) {
+        if self.reachable() {
+            return;
+        }
         self.color.set(Color::Reachable);
 
-        for field in &self.data.fields {
-            if let Some(ptr) = field.get_object_ptr() {
-                ptr.mark();
+        let mut pending = Vec::new();
+        let trace = |fields: &[Value], pending: &mut Vec<ObjectPtr>| {
+            for field in fields {
+                if let Some(ptr) = field.get_object_ptr().filter(|ptr| !ptr.node().reachable()) {
+                    ptr.node().color.set(Color::Reachable);
+                    pending.push(ptr);
+                }
             }
+        };
+        trace(&self.data.fields, &mut pending);
+        while let Some(ptr) = pending.pop() {
+            trace(&ptr.node().data.fields, &mut pending);
         }
     }
 
-    pub fn unmark(&self) {
+    pub(crate) fn unmark(&self) {
         self.color.set(Color::Unmarked);
     }
 }
 
 impl ObjectPtr {
-    pub fn new(obj: HeapObject) -> Self {
-        Self(NonNull::from(&obj))
+    /// # Safety
+    ///
+    /// `ptr` must point to a live object owned by a `Heap`, such as one
+    /// previously returned by `as_raw`, and must not be used once that object
+    /// has been freed.
+    pub unsafe fn from_raw(ptr: NonNull<HeapObject>) -> Self {
+        Self(ptr)
     }
-}
 
-impl Deref for ObjectPtr {
-    type Target = HeapObject;
+    pub fn as_raw(self) -> NonNull<HeapObject> {
+        self.0
+    }
 
-    fn deref(&self) -> &Self::Target {
+    // Only for pointers known to be live; the host goes through the heap.
+    pub(crate) fn node(&self) -> &HeapObject {
         unsafe { self.0.as_ref() }
     }
+
+    pub(crate) fn id(self) -> u64 {
+        self.node().id
+    }
 }
 
-impl DerefMut for ObjectPtr {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe { self.0.as_mut() }
+// None of the heap's raw pointers can be forged or written from safe code
+// outside the crate, nor followed without the heap checking them.
+#[cfg(doctest)]
+/// ```compile_fail
+/// let ptr = andrea::heap::ObjectPtr(std::ptr::NonNull::dangling());
+/// ```
+///
+/// ```compile_fail
+/// let mut vm = andrea::vm::VM::default();
+/// let ptr = vm.alloc_object(1, vec![]);
+/// let id = ptr.id();
+/// ```
+///
+/// ```compile_fail
+/// let mut vm = andrea::vm::VM::default();
+/// let ptr = vm.alloc_object(1, vec![]);
+/// let next = ptr.next;
+/// ```
+///
+/// ```compile_fail
+/// let mut vm = andrea::vm::VM::default();
+/// let mut ptr = vm.alloc_object(1, vec![]);
+/// ptr.data = andrea::heap::Object::new(2, vec![]);
+/// ```
+///
+/// ```compile_fail
+/// let mut heap = andrea::heap::Heap::new();
+/// heap.sweep();
+/// ```
+///
+/// ```compile_fail
+/// let mut vm = andrea::vm::VM::default();
+/// let view = vm.heap_view();
+/// vm.execute_all().unwrap();
+/// view.len();
+/// ```
+mod compile_fail {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::VM;
+
+    fn small(i: i64) -> Object {
+        Object::new(1, vec![Value::Integer(i)])
+    }
+
+    #[test]
+    fn test_free_list_recycling() {
+        let mut heap = Heap::new();
+        let live = heap.new_object(small(-1));
+        for i in 0..10 {
+            heap.new_object(small(i));
+        }
+        live.node().mark();
+        heap.sweep();
+
+        assert_eq!(heap.free_list_len(), 10);
+        let stats = heap.stats();
+        assert_eq!(
+            (stats.live, stats.fresh, stats.recycled, stats.freed),
+            (1, 11, 0, 10)
+        );
+
+        let reused: Vec<_> = (0..4).map(|i| heap.new_object(small(100 + i))).collect();
+        let stats = heap.stats();
+        assert_eq!((stats.live, stats.fresh, stats.recycled), (5, 11, 4));
+        assert_eq!(heap.free_list_len(), 6);
+        for (i, ptr) in reused.iter().enumerate() {
+            assert_eq!(heap.get(*ptr), &small(100 + i as i64));
+            assert!(!ptr.node().reachable());
+        }
+
+        heap.set_free_list_cap(2);
+        assert_eq!(heap.free_list_len(), 2);
+        for _ in 0..3 {
+            heap.new_object(small(0));
+        }
+        let stats = heap.stats();
+        assert_eq!((stats.fresh, stats.recycled), (12, 6));
+    }
+
+    #[test]
+    fn test_free_list_cap() {
+        let mut heap = Heap::new();
+        heap.set_free_list_cap(3);
+        for i in 0..10 {
+            heap.new_object(small(i));
+        }
+        heap.sweep();
+        assert_eq!(heap.free_list_len(), 3);
+        assert!(heap.is_empty());
+        assert_eq!(heap.stats().freed, 10);
+
+        heap.set_free_list_cap(0);
+        heap.new_object(small(0));
+        heap.sweep();
+        assert_eq!(heap.free_list_len(), 0);
+        assert_eq!(heap.stats().fresh, 11);
+    }
+
+    #[test]
+    fn test_view_across_threads() {
+        let mut vm = VM::default();
+        let mut list = Value::Null;
+        for i in 0..100 {
+            list = Value::ObjectPtr(vm.alloc_object(1, vec![Value::Integer(i), list]));
+        }
+
+        let view = vm.heap_view();
+        let (counts, heads) = std::thread::scope(|s| {
+            let walkers: Vec<_> = (0..2)
+                .map(|_| {
+                    s.spawn(|| {
+                        let integers = view
+                            .iter()
+                            .filter(|&ptr| matches!(view.get(ptr).fields[0], Value::Integer(_)))
+                            .count();
+                        // newest first, so the head of the list
+                        let head = view.iter().next().unwrap();
+                        (integers, view.format_object(head, 1, 1))
+                    })
+                })
+                .collect();
+            walkers
+                .into_iter()
+                .map(|walker| walker.join().unwrap())
+                .unzip::<_, _, Vec<_>, Vec<_>>()
+        });
+        assert_eq!(counts, [100, 100]);
+        assert_eq!(heads[0], "{tag 1: [99, <truncated>]}");
+        assert_eq!(heads[0], heads[1]);
+        assert_eq!(view.snapshot().objects(1), 100);
+        vm.collect_garbage();
+        assert_eq!(vm.heap().len(), 100);
+    }
+
+    #[test]
+    fn test_iter() {
+        let mut heap = Heap::new();
+        let ptrs: Vec<_> = (0..5).map(|i| heap.new_object(small(i))).collect();
+        ptrs[1].node().mark();
+        ptrs[3].node().mark();
+        heap.sweep();
+
+        let live: Vec<_> = heap.iter().map(|ptr| ptr.node().data().fields[0]).collect();
+        assert_eq!(live, [Value::Integer(3), Value::Integer(1)]);
+        assert!(heap.iter().all(|ptr| ptr.node().color() == Color::Unmarked));
     }
 }
@@ -0,0 +1,365 @@
+use crate::error::VmError;
+use crate::heap::Heap;
+use crate::opcode::OpCode;
+use crate::value::Value;
+use std::collections::BTreeSet;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HookAction {
+    Continue,
+    Pause,
+    Abort(VmError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WatchpointHit {
+    pub index: usize,
+    pub old: Option<Value>,
+    pub new: Value,
+    pub ip: usize,
+}
+
+// Read-only window onto the VM handed to hooks.
+#[derive(Clone, Copy)]
+pub struct VmView<'a> {
+    pub(crate) stack: &'a [Value],
+    pub(crate) locals: &'a [Option<Value>],
+    pub(crate) heap: &'a Heap,
+}
+
+impl VmView<'_> {
+    pub fn stack(&self) -> &[Value] {
+        self.stack
+    }
+
+    pub fn local(&self, index: usize) -> Option<Value> {
+        self.locals.get(index).copied().flatten()
+    }
+
+    pub fn locals_len(&self) -> usize {
+        self.locals.len()
+    }
+
+    pub fn heap_len(&self) -> usize {
+        self.heap.len()
+    }
+}
+
+pub trait Hook {
+    fn before_instruction(&mut self, _vm: &VmView, _ip: usize, _op: OpCode) -> HookAction {
+        HookAction::Continue
+    }
+
+    fn after_instruction(&mut self, _vm: &VmView, _ip: usize, _op: OpCode) -> HookAction {
+        HookAction::Continue
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResumePoint {
+    // The instruction following the one that trapped.
+    NextInstruction,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrapDecision {
+    Propagate,
+    Recover {
+        push: Option<Value>,
+        resume_at: ResumePoint,
+    },
+    // Recovers with an error object describing the trap pushed; see
+    // `Object::error`.
+    RecoverWithError {
+        resume_at: ResumePoint,
+    },
+}
+
+// Called when an instruction traps, with the stack as the instruction left
+// it. Recovering is only offered for errors in the guest's own code, never
+// for exceeding the policy's limits or running out of fuel.
+pub type TrapHandler = Box<dyn FnMut(&VmError, &VmView) -> TrapDecision>;
+
+// Aborts with `FuelExhausted` before the instruction that would exceed the
+// budget, so execution can resume once more fuel is added.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Fuel(pub u64);
+
+impl Hook for Fuel {
+    fn before_instruction(&mut self, _vm: &VmView, _ip: usize, _op: OpCode) -> HookAction {
+        if self.0 == 0 {
+            HookAction::Abort(VmError::FuelExhausted)
+        } else {
+            HookAction::Continue
+        }
+    }
+
+    fn after_instruction(&mut self, _vm: &VmView, _ip: usize, _op: OpCode) -> HookAction {
+        self.0 -= 1;
+        HookAction::Continue
+    }
+}
+
+// Pauses before executing an instruction at any of the registered offsets.
+// Resuming from a breakpoint steps over it once.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Breakpoints {
+    ips: BTreeSet<usize>,
+    resumed: Option<usize>,
+}
+
+impl Breakpoints {
+    pub fn is_empty(&self) -> bool {
+        self.ips.is_empty()
+    }
+
+    pub fn insert(&mut self, ip: usize) -> bool {
+        self.ips.insert(ip)
+    }
+
+    pub fn remove(&mut self, ip: usize) -> bool {
+        self.ips.remove(&ip)
+    }
+}
+
+impl Hook for Breakpoints {
+    fn before_instruction(&mut self, _vm: &VmView, ip: usize, _op: OpCode) -> HookAction {
+        if self.resumed.take() == Some(ip) || !self.ips.contains(&ip) {
+            HookAction::Continue
+        } else {
+            self.resumed = Some(ip);
+            HookAction::Pause
+        }
+    }
+}
+
+// Hooks run in registration order, after fuel and breakpoints. The first
+// hook that doesn't continue decides the outcome of the instruction.
+#[derive(Default)]
+pub(crate) struct Hooks {
+    pub(crate) fuel: Option<Fuel>,
+    pub(crate) breakpoints: Breakpoints,
+    pub(crate) user: Vec<Box<dyn Hook>>,
+    pub(crate) trap: Option<TrapHandler>,
+}
+
+impl Hooks {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.fuel.is_none() && self.breakpoints.is_empty() && self.user.is_empty()
+    }
+
+    fn iter_mut(&mut self) -> impl Iterator<Item = &mut (dyn Hook + 'static)> {
+        let fuel = self.fuel.as_mut().map(|fuel| fuel as &mut dyn Hook);
+        let breakpoints = (!self.breakpoints.is_empty()).then_some(&mut self.breakpoints);
+        let breakpoints = breakpoints.map(|breakpoints| breakpoints as &mut dyn Hook);
+        let user = self.user.iter_mut().map(|hook| hook.as_mut());
+        fuel.into_iter().chain(breakpoints).chain(user)
+    }
+
+    pub(crate) fn before(&mut self, vm: &VmView, ip: usize, op: OpCode) -> HookAction {
+        self.iter_mut()
+            .map(|hook| hook.before_instruction(vm, ip, op))
+            .find(|action| *action != HookAction::Continue)
+            .unwrap_or(HookAction::Continue)
+    }
+
+    pub(crate) fn after(&mut self, vm: &VmView, ip: usize, op: OpCode) -> HookAction {
+        self.iter_mut()
+            .map(|hook| hook.after_instruction(vm, ip, op))
+            .find(|action| *action != HookAction::Continue)
+            .unwrap_or(HookAction::Continue)
+    }
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Hooks")
+            .field("fuel", &self.fuel)
+            .field("breakpoints", &self.breakpoints)
+            .field("user", &self.user.len())
+            .field("trap", &self.trap.is_some())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::{self, ChunkBuilder};
+    use crate::vm::{Status, Termination, VM};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct PauseOnNth {
+        op: OpCode,
+        n: usize,
+        seen: usize,
+    }
+
+    impl Hook for PauseOnNth {
+        fn before_instruction(&mut self, _vm: &VmView, _ip: usize, op: OpCode) -> HookAction {
+            if op == self.op {
+                self.seen += 1;
+                if self.seen == self.n {
+                    return HookAction::Pause;
+                }
+            }
+            HookAction::Continue
+        }
+    }
+
+    struct MaxDepth(usize);
+
+    impl Hook for MaxDepth {
+        fn before_instruction(&mut self, vm: &VmView, _ip: usize, _op: OpCode) -> HookAction {
+            if vm.stack().len() > self.0 {
+                HookAction::Abort(VmError::StackOverflow)
+            } else {
+                HookAction::Continue
+            }
+        }
+    }
+
+    struct Log(Rc<RefCell<Vec<(&'static str, usize)>>>, &'static str);
+
+    impl Hook for Log {
+        fn before_instruction(&mut self, _vm: &VmView, ip: usize, _op: OpCode) -> HookAction {
+            self.0.borrow_mut().push((self.1, ip));
+            HookAction::Continue
+        }
+    }
+
+    #[test]
+    fn test_pause_on_nth_mul() {
+        let mut vm = VM::new(builder::tests::factorial(20).build().unwrap());
+        vm.add_hook(Box::new(PauseOnNth {
+            op: OpCode::MulI,
+            n: 10,
+            seen: 0,
+        }))
+        .unwrap();
+
+        assert_eq!(vm.execute_all().map(|o| o.status), Ok(Status::Paused));
+        let partial: i64 = (12..=20).product();
+        assert_eq!(vm.local(1), Some(Value::Integer(partial)));
+        assert_eq!(vm.local(0), Some(Value::Integer(11)));
+
+        assert_eq!(
+            vm.execute_all().map(|o| o.status),
+            Ok(Status::Finished(Termination::Return))
+        );
+        assert_eq!(vm.stack(), [Value::Integer((1..=20).product())]);
+    }
+
+    #[test]
+    fn test_abort_on_stack_depth() {
+        let mut b = ChunkBuilder::new();
+        for _ in 0..8 {
+            b.imm_i(1);
+        }
+        let mut vm = VM::new(b.build().unwrap());
+        vm.add_hook(Box::new(MaxDepth(5))).unwrap();
+
+        assert_eq!(vm.execute_all(), Err(VmError::StackOverflow));
+        assert_eq!(vm.stack().len(), 6);
+    }
+
+    #[test]
+    fn test_hooks_run_in_registration_order() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = VM::new(ChunkBuilder::new().imm_i(0).imm_i(1).build().unwrap());
+        vm.add_hook(Box::new(Log(log.clone(), "a"))).unwrap();
+        vm.add_hook(Box::new(Log(log.clone(), "b"))).unwrap();
+
+        assert_eq!(
+            vm.execute_all().map(|o| o.status),
+            Ok(Status::Finished(Termination::EndOfChunk))
+        );
+        assert_eq!(*log.borrow(), [("a", 0), ("b", 0), ("a", 1), ("b", 1)]);
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut vm = VM::new(builder::tests::factorial(5).build().unwrap());
+        let loop_head = 9;
+        vm.set_breakpoint(loop_head).unwrap();
+
+        let mut hits = Vec::new();
+        while vm.execute_all().map(|o| o.status) == Ok(Status::Paused) {
+            assert_eq!(vm.ip(), loop_head);
+            let Some(Value::Integer(n)) = vm.local(0) else {
+                panic!("n should be set at the loop head");
+            };
+            hits.push(n);
+        }
+        assert_eq!(hits, [5, 4, 3, 2, 1, 0]);
+        assert_eq!(vm.stack(), [Value::Integer(120)]);
+    }
+
+    #[test]
+    fn test_watch_local() {
+        let chunk = builder::tests::factorial(5).build().unwrap();
+        let mut vm = VM::new(chunk.clone());
+        vm.watch_local(1);
+
+        let mut writes = Vec::new();
+        while let Ok(Status::Watchpoint(hit)) = vm.execute_all().map(|o| o.status) {
+            assert_eq!(hit.index, 1);
+            assert_eq!(chunk.code()[hit.ip], OpCode::Store as u8);
+            assert_eq!(vm.local(1), Some(hit.new));
+            writes.push((hit.old, hit.new));
+        }
+
+        let int = Value::Integer;
+        assert_eq!(
+            writes,
+            [
+                (None, int(1)),
+                (Some(int(1)), int(5)),
+                (Some(int(5)), int(20)),
+                (Some(int(20)), int(60)),
+                (Some(int(60)), int(120)),
+                (Some(int(120)), int(120)),
+            ]
+        );
+        assert_eq!(vm.stack(), [int(120)]);
+    }
+
+    #[test]
+    fn test_watchpoints_with_breakpoints() {
+        let mut vm = VM::new(builder::tests::factorial(3).build().unwrap());
+        vm.watch_local(1);
+        vm.set_breakpoint(9).unwrap();
+
+        let mut events = Vec::new();
+        loop {
+            match vm.execute_all().map(|o| o.status) {
+                Ok(Status::Paused) => events.push("break"),
+                Ok(Status::Watchpoint(_)) => events.push("watch"),
+                Ok(Status::Finished(_)) => break,
+                Err(err) => panic!("{err}"),
+            }
+        }
+        assert_eq!(
+            events,
+            ["watch", "break", "watch", "break", "watch", "break", "watch", "break"]
+        );
+    }
+
+    #[test]
+    fn test_fuel() {
+        let mut vm = VM::new(builder::tests::factorial(5).build().unwrap());
+        vm.set_fuel(Some(10)).unwrap();
+
+        assert_eq!(vm.execute_all(), Err(VmError::FuelExhausted));
+        assert_eq!(vm.fuel(), Some(0));
+        assert_eq!(vm.stack().len(), 2);
+
+        vm.set_fuel(Some(1000)).unwrap();
+        let outcome = vm.execute_all().unwrap();
+        assert_eq!(outcome.status, Status::Finished(Termination::Return));
+        assert_eq!(outcome.value, Some(Value::Integer(120)));
+        assert_eq!(outcome.instructions, 1000 - vm.fuel().unwrap());
+    }
+}
@@ -0,0 +1,419 @@
+use crate::chunk::Chunk;
+use crate::encode;
+use crate::error::DecodeError;
+use crate::opcode::OpCode;
+
+// Operands are encoded as `encode` does, in the bytes following the
+// opcode.
+trait Operand: Sized {
+    fn read(bytes: &[u8]) -> Self;
+    fn write(self, out: &mut Vec<u8>);
+}
+
+impl Operand for u8 {
+    fn read(bytes: &[u8]) -> Self {
+        bytes[0]
+    }
+
+    fn write(self, out: &mut Vec<u8>) {
+        out.push(self);
+    }
+}
+
+impl Operand for i8 {
+    fn read(bytes: &[u8]) -> Self {
+        bytes[0] as i8
+    }
+
+    fn write(self, out: &mut Vec<u8>) {
+        out.push(self as u8);
+    }
+}
+
+impl Operand for i16 {
+    fn read(bytes: &[u8]) -> Self {
+        u16::read(bytes) as i16
+    }
+
+    fn write(self, out: &mut Vec<u8>) {
+        (self as u16).write(out);
+    }
+}
+
+impl Operand for u16 {
+    fn read(bytes: &[u8]) -> Self {
+        encode::read_u16(bytes).unwrap()
+    }
+
+    fn write(self, out: &mut Vec<u8>) {
+        encode::put_u16(out, self);
+    }
+}
+
+impl Operand for u64 {
+    fn read(bytes: &[u8]) -> Self {
+        encode::read_u64(bytes).unwrap()
+    }
+
+    fn write(self, out: &mut Vec<u8>) {
+        encode::put_u64(out, self);
+    }
+}
+
+impl Operand for i64 {
+    fn read(bytes: &[u8]) -> Self {
+        encode::read_i64(bytes).unwrap()
+    }
+
+    fn write(self, out: &mut Vec<u8>) {
+        encode::put_i64(out, self);
+    }
+}
+
+impl Operand for f64 {
+    fn read(bytes: &[u8]) -> Self {
+        encode::read_f64_bits(bytes).unwrap()
+    }
+
+    fn write(self, out: &mut Vec<u8>) {
+        encode::put_f64_bits(out, self);
+    }
+}
+
+// One variant per opcode, carrying its decoded operand if it has one.
+macro_rules! instructions {
+    (@wild $operand:ty) => { _ };
+    (@bind $name:ident $operand:ty) => { $name };
+    ($($op:ident $(($operand:ty))?,)*) => {
+        #[derive(Debug, Clone, Copy, PartialEq)]
+        pub enum Instruction {
+            $($op $(($operand))?,)*
+        }
+
+        impl Instruction {
+            pub const fn opcode(&self) -> OpCode {
+                match self {
+                    $(Self::$op $((instructions!(@wild $operand)))? => OpCode::$op,)*
+                }
+            }
+
+            // `operands` holds exactly the operand bytes of `op`.
+            pub(crate) fn from_parts(op: OpCode, operands: &[u8]) -> Self {
+                match op {
+                    $(OpCode::$op => Self::$op $((<$operand>::read(operands)))?,)*
+                }
+            }
+
+            fn write_operand(self, out: &mut Vec<u8>) {
+                match self {
+                    $(Self::$op $((instructions!(@bind operand $operand)))? => {
+                        $(<$operand>::write(instructions!(@bind operand $operand), out);)?
+                    })*
+                }
+            }
+        }
+    };
+}
+
+instructions! {
+    Return,
+    Goto(u16),
+    GotoIf(u16),
+    Load(u16),
+    Store(u16),
+    ImmI(i64),
+    ImmF(f64),
+    ImmW(u64),
+    AddI,
+    SubI,
+    MulI,
+    DivI,
+    CmpEqI,
+    CmpGtI,
+    CmpGeI,
+    CmpLtI,
+    CmpLeI,
+    GetField(u16),
+    SetField(u16),
+    ObjEq,
+    ObjCloneShallow,
+    ObjCloneDeep,
+    Imm0,
+    Imm1,
+    ImmNeg1,
+    AndW,
+    OrW,
+    XorW,
+    ShlW,
+    ShrW,
+    RotlW,
+    RotrW,
+    ClzW,
+    CtzW,
+    PopcntW,
+    F2Bits,
+    Bits2F,
+    AddI32,
+    SubI32,
+    MulI32,
+    DivI32,
+    I64toI32,
+    ParseInt,
+    ParseFloat,
+    IntToStr,
+    FloatToStr,
+    MapNew,
+    MapGet,
+    MapSet,
+    MapContains,
+    MapLen,
+    MapDelete,
+    LoadConst(u16),
+    Intern,
+    StrEq,
+    NewWeak,
+    WeakGet,
+    Gc,
+    HeapInfo,
+    PushIp,
+    GotoDyn,
+    CallNative(u16),
+    Call(u16),
+    ArrayNew,
+    ArrayGet,
+    ArraySet,
+    ArrayLen,
+    ModI,
+    DivFloorI,
+    ModEuclidI,
+    Nop,
+    ImmI8(i8),
+    ImmI16(i16),
+    CmpEqW,
+    CmpGtW,
+    CmpGeW,
+    CmpLtW,
+    CmpLeW,
+    Clock,
+    Rand,
+    StrCmp,
+    StrLen,
+    CharAt,
+    Substr,
+    ImmW8(u8),
+    ImmW16(u16),
+    PushChunkLen,
+    StackDepth,
+    FrameDepth,
+    FuelRemaining,
+    ReturnN(u8),
+    StoreIf(u16),
+    LoadOrDefault(u16),
+    IterNew,
+    IterNext,
+    LoadArg(u8),
+    StoreArg(u8),
+    ArrayCopy,
+    ArrayFill,
+    ArraySlice,
+    BufNew,
+    BufLoad8,
+    BufLoad16,
+    BufLoad32,
+    BufLoad64,
+    BufStore8,
+    BufStore16,
+    BufStore32,
+    BufStore64,
+    BufLen,
+    ArrayPush,
+    ArrayPop,
+    FieldCount,
+    GetFieldDyn,
+    SetFieldDyn,
+    AddToLocal(u16),
+    MulToLocal(u16),
+    MinToLocal(u16),
+    MaxToLocal(u16),
+    ArrayGetUnchecked,
+    ArraySetUnchecked,
+    FmtFloat,
+    ArrayGetRel,
+    ArraySetRel,
+    Unreachable,
+    TrapMsg,
+}
+
+impl Instruction {
+    // Encoded length in bytes, including the opcode.
+    pub const fn encoded_len(&self) -> usize {
+        1 + self.opcode().operand_len()
+    }
+
+    pub fn decode(code: &[u8], offset: usize) -> Result<Self, DecodeError> {
+        let byte = *code.get(offset).ok_or(DecodeError::Truncated { offset })?;
+        let op =
+            OpCode::try_from(byte).map_err(|byte| DecodeError::InvalidOpcode { offset, byte })?;
+        let operands = code
+            .get(offset + 1..offset + 1 + op.operand_len())
+            .ok_or(DecodeError::Truncated { offset })?;
+        Ok(Self::from_parts(op, operands))
+    }
+
+    pub fn encode_into(self, out: &mut Vec<u8>) {
+        out.push(self.opcode() as u8);
+        self.write_operand(out);
+    }
+}
+
+pub fn encode(instructions: &[Instruction]) -> Chunk {
+    let mut code = Vec::with_capacity(instructions.iter().map(Instruction::encoded_len).sum());
+    for instruction in instructions {
+        instruction.encode_into(&mut code);
+    }
+    Chunk::new(code)
+}
+
+impl Chunk {
+    // Decodes the chunk front to back, stopping after the first offset that
+    // doesn't hold a valid instruction.
+    pub fn instructions(
+        &self,
+    ) -> impl Iterator<Item = Result<(usize, Instruction), DecodeError>> + '_ {
+        let mut offset = 0;
+        let mut failed = false;
+        std::iter::from_fn(move || {
+            if failed || offset >= self.len() {
+                return None;
+            }
+            let at = offset;
+            match Instruction::decode(self.code(), at) {
+                Ok(instruction) => {
+                    offset += instruction.encoded_len();
+                    Some(Ok((at, instruction)))
+                }
+                Err(err) => {
+                    failed = true;
+                    Some(Err(err))
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{PatchError, VmError};
+    use crate::opcode::OpCode::*;
+    use crate::value::Value;
+    use crate::verifier;
+    use crate::vm::{self, VM};
+
+    fn every_instruction() -> Vec<Instruction> {
+        (0..=u8::MAX)
+            .filter_map(|byte| OpCode::try_from(byte).ok())
+            .map(|op| {
+                let operands = [0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf0];
+                Instruction::from_parts(op, &operands[..op.operand_len()])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_factorial_round_trip() {
+        let chunk = vm::tests::factorial();
+        let decoded: Vec<_> = chunk.instructions().map(|r| r.unwrap().1).collect();
+        assert_eq!(decoded[0], Instruction::ImmI(5));
+        assert_eq!(decoded[7], Instruction::GotoIf(69));
+        assert_eq!(encode(&decoded), chunk);
+
+        let offsets: Vec<_> = chunk.instructions().map(|r| r.unwrap().0).collect();
+        assert!(offsets.iter().all(|&offset| chunk.is_boundary(offset)));
+    }
+
+    #[test]
+    fn test_single_instruction_round_trip() {
+        let all = every_instruction();
+        assert_eq!(all.len(), OpCode::TrapMsg as usize + 1);
+        for instruction in all {
+            let chunk = encode(&[instruction]);
+            assert_eq!(chunk.len(), instruction.encoded_len());
+            assert_eq!(chunk.code()[0], instruction.opcode() as u8);
+            let decoded: Vec<_> = chunk.instructions().collect();
+            assert_eq!(decoded, [Ok((0, instruction))]);
+            assert_eq!(encode(&[decoded[0].unwrap().1]), chunk);
+        }
+    }
+
+    #[test]
+    fn test_patch() {
+        // factorial of local 2, specialized below for 0
+        let mut chunk = vm::tests::factorial();
+        let arg = 0;
+        assert_eq!(chunk.code()[arg], OpCode::ImmI as u8);
+        chunk.patch(arg, Instruction::Load(2)).unwrap();
+        assert!(chunk.is_boundary(3) && !chunk.is_boundary(2));
+
+        let mut vm = VM::new(chunk.clone());
+        assert_eq!(vm.execute_all(), Err(VmError::UninitializedLocal(2)));
+        let mut vm = VM::new(chunk.clone());
+        vm.set_local(2, Value::Integer(0)).unwrap();
+        vm.execute_all().unwrap();
+        assert_eq!(vm.stack(), [Value::Integer(1)]);
+
+        chunk.patch(arg, Instruction::Imm0).unwrap();
+        assert_eq!(
+            chunk.code()[..4],
+            [Imm0 as u8, Nop as u8, Nop as u8, Nop as u8]
+        );
+        assert!((0..4).all(|offset| chunk.is_boundary(offset)));
+        assert_eq!(verifier::verify(&chunk), Ok(()));
+        let mut vm = VM::new(chunk.clone());
+        vm.execute_all().unwrap();
+        assert_eq!(vm.stack(), [Value::Integer(1)]);
+
+        assert_eq!(
+            chunk.patch(arg, Instruction::Load(0)),
+            Err(PatchError::TooLong {
+                offset: 0,
+                len: 3,
+                available: 1
+            })
+        );
+        let store = chunk
+            .instructions()
+            .map(Result::unwrap)
+            .find(|(_, instruction)| matches!(instruction, Instruction::Store(_)))
+            .unwrap()
+            .0;
+        assert_eq!(
+            chunk.patch(store + 1, Instruction::Imm0),
+            Err(PatchError::NotABoundary(store + 1))
+        );
+        assert_eq!(
+            chunk.patch(chunk.len(), Instruction::Imm0),
+            Err(PatchError::NotABoundary(chunk.len()))
+        );
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let chunk = Chunk::new(vec![OpCode::Imm0 as u8, 0xff, OpCode::Imm0 as u8]);
+        let decoded: Vec<_> = chunk.instructions().collect();
+        assert_eq!(
+            decoded,
+            [
+                Ok((0, Instruction::Imm0)),
+                Err(DecodeError::InvalidOpcode {
+                    offset: 1,
+                    byte: 0xff
+                })
+            ]
+        );
+
+        let chunk = Chunk::new(vec![OpCode::Load as u8, 0]);
+        let decoded: Vec<_> = chunk.instructions().collect();
+        assert_eq!(decoded, [Err(DecodeError::Truncated { offset: 0 })]);
+    }
+}
@@ -1,4 +1,72 @@
-mod heap;
-mod opcode;
-mod value;
-mod vm;
\ No newline at end of file
+// Diagnostics, through the `log` module with the "log" feature and compiled
+// out entirely without it.
+#[cfg(feature = "log")]
+macro_rules! log_record {
+    ($level:ident, $($arg:tt)+) => {
+        $crate::log::log($crate::log::Level::$level, module_path!(), format_args!($($arg)+))
+    };
+}
+
+#[cfg(not(feature = "log"))]
+macro_rules! log_record {
+    ($level:ident, $($arg:tt)+) => {};
+}
+
+macro_rules! trace {
+    ($($arg:tt)+) => { log_record!(Trace, $($arg)+) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => { log_record!(Debug, $($arg)+) };
+}
+
+macro_rules! warn {
+    ($($arg:tt)+) => { log_record!(Warn, $($arg)+) };
+}
+
+pub mod alloc_profile;
+pub mod buffer;
+pub mod builder;
+pub mod checkpoint;
+pub mod chunk;
+pub mod clock;
+pub mod config;
+pub mod differential;
+pub mod encode;
+pub mod error;
+pub mod eval;
+pub mod frontend;
+pub mod function_profile;
+pub mod fuzz;
+pub mod hash;
+pub mod heap;
+pub mod hook;
+pub mod instruction;
+pub mod link;
+#[cfg(feature = "log")]
+pub mod log;
+pub mod map;
+pub mod marshal;
+#[cfg(unix)]
+pub mod mmap;
+pub mod native;
+pub mod opcode;
+pub mod optimizer;
+pub mod policy;
+pub mod pretty;
+#[cfg(feature = "profiler")]
+pub mod profile;
+pub mod replay;
+pub mod scheduler;
+pub mod serialize;
+pub mod shadow;
+pub mod snapshot;
+pub mod stdlib;
+#[cfg(test)]
+mod testing;
+pub mod usage;
+pub mod value;
+pub mod verifier;
+pub mod vm;
+pub mod watch;
+pub mod workloads;
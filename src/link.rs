@@ -0,0 +1,298 @@
+use crate::chunk::Chunk;
+use crate::error::LinkError;
+use crate::instruction::Instruction;
+use crate::serialize;
+use crate::verifier;
+use std::collections::HashMap;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LinkStats {
+    // Summed over the chunks' own pools.
+    pub constants_before: usize,
+    pub constants_after: usize,
+}
+
+// Combines separately built chunks into one, resolving each chunk's imports
+// against the exports of all of them. The first chunk's top level is the
+// program's: the others are laid out after it, past a jump to the end, and
+// are only entered through calls. Code and function tables are
+// concatenated, and the constant pools merged so that each distinct constant
+// is kept once, with every index and jump target relocated to match. The
+// result keeps every export, so it can be linked again.
+pub fn link(chunks: &[Chunk]) -> Result<Chunk, LinkError> {
+    link_with_stats(chunks).map(|(linked, _)| linked)
+}
+
+pub fn link_with_stats(chunks: &[Chunk]) -> Result<(Chunk, LinkStats), LinkError> {
+    let mut exports = HashMap::new();
+    let mut function_base = 0;
+    for (index, chunk) in chunks.iter().enumerate() {
+        verifier::verify(chunk).map_err(|err| LinkError::Invalid { chunk: index, err })?;
+        for (name, function) in chunk.exports() {
+            if *function as usize >= chunk.functions().len() {
+                return Err(LinkError::InvalidExport {
+                    name: name.clone(),
+                    function: *function,
+                });
+            }
+            let resolved = function_base + *function as usize;
+            if exports.insert(name.as_str(), resolved).is_some() {
+                return Err(LinkError::DuplicateSymbol(name.clone()));
+            }
+        }
+        function_base += chunk.functions().len();
+    }
+    if function_base > u16::MAX as usize {
+        return Err(LinkError::TooManyFunctions(function_base));
+    }
+
+    // Constants are the same when they serialize the same, so strings match
+    // by content and floats by their bits. Every `LoadConst` allocates its
+    // string afresh, so sharing the entry can't be told apart.
+    let mut constants = Vec::new();
+    let mut pooled = HashMap::new();
+    let mut pool_indices = Vec::with_capacity(chunks.len());
+    for chunk in chunks {
+        let indices: Vec<_> = chunk
+            .constants()
+            .iter()
+            .map(|constant| {
+                let mut key = Vec::new();
+                serialize::put_constant(&mut key, constant);
+                *pooled.entry(key).or_insert_with(|| {
+                    constants.push(constant.clone());
+                    constants.len() - 1
+                })
+            })
+            .collect();
+        pool_indices.push(indices);
+    }
+    if constants.len() > u16::MAX as usize + 1 {
+        return Err(LinkError::TooManyConstants(constants.len()));
+    }
+    let stats = LinkStats {
+        constants_before: chunks.iter().map(|chunk| chunk.constants().len()).sum(),
+        constants_after: constants.len(),
+    };
+
+    // where each chunk's code starts, leaving room for the jump past the
+    // rest after the first
+    let skip = Instruction::Goto(0).encoded_len();
+    let mut bases = Vec::with_capacity(chunks.len());
+    let mut len = 0;
+    for (index, chunk) in chunks.iter().enumerate() {
+        bases.push(len);
+        len += chunk.len();
+        if index == 0 && chunks.len() > 1 {
+            len += skip;
+        }
+    }
+    let relocate =
+        |target: usize| u16::try_from(target).map_err(|_| LinkError::JumpOutOfRange(target));
+
+    let mut code = Vec::with_capacity(len);
+    let mut functions = Vec::new();
+    let mut features = 0;
+    let mut max_locals = Some(0);
+    for (index, chunk) in chunks.iter().enumerate() {
+        let base = bases[index];
+        // verified, so every index is in the pool
+        let pooled = |constant: u16| pool_indices[index][constant as usize] as u16;
+        let function_base = functions.len() as u16;
+        for decoded in chunk.instructions() {
+            let (_, instruction) = decoded.map_err(|err| LinkError::Invalid {
+                chunk: index,
+                err: err.into(),
+            })?;
+            let instruction = match instruction {
+                Instruction::Goto(target) => Instruction::Goto(relocate(base + target as usize)?),
+                Instruction::GotoIf(target) => {
+                    Instruction::GotoIf(relocate(base + target as usize)?)
+                }
+                Instruction::GotoDyn => return Err(LinkError::DynamicJumps { chunk: index }),
+                Instruction::LoadConst(constant) => Instruction::LoadConst(pooled(constant)),
+                Instruction::CallNative(name) => Instruction::CallNative(pooled(name)),
+                Instruction::Call(function) => match chunk.import(function) {
+                    Some(name) => {
+                        let resolved = exports
+                            .get(name)
+                            .ok_or_else(|| LinkError::UnresolvedSymbol(name.to_string()))?;
+                        Instruction::Call(*resolved as u16)
+                    }
+                    None => Instruction::Call(function_base + function),
+                },
+                instruction => instruction,
+            };
+            instruction.encode_into(&mut code);
+        }
+        if index == 0 && chunks.len() > 1 {
+            Instruction::Goto(relocate(len)?).encode_into(&mut code);
+        }
+
+        functions.extend(chunk.functions().iter().map(|function| {
+            let mut function = function.clone();
+            function.entry += base;
+            function
+        }));
+        features |= chunk.features();
+        max_locals = max_locals.zip(chunk.max_locals()).map(|(a, b)| a.max(b));
+    }
+
+    let mut exports: Vec<_> = exports
+        .into_iter()
+        .map(|(name, function)| (name.to_string(), function as u16))
+        .collect();
+    exports.sort();
+    let linked = Chunk::new(code)
+        .with_constants(constants)
+        .with_functions(functions)
+        .with_exports(exports)
+        .with_features(features);
+    let linked = match max_locals {
+        Some(max) if !chunks.is_empty() => linked.with_max_locals(max),
+        _ => linked,
+    };
+    Ok((linked, stats))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::ChunkBuilder;
+    use crate::chunk::Constant;
+    use crate::opcode::OpCode::*;
+    use crate::value::Value;
+    use crate::vm::VM;
+
+    // Exports `add` and `abs`, behind a top level that returns at once.
+    fn library() -> Chunk {
+        let mut b = ChunkBuilder::new();
+        let (add, abs, negative) = (b.label(), b.label(), b.label());
+        b.op(Return);
+        b.bind(add).load(0).load(1).op(AddI).op(Return);
+        b.bind(abs)
+            .load(0)
+            .load_const(Constant::Integer(0))
+            .op(CmpLtI);
+        b.goto_if(negative).load(0).op(Return);
+        b.bind(negative).imm_i(0).load(0).op(SubI).op(Return);
+        let add = b.function(add, 2);
+        let abs = b.function(abs, 1);
+        b.returns(add, 1).returns(abs, 1);
+        b.export(add, "add").export(abs, "abs");
+        b.build().unwrap()
+    }
+
+    // abs(add(2, -110)), with the constant in its own pool
+    fn program() -> Chunk {
+        let mut b = ChunkBuilder::new();
+        let (add, abs) = (b.import("add"), b.import("abs"));
+        b.imm_i(2).load_const(Constant::Integer(-110));
+        b.call(add).call(abs).imm_i(1).op(AddI);
+        b.build().unwrap()
+    }
+
+    #[test]
+    fn test_link() {
+        let program = program();
+        assert_eq!(program.imports(), ["add", "abs"]);
+        assert_eq!(
+            VM::new(program.clone()).execute_all(),
+            Err(crate::error::VmError::UnknownFunction(u16::MAX))
+        );
+
+        let linked = link(&[program, library()]).unwrap();
+        assert_eq!(verifier::verify_stack(&linked), Ok(()));
+        assert!(linked.imports().is_empty());
+        assert_eq!(
+            linked.exports(),
+            [("abs".to_string(), 1), ("add".to_string(), 0)]
+        );
+        let mut vm = VM::new(linked);
+        let outcome = vm.execute_all().unwrap();
+        assert_eq!(outcome.value, Some(Value::Integer(109)));
+        assert_eq!(vm.stack(), [Value::Integer(109)]);
+    }
+
+    #[test]
+    fn test_link_shares_constants() {
+        // a chunk exporting `name`, a function of no arguments running `body`
+        let export = |name: &str, body: &dyn Fn(&mut ChunkBuilder)| {
+            let mut b = ChunkBuilder::new();
+            let entry = b.label();
+            b.op(Return).bind(entry);
+            body(&mut b);
+            b.op(Return);
+            let function = b.function(entry, 0);
+            b.returns(function, 1).export(function, name);
+            b.build().unwrap()
+        };
+        let error = Constant::Str("error".to_string());
+        let int = Constant::Integer;
+        // 42 + len("error")
+        let a = export("a", &|b| {
+            b.load_const(int(42)).load_const(error.clone());
+            b.op(StrLen).op(AddI);
+        });
+        // 7 + len("error") - 42, with the shared constants at other indices
+        let b = export("b", &|b| {
+            b.load_const(int(7)).load_const(error.clone());
+            b.op(StrLen).op(AddI).load_const(int(42)).op(SubI);
+        });
+
+        let mut builder = ChunkBuilder::new();
+        let (f, g) = (builder.import("a"), builder.import("b"));
+        builder
+            .load_const(int(42))
+            .call(f)
+            .op(AddI)
+            .call(g)
+            .op(AddI);
+        builder.load_const(error.clone()).op(StrLen).op(AddI);
+        let program = builder.build().unwrap();
+
+        let (linked, stats) = link_with_stats(&[program, a, b]).unwrap();
+        assert_eq!(
+            stats,
+            LinkStats {
+                constants_before: 7,
+                constants_after: 3
+            }
+        );
+        assert_eq!(linked.constants(), [int(42), error, int(7)]);
+        assert_eq!(verifier::verify_stack(&linked), Ok(()));
+        let outcome = VM::new(linked).execute_all().unwrap();
+        assert_eq!(outcome.value, Some(Value::Integer(42 + 47 - 30 + 5)));
+    }
+
+    #[test]
+    fn test_link_errors() {
+        assert_eq!(
+            link(&[program()]),
+            Err(LinkError::UnresolvedSymbol("add".to_string()))
+        );
+        assert_eq!(
+            link(&[program(), library(), library()]),
+            Err(LinkError::DuplicateSymbol("add".to_string()))
+        );
+        assert_eq!(
+            link(&[program(), library()]).and_then(|linked| link(&[linked, library()])),
+            Err(LinkError::DuplicateSymbol("add".to_string()))
+        );
+        assert_eq!(
+            link(&[library().with_exports(vec![("f".to_string(), 2)])]),
+            Err(LinkError::InvalidExport {
+                name: "f".to_string(),
+                function: 2
+            })
+        );
+
+        let dynamic = ChunkBuilder::new().op(PushIp).op(GotoDyn).build().unwrap();
+        assert_eq!(
+            link(&[program(), library(), dynamic]),
+            Err(LinkError::DynamicJumps { chunk: 2 })
+        );
+        let message = link(&[program()]).unwrap_err().to_string();
+        assert!(message.contains("\"add\""), "{message}");
+    }
+}
@@ -0,0 +1,133 @@
+use std::fmt;
+use std::sync::OnceLock;
+
+// A facade in the shape of the `log` crate's, which the VM's diagnostics go
+// through: every instruction run at trace level, every collection at debug
+// and every trap a handler recovered from at warn. A host already using
+// `log` forwards records with a `Log` that calls `log::log!`. Without the
+// "log" feature the macros emitting them expand to nothing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+// `target` is the module the record comes from, such as `andrea::vm`.
+#[derive(Debug, Clone, Copy)]
+pub struct Record<'a> {
+    pub level: Level,
+    pub target: &'a str,
+    pub args: fmt::Arguments<'a>,
+}
+
+pub trait Log: Sync + Send {
+    // Asked before a record is formatted, so a logger that drops trace
+    // records costs the VM one call per instruction.
+    fn enabled(&self, level: Level, target: &str) -> bool;
+    fn log(&self, record: &Record);
+}
+
+static LOGGER: OnceLock<&'static dyn Log> = OnceLock::new();
+
+// As with `log::set_logger`, only the first logger set is ever used;
+// returns whether it was this one.
+pub fn set_logger(logger: &'static dyn Log) -> bool {
+    LOGGER.set(logger).is_ok()
+}
+
+pub(crate) fn log(level: Level, target: &str, args: fmt::Arguments) {
+    if let Some(logger) = LOGGER.get().filter(|logger| logger.enabled(level, target)) {
+        logger.log(&Record {
+            level,
+            target,
+            args,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::ChunkBuilder;
+    use crate::hook::{ResumePoint, TrapDecision};
+    use crate::opcode::OpCode::*;
+    use crate::value::Value;
+    use crate::vm::VM;
+    use std::cell::RefCell;
+
+    // Records are only kept on threads that asked for them, since every
+    // test in the binary logs through the same logger once it is set.
+    struct Capture;
+
+    thread_local! {
+        static CAPTURED: RefCell<Option<Vec<(Level, String)>>> = const { RefCell::new(None) };
+    }
+
+    impl Log for Capture {
+        fn enabled(&self, _level: Level, _target: &str) -> bool {
+            CAPTURED.with(|captured| captured.borrow().is_some())
+        }
+
+        fn log(&self, record: &Record) {
+            CAPTURED.with(|captured| {
+                if let Some(records) = &mut *captured.borrow_mut() {
+                    records.push((record.level, record.args.to_string()));
+                }
+            });
+        }
+    }
+
+    fn capture(run: impl FnOnce()) -> Vec<(Level, String)> {
+        static CAPTURE: Capture = Capture;
+        set_logger(&CAPTURE);
+        CAPTURED.with(|captured| *captured.borrow_mut() = Some(Vec::new()));
+        run();
+        CAPTURED.with(|captured| captured.borrow_mut().take().unwrap())
+    }
+
+    fn at(records: &[(Level, String)], level: Level) -> Vec<&str> {
+        let at_level = records.iter().filter(|(found, _)| *found == level);
+        at_level.map(|(_, message)| &message[..]).collect()
+    }
+
+    #[test]
+    fn test_collection_is_one_debug_record() {
+        let records = capture(|| {
+            let mut vm = VM::default();
+            let kept = vm.alloc_object(1, Vec::new());
+            for _ in 0..10 {
+                let ptr = vm.alloc_object(1, vec![Value::ObjectPtr(kept)]);
+                vm.unroot(ptr);
+            }
+            vm.collect_garbage();
+        });
+        assert_eq!(
+            at(&records, Level::Debug),
+            ["collected 10 of 11 objects (Explicit), next at 1024"]
+        );
+    }
+
+    #[test]
+    fn test_instructions_and_recoveries() {
+        let mut b = ChunkBuilder::new();
+        b.op(Imm1).op(Imm0).op(DivI);
+        let mut vm = VM::new(b.build().unwrap());
+        vm.set_trap_handler(Box::new(|_, _| TrapDecision::Recover {
+            push: Some(Value::Integer(0)),
+            resume_at: ResumePoint::NextInstruction,
+        }))
+        .unwrap();
+        let records = capture(|| {
+            vm.execute_all().unwrap();
+        });
+        assert_eq!(at(&records, Level::Trace), ["0 Imm1", "1 Imm0", "2 DivI"]);
+        assert_eq!(
+            at(&records, Level::Warn),
+            ["recovered from division by zero at 2"]
+        );
+        assert!(at(&records, Level::Debug).is_empty());
+    }
+}
@@ -0,0 +1,139 @@
+use crate::error::VmError;
+use crate::heap::{tag, Heap, Object};
+use crate::value::Value;
+use std::collections::HashMap;
+
+// Keys hash and compare by variant and contents, so `Integer(1)` and
+// `Word(1)` are distinct keys and strings match by their characters. Floats,
+// null and non-string objects have no well-defined key identity and are
+// rejected.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum MapKey {
+    Char(char),
+    Integer(i64),
+    Word(u64),
+    Str(String),
+}
+
+impl MapKey {
+    pub fn new(key: Value, heap: &Heap) -> Result<Self, VmError> {
+        match key {
+            Value::Char(c) => Ok(Self::Char(c)),
+            Value::Integer(i) => Ok(Self::Integer(i)),
+            Value::Word(w) => Ok(Self::Word(w)),
+            Value::ObjectPtr(ptr) => match heap.get(ptr).as_string() {
+                Some(s) => Ok(Self::Str(s)),
+                None => Err(VmError::InvalidKey("object")),
+            },
+            Value::Float(_) => Err(VmError::InvalidKey("float")),
+            Value::Null => Err(VmError::InvalidKey("null")),
+        }
+    }
+}
+
+// Maps keep their entries in the fields as alternating keys and values, so
+// the collector and the object utilities treat them like any other object.
+// The index from key to entry number is derived from those fields.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MapIndex {
+    entries: HashMap<MapKey, usize>,
+    keys: Vec<MapKey>,
+    // Counts insertions and deletions, which move entries, so iterators
+    // can tell that the entries have changed under them.
+    version: u64,
+}
+
+impl Object {
+    pub fn map() -> Self {
+        Self {
+            tag: tag::MAP,
+            fields: Vec::new(),
+            map_index: Some(Default::default()),
+            weak: None,
+            buffer: None,
+        }
+    }
+
+    pub fn is_map(&self) -> bool {
+        self.map_index.is_some()
+    }
+
+    fn index(&self) -> Result<&MapIndex, VmError> {
+        self.map_index.as_deref().ok_or(VmError::TypeMismatch {
+            expected: "map",
+            found: "object",
+        })
+    }
+
+    fn index_mut(&mut self) -> Result<&mut MapIndex, VmError> {
+        self.map_index.as_deref_mut().ok_or(VmError::TypeMismatch {
+            expected: "map",
+            found: "object",
+        })
+    }
+
+    pub fn map_len(&self) -> Result<usize, VmError> {
+        Ok(self.index()?.keys.len())
+    }
+
+    pub fn map_version(&self) -> Result<u64, VmError> {
+        Ok(self.index()?.version)
+    }
+
+    pub fn map_get(&self, key: &MapKey) -> Result<Option<Value>, VmError> {
+        let entry = self.index()?.entries.get(key);
+        Ok(entry.map(|&i| self.fields[2 * i + 1]))
+    }
+
+    pub fn map_set(&mut self, key: MapKey, key_val: Value, val: Value) -> Result<(), VmError> {
+        let index = self.index_mut()?;
+        match index.entries.get(&key) {
+            Some(&i) => self.fields[2 * i + 1] = val,
+            None => {
+                index.entries.insert(key.clone(), index.keys.len());
+                index.keys.push(key);
+                index.version += 1;
+                self.fields.extend([key_val, val]);
+            }
+        }
+        Ok(())
+    }
+
+    // Removes an entry by moving the last entry into its place.
+    pub fn map_delete(&mut self, key: &MapKey) -> Result<bool, VmError> {
+        let index = self.index_mut()?;
+        let Some(i) = index.entries.remove(key) else {
+            return Ok(false);
+        };
+
+        index.keys.swap_remove(i);
+        index.version += 1;
+        if let Some(moved) = index.keys.get(i) {
+            index.entries.insert(moved.clone(), i);
+        }
+        self.fields.swap_remove(2 * i + 1);
+        self.fields.swap_remove(2 * i);
+        Ok(true)
+    }
+}
+
+impl MapIndex {
+    // The keys in entry order, which with the version is all a checkpoint
+    // needs to rebuild the index.
+    pub(crate) fn keys(&self) -> &[MapKey] {
+        &self.keys
+    }
+
+    pub(crate) fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub(crate) fn from_keys(keys: Vec<MapKey>, version: u64) -> Self {
+        let entries = keys.iter().cloned().zip(0..).collect();
+        Self {
+            entries,
+            keys,
+            version,
+        }
+    }
+}
@@ -0,0 +1,350 @@
+use crate::error::MarshalError;
+use crate::heap::{tag, ObjectPtr};
+use crate::value::Value;
+use crate::vm::VM;
+
+// Builds a guest value on the VM's heap. Objects come back rooted on
+// behalf of the host, as with `VM::alloc_object`; `release` unroots them.
+// Children are kept rooted while their parent is allocated, so building a
+// graph is safe however often the heap collects.
+pub trait IntoGuest {
+    fn into_guest(self, vm: &mut VM) -> Value;
+}
+
+// Reads a guest value back, failing if the graph doesn't have the shape
+// the type expects.
+pub trait FromGuest: Sized {
+    fn from_guest(vm: &VM, val: Value) -> Result<Self, MarshalError>;
+}
+
+// Undoes the rooting of `IntoGuest` for one value.
+pub fn release(vm: &mut VM, val: Value) {
+    if let Value::ObjectPtr(ptr) = val {
+        vm.unroot(ptr);
+    }
+}
+
+// Allocates an object from marshaled fields, which are rooted until it
+// holds them.
+fn alloc(vm: &mut VM, tag: u8, fields: Vec<Value>) -> ObjectPtr {
+    let ptr = vm.alloc_object(tag, fields.clone());
+    for &field in fields.iter().rev() {
+        release(vm, field);
+    }
+    ptr
+}
+
+fn mismatch(expected: &'static str, found: Value) -> MarshalError {
+    MarshalError::TypeMismatch {
+        expected,
+        found: found.type_name(),
+    }
+}
+
+// The fields of `val`, which must be an object with the given tag.
+fn fields(vm: &VM, val: Value, tag: u8) -> Result<&[Value], MarshalError> {
+    let ptr = val
+        .get_object_ptr()
+        .ok_or_else(|| mismatch("object", val))?;
+    let obj = vm.heap().get(ptr);
+    if obj.tag != tag {
+        return Err(MarshalError::WrongTag {
+            expected: tag,
+            found: obj.tag,
+        });
+    }
+    Ok(&obj.fields)
+}
+
+impl IntoGuest for Value {
+    fn into_guest(self, vm: &mut VM) -> Value {
+        if let Value::ObjectPtr(ptr) = self {
+            vm.root(ptr);
+        }
+        self
+    }
+}
+
+impl FromGuest for Value {
+    fn from_guest(_vm: &VM, val: Value) -> Result<Self, MarshalError> {
+        Ok(val)
+    }
+}
+
+macro_rules! primitives {
+    ($($ty:ty => $variant:ident, $name:literal;)*) => {$(
+        impl IntoGuest for $ty {
+            fn into_guest(self, _vm: &mut VM) -> Value {
+                Value::$variant(self)
+            }
+        }
+
+        impl FromGuest for $ty {
+            fn from_guest(_vm: &VM, val: Value) -> Result<Self, MarshalError> {
+                match val {
+                    Value::$variant(x) => Ok(x),
+                    _ => Err(mismatch($name, val)),
+                }
+            }
+        }
+    )*};
+}
+
+primitives! {
+    i64 => Integer, "integer";
+    u64 => Word, "word";
+    f64 => Float, "float";
+    char => Char, "char";
+}
+
+// Booleans are words, as the VM's conditions read them.
+impl IntoGuest for bool {
+    fn into_guest(self, _vm: &mut VM) -> Value {
+        Value::Word(self as u64)
+    }
+}
+
+impl FromGuest for bool {
+    fn from_guest(vm: &VM, val: Value) -> Result<Self, MarshalError> {
+        Ok(u64::from_guest(vm, val)? != 0)
+    }
+}
+
+impl IntoGuest for &str {
+    fn into_guest(self, vm: &mut VM) -> Value {
+        Value::ObjectPtr(vm.alloc_string(self))
+    }
+}
+
+impl IntoGuest for String {
+    fn into_guest(self, vm: &mut VM) -> Value {
+        self.as_str().into_guest(vm)
+    }
+}
+
+impl FromGuest for String {
+    fn from_guest(vm: &VM, val: Value) -> Result<Self, MarshalError> {
+        fields(vm, val, tag::STRING)?
+            .iter()
+            .map(|&field| char::from_guest(vm, field))
+            .collect()
+    }
+}
+
+// `None` is null, so an `Option<Option<T>>` reads back as `None` or
+// `Some(Some(_))`.
+impl<T: IntoGuest> IntoGuest for Option<T> {
+    fn into_guest(self, vm: &mut VM) -> Value {
+        match self {
+            Some(x) => x.into_guest(vm),
+            None => Value::Null,
+        }
+    }
+}
+
+impl<T: FromGuest> FromGuest for Option<T> {
+    fn from_guest(vm: &VM, val: Value) -> Result<Self, MarshalError> {
+        match val {
+            Value::Null => Ok(None),
+            _ => T::from_guest(vm, val).map(Some),
+        }
+    }
+}
+
+impl<T: IntoGuest> IntoGuest for Vec<T> {
+    fn into_guest(self, vm: &mut VM) -> Value {
+        let fields = self.into_iter().map(|x| x.into_guest(vm)).collect();
+        Value::ObjectPtr(alloc(vm, tag::ARRAY, fields))
+    }
+}
+
+impl<T: FromGuest> FromGuest for Vec<T> {
+    fn from_guest(vm: &VM, val: Value) -> Result<Self, MarshalError> {
+        fields(vm, val, tag::ARRAY)?
+            .iter()
+            .map(|&field| T::from_guest(vm, field))
+            .collect()
+    }
+}
+
+// Tuples are arrays of exactly their arity.
+macro_rules! tuples {
+    ($($len:literal => ($($name:ident),*);)*) => {$(
+        impl<$($name: IntoGuest),*> IntoGuest for ($($name,)*) {
+            #[allow(non_snake_case)]
+            fn into_guest(self, vm: &mut VM) -> Value {
+                let ($($name,)*) = self;
+                let fields = vec![$($name.into_guest(vm)),*];
+                Value::ObjectPtr(alloc(vm, tag::ARRAY, fields))
+            }
+        }
+
+        impl<$($name: FromGuest),*> FromGuest for ($($name,)*) {
+            fn from_guest(vm: &VM, val: Value) -> Result<Self, MarshalError> {
+                let mut reader = ObjectReader::open(vm, val, tag::ARRAY, $len)?;
+                Ok(($(reader.field::<$name>()?,)*))
+            }
+        }
+    )*};
+}
+
+tuples! {
+    1 => (A);
+    2 => (A, B);
+    3 => (A, B, C);
+    4 => (A, B, C, D);
+}
+
+// Marshals a host struct field by field into an object with a tag of the
+// embedder's choosing. Fields stay rooted until `build`.
+#[derive(Debug)]
+pub struct ObjectBuilder {
+    tag: u8,
+    fields: Vec<Value>,
+}
+
+impl ObjectBuilder {
+    pub fn new(tag: u8) -> Self {
+        Self {
+            tag,
+            fields: Vec::new(),
+        }
+    }
+
+    pub fn field(mut self, vm: &mut VM, value: impl IntoGuest) -> Self {
+        self.fields.push(value.into_guest(vm));
+        self
+    }
+
+    // Rooted, as with `IntoGuest`.
+    pub fn build(self, vm: &mut VM) -> ObjectPtr {
+        alloc(vm, self.tag, self.fields)
+    }
+}
+
+// Reads an object built by `ObjectBuilder` back, field by field in order.
+#[derive(Debug)]
+pub struct ObjectReader<'a> {
+    vm: &'a VM,
+    fields: &'a [Value],
+    next: usize,
+}
+
+impl<'a> ObjectReader<'a> {
+    pub fn open(vm: &'a VM, val: Value, tag: u8, len: usize) -> Result<Self, MarshalError> {
+        let fields = fields(vm, val, tag)?;
+        if fields.len() != len {
+            return Err(MarshalError::WrongLength {
+                expected: len,
+                found: fields.len(),
+            });
+        }
+        Ok(Self {
+            vm,
+            fields,
+            next: 0,
+        })
+    }
+
+    pub fn field<T: FromGuest>(&mut self) -> Result<T, MarshalError> {
+        let index = self.next;
+        let &val = self.fields.get(index).ok_or(MarshalError::WrongLength {
+            expected: index + 1,
+            found: self.fields.len(),
+        })?;
+        self.next += 1;
+        T::from_guest(self.vm, val).map_err(|err| MarshalError::InField {
+            index,
+            err: Box::new(err),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::Chunk;
+
+    fn stressed() -> VM {
+        let mut vm = VM::new(Chunk::new(vec![]));
+        vm.set_gc_stress(true).unwrap();
+        vm
+    }
+
+    #[test]
+    fn test_nested_round_trip() {
+        let data: Vec<Option<(i64, f64)>> = (0..50)
+            .map(|i| (i % 3 != 0).then_some((i, i as f64 / 4.0)))
+            .collect();
+        let mut vm = stressed();
+        let val = data.clone().into_guest(&mut vm);
+        assert!(val.get_object_ptr().is_some());
+
+        // everything but the rooted array is garbage by now
+        vm.collect_garbage();
+        assert_eq!(vm.heap().len(), 1 + data.iter().flatten().count());
+        assert_eq!(Vec::<Option<(i64, f64)>>::from_guest(&vm, val), Ok(data));
+
+        release(&mut vm, val);
+        vm.collect_garbage();
+        assert_eq!(vm.heap().len(), 0);
+    }
+
+    #[test]
+    fn test_strings_and_structs() {
+        let mut vm = stressed();
+        let pairs = vec![("λ".to_string(), -1i64), ("two".to_string(), 2)];
+        let val = pairs.clone().into_guest(&mut vm);
+        vm.collect_garbage();
+        assert_eq!(Vec::<(String, i64)>::from_guest(&vm, val), Ok(pairs));
+
+        let point = ObjectBuilder::new(7)
+            .field(&mut vm, 3i64)
+            .field(&mut vm, "origin")
+            .field(&mut vm, Some(true))
+            .build(&mut vm);
+        vm.collect_garbage();
+        let mut reader = ObjectReader::open(&vm, Value::ObjectPtr(point), 7, 3).unwrap();
+        assert_eq!(reader.field::<i64>(), Ok(3));
+        assert_eq!(reader.field::<String>(), Ok("origin".to_string()));
+        assert_eq!(reader.field::<Option<bool>>(), Ok(Some(true)));
+        assert!(reader.field::<Value>().is_err());
+    }
+
+    #[test]
+    fn test_shape_mismatch() {
+        let mut vm = stressed();
+        let val = vec![(1i64, 2.0f64)].into_guest(&mut vm);
+        assert_eq!(
+            Vec::<(i64, i64)>::from_guest(&vm, val),
+            Err(MarshalError::InField {
+                index: 1,
+                err: Box::new(MarshalError::TypeMismatch {
+                    expected: "integer",
+                    found: "float"
+                })
+            })
+        );
+        assert_eq!(
+            Vec::<(i64, f64, f64)>::from_guest(&vm, val),
+            Err(MarshalError::WrongLength {
+                expected: 3,
+                found: 2
+            })
+        );
+        assert_eq!(
+            String::from_guest(&vm, val),
+            Err(MarshalError::WrongTag {
+                expected: tag::STRING,
+                found: tag::ARRAY
+            })
+        );
+        assert_eq!(
+            Vec::<i64>::from_guest(&vm, Value::Integer(1)),
+            Err(MarshalError::TypeMismatch {
+                expected: "object",
+                found: "integer"
+            })
+        );
+    }
+}
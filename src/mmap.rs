@@ -0,0 +1,79 @@
+use std::ffi::{c_int, c_void};
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr;
+
+const PROT_READ: c_int = 1;
+const MAP_PRIVATE: c_int = 2;
+
+extern "C" {
+    fn mmap(
+        addr: *mut c_void,
+        len: usize,
+        prot: c_int,
+        flags: c_int,
+        fd: c_int,
+        off: i64,
+    ) -> *mut c_void;
+    fn munmap(addr: *mut c_void, len: usize) -> c_int;
+}
+
+// A read-only mapping of a whole file, which can back a chunk through
+// `Chunk::deserialize_image`. The file must not be truncated while mapped.
+#[derive(Debug)]
+pub struct MappedFile {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+// The mapping is never written through.
+unsafe impl Send for MappedFile {}
+unsafe impl Sync for MappedFile {}
+
+impl MappedFile {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let len = usize::try_from(file.metadata()?.len())
+            .map_err(|_| io::Error::from(io::ErrorKind::OutOfMemory))?;
+        // empty mappings are an error, and there is nothing to map anyway
+        if len == 0 {
+            return Ok(Self {
+                ptr: ptr::null_mut(),
+                len,
+            });
+        }
+        let ptr = unsafe {
+            mmap(
+                ptr::null_mut(),
+                len,
+                PROT_READ,
+                MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr as isize == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { ptr, len })
+    }
+}
+
+impl AsRef<[u8]> for MappedFile {
+    fn as_ref(&self) -> &[u8] {
+        match self.len {
+            0 => &[],
+            len => unsafe { std::slice::from_raw_parts(self.ptr as *const u8, len) },
+        }
+    }
+}
+
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            unsafe { munmap(self.ptr, self.len) };
+        }
+    }
+}
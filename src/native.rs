@@ -0,0 +1,28 @@
+use crate::error::VmError;
+use crate::vm::VM;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+// Natives take their arguments from the stack and push their results, like
+// any other instruction.
+pub type Native = Rc<dyn Fn(&mut VM) -> Result<(), VmError>>;
+
+#[derive(Default, Clone)]
+pub(crate) struct Natives(HashMap<String, Native>);
+
+impl Natives {
+    pub(crate) fn insert(&mut self, name: &str, native: Native) {
+        self.0.insert(name.to_string(), native);
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<Native> {
+        self.0.get(name).cloned()
+    }
+}
+
+impl fmt::Debug for Natives {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
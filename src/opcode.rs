@@ -1,3 +1,28 @@
+use crate::chunk::feature;
+
+// What an opcode does besides computing its results from its operands, for
+// passes that move, merge or drop instructions. Any opcode may trap on its
+// operands, which isn't counted. `SIDE_EFFECT` is whatever else the VM or
+// the host could tell apart: natives, calls, the clock and randomness, and
+// reading the machine's own state, so passes treat it as conflicting with
+// everything.
+pub mod effect {
+    pub const PURE: u32 = 0;
+    pub const READS_LOCALS: u32 = 1 << 0;
+    pub const WRITES_LOCALS: u32 = 1 << 1;
+    pub const READS_HEAP: u32 = 1 << 2;
+    // Allocating and collecting count as writes.
+    pub const WRITES_HEAP: u32 = 1 << 3;
+    // Jumps, returns and calls, and anything whose result depends on where
+    // the code is laid out.
+    pub const CONTROL_FLOW: u32 = 1 << 4;
+    pub const SIDE_EFFECT: u32 = 1 << 5;
+}
+
+// Binary operators take their right operand from the top of the stack and
+// their left from below it, so `a b SubI` computes `a - b` and `a b CmpLtI`
+// tests `a < b`. Chunks from before serialization version 6 had it the
+// other way round.
 #[derive(Debug, Clone, Copy, PartialEq, int_enum::IntEnum)]
 #[repr(u8)]
 pub enum OpCode {
@@ -18,4 +43,325 @@ pub enum OpCode {
     CmpGeI = 14,
     CmpLtI = 15,
     CmpLeI = 16,
-}
\ No newline at end of file
+    GetField = 17,
+    SetField = 18,
+    ObjEq = 19,
+    ObjCloneShallow = 20,
+    ObjCloneDeep = 21,
+    Imm0 = 22,
+    Imm1 = 23,
+    ImmNeg1 = 24,
+    AndW = 25,
+    OrW = 26,
+    XorW = 27,
+    ShlW = 28,
+    ShrW = 29,
+    RotlW = 30,
+    RotrW = 31,
+    ClzW = 32,
+    CtzW = 33,
+    PopcntW = 34,
+    F2Bits = 35,
+    Bits2F = 36,
+    AddI32 = 37,
+    SubI32 = 38,
+    MulI32 = 39,
+    DivI32 = 40,
+    I64toI32 = 41,
+    ParseInt = 42,
+    ParseFloat = 43,
+    IntToStr = 44,
+    FloatToStr = 45,
+    MapNew = 46,
+    MapGet = 47,
+    MapSet = 48,
+    MapContains = 49,
+    MapLen = 50,
+    MapDelete = 51,
+    LoadConst = 52,
+    Intern = 53,
+    StrEq = 54,
+    NewWeak = 55,
+    WeakGet = 56,
+    Gc = 57,
+    HeapInfo = 58,
+    PushIp = 59,
+    GotoDyn = 60,
+    CallNative = 61,
+    Call = 62,
+    ArrayNew = 63,
+    ArrayGet = 64,
+    ArraySet = 65,
+    ArrayLen = 66,
+    ModI = 67,
+    DivFloorI = 68,
+    ModEuclidI = 69,
+    Nop = 70,
+    ImmI8 = 71,
+    ImmI16 = 72,
+    CmpEqW = 73,
+    CmpGtW = 74,
+    CmpGeW = 75,
+    CmpLtW = 76,
+    CmpLeW = 77,
+    Clock = 78,
+    Rand = 79,
+    StrCmp = 80,
+    StrLen = 81,
+    CharAt = 82,
+    Substr = 83,
+    ImmW8 = 84,
+    ImmW16 = 85,
+    PushChunkLen = 86,
+    StackDepth = 87,
+    FrameDepth = 88,
+    FuelRemaining = 89,
+    ReturnN = 90,
+    StoreIf = 91,
+    LoadOrDefault = 92,
+    IterNew = 93,
+    IterNext = 94,
+    LoadArg = 95,
+    StoreArg = 96,
+    ArrayCopy = 97,
+    ArrayFill = 98,
+    ArraySlice = 99,
+    BufNew = 100,
+    BufLoad8 = 101,
+    BufLoad16 = 102,
+    BufLoad32 = 103,
+    BufLoad64 = 104,
+    BufStore8 = 105,
+    BufStore16 = 106,
+    BufStore32 = 107,
+    BufStore64 = 108,
+    BufLen = 109,
+    ArrayPush = 110,
+    ArrayPop = 111,
+    FieldCount = 112,
+    GetFieldDyn = 113,
+    SetFieldDyn = 114,
+    AddToLocal = 115,
+    MulToLocal = 116,
+    MinToLocal = 117,
+    MaxToLocal = 118,
+    // Internal: only `optimizer::eliminate_range_checks` emits these, and
+    // chunks are serialized and hashed with `ArrayGet` and `ArraySet` in
+    // their place.
+    ArrayGetUnchecked = 119,
+    ArraySetUnchecked = 120,
+    FmtFloat = 121,
+    // As `ArrayGet` and `ArraySet`, with negative indices counting back from
+    // the end.
+    ArrayGetRel = 122,
+    ArraySetRel = 123,
+    // Marks code a compiler knows can't be reached, and traps if it is.
+    Unreachable = 124,
+    // Pops a string and traps with it as the message, for guest assertions.
+    TrapMsg = 125,
+}
+
+impl OpCode {
+    // Number of operand bytes following the opcode byte.
+    pub const fn operand_len(self) -> usize {
+        use OpCode::*;
+        match self {
+            Return | Nop | AddI | SubI | MulI | DivI => 0,
+            Unreachable | TrapMsg => 0,
+            ModI | DivFloorI | ModEuclidI => 0,
+            CmpEqI | CmpGtI | CmpGeI | CmpLtI | CmpLeI => 0,
+            CmpEqW | CmpGtW | CmpGeW | CmpLtW | CmpLeW => 0,
+            ObjEq | ObjCloneShallow | ObjCloneDeep => 0,
+            FieldCount | GetFieldDyn | SetFieldDyn => 0,
+            Imm0 | Imm1 | ImmNeg1 => 0,
+            AndW | OrW | XorW | ShlW | ShrW | RotlW | RotrW => 0,
+            ClzW | CtzW | PopcntW => 0,
+            F2Bits | Bits2F => 0,
+            AddI32 | SubI32 | MulI32 | DivI32 | I64toI32 => 0,
+            ParseInt | ParseFloat | IntToStr | FloatToStr | FmtFloat => 0,
+            MapNew | MapGet | MapSet | MapContains | MapLen | MapDelete => 0,
+            Intern | StrEq | StrCmp => 0,
+            StrLen | CharAt | Substr => 0,
+            NewWeak | WeakGet => 0,
+            Gc | HeapInfo => 0,
+            PushIp | PushChunkLen | GotoDyn => 0,
+            Clock | Rand => 0,
+            StackDepth | FrameDepth | FuelRemaining => 0,
+            ArrayNew | ArrayGet | ArraySet | ArrayLen => 0,
+            ArrayGetUnchecked | ArraySetUnchecked => 0,
+            ArrayGetRel | ArraySetRel => 0,
+            ArrayCopy | ArrayFill | ArraySlice | ArrayPush | ArrayPop => 0,
+            BufNew | BufLen => 0,
+            BufLoad8 | BufLoad16 | BufLoad32 | BufLoad64 => 0,
+            BufStore8 | BufStore16 | BufStore32 | BufStore64 => 0,
+            IterNew | IterNext => 0,
+            Goto | GotoIf | Load | Store | GetField | SetField | LoadConst => 2,
+            StoreIf | LoadOrDefault => 2,
+            AddToLocal | MulToLocal | MinToLocal | MaxToLocal => 2,
+            CallNative | Call => 2,
+            ImmI8 | ImmW8 | ReturnN | LoadArg | StoreArg => 1,
+            ImmI16 | ImmW16 => 2,
+            ImmI | ImmF | ImmW => 8,
+        }
+    }
+
+    pub const fn is_jump(self) -> bool {
+        matches!(self, Self::Goto | Self::GotoIf)
+    }
+
+    // The opcode an internal one stands in for, which is itself for the
+    // rest.
+    pub const fn public(self) -> Self {
+        match self {
+            Self::ArrayGetUnchecked => Self::ArrayGet,
+            Self::ArraySetUnchecked => Self::ArraySet,
+            op => op,
+        }
+    }
+
+    pub const fn is_internal(self) -> bool {
+        matches!(self, Self::ArrayGetUnchecked | Self::ArraySetUnchecked)
+    }
+
+    // Values popped and then pushed, for opcodes whose effect doesn't depend
+    // on their operand or on the function table. Jumps are included; only
+    // their effect on the stack is described.
+    pub const fn stack_effect(self) -> Option<(usize, usize)> {
+        use OpCode::*;
+        Some(match self {
+            Return | ReturnN | Call | CallNative | GotoDyn | IterNext => return None,
+            Unreachable => return None,
+            Nop | Goto | Gc => (0, 0),
+            TrapMsg => (1, 0),
+            GotoIf | Store | StoreArg => (1, 0),
+            AddToLocal | MulToLocal | MinToLocal | MaxToLocal => (1, 0),
+            Load | LoadOrDefault | LoadArg | LoadConst | MapNew | HeapInfo => (0, 1),
+            PushIp | PushChunkLen => (0, 1),
+            ImmI | ImmI8 | ImmI16 | ImmF | ImmW | ImmW8 | ImmW16 | Imm0 | Imm1 | ImmNeg1 => (0, 1),
+            Clock | Rand | StackDepth | FrameDepth | FuelRemaining => (0, 1),
+            AddI | SubI | MulI | DivI | ModI | DivFloorI | ModEuclidI => (2, 1),
+            CmpEqI | CmpGtI | CmpGeI | CmpLtI | CmpLeI => (2, 1),
+            CmpEqW | CmpGtW | CmpGeW | CmpLtW | CmpLeW => (2, 1),
+            AndW | OrW | XorW | ShlW | ShrW | RotlW | RotrW => (2, 1),
+            AddI32 | SubI32 | MulI32 | DivI32 => (2, 1),
+            ObjEq | StrEq | StrCmp | CharAt | ArrayGet | ArrayGetUnchecked => (2, 1),
+            ArrayGetRel => (2, 1),
+            MapGet | MapContains | MapDelete => (2, 1),
+            ClzW | CtzW | PopcntW | F2Bits | Bits2F | I64toI32 => (1, 1),
+            ParseInt | ParseFloat | IntToStr | FloatToStr => (1, 1),
+            FmtFloat => (2, 1),
+            GetField | ObjCloneShallow | ObjCloneDeep | Intern | NewWeak | WeakGet => (1, 1),
+            MapLen | StrLen | ArrayNew | ArrayLen | IterNew => (1, 1),
+            BufNew | BufLen => (1, 1),
+            BufLoad8 | BufLoad16 | BufLoad32 | BufLoad64 => (2, 1),
+            BufStore8 | BufStore16 | BufStore32 | BufStore64 => (3, 0),
+            SetField | StoreIf => (2, 0),
+            MapSet | ArraySet | ArraySetUnchecked | ArraySetRel => (3, 0),
+            ArrayFill => (4, 0),
+            ArrayPush => (2, 0),
+            ArrayPop => (1, 1),
+            FieldCount => (1, 1),
+            GetFieldDyn => (2, 1),
+            SetFieldDyn => (3, 0),
+            ArrayCopy => (5, 0),
+            Substr | ArraySlice => (3, 1),
+        })
+    }
+
+    // The `effect` bits, which every opcode lists explicitly so that a new
+    // one can't be assumed pure by default.
+    pub const fn effects(self) -> u32 {
+        use effect::*;
+        use OpCode::*;
+        const READS_WRITES_HEAP: u32 = READS_HEAP | WRITES_HEAP;
+        match self {
+            Nop | ImmI | ImmI8 | ImmI16 | ImmF | ImmW | ImmW8 | ImmW16 => PURE,
+            Imm0 | Imm1 | ImmNeg1 => PURE,
+            AddI | SubI | MulI | DivI | ModI | DivFloorI | ModEuclidI => PURE,
+            CmpEqI | CmpGtI | CmpGeI | CmpLtI | CmpLeI => PURE,
+            CmpEqW | CmpGtW | CmpGeW | CmpLtW | CmpLeW => PURE,
+            AndW | OrW | XorW | ShlW | ShrW | RotlW | RotrW => PURE,
+            ClzW | CtzW | PopcntW | F2Bits | Bits2F => PURE,
+            AddI32 | SubI32 | MulI32 | DivI32 | I64toI32 => PURE,
+            Load | LoadOrDefault | LoadArg => READS_LOCALS,
+            Store | StoreIf | StoreArg => WRITES_LOCALS,
+            AddToLocal | MulToLocal | MinToLocal | MaxToLocal => READS_LOCALS | WRITES_LOCALS,
+            Goto | GotoIf | GotoDyn | Return | ReturnN | PushIp | PushChunkLen => CONTROL_FLOW,
+            Unreachable => CONTROL_FLOW,
+            TrapMsg => CONTROL_FLOW | READS_HEAP,
+            Call => CONTROL_FLOW | SIDE_EFFECT,
+            CallNative | Clock | Rand | StackDepth | FrameDepth | FuelRemaining => SIDE_EFFECT,
+            GetField | ObjEq | FieldCount | GetFieldDyn => READS_HEAP,
+            ParseInt | ParseFloat | StrEq | StrCmp | StrLen | CharAt => READS_HEAP,
+            MapGet | MapContains | MapLen | WeakGet | HeapInfo => READS_HEAP,
+            ArrayGet | ArrayGetUnchecked | ArrayGetRel | ArrayLen => READS_HEAP,
+            BufLoad8 | BufLoad16 | BufLoad32 | BufLoad64 | BufLen => READS_HEAP,
+            SetField | SetFieldDyn | ArraySet | ArraySetUnchecked | ArraySetRel => WRITES_HEAP,
+            ArrayFill => WRITES_HEAP,
+            BufStore8 | BufStore16 | BufStore32 | BufStore64 => WRITES_HEAP,
+            IntToStr | FloatToStr | FmtFloat | LoadConst => WRITES_HEAP,
+            MapNew | ArrayNew | BufNew | NewWeak | Gc => WRITES_HEAP,
+            ObjCloneShallow | ObjCloneDeep | Intern | Substr => READS_WRITES_HEAP,
+            MapSet | MapDelete | ArrayCopy | ArraySlice | ArrayPush | ArrayPop => READS_WRITES_HEAP,
+            IterNew | IterNext => READS_WRITES_HEAP,
+        }
+    }
+
+    pub const fn is_pure(self) -> bool {
+        self.effects() == effect::PURE
+    }
+
+    // The `feature` bits a chunk containing this opcode requires. Whether
+    // `LoadConst` needs objects depends on its constant.
+    pub const fn features(self) -> u32 {
+        use OpCode::*;
+        match self {
+            GetField | SetField | ObjEq | ObjCloneShallow | ObjCloneDeep => feature::OBJECTS,
+            FieldCount | GetFieldDyn | SetFieldDyn => feature::OBJECTS,
+            ParseInt | ParseFloat | IntToStr | FloatToStr | FmtFloat => feature::OBJECTS,
+            MapNew | MapGet | MapSet | MapContains | MapLen | MapDelete => feature::OBJECTS,
+            Intern | StrEq | StrCmp | StrLen | CharAt | Substr => feature::OBJECTS,
+            NewWeak | WeakGet | Gc | HeapInfo => feature::OBJECTS,
+            TrapMsg => feature::OBJECTS,
+            ArrayNew | ArrayGet | ArraySet | ArrayLen => feature::OBJECTS,
+            ArrayGetUnchecked | ArraySetUnchecked => feature::OBJECTS,
+            ArrayGetRel | ArraySetRel => feature::OBJECTS,
+            ArrayCopy | ArrayFill | ArraySlice | ArrayPush | ArrayPop => feature::OBJECTS,
+            BufNew | BufLen => feature::OBJECTS,
+            BufLoad8 | BufLoad16 | BufLoad32 | BufLoad64 => feature::OBJECTS,
+            BufStore8 | BufStore16 | BufStore32 | BufStore64 => feature::OBJECTS,
+            IterNew | IterNext => feature::OBJECTS,
+            CallNative => feature::NATIVES,
+            PushIp | PushChunkLen | GotoDyn => feature::DYNAMIC_JUMPS,
+            _ => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::effect::*;
+    use super::OpCode::*;
+    use super::*;
+
+    #[test]
+    fn test_effects() {
+        let all = (0..=u8::MAX).filter_map(|byte| OpCode::try_from(byte).ok());
+        for op in all {
+            let effects = op.effects();
+            if op.is_jump() || op.stack_effect().is_none() {
+                let unknown = CONTROL_FLOW | SIDE_EFFECT | READS_HEAP | WRITES_HEAP;
+                assert_ne!(effects & unknown, 0, "{op:?}");
+            }
+            if op.features() & feature::OBJECTS != 0 {
+                assert_ne!(effects & (READS_HEAP | WRITES_HEAP), 0, "{op:?}");
+            }
+            if op.is_pure() {
+                assert_eq!(op.features(), 0, "{op:?}");
+            }
+            assert_eq!(op.public().effects(), effects, "{op:?}");
+        }
+        assert!(AddI.is_pure() && DivI.is_pure());
+        assert!(!Clock.is_pure() && !Load.is_pure() && !ArrayLen.is_pure());
+    }
+}
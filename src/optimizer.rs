@@ -0,0 +1,939 @@
+use crate::chunk::{feature, Chunk};
+use crate::error::VerifyError;
+use crate::instruction::{self, Instruction};
+use crate::opcode::{effect, OpCode};
+use crate::value::Value;
+use crate::vm::VM;
+use std::collections::{HashMap, HashSet};
+
+// Where the passes look up what an opcode may do; always `OpCode::effects`,
+// except in tests checking that a pass holds back from some effect.
+type Effects = fn(OpCode) -> u32;
+
+fn narrow(instruction: Instruction) -> Instruction {
+    match instruction {
+        Instruction::ImmI(0) => Instruction::Imm0,
+        Instruction::ImmI(1) => Instruction::Imm1,
+        Instruction::ImmI(-1) => Instruction::ImmNeg1,
+        Instruction::ImmI(i) => match (i8::try_from(i), i16::try_from(i)) {
+            (Ok(i), _) => Instruction::ImmI8(i),
+            (_, Ok(i)) => Instruction::ImmI16(i),
+            _ => instruction,
+        },
+        Instruction::ImmW(w) => match (u8::try_from(w), u16::try_from(w)) {
+            (Ok(w), _) => Instruction::ImmW8(w),
+            (_, Ok(w)) => Instruction::ImmW16(w),
+            _ => instruction,
+        },
+        _ => instruction,
+    }
+}
+
+fn is_literal(instruction: Instruction) -> bool {
+    use Instruction::*;
+    matches!(
+        instruction,
+        ImmI(_)
+            | ImmI8(_)
+            | ImmI16(_)
+            | Imm0
+            | Imm1
+            | ImmNeg1
+            | ImmW(_)
+            | ImmW8(_)
+            | ImmW16(_)
+            | ImmF(_)
+    )
+}
+
+// Runs the operation on its operands to get exactly what the VM would,
+// leaving anything that traps to trap at run time.
+fn evaluate(group: &[(usize, Instruction)]) -> Option<Instruction> {
+    let instructions: Vec<_> = group.iter().map(|&(_, instruction)| instruction).collect();
+    let mut vm = VM::new(instruction::encode(&instructions));
+    vm.execute_all().ok()?;
+    match vm.stack() {
+        [Value::Integer(i)] => Some(Instruction::ImmI(*i)),
+        [Value::Word(w)] => Some(Instruction::ImmW(*w)),
+        [Value::Float(f)] => Some(Instruction::ImmF(*f)),
+        _ => None,
+    }
+}
+
+// Replaces pure operations on literals with their result, including chains
+// of them, unless the result takes more bytes. Only the first instruction of
+// a group may be a jump target, so a group is always executed from its
+// start.
+fn fold(
+    instructions: Vec<(usize, Instruction)>,
+    targets: &HashSet<usize>,
+    effects: Effects,
+) -> Vec<(usize, Instruction)> {
+    let mut out: Vec<(usize, Instruction)> = Vec::with_capacity(instructions.len());
+    for entry in instructions {
+        out.push(entry);
+        loop {
+            let op = out[out.len() - 1].1.opcode();
+            let pure = effects(op) == effect::PURE;
+            let Some((pops @ 1.., 1)) = op.stack_effect().filter(|_| pure) else {
+                break;
+            };
+            let Some(start) = out.len().checked_sub(pops + 1) else {
+                break;
+            };
+            let group = &out[start..];
+            if !group[..pops]
+                .iter()
+                .all(|&(_, operand)| is_literal(operand))
+                || group[1..].iter().any(|(ip, _)| targets.contains(ip))
+            {
+                break;
+            }
+            let len: usize = group.iter().map(|(_, i)| narrow(*i).encoded_len()).sum();
+            let Some(result) = evaluate(group).filter(|r| narrow(*r).encoded_len() <= len) else {
+                break;
+            };
+            let ip = group[0].0;
+            out.truncate(start);
+            out.push((ip, result));
+        }
+    }
+    out
+}
+
+// Whether straight-line execution can't continue past the instruction, or
+// it may have effects that no pass sees through.
+fn ends_region(instruction: Instruction, effects: Effects) -> bool {
+    effects(instruction.opcode()) & (effect::CONTROL_FLOW | effect::SIDE_EFFECT) != 0
+}
+
+// The local the instruction's operand names, arguments included.
+fn local_operand(instruction: Instruction) -> Option<u16> {
+    use Instruction::*;
+    match instruction {
+        Load(k) | LoadOrDefault(k) | Store(k) | StoreIf(k) => Some(k),
+        AddToLocal(k) | MulToLocal(k) | MinToLocal(k) | MaxToLocal(k) => Some(k),
+        LoadArg(k) | StoreArg(k) => Some(k as u16),
+        _ => None,
+    }
+}
+
+// Whether the instruction may read or write `local`, as the `access` bits
+// say. One that does so without naming a local could touch any of them.
+fn accesses(instruction: Instruction, local: u16, access: u32, effects: Effects) -> bool {
+    effects(instruction.opcode()) & access != 0
+        && local_operand(instruction).is_none_or(|k| k == local)
+}
+
+// Removes a literal stored to a local that is stored to again before it is
+// read, within one straight-line region. Only hooks, trap handlers and the
+// state left by a trap between the two stores could show the difference.
+fn drop_dead_stores(
+    instructions: Vec<(usize, Instruction)>,
+    targets: &HashSet<usize>,
+    effects: Effects,
+) -> Vec<(usize, Instruction)> {
+    let mut dead = HashSet::new();
+    for (i, pair) in instructions.windows(2).enumerate() {
+        let [(push_ip, push), (ip, Instruction::Store(local))] = *pair else {
+            continue;
+        };
+        if !is_literal(push) || targets.contains(&push_ip) || targets.contains(&ip) {
+            continue;
+        }
+        for &(ip, instruction) in &instructions[i + 2..] {
+            if targets.contains(&ip)
+                || ends_region(instruction, effects)
+                || accesses(instruction, local, effect::READS_LOCALS, effects)
+            {
+                break;
+            }
+            if instruction == Instruction::Store(local) {
+                dead.extend([i, i + 1]);
+                break;
+            }
+        }
+    }
+    instructions
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| !dead.contains(i))
+        .map(|(_, entry)| entry)
+        .collect()
+}
+
+// Turns `Load k, AddI, Store k`, and the same with `MulI`, into the opcode
+// that combines the value below into local `k` in place. The `Load` may be
+// a jump target, since the value is on the stack by then either way, but
+// the other two may not. Traps are the same, though a mistyped operand is
+// reported at the combined instruction.
+fn combine_in_place(
+    instructions: Vec<(usize, Instruction)>,
+    targets: &HashSet<usize>,
+) -> Vec<(usize, Instruction)> {
+    let mut out: Vec<(usize, Instruction)> = Vec::with_capacity(instructions.len());
+    for entry in instructions {
+        out.push(entry);
+        let Some(start) = out.len().checked_sub(3) else {
+            continue;
+        };
+        let [(ip, Instruction::Load(read)), (op_ip, op), (store_ip, Instruction::Store(written))] =
+            out[start..]
+        else {
+            continue;
+        };
+        if read != written || targets.contains(&op_ip) || targets.contains(&store_ip) {
+            continue;
+        }
+        let combined = match op {
+            Instruction::AddI => Instruction::AddToLocal(read),
+            Instruction::MulI => Instruction::MulToLocal(read),
+            _ => continue,
+        };
+        out.truncate(start);
+        out.push((ip, combined));
+    }
+    out
+}
+
+// Code that pushes its own offsets or length, or jumps to computed ones,
+// depends on where every instruction is, as does any chunk declaring that
+// it may.
+fn is_position_dependent(chunk: &Chunk, instructions: &[(usize, Instruction)]) -> bool {
+    chunk.features() & feature::DYNAMIC_JUMPS != 0
+        || instructions
+            .iter()
+            .any(|&(_, instruction)| instruction.opcode().features() & feature::DYNAMIC_JUMPS != 0)
+}
+
+// Rewrites instructions into shorter equivalents, relocating jump targets
+// to account for the bytes saved. Chunks with computed jumps or offsets are
+// left alone, since those can't be relocated.
+pub fn peephole(chunk: &Chunk) -> Result<Chunk, VerifyError> {
+    rewrite(chunk, combine_in_place)
+}
+
+// As `peephole`, after folding constant expressions and dropping dead stores
+// of constants.
+pub fn optimize(chunk: &Chunk) -> Result<Chunk, VerifyError> {
+    rewrite(chunk, |instructions, targets| {
+        let folded = fold(instructions, targets, OpCode::effects);
+        let live = drop_dead_stores(folded, targets, OpCode::effects);
+        combine_in_place(live, targets)
+    })
+}
+
+fn is_integer(instruction: Instruction, i: i64) -> bool {
+    use Instruction::*;
+    match instruction {
+        Imm0 => i == 0,
+        Imm1 => i == 1,
+        ImmI(j) => i == j,
+        ImmI8(j) => i == j as i64,
+        ImmI16(j) => i == j as i64,
+        _ => false,
+    }
+}
+
+// Finds loops of the shape
+//
+//         <0> Store i      the last write to i before the head
+//   head: Load i, Load a, ArrayLen, CmpGeI, GotoIf exit
+//         body
+//         Load i, <1>, AddI, Store i
+//         Goto head
+//
+// where the increment may also be `<1>, Load i, AddI, Store i` or `<1>,
+// AddToLocal i`, and turns `Load a, Load i, ArrayGet` in the body, and
+// `Load a, Load i, <push>, ArraySet`, into the internal unchecked opcodes.
+// Only the back edge may jump to the head and nothing may jump into the
+// body, which has to be straight-line code without calls or pushes and pops
+// that could shrink the array, and nothing but the increment may write i or
+// a in it. Each rewrite keeps its offset, so nothing is relocated; chunks
+// with computed jumps or offsets are left alone. This isn't part of
+// `optimize`, since the chunk it gives is only for running.
+pub fn eliminate_range_checks(chunk: &Chunk) -> Result<Chunk, VerifyError> {
+    let instructions = chunk.instructions().collect::<Result<Vec<_>, _>>()?;
+    if is_position_dependent(chunk, &instructions) {
+        return Ok(chunk.clone());
+    }
+
+    let mut jumps: Vec<_> = chunk.functions().iter().map(|f| (None, f.entry)).collect();
+    for &(ip, instruction) in &instructions {
+        if let Instruction::Goto(target) | Instruction::GotoIf(target) = instruction {
+            jumps.push((Some(ip), target as usize));
+        }
+    }
+
+    let mut out = chunk.clone();
+    for (end, &(_, instruction)) in instructions.iter().enumerate() {
+        let Instruction::Goto(head) = instruction else {
+            continue;
+        };
+        let Some(head) = instructions[..end]
+            .iter()
+            .position(|&(ip, _)| ip == head as usize)
+        else {
+            continue;
+        };
+        for (ip, unchecked) in counted_loop(&instructions, head, end, &jumps) {
+            // the same length as what it replaces
+            out.patch(ip, unchecked).unwrap();
+        }
+    }
+    Ok(out)
+}
+
+// The rewrites for the loop from `instructions[head]` to its back edge at
+// `instructions[end]`, if it has the shape `eliminate_range_checks` needs.
+// `jumps` holds the source and target of every jump, with function entries
+// coming from nowhere.
+fn counted_loop(
+    instructions: &[(usize, Instruction)],
+    head: usize,
+    end: usize,
+    jumps: &[(Option<usize>, usize)],
+) -> Vec<(usize, Instruction)> {
+    use Instruction::*;
+    let Some(
+        &[(head_ip, Load(index)), (_, Load(array)), (_, ArrayLen), (_, CmpGeI), (_, GotoIf(_))],
+    ) = instructions.get(head..head + 5)
+    else {
+        return Vec::new();
+    };
+    let (back_edge, _) = instructions[end];
+    let loop_ = head_ip..back_edge + 1;
+    if index == array
+        || jumps.iter().any(|&(from, to)| {
+            (to == head_ip && from != Some(back_edge)) || (loop_.contains(&to) && to != head_ip)
+        })
+    {
+        return Vec::new();
+    }
+
+    // the index starts at zero on the way in
+    let targets: HashSet<_> = jumps.iter().map(|&(_, to)| to).collect();
+    let mut initialized = false;
+    for i in (1..head).rev() {
+        let (ip, instruction) = instructions[i];
+        if targets.contains(&ip) || ends_region(instruction, OpCode::effects) {
+            break;
+        }
+        if accesses(instruction, index, effect::WRITES_LOCALS, OpCode::effects) {
+            initialized = instruction == Store(index) && is_integer(instructions[i - 1].1, 0);
+            break;
+        }
+    }
+    if !initialized {
+        return Vec::new();
+    }
+
+    let body = &instructions[head + 5..end];
+    let increment = match *body {
+        [.., (_, Load(k)), (_, one), (_, AddI), (_, Store(j))]
+        | [.., (_, one), (_, Load(k)), (_, AddI), (_, Store(j))]
+            if k == index && j == index && is_integer(one, 1) =>
+        {
+            4
+        }
+        [.., (_, one), (_, AddToLocal(k))] if k == index && is_integer(one, 1) => 2,
+        _ => return Vec::new(),
+    };
+    let body = &body[..body.len() - increment];
+    // any array in the body may be a, so none of them may change length
+    if body.iter().any(|&(_, instruction)| {
+        ends_region(instruction, OpCode::effects)
+            || matches!(instruction, ArrayPush | ArrayPop)
+            || [index, array]
+                .into_iter()
+                .any(|local| accesses(instruction, local, effect::WRITES_LOCALS, OpCode::effects))
+    }) {
+        return Vec::new();
+    }
+
+    let mut rewrites = Vec::new();
+    for (i, &(ip, instruction)) in body.iter().enumerate() {
+        let operands = match instruction {
+            ArrayGet => i.checked_sub(2).map(|start| &body[start..i]),
+            ArraySet => i
+                .checked_sub(3)
+                .map(|start| &body[start..i])
+                .filter(|operands| operands[2].1.opcode().stack_effect() == Some((0, 1))),
+            _ => None,
+        };
+        if let Some(&[(_, Load(a)), (_, Load(k)), ..]) = operands {
+            if a == array && k == index {
+                let unchecked = match instruction {
+                    ArrayGet => ArrayGetUnchecked,
+                    _ => ArraySetUnchecked,
+                };
+                rewrites.push((ip, unchecked));
+            }
+        }
+    }
+    rewrites
+}
+
+// `pass` may drop or merge instructions, keeping the original offset of the
+// first in each merged group, but never removes a jump target.
+fn rewrite(
+    chunk: &Chunk,
+    pass: impl FnOnce(Vec<(usize, Instruction)>, &HashSet<usize>) -> Vec<(usize, Instruction)>,
+) -> Result<Chunk, VerifyError> {
+    let instructions = chunk.instructions().collect::<Result<Vec<_>, _>>()?;
+    if is_position_dependent(chunk, &instructions) {
+        return Ok(chunk.clone());
+    }
+
+    let mut targets: HashSet<_> = chunk.functions().iter().map(|f| f.entry).collect();
+    for &(_, instruction) in &instructions {
+        if let Instruction::Goto(target) | Instruction::GotoIf(target) = instruction {
+            targets.insert(target as usize);
+        }
+    }
+
+    let mut offsets = HashMap::new();
+    let mut len = 0;
+    let mut out = Vec::with_capacity(instructions.len());
+    for (ip, instruction) in pass(instructions, &targets) {
+        offsets.insert(ip, len);
+        let instruction = narrow(instruction);
+        len += instruction.encoded_len();
+        out.push((ip, instruction));
+    }
+    offsets.insert(chunk.len(), len);
+
+    let relocate = |offset: usize, target: u16| {
+        let target = target as usize;
+        offsets
+            .get(&target)
+            .map(|&target| target as u16)
+            .ok_or(VerifyError::InvalidJump { offset, target })
+    };
+    for (ip, instruction) in &mut out {
+        match instruction {
+            Instruction::Goto(target) | Instruction::GotoIf(target) => {
+                *target = relocate(*ip, *target)?;
+            }
+            _ => {}
+        }
+    }
+
+    let mut functions = chunk.functions().to_vec();
+    for (index, function) in functions.iter_mut().enumerate() {
+        let entry = function.entry;
+        function.entry = *offsets.get(&entry).ok_or(VerifyError::InvalidEntry {
+            index: index as u16,
+            entry,
+        })?;
+    }
+
+    let instructions: Vec<_> = out
+        .into_iter()
+        .map(|(_, instruction)| instruction)
+        .collect();
+    let mut out = instruction::encode(&instructions)
+        .with_constants(chunk.constants().to_vec())
+        .with_functions(functions)
+        .with_exports(chunk.exports().to_vec())
+        .with_imports(chunk.imports().to_vec())
+        .with_features(chunk.features());
+    if let Some(max) = chunk.max_locals() {
+        out = out.with_max_locals(max);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::{self, ChunkBuilder};
+    use crate::error::VmError;
+    use crate::opcode::OpCode::*;
+    use crate::testing::Rng;
+    use crate::vm;
+    use crate::workloads;
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    fn decoded(chunk: &Chunk) -> Vec<Instruction> {
+        chunk.instructions().map(|r| r.unwrap().1).collect()
+    }
+
+    // The stack and locals, with objects hashed by their tag and fields
+    // rather than by address.
+    fn state_hash(vm: &VM) -> u64 {
+        fn hash_value(val: &Value, vm: &VM, hasher: &mut DefaultHasher) {
+            match val.get_object_ptr() {
+                Some(ptr) => {
+                    let object = vm.heap().get(ptr);
+                    object.tag.hash(hasher);
+                    for field in &object.fields {
+                        field.type_name().hash(hasher);
+                        if field.get_object_ptr().is_none() {
+                            field.to_string().hash(hasher);
+                        }
+                    }
+                }
+                None => val.to_string().hash(hasher),
+            }
+        }
+        let mut hasher = DefaultHasher::new();
+        for val in vm.stack() {
+            hash_value(val, vm, &mut hasher);
+        }
+        for local in vm.locals() {
+            match local {
+                Some(val) => hash_value(val, vm, &mut hasher),
+                None => "unset".hash(&mut hasher),
+            }
+        }
+        hasher.finish()
+    }
+
+    #[test]
+    fn test_fold_constants() {
+        let mut b = ChunkBuilder::new();
+        b.imm_i(6).imm_i(7).op(MulI);
+        b.imm_i(2).imm_i(3).op(AddI).imm_i(4).op(MulI);
+        b.imm_i(2).imm_i(1).op(CmpLtI);
+        b.imm_w(0xf0).op(PopcntW);
+        // traps when run, so it is left to trap
+        b.imm_i(1).imm_i(0).op(DivI);
+        let chunk = optimize(&b.build().unwrap()).unwrap();
+        assert_eq!(
+            decoded(&chunk),
+            [
+                Instruction::ImmI8(42),
+                Instruction::ImmI8(20),
+                Instruction::ImmW8(0),
+                Instruction::ImmI8(4),
+                Instruction::Imm1,
+                Instruction::Imm0,
+                Instruction::DivI,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unreachable_ends_region() {
+        use Instruction::*;
+        // the first store is all the trap leaves behind, and nothing after
+        // the Unreachable runs with it
+        let code = [Imm0, Store(0), Unreachable, Imm1, Store(0), Load(0)];
+        let optimized = optimize(&instruction::encode(&code)).unwrap();
+        assert_eq!(decoded(&optimized), code);
+        assert!(ends_region(Unreachable, OpCode::effects));
+    }
+
+    #[test]
+    fn test_fold_stops_at_jump_targets() {
+        // the loop jumps back between the two operands
+        let mut b = ChunkBuilder::new();
+        let head = b.label();
+        b.imm_i(10).store(0).load(0);
+        b.bind(head)
+            .imm_i(1)
+            .op(SubI)
+            .store(0)
+            .imm_i(0)
+            .load(0)
+            .op(CmpGeI);
+        b.goto_if(head);
+        b.imm_i(3).imm_i(4).op(AddI);
+        let chunk = b.build().unwrap();
+        let optimized = optimize(&chunk).unwrap();
+        assert_eq!(decoded(&optimized)[..3], decoded(&chunk)[..3]);
+        assert_eq!(decoded(&optimized).last(), Some(&Instruction::ImmI8(7)));
+    }
+
+    #[test]
+    fn test_drop_dead_stores() {
+        let mut b = ChunkBuilder::new();
+        let end = b.label();
+        b.imm_i(1).store(0).imm_i(2).store(1).imm_i(3).store(0);
+        // read before the next store, so it stays
+        b.imm_i(4).store(1).load(1).imm_i(5).store(1);
+        // stored again only past the end of the region
+        b.imm_i(6).store(2).imm_i(1).goto_if(end);
+        b.bind(end).imm_i(7).store(2);
+        let chunk = optimize(&b.build().unwrap()).unwrap();
+
+        use Instruction::*;
+        assert_eq!(
+            decoded(&chunk),
+            [
+                ImmI8(3),
+                Store(0),
+                ImmI8(4),
+                Store(1),
+                Load(1),
+                ImmI8(5),
+                Store(1),
+                ImmI8(6),
+                Store(2),
+                Imm1,
+                GotoIf(27),
+                ImmI8(7),
+                Store(2),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_effects_hold_back_rewrites() {
+        use Instruction::*;
+        let run = |pass: fn(_, &_, Effects) -> Vec<(usize, Instruction)>, effects, code| {
+            let chunk = instruction::encode(code);
+            let instructions = chunk.instructions().collect::<Result<_, _>>().unwrap();
+            let out = pass(instructions, &HashSet::new(), effects);
+            out.into_iter().map(|(_, i)| i).collect::<Vec<_>>()
+        };
+        // as if AddI were effectful
+        let add_has_effects: Effects = |op| match op {
+            OpCode::AddI => effect::SIDE_EFFECT,
+            op => op.effects(),
+        };
+        let sum = [Imm1, Imm1, AddI];
+        assert_eq!(run(fold, OpCode::effects, &sum), [ImmI(2)]);
+        assert_eq!(run(fold, add_has_effects, &sum), sum);
+
+        // and as if Nop read some local, or had other effects
+        let nop_reads: Effects = |op| match op {
+            OpCode::Nop => effect::READS_LOCALS,
+            op => op.effects(),
+        };
+        let nop_has_effects: Effects = |op| match op {
+            OpCode::Nop => effect::SIDE_EFFECT,
+            op => op.effects(),
+        };
+        let stores = [Imm0, Store(0), Nop, Imm1, Store(0)];
+        let live = [Nop, Imm1, Store(0)];
+        assert_eq!(run(drop_dead_stores, OpCode::effects, &stores), live);
+        assert_eq!(run(drop_dead_stores, nop_reads, &stores), stores);
+        assert_eq!(run(drop_dead_stores, nop_has_effects, &stores), stores);
+    }
+
+    #[test]
+    fn test_optimize_preserves_workloads() {
+        for workload in workloads::standard() {
+            let optimized = optimize(&workload.chunk).unwrap();
+            let mut before = VM::new(workload.chunk.clone());
+            let mut after = VM::new(optimized);
+            let result = before.execute_all().unwrap().value;
+            assert_eq!(
+                after.execute_all().unwrap().value,
+                result,
+                "{}",
+                workload.name
+            );
+            assert_eq!(state_hash(&after), state_hash(&before), "{}", workload.name);
+        }
+    }
+
+    #[test]
+    fn test_combine_in_place() {
+        let chunk = workloads::array_sum(1000);
+        let combined = peephole(&chunk).unwrap();
+        // the sum and both index increments
+        assert_eq!(count(&chunk, AddToLocal), 0);
+        assert_eq!(count(&combined, AddToLocal), 3);
+        assert!(combined.len() < chunk.len());
+
+        let mut before = VM::new(chunk);
+        let mut after = VM::new(combined);
+        let result = before.execute_all().unwrap();
+        let outcome = after.execute_all().unwrap();
+        assert_eq!(outcome.value, result.value);
+        assert_eq!(outcome.value, Some(Value::Integer(1000 * 999 / 2)));
+        assert!(outcome.instructions < result.instructions);
+        assert_eq!(state_hash(&after), state_hash(&before));
+
+        // different locals, and a jump to the operation in between
+        let mut b = ChunkBuilder::new();
+        let (skip, op) = (b.label(), b.label());
+        b.imm_i(2).store(0).imm_i(3).store(1);
+        b.imm_i(5).load(0).op(MulI).store(1);
+        b.imm_i(7).imm_w(0).goto_if(skip).op(Imm1).goto(op);
+        b.bind(skip).load(1).bind(op).op(AddI).store(1);
+        b.op(Imm1).load(0).op(MulI).store(0);
+        let chunk = b.build().unwrap();
+        let combined = peephole(&chunk).unwrap();
+        assert_eq!(count(&combined, MulToLocal), 1);
+        assert_eq!(count(&combined, AddToLocal), 0);
+        let mut vm = VM::new(combined);
+        vm.execute_all().unwrap();
+        assert_eq!(
+            vm.locals(),
+            [Some(Value::Integer(2)), Some(Value::Integer(8))]
+        );
+    }
+
+    fn count(chunk: &Chunk, op: OpCode) -> usize {
+        decoded(chunk)
+            .iter()
+            .filter(|instruction| instruction.opcode() == op)
+            .count()
+    }
+
+    #[test]
+    fn test_eliminate_range_checks() {
+        // the fill loop is bounded by a literal rather than the length
+        let chunk = workloads::array_sum(1000);
+        let unchecked = eliminate_range_checks(&chunk).unwrap();
+        assert_eq!(count(&unchecked, ArrayGetUnchecked), 1);
+        assert_eq!(count(&unchecked, ArraySet), 1);
+        assert_eq!(unchecked.len(), chunk.len());
+        assert_eq!(unchecked.serialize(), chunk.serialize());
+        assert_eq!(unchecked.content_hash(), chunk.content_hash());
+        // the increment `optimize` combines is recognized too
+        let optimized = optimize(&chunk).unwrap();
+        assert_eq!(
+            count(
+                &eliminate_range_checks(&optimized).unwrap(),
+                ArrayGetUnchecked
+            ),
+            1
+        );
+
+        let mut before = VM::new(chunk);
+        let mut after = VM::new(unchecked);
+        let result = before.execute_all().unwrap();
+        assert_eq!(after.execute_all().unwrap(), result);
+        assert_eq!(state_hash(&after), state_hash(&before));
+    }
+
+    // Turns each element of a random array into the sum of those up to it,
+    // then reads past the end or doesn't.
+    fn prefix_sums(rng: &mut Rng) -> Chunk {
+        let len = rng.below(20);
+        let mut b = ChunkBuilder::new();
+        let (head, done) = (b.label(), b.label());
+        b.imm_i(len as i64).op(ArrayNew).store(0);
+        for index in 0..len {
+            b.load(0)
+                .imm_i(index as i64)
+                .imm_i(rng.integer())
+                .op(ArraySet);
+        }
+        b.op(Imm0).store(2).op(Imm0).store(1);
+        b.bind(head)
+            .load(1)
+            .load(0)
+            .op(ArrayLen)
+            .op(CmpGeI)
+            .goto_if(done);
+        b.load(0).load(1).op(ArrayGet).load(2).op(AddI).store(2);
+        b.load(0).load(1).load(2).op(ArraySet);
+        if rng.chance(50) {
+            b.op(Imm1).add_to_local(1);
+        } else {
+            b.load(1).op(Imm1).op(AddI).store(1);
+        }
+        b.goto(head);
+        b.bind(done);
+        if rng.chance(20) {
+            b.load(0).load(1).op(ArrayGet);
+        }
+        b.load(2);
+        b.build().unwrap()
+    }
+
+    #[test]
+    fn test_eliminate_range_checks_differential() {
+        let mut rng = Rng::new(184);
+        for _ in 0..200 {
+            let chunk = prefix_sums(&mut rng);
+            let unchecked = eliminate_range_checks(&chunk).unwrap();
+            assert_eq!(count(&unchecked, ArrayGetUnchecked), 1);
+            assert_eq!(count(&unchecked, ArraySetUnchecked), 1);
+
+            let mut before = VM::new(chunk);
+            let mut after = VM::new(unchecked);
+            assert_eq!(after.execute_all(), before.execute_all());
+            assert_eq!(state_hash(&after), state_hash(&before));
+        }
+    }
+
+    #[test]
+    fn test_eliminate_range_checks_conservative() {
+        // locals: the array, the index, the sum and a scratch
+        let counted = |body: fn(&mut ChunkBuilder)| {
+            let mut b = ChunkBuilder::new();
+            let (head, done) = (b.label(), b.label());
+            b.imm_i(5)
+                .op(ArrayNew)
+                .store(0)
+                .op(Imm0)
+                .store(2)
+                .op(Imm0)
+                .store(1);
+            b.bind(head)
+                .load(1)
+                .load(0)
+                .op(ArrayLen)
+                .op(CmpGeI)
+                .goto_if(done);
+            b.load(0).load(1).op(ArrayGet);
+            body(&mut b);
+            b.load(1).op(Imm1).op(AddI).store(1).goto(head);
+            b.bind(done).load(2);
+            b.build().unwrap()
+        };
+        let unchanged = |chunk: Chunk| {
+            assert_eq!(eliminate_range_checks(&chunk).unwrap(), chunk);
+        };
+
+        assert_eq!(
+            count(
+                &eliminate_range_checks(&counted(|b| {
+                    b.op(Nop).store(3);
+                }))
+                .unwrap(),
+                ArrayGetUnchecked
+            ),
+            1
+        );
+        // the index skips ahead in the body, so the next read is past the
+        // end and has to trap
+        let skips = counted(|b| {
+            b.store(3)
+                .op(Imm1)
+                .add_to_local(1)
+                .load(0)
+                .load(1)
+                .op(ArrayGet)
+                .store(3);
+        });
+        assert_eq!(
+            VM::new(skips.clone()).execute_all(),
+            Err(VmError::IndexOutOfBounds { index: 5, len: 5 })
+        );
+        unchanged(skips);
+        // the array shrinks under the index, so a read after the pop is
+        // past the end and has to trap
+        let pops = counted(|b| {
+            b.store(3)
+                .load(0)
+                .op(ArrayPop)
+                .store(3)
+                .load(0)
+                .load(1)
+                .op(ArrayGet)
+                .store(3);
+        });
+        assert_eq!(
+            VM::new(pops.clone()).execute_all(),
+            Err(VmError::IndexOutOfBounds { index: 2, len: 2 })
+        );
+        unchanged(pops);
+        unchanged(counted(|b| {
+            b.store(3).load(0).op(Imm0).op(ArrayPush);
+        }));
+        unchanged(counted(|b| {
+            b.store(1);
+        }));
+        unchanged(counted(|b| {
+            b.store(3).imm_i(3).op(ArrayNew).store(0);
+        }));
+        unchanged(counted(|b| {
+            b.store_arg(0);
+        }));
+        unchanged(counted(|b| {
+            let next = b.label();
+            b.store(3).op(Imm1).goto_if(next).bind(next);
+        }));
+
+        // the index isn't known to start at zero
+        let mut b = ChunkBuilder::new();
+        let (head, done) = (b.label(), b.label());
+        b.imm_i(5).op(ArrayNew).store(0).op(Imm1).store(1);
+        b.bind(head)
+            .load(1)
+            .load(0)
+            .op(ArrayLen)
+            .op(CmpGeI)
+            .goto_if(done);
+        b.load(0).load(1).op(ArrayGet).store(3);
+        b.load(1).op(Imm1).op(AddI).store(1).goto(head);
+        b.bind(done);
+        unchanged(b.build().unwrap());
+    }
+
+    #[test]
+    fn test_peephole_small_immediates() {
+        let chunk = peephole(&vm::tests::factorial()).unwrap();
+        assert_eq!(chunk, builder::tests::factorial(5).build().unwrap());
+
+        let mut vm = VM::new(chunk);
+        vm.execute_all().unwrap();
+        assert_eq!(vm.stack(), [Value::Integer(120)]);
+    }
+
+    #[test]
+    fn test_peephole_narrows_to_width() {
+        let wide = [-1, -128, -129, -32768, -32769].map(Instruction::ImmI);
+        let chunk = peephole(&instruction::encode(&wide)).unwrap();
+        let narrowed: Vec<_> = chunk.instructions().map(|r| r.unwrap().1).collect();
+        assert_eq!(
+            narrowed,
+            [
+                Instruction::ImmNeg1,
+                Instruction::ImmI8(-128),
+                Instruction::ImmI16(-129),
+                Instruction::ImmI16(-32768),
+                Instruction::ImmI(-32769),
+            ]
+        );
+
+        let mut vm = VM::new(chunk);
+        vm.execute_all().unwrap();
+        assert_eq!(
+            vm.stack(),
+            [-1, -128, -129, -32768, -32769].map(Value::Integer)
+        );
+
+        let wide = [0xff, 0x100, 0x1_0000].map(Instruction::ImmW);
+        let chunk = peephole(&instruction::encode(&wide)).unwrap();
+        let narrowed: Vec<_> = chunk.instructions().map(|r| r.unwrap().1).collect();
+        assert_eq!(
+            narrowed,
+            [
+                Instruction::ImmW8(0xff),
+                Instruction::ImmW16(0x100),
+                Instruction::ImmW(0x1_0000),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_peephole_invalid_jump() {
+        let chunk = Chunk::new(vec![Goto as u8, 0, 2, Return as u8]);
+        assert_eq!(
+            peephole(&chunk),
+            Err(VerifyError::InvalidJump {
+                offset: 0,
+                target: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_peephole_skips_computed_jumps() {
+        let imm_zero = [&[ImmI as u8][..], &[0; 8]].concat();
+        let chunk = Chunk::new([&imm_zero[..], &[PushIp as u8, GotoDyn as u8]].concat());
+        assert_eq!(peephole(&chunk), Ok(chunk.clone()));
+
+        // narrowing the immediate would change what these push
+        for op in [PushIp, PushChunkLen] {
+            let chunk = Chunk::new([&imm_zero[..], &[op as u8]].concat());
+            assert_eq!(peephole(&chunk), Ok(chunk.clone()));
+            assert_eq!(optimize(&chunk), Ok(chunk.clone()));
+        }
+
+        let chunk = Chunk::new(imm_zero.clone());
+        assert_eq!(peephole(&chunk).unwrap().code(), [Imm0 as u8]);
+        let chunk = chunk.with_features(feature::DYNAMIC_JUMPS);
+        assert_eq!(peephole(&chunk), Ok(chunk.clone()));
+    }
+}
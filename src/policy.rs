@@ -0,0 +1,360 @@
+use crate::chunk::MAX_LEN;
+use crate::opcode::OpCode;
+use std::collections::BTreeSet;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OpSet([u64; 4]);
+
+impl OpSet {
+    pub const fn empty() -> Self {
+        Self([0; 4])
+    }
+
+    pub const fn all() -> Self {
+        Self([u64::MAX; 4])
+    }
+
+    pub fn insert(&mut self, op: OpCode) {
+        let op = op as usize;
+        self.0[op / 64] |= 1 << (op % 64);
+    }
+
+    pub fn remove(&mut self, op: OpCode) {
+        let op = op as usize;
+        self.0[op / 64] &= !(1 << (op % 64));
+    }
+
+    pub const fn contains(&self, op: OpCode) -> bool {
+        let op = op as usize;
+        self.0[op / 64] & (1 << (op % 64)) != 0
+    }
+}
+
+impl FromIterator<OpCode> for OpSet {
+    fn from_iter<I: IntoIterator<Item = OpCode>>(iter: I) -> Self {
+        let mut set = Self::empty();
+        iter.into_iter().for_each(|op| set.insert(op));
+        set
+    }
+}
+
+// Restrictions for running untrusted chunks. `verifier::verify_with_policy`
+// rejects chunks that would break them statically, and the VM enforces them
+// again at run time for chunks that weren't verified.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecutionPolicy {
+    pub allowed: OpSet,
+    pub max_fuel: Option<u64>,
+    pub max_stack: Option<usize>,
+    pub max_heap_bytes: Option<usize>,
+    // Longest chunk accepted, in bytes, below the hard `chunk::MAX_LEN`.
+    pub max_code_len: Option<usize>,
+    // `None` allows every registered native.
+    pub natives: Option<BTreeSet<String>>,
+}
+
+impl ExecutionPolicy {
+    pub fn permissive() -> Self {
+        Self {
+            allowed: OpSet::all(),
+            max_fuel: None,
+            max_stack: None,
+            max_heap_bytes: None,
+            max_code_len: None,
+            natives: None,
+        }
+    }
+
+    // Arithmetic, control flow and locals only: no allocation, natives or
+    // computed jumps.
+    pub fn pure() -> Self {
+        use OpCode::*;
+        #[rustfmt::skip]
+        let allowed = [
+            Return, ReturnN, Nop, Call, Goto, GotoIf, Load, Store, ImmI, ImmI8, ImmI16, ImmF, ImmW, ImmW8, ImmW16,
+            StoreIf, LoadOrDefault, LoadArg, StoreArg,
+            AddToLocal, MulToLocal, MinToLocal, MaxToLocal,
+            Imm0, Imm1, ImmNeg1,
+            AddI, SubI, MulI, DivI, ModI, DivFloorI, ModEuclidI, CmpEqI, CmpGtI, CmpGeI, CmpLtI, CmpLeI,
+            CmpEqW, CmpGtW, CmpGeW, CmpLtW, CmpLeW,
+            AndW, OrW, XorW, ShlW, ShrW, RotlW, RotrW, ClzW, CtzW, PopcntW,
+            F2Bits, Bits2F, AddI32, SubI32, MulI32, DivI32, I64toI32,
+            StackDepth, FrameDepth, FuelRemaining, Unreachable,
+        ];
+        Self {
+            allowed: allowed.into_iter().collect(),
+            natives: Some(BTreeSet::new()),
+            ..Self::permissive()
+        }
+    }
+
+    pub fn allows(&self, op: OpCode) -> bool {
+        self.allowed.contains(op)
+    }
+
+    // The chunk length limit in force, never above `chunk::MAX_LEN`.
+    pub fn code_len_limit(&self) -> usize {
+        self.max_code_len.map_or(MAX_LEN, |max| max.min(MAX_LEN))
+    }
+
+    pub fn allows_native(&self, name: &str) -> bool {
+        self.natives
+            .as_ref()
+            .is_none_or(|natives| natives.contains(name))
+    }
+}
+
+impl Default for ExecutionPolicy {
+    fn default() -> Self {
+        Self::permissive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::ChunkBuilder;
+    use crate::chunk::Chunk;
+    use crate::config::VmBuilder;
+    use crate::error::{VerifyError, VmError};
+    use crate::hook::{Fuel, TrapDecision};
+    use crate::native::Native;
+    use crate::value::Value;
+    use crate::verifier::verify_with_policy;
+    use crate::vm::VM;
+    use std::rc::Rc;
+
+    fn double_chunk() -> Chunk {
+        ChunkBuilder::new()
+            .imm_i(21)
+            .call_native("double")
+            .build()
+            .unwrap()
+    }
+
+    fn vm_with_double(policy: ExecutionPolicy) -> VM {
+        let mut vm = VM::new(double_chunk());
+        vm.register_native(
+            "double",
+            Rc::new(|vm| {
+                let i = vm.get_integer()?;
+                vm.push(Value::Integer(2 * i));
+                Ok(())
+            }),
+        )
+        .unwrap();
+        vm.set_policy(Some(policy)).unwrap();
+        vm
+    }
+
+    #[test]
+    fn test_pure_rejects_natives() {
+        assert_eq!(
+            verify_with_policy(&double_chunk(), &ExecutionPolicy::pure()),
+            Err(VerifyError::ForbiddenOpcode {
+                offset: 2,
+                byte: OpCode::CallNative as u8
+            })
+        );
+    }
+
+    #[test]
+    fn test_permissive_runs_natives() {
+        let policy = ExecutionPolicy::permissive();
+        assert_eq!(verify_with_policy(&double_chunk(), &policy), Ok(()));
+        let mut vm = vm_with_double(policy);
+        vm.execute_all().unwrap();
+        assert_eq!(vm.stack(), [Value::Integer(42)]);
+    }
+
+    #[test]
+    fn test_runtime_enforcement() {
+        let mut vm = vm_with_double(ExecutionPolicy::pure());
+        assert_eq!(
+            vm.execute_all(),
+            Err(VmError::ForbiddenOpcode(OpCode::CallNative as u8))
+        );
+        assert_eq!(vm.stack(), [Value::Integer(21)]);
+
+        let policy = ExecutionPolicy {
+            natives: Some(["print".to_string()].into()),
+            ..ExecutionPolicy::permissive()
+        };
+        assert_eq!(
+            verify_with_policy(&double_chunk(), &policy),
+            Err(VerifyError::ForbiddenNative {
+                offset: 2,
+                index: 0
+            })
+        );
+        let mut vm = vm_with_double(policy);
+        assert_eq!(vm.execute_all(), Err(VmError::ForbiddenNative(0)));
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut b = ChunkBuilder::new();
+        let head = b.label();
+        b.bind(head).imm_i(1).goto(head);
+        let chunk = b.build().unwrap();
+
+        let mut vm = VM::new(chunk.clone());
+        vm.set_policy(Some(ExecutionPolicy {
+            max_stack: Some(100),
+            ..ExecutionPolicy::pure()
+        }))
+        .unwrap();
+        assert_eq!(vm.execute_all(), Err(VmError::StackOverflow));
+        assert_eq!(vm.stack().len(), 101);
+
+        let mut vm = VM::new(chunk);
+        vm.set_policy(Some(ExecutionPolicy {
+            max_fuel: Some(10),
+            ..ExecutionPolicy::pure()
+        }))
+        .unwrap();
+        vm.set_fuel(Some(1000)).unwrap();
+        assert_eq!(vm.execute_all(), Err(VmError::FuelExhausted));
+        assert_eq!(vm.stack().len(), 5);
+
+        let mut b = ChunkBuilder::new();
+        let head = b.label();
+        b.bind(head).op(OpCode::MapNew).goto(head);
+        let mut vm = VM::new(b.build().unwrap());
+        vm.set_policy(Some(ExecutionPolicy {
+            max_heap_bytes: Some(4096),
+            ..ExecutionPolicy::permissive()
+        }))
+        .unwrap();
+        assert_eq!(vm.execute_all(), Err(VmError::HeapExhausted));
+        assert!(vm.heap().bytes() > 4096);
+    }
+
+    #[test]
+    fn test_code_len_limit() {
+        let policy = ExecutionPolicy {
+            max_code_len: Some(16),
+            ..ExecutionPolicy::permissive()
+        };
+        let at_limit = Chunk::new(vec![OpCode::Nop as u8; 16]);
+        assert_eq!(verify_with_policy(&at_limit, &policy), Ok(()));
+        let mut vm = VM::new(at_limit);
+        vm.set_policy(Some(policy.clone())).unwrap();
+        assert_eq!(vm.execute_all().map(|outcome| outcome.instructions), Ok(16));
+
+        let over = Chunk::new(vec![OpCode::Nop as u8; 17]);
+        assert_eq!(
+            verify_with_policy(&over, &policy),
+            Err(VerifyError::ChunkTooLarge { len: 17, max: 16 })
+        );
+        // rejected as it is loaded
+        let too_large = VmError::ChunkTooLarge { len: 17, max: 16 };
+        let loaded = Chunk::deserialize(&over.serialize()).unwrap();
+        let built = VmBuilder::new().policy(policy.clone()).build(loaded);
+        assert_eq!(built.err(), Some(too_large.clone()));
+        let mut vm = VM::new(over);
+        assert_eq!(vm.set_policy(Some(policy)), Err(too_large));
+        assert_eq!(vm.policy(), None);
+
+        // the hard limit can't be raised
+        let policy = ExecutionPolicy {
+            max_code_len: Some(usize::MAX),
+            ..ExecutionPolicy::permissive()
+        };
+        assert_eq!(policy.code_len_limit(), MAX_LEN);
+    }
+
+    #[test]
+    fn test_op_set() {
+        let mut set: OpSet = [OpCode::AddI, OpCode::CallNative].into_iter().collect();
+        assert!(set.contains(OpCode::AddI) && set.contains(OpCode::CallNative));
+        assert!(!set.contains(OpCode::SubI));
+        set.remove(OpCode::AddI);
+        assert!(!set.contains(OpCode::AddI));
+        assert!(OpSet::all().contains(OpCode::Return));
+    }
+
+    #[test]
+    fn test_natives_cannot_reconfigure() {
+        let chunk = ChunkBuilder::new().call_native("escape").build().unwrap();
+        let mut vm = VM::new(chunk);
+        vm.register_native(
+            "escape",
+            Rc::new(|vm| {
+                vm.set_incremental_gc(None)?;
+                vm.set_policy(None)
+            }),
+        )
+        .unwrap();
+        vm.set_policy(Some(ExecutionPolicy::permissive())).unwrap();
+        assert_eq!(vm.execute_all(), Err(VmError::ReconfiguredWhileRunning));
+        assert!(vm.policy().is_some());
+
+        // between instructions the host still can
+        vm.set_policy(None).unwrap();
+        assert_eq!(vm.policy(), None);
+
+        // nor lift the run's fuel or deadline, change what watches it, or
+        // change any setting `VmBuilder` makes
+        #[allow(unused_mut)]
+        let mut attempts: Vec<Native> = vec![
+            Rc::new(|vm| vm.set_fuel(None)),
+            Rc::new(|vm| vm.add_hook(Box::new(Fuel(1)))),
+            Rc::new(|vm| vm.set_trap_handler(Box::new(|_, _| TrapDecision::Propagate))),
+            Rc::new(|vm| vm.clear_trap_handler()),
+            Rc::new(|vm| vm.set_breakpoint(0).map(drop)),
+            Rc::new(|vm| vm.clear_breakpoint(0).map(drop)),
+            Rc::new(|vm| vm.set_deadline(None)),
+            Rc::new(|vm| vm.set_timeout(None)),
+            Rc::new(|vm| vm.set_clock(Box::new(crate::clock::MonotonicClock::new()))),
+            Rc::new(|vm| vm.set_gc_stress(true)),
+            Rc::new(|vm| vm.set_free_list_cap(0)),
+            Rc::new(|vm| vm.set_gc_observer(None)),
+            Rc::new(|vm| vm.register_finalizer(1, Box::new(|_| {}))),
+            Rc::new(|vm| vm.register_native("other", Rc::new(|_| Ok(())))),
+            Rc::new(|vm| vm.seed_rng(1)),
+            Rc::new(|vm| vm.set_shadow_checking(true)),
+            Rc::new(|vm| vm.set_canonical_nans(true)),
+            Rc::new(|vm| vm.set_function_profiling(true)),
+            Rc::new(|vm| vm.set_allocation_profiling(true)),
+            Rc::new(|vm| vm.reset()),
+            Rc::new(|vm| vm.record()),
+            Rc::new(|vm| vm.replay(crate::replay::Log::default())),
+            Rc::new(|vm| vm.take_log().map(drop)),
+        ];
+        #[cfg(feature = "profiler")]
+        attempts.push(Rc::new(|vm| vm.set_profiler(None)));
+        #[cfg(feature = "verified-fast")]
+        attempts.push(Rc::new(|vm| vm.set_unchecked_returns(true)));
+        for attempt in attempts {
+            let chunk = ChunkBuilder::new().call_native("escape").build().unwrap();
+            let mut vm = VM::new(chunk);
+            vm.register_native("escape", attempt).unwrap();
+            vm.set_fuel(Some(10)).unwrap();
+            assert_eq!(vm.execute_all(), Err(VmError::ReconfiguredWhileRunning));
+            assert!(vm.fuel().is_some());
+        }
+
+        // a native that carries on past the refusals changes nothing: the
+        // rng isn't reseeded and the program isn't restarted
+        let mut b = ChunkBuilder::new();
+        b.op(OpCode::Rand).call_native("meddle").op(OpCode::Rand);
+        let chunk = b.build().unwrap();
+        let mut reference = VM::new(chunk.clone());
+        reference
+            .register_native("meddle", Rc::new(|_| Ok(())))
+            .unwrap();
+        reference.execute_all().unwrap();
+        let mut vm = VM::new(chunk);
+        let meddle: Native = Rc::new(|vm| {
+            let _ = vm.seed_rng(7);
+            let _ = vm.set_gc_stress(true);
+            let _ = vm.reset();
+            Ok(())
+        });
+        vm.register_native("meddle", meddle).unwrap();
+        vm.execute_all().unwrap();
+        assert_eq!(vm.stack(), reference.stack());
+        assert!(!vm.heap().is_stress());
+    }
+}
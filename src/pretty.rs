@@ -0,0 +1,233 @@
+use crate::heap::{Heap, ObjectPtr};
+use crate::value::Value;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Write};
+
+// How much of an object graph dumps and `Value::display` show.
+pub const MAX_DEPTH: usize = 3;
+pub const MAX_FIELDS: usize = 8;
+
+// Objects are rendered as `{tag 3: [1, {tag 5: ...}, <truncated>]}`, and
+// strings as string literals. Nesting deeper than `max_depth` shows only
+// the tag, and fields past the first `max_fields` are left out. An object
+// shown a second time, as in a cycle, is a back-reference `#0` to where it
+// was first shown, which then carries the label `#0=`.
+impl Heap {
+    pub fn format_object(&self, ptr: ObjectPtr, max_depth: usize, max_fields: usize) -> String {
+        let mut printer = Printer {
+            heap: self,
+            max_depth,
+            max_fields,
+            out: String::new(),
+            shown: HashMap::new(),
+            referenced: HashSet::new(),
+        };
+        // the first pass only finds the objects that need labels, so that
+        // labels can be numbered in the order they appear
+        printer.object(ptr, 0);
+        printer.out.clear();
+        printer.shown.clear();
+        printer.object(ptr, 0);
+        printer.out
+    }
+
+    // Every live object, one a line in the order they were allocated, as
+    // `#4 tag 252: [1, object #2, null]` with strings shown as literals and
+    // buffers by their length. Nothing in it depends on where objects are
+    // in memory, so two runs that did the same dump the same.
+    pub fn dump(&self, out: &mut impl Write) -> fmt::Result {
+        let mut objects: Vec<_> = self.iter().collect();
+        objects.sort_by_key(|ptr| ptr.id());
+        for ptr in objects {
+            let obj = self.get(ptr);
+            write!(out, "#{} tag {}: ", ptr.id(), obj.tag)?;
+            if let Some(s) = obj.as_string() {
+                writeln!(out, "{s:?}")?;
+            } else if let Some(bytes) = obj.as_buffer() {
+                writeln!(out, "{} bytes", bytes.len())?;
+            } else {
+                out.write_char('[')?;
+                for (index, field) in obj.fields.iter().enumerate() {
+                    if index > 0 {
+                        out.write_str(", ")?;
+                    }
+                    write!(out, "{}", field.display(self))?;
+                }
+                out.write_str("]\n")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Printer<'a> {
+    heap: &'a Heap,
+    max_depth: usize,
+    max_fields: usize,
+    out: String,
+    // the objects expanded so far, with their labels
+    shown: HashMap<ObjectPtr, Option<usize>>,
+    // the objects there are back-references to
+    referenced: HashSet<ObjectPtr>,
+}
+
+impl Printer<'_> {
+    fn value(&mut self, val: Value, depth: usize) {
+        match val {
+            Value::ObjectPtr(ptr) => self.object(ptr, depth),
+            val => write!(self.out, "{val}").unwrap(),
+        }
+    }
+
+    fn object(&mut self, ptr: ObjectPtr, depth: usize) {
+        if let Some(label) = self.shown.get(&ptr) {
+            match label {
+                Some(label) => write!(self.out, "#{label}").unwrap(),
+                None => _ = self.referenced.insert(ptr),
+            }
+            return;
+        }
+        let obj = self.heap.get(ptr);
+        if let Some(s) = obj.as_string() {
+            write!(self.out, "{s:?}").unwrap();
+            return;
+        }
+        if depth >= self.max_depth {
+            write!(self.out, "{{tag {}: ...}}", obj.tag).unwrap();
+            return;
+        }
+
+        let label = self.referenced.contains(&ptr).then(|| {
+            let label = self.shown.values().flatten().count();
+            write!(self.out, "#{label}=").unwrap();
+            label
+        });
+        self.shown.insert(ptr, label);
+        write!(self.out, "{{tag {}: [", obj.tag).unwrap();
+        for (index, &field) in obj.fields.iter().take(self.max_fields).enumerate() {
+            if index > 0 {
+                self.out.push_str(", ");
+            }
+            self.value(field, depth + 1);
+        }
+        if obj.fields.len() > self.max_fields {
+            if self.max_fields > 0 {
+                self.out.push_str(", ");
+            }
+            self.out.push_str("<truncated>");
+        }
+        self.out.push_str("]}");
+    }
+}
+
+// A value shown with the heap it lives in: `{:#}` renders objects with
+// `Heap::format_object`, and `{}` is the value's own `Display` but with
+// objects named by their heap id.
+pub struct ValueDisplay<'a> {
+    val: Value,
+    heap: &'a Heap,
+}
+
+impl Value {
+    pub fn display(self, heap: &Heap) -> ValueDisplay<'_> {
+        ValueDisplay { val: self, heap }
+    }
+}
+
+impl fmt::Display for ValueDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.val {
+            Value::ObjectPtr(ptr) if f.alternate() => {
+                f.write_str(&self.heap.format_object(ptr, MAX_DEPTH, MAX_FIELDS))
+            }
+            Value::ObjectPtr(ptr) => write!(f, "object #{}", self.heap.id(ptr)),
+            val => write!(f, "{val}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heap::{tag, Object};
+
+    #[test]
+    fn test_truncation() {
+        let mut heap = Heap::new();
+        let inner = heap.new_object(Object::new(3, vec![Value::Float(2.5)]));
+        let middle = heap.new_object(Object::new(2, vec![Value::ObjectPtr(inner)]));
+        let fields = vec![Value::Integer(1), Value::ObjectPtr(middle)];
+        let outer = heap.new_object(Object::new(1, fields));
+        assert_eq!(
+            heap.format_object(outer, 3, 8),
+            "{tag 1: [1, {tag 2: [{tag 3: [2.5]}]}]}"
+        );
+        assert_eq!(
+            heap.format_object(outer, 2, 8),
+            "{tag 1: [1, {tag 2: [{tag 3: ...}]}]}"
+        );
+        assert_eq!(heap.format_object(outer, 0, 8), "{tag 1: ...}");
+
+        let wide = heap.new_object(Object::new(4, (0..10).map(Value::Integer).collect()));
+        assert_eq!(
+            heap.format_object(wide, 1, 3),
+            "{tag 4: [0, 1, 2, <truncated>]}"
+        );
+        assert_eq!(heap.format_object(wide, 1, 0), "{tag 4: [<truncated>]}");
+        assert_eq!(heap.format_object(wide, 1, 10).matches(", ").count(), 9);
+
+        let s = heap.new_object(Object::string("hi \"there\""));
+        assert_eq!(heap.format_object(s, 0, 0), r#""hi \"there\"""#);
+    }
+
+    #[test]
+    fn test_dump() {
+        let mut heap = Heap::new();
+        let s = heap.new_object(Object::string("hi"));
+        heap.new_object(Object::buffer(vec![0; 3]));
+        heap.new_object(Object::new(7, vec![Value::ObjectPtr(s), Value::Null]));
+        let mut out = String::new();
+        heap.dump(&mut out).unwrap();
+        assert_eq!(
+            out,
+            format!(
+                "#0 tag {}: \"hi\"\n#1 tag {}: 3 bytes\n#2 tag 7: [object #0, null]\n",
+                tag::STRING,
+                tag::BUFFER
+            )
+        );
+    }
+
+    #[test]
+    fn test_back_references() {
+        let mut heap = Heap::new();
+        let name = heap.new_object(Object::string("s"));
+        let a = heap.new_object(Object::new(1, vec![Value::Null]));
+        let b = heap.new_object(Object::new(2, [a, name].map(Value::ObjectPtr).into()));
+        heap.get_mut(a).fields[0] = Value::ObjectPtr(b);
+        assert_eq!(
+            heap.format_object(a, usize::MAX, MAX_FIELDS),
+            r#"#0={tag 1: [{tag 2: [#0, "s"]}]}"#
+        );
+        assert_eq!(
+            heap.format_object(b, usize::MAX, MAX_FIELDS),
+            r#"#0={tag 2: [{tag 1: [#0]}, "s"]}"#
+        );
+
+        // shared objects are shown once, whether or not there's a cycle
+        let shared = heap.new_object(Object::new(5, vec![Value::Integer(7)]));
+        let fields = [shared, a, shared].map(Value::ObjectPtr).into();
+        let diamond = heap.new_object(Object::new(3, fields));
+        assert_eq!(
+            heap.format_object(diamond, 4, MAX_FIELDS),
+            "{tag 3: [#0={tag 5: [7]}, #1={tag 1: [{tag 2: [#1, \"s\"]}]}, #0]}"
+        );
+
+        let val = Value::ObjectPtr(shared);
+        assert_eq!(format!("{:#}", val.display(&heap)), "{tag 5: [7]}");
+        assert_eq!(val.display(&heap).to_string(), "object #3");
+        assert_eq!(val.to_string(), "object");
+        let val = Value::Integer(-3);
+        assert_eq!(format!("{:#}", val.display(&heap)), "-3");
+    }
+}
@@ -0,0 +1,124 @@
+use crate::clock::Clock;
+use crate::opcode::OpCode;
+use std::fmt;
+
+// Wall time spent in each opcode's handler, read from `clock` before and
+// after every `every`th instruction. Counts are of the timed instructions
+// only.
+pub struct Profiler {
+    clock: Box<dyn Clock>,
+    every: u64,
+    seen: u64,
+    // (count, total_ns), indexed by opcode byte.
+    stats: Vec<(u64, u64)>,
+}
+
+impl Profiler {
+    pub fn new(clock: Box<dyn Clock>) -> Self {
+        Self::sampled(clock, 1)
+    }
+
+    pub fn sampled(clock: Box<dyn Clock>, every: u64) -> Self {
+        Self {
+            clock,
+            every: every.max(1),
+            seen: 0,
+            stats: vec![(0, 0); 256],
+        }
+    }
+
+    // The start time, if this instruction is one to time.
+    pub(crate) fn start(&mut self) -> Option<u64> {
+        let sampled = self.seen.is_multiple_of(self.every);
+        self.seen += 1;
+        sampled.then(|| self.clock.now())
+    }
+
+    pub(crate) fn finish(&mut self, op: OpCode, started: u64) {
+        let elapsed = self.clock.now().saturating_sub(started);
+        let (count, total) = &mut self.stats[op as usize];
+        *count += 1;
+        *total += elapsed;
+    }
+
+    // (opcode, count, total_ns, avg_ns) for every opcode that was timed,
+    // most total time first.
+    pub fn report(&self) -> Vec<(OpCode, u64, u64, u64)> {
+        let mut report: Vec<_> = self
+            .stats
+            .iter()
+            .enumerate()
+            .filter(|(_, (count, _))| *count > 0)
+            .map(|(byte, &(count, total))| {
+                let op = OpCode::try_from(byte as u8).unwrap();
+                (op, count, total, total / count)
+            })
+            .collect();
+        report.sort_by_key(|&(op, _, total, _)| (std::cmp::Reverse(total), op as u8));
+        report
+    }
+}
+
+impl fmt::Debug for Profiler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Profiler")
+            .field("every", &self.every)
+            .field("seen", &self.seen)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::VmBuilder;
+    use crate::opcode::OpCode::{CmpGeI, SubI};
+    use crate::workloads;
+
+    // Advances by a fixed step on every reading.
+    struct Ticking(u64);
+
+    impl Clock for Ticking {
+        fn now(&mut self) -> u64 {
+            self.0 += 7;
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_report() {
+        let mut vm = VmBuilder::new()
+            .profiler(Profiler::new(Box::new(Ticking(0))))
+            .build(workloads::countdown(3))
+            .unwrap();
+        let outcome = vm.execute_all().unwrap();
+        let report = vm.profiler().unwrap().report();
+
+        for &(_, count, total, avg) in &report {
+            assert_eq!(total, count * 7);
+            assert_eq!(avg, 7);
+        }
+        let count = |op| report.iter().find(|row| row.0 == op).unwrap().1;
+        assert_eq!(count(CmpGeI), 4);
+        assert_eq!(count(SubI), 3);
+        let counts: u64 = report.iter().map(|row| row.1).sum();
+        assert_eq!(counts, outcome.instructions);
+        assert!(report.windows(2).all(|pair| pair[0].2 >= pair[1].2));
+    }
+
+    #[test]
+    fn test_sampling() {
+        let mut vm = VmBuilder::new()
+            .profiler(Profiler::sampled(Box::new(Ticking(0)), 4))
+            .build(workloads::countdown(3))
+            .unwrap();
+        let outcome = vm.execute_all().unwrap();
+        let report = vm.profiler().unwrap().report();
+
+        let counts: u64 = report.iter().map(|row| row.1).sum();
+        assert_eq!(counts, outcome.instructions.div_ceil(4));
+        assert!(report
+            .iter()
+            .all(|&(_, count, total, _)| total == count * 7));
+    }
+}
@@ -0,0 +1,610 @@
+use crate::error::{LogError, VmError};
+use crate::opcode::OpCode;
+use crate::value::Value;
+
+const MAGIC: &[u8; 4] = b"ANDL";
+const VERSION: u8 = 3;
+
+mod event_tag {
+    pub const CLOCK: u8 = 0;
+    pub const RAND: u8 = 1;
+    pub const NATIVE: u8 = 2;
+    pub const NATIVE_FAILED: u8 = 3;
+    pub const RECOVER: u8 = 4;
+}
+
+mod value_tag {
+    pub const INTEGER: u8 = 0;
+    pub const WORD: u8 = 1;
+    pub const FLOAT: u8 = 2;
+    pub const CHAR: u8 = 3;
+    pub const NULL: u8 = 4;
+}
+
+// An input from outside the guest, which a replay takes from the log
+// instead of asking the host again.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    Clock(u64),
+    Rand(u64),
+    // Only a native's effect on the stack is replayed: it popped `popped`
+    // values, then pushed `pushed`.
+    Native { popped: usize, pushed: Vec<Value> },
+    // The error itself isn't kept; it replays as `ReplayedNativeFailure`.
+    NativeFailed,
+    // The trap handler recovered, pushing the value if there is one.
+    Recover(Option<Value>),
+}
+
+// The opcode of every step of a run, the id of every object it allocated
+// and the inputs it took, each tagged with the step that allocated or took
+// it, along with the content hash of the chunk it ran.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Log {
+    chunk_hash: Option<[u8; 32]>,
+    ops: Vec<u8>,
+    allocations: Vec<(u64, u64)>,
+    events: Vec<(u64, Event)>,
+}
+
+impl Log {
+    pub fn chunk_hash(&self) -> Option<[u8; 32]> {
+        self.chunk_hash
+    }
+
+    // Without a hash the log replays against any chunk, for as long as the
+    // run keeps matching it.
+    pub fn set_chunk_hash(&mut self, hash: Option<[u8; 32]>) {
+        self.chunk_hash = hash;
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.ops.len() as u64
+    }
+
+    pub fn events(&self) -> &[(u64, Event)] {
+        &self.events
+    }
+
+    pub fn allocations(&self) -> &[(u64, u64)] {
+        &self.allocations
+    }
+
+    // FNV-1a over the opcode stream, then each allocation's step and id,
+    // so two runs that allocate in a different order hash differently.
+    pub fn trace_hash(&self) -> u64 {
+        let allocations = self
+            .allocations
+            .iter()
+            .flat_map(|&(step, id)| [step, id])
+            .flat_map(u64::to_be_bytes);
+        self.ops
+            .iter()
+            .copied()
+            .chain(allocations)
+            .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+                (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+            })
+    }
+
+    // Layout, all integers big-endian:
+    //   magic, version: u8
+    //   chunk hash: u8 flag, then 32 bytes when set
+    //   ops: u32 count, one byte per step
+    //   allocations: u32 count, each a u64 step and a u64 id
+    //   events: u32 count, each a u64 step, a tag byte and its payload
+    // A count past what its u32 holds fails with `TooLarge`.
+    pub fn serialize(&self) -> Result<Vec<u8>, LogError> {
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        match self.chunk_hash {
+            Some(hash) => {
+                out.push(1);
+                out.extend(hash);
+            }
+            None => out.push(0),
+        }
+        put_len(&mut out, self.ops.len())?;
+        out.extend(&self.ops);
+        put_len(&mut out, self.allocations.len())?;
+        for (step, id) in &self.allocations {
+            out.extend(step.to_be_bytes());
+            out.extend(id.to_be_bytes());
+        }
+
+        put_len(&mut out, self.events.len())?;
+        for (step, event) in &self.events {
+            out.extend(step.to_be_bytes());
+            match event {
+                Event::Clock(now) => {
+                    out.push(event_tag::CLOCK);
+                    out.extend(now.to_be_bytes());
+                }
+                Event::Rand(r) => {
+                    out.push(event_tag::RAND);
+                    out.extend(r.to_be_bytes());
+                }
+                Event::Native { popped, pushed } => {
+                    out.push(event_tag::NATIVE);
+                    put_len(&mut out, *popped)?;
+                    put_len(&mut out, pushed.len())?;
+                    pushed.iter().for_each(|val| put_value(&mut out, val));
+                }
+                Event::NativeFailed => out.push(event_tag::NATIVE_FAILED),
+                Event::Recover(push) => {
+                    out.push(event_tag::RECOVER);
+                    match push {
+                        Some(val) => {
+                            out.push(1);
+                            put_value(&mut out, val);
+                        }
+                        None => out.push(0),
+                    }
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, LogError> {
+        let mut r = Reader(bytes);
+        if r.take(MAGIC.len())? != MAGIC {
+            return Err(LogError::BadMagic);
+        }
+        let version = r.u8()?;
+        if version != VERSION {
+            return Err(LogError::UnsupportedVersion(version));
+        }
+        let chunk_hash = match r.u8()? {
+            0 => None,
+            _ => Some(r.array()?),
+        };
+        let len = r.len()?;
+        let ops = r.take(len)?.to_vec();
+        let count = r.len()?;
+        let mut allocations = Vec::with_capacity(count.min(r.0.len()));
+        for _ in 0..count {
+            allocations.push((r.u64()?, r.u64()?));
+        }
+
+        let count = r.len()?;
+        let mut events = Vec::with_capacity(count.min(r.0.len()));
+        for _ in 0..count {
+            let step = r.u64()?;
+            let event = match r.u8()? {
+                event_tag::CLOCK => Event::Clock(r.u64()?),
+                event_tag::RAND => Event::Rand(r.u64()?),
+                event_tag::NATIVE => {
+                    let popped = r.len()?;
+                    let count = r.len()?;
+                    let mut pushed = Vec::with_capacity(count.min(r.0.len()));
+                    for _ in 0..count {
+                        pushed.push(r.value()?);
+                    }
+                    Event::Native { popped, pushed }
+                }
+                event_tag::NATIVE_FAILED => Event::NativeFailed,
+                event_tag::RECOVER => Event::Recover(match r.u8()? {
+                    0 => None,
+                    _ => Some(r.value()?),
+                }),
+                tag => return Err(LogError::InvalidEvent(tag)),
+            };
+            events.push((step, event));
+        }
+
+        if !r.0.is_empty() {
+            return Err(LogError::TrailingBytes(r.0.len()));
+        }
+        Ok(Self {
+            chunk_hash,
+            ops,
+            allocations,
+            events,
+        })
+    }
+}
+
+fn put_len(out: &mut Vec<u8>, len: usize) -> Result<(), LogError> {
+    let len = u32::try_from(len).map_err(|_| LogError::TooLarge(len as u64))?;
+    out.extend(len.to_be_bytes());
+    Ok(())
+}
+
+fn put_value(out: &mut Vec<u8>, val: &Value) {
+    match val {
+        Value::Integer(i) => {
+            out.push(value_tag::INTEGER);
+            out.extend(i.to_be_bytes());
+        }
+        Value::Word(w) => {
+            out.push(value_tag::WORD);
+            out.extend(w.to_be_bytes());
+        }
+        Value::Float(f) => {
+            out.push(value_tag::FLOAT);
+            out.extend(f.to_bits().to_be_bytes());
+        }
+        Value::Char(c) => {
+            out.push(value_tag::CHAR);
+            out.extend((*c as u32).to_be_bytes());
+        }
+        Value::Null => out.push(value_tag::NULL),
+        Value::ObjectPtr(_) => unreachable!("objects are refused when recorded"),
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], LogError> {
+        if n > self.0.len() {
+            return Err(LogError::Truncated);
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], LogError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, LogError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u64(&mut self) -> Result<u64, LogError> {
+        Ok(u64::from_be_bytes(self.array()?))
+    }
+
+    fn len(&mut self) -> Result<usize, LogError> {
+        Ok(u32::from_be_bytes(self.array()?) as usize)
+    }
+
+    fn value(&mut self) -> Result<Value, LogError> {
+        Ok(match self.u8()? {
+            value_tag::INTEGER => Value::Integer(self.u64()? as i64),
+            value_tag::WORD => Value::Word(self.u64()?),
+            value_tag::FLOAT => Value::Float(f64::from_bits(self.u64()?)),
+            value_tag::CHAR => {
+                let c = u32::from_be_bytes(self.array()?);
+                Value::Char(char::from_u32(c).ok_or(LogError::InvalidChar(c))?)
+            }
+            value_tag::NULL => Value::Null,
+            tag => return Err(LogError::InvalidValue(tag)),
+        })
+    }
+}
+
+// What the VM records into and, when replaying, reads from. A replay
+// records too, so its log can be compared against the original.
+#[derive(Debug, Default)]
+pub(crate) struct Tape {
+    log: Log,
+    source: Option<Source>,
+}
+
+#[derive(Debug)]
+struct Source {
+    log: Log,
+    next_event: usize,
+}
+
+impl Tape {
+    pub(crate) fn recording(chunk_hash: [u8; 32]) -> Self {
+        Self {
+            log: Log {
+                chunk_hash: Some(chunk_hash),
+                ..Log::default()
+            },
+            source: None,
+        }
+    }
+
+    pub(crate) fn replaying(log: Log, chunk_hash: [u8; 32]) -> Self {
+        Self {
+            source: Some(Source { log, next_event: 0 }),
+            ..Self::recording(chunk_hash)
+        }
+    }
+
+    pub(crate) fn is_replaying(&self) -> bool {
+        self.source.is_some()
+    }
+
+    pub(crate) fn into_log(self) -> Log {
+        self.log
+    }
+
+    // The step being executed, once `step` has counted it.
+    pub(crate) fn current_step(&self) -> u64 {
+        self.log.steps().saturating_sub(1)
+    }
+
+    // Counts a step about to run `op`, checking it against the recording.
+    pub(crate) fn step(&mut self, op: OpCode) -> Result<(), VmError> {
+        if let Some(source) = &self.source {
+            let step = self.log.steps();
+            let expected = source.log.ops.get(step as usize).copied();
+            if expected != Some(op as u8) {
+                return Err(VmError::ReplayDiverged {
+                    step,
+                    expected,
+                    found: op as u8,
+                });
+            }
+        }
+        self.log.ops.push(op as u8);
+        Ok(())
+    }
+
+    // Allocations aren't checked against a replayed recording, which can
+    // still compare trace hashes.
+    pub(crate) fn allocated(&mut self, id: u64) {
+        self.log.allocations.push((self.current_step(), id));
+    }
+
+    pub(crate) fn record(&mut self, event: Event) -> Result<(), VmError> {
+        let values = match &event {
+            Event::Native { pushed, .. } => pushed.as_slice(),
+            Event::Recover(Some(val)) => std::slice::from_ref(val),
+            _ => &[],
+        };
+        if values.iter().any(|val| val.get_object_ptr().is_some()) {
+            return Err(VmError::UnrecordableValue);
+        }
+        self.log.events.push((self.current_step(), event));
+        Ok(())
+    }
+
+    // The input the recording took at this step, or `None` when not
+    // replaying.
+    pub(crate) fn replayed(&mut self) -> Result<Option<Event>, VmError> {
+        let step = self.current_step();
+        let Some(source) = &mut self.source else {
+            return Ok(None);
+        };
+        match source.log.events.get(source.next_event) {
+            Some((at, event)) if *at == step => {
+                source.next_event += 1;
+                Ok(Some(event.clone()))
+            }
+            _ => Err(VmError::ReplayEventMissing { step }),
+        }
+    }
+
+    // Whether the recording recovered from a trap at this step, consuming
+    // the recovery if so.
+    pub(crate) fn replayed_recovery(&mut self) -> Option<Option<Value>> {
+        let step = self.current_step();
+        let source = self.source.as_mut()?;
+        match source.log.events.get(source.next_event) {
+            Some((at, Event::Recover(push))) if *at == step => {
+                source.next_event += 1;
+                Some(*push)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::ChunkBuilder;
+    use crate::chunk::Chunk;
+    use crate::config::VmBuilder;
+    use crate::hook::{ResumePoint, TrapDecision};
+    use crate::opcode::OpCode::{self, *};
+    use crate::vm::VM;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    struct SteppingClock(u64);
+
+    impl crate::clock::Clock for SteppingClock {
+        fn now(&mut self) -> u64 {
+            self.0 += 10;
+            self.0
+        }
+    }
+
+    // Three rounds of reading the clock, scaling by the native and drawing
+    // a random number, then a division by zero the trap handler recovers
+    // from, and `extra` more instructions.
+    fn inputs(extra: usize) -> Chunk {
+        let mut b = ChunkBuilder::new();
+        for i in 1..=3 {
+            b.op(OpCode::Clock);
+            b.imm_i(i).call_native("sample");
+            b.op(Rand);
+        }
+        b.imm_i(1).imm_i(0).op(DivI).op(Nop);
+        for _ in 0..extra {
+            b.op(Imm0);
+        }
+        b.build().unwrap()
+    }
+
+    fn recorded(chunk: &Chunk) -> (VM, Log) {
+        let calls = Rc::new(Cell::new(0));
+        let mut vm = VmBuilder::new()
+            .record()
+            .clock(Box::new(SteppingClock(0)))
+            .rng_seed(7)
+            .native(
+                "sample",
+                Rc::new(move |vm: &mut VM| {
+                    calls.set(calls.get() + 1);
+                    let scaled = vm.get_integer()? * 100 + calls.get();
+                    vm.push(Value::Integer(scaled));
+                    Ok(())
+                }),
+            )
+            .trap_handler(Box::new(|_, _| TrapDecision::Recover {
+                push: Some(Value::Integer(-1)),
+                resume_at: ResumePoint::NextInstruction,
+            }))
+            .build(chunk.clone())
+            .unwrap();
+        vm.execute_all().unwrap();
+        let log = vm.take_log().unwrap().unwrap();
+        (vm, log)
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let chunk = inputs(0);
+        let (original, log) = recorded(&chunk);
+        assert_eq!(log.steps(), 16);
+        assert_eq!(log.events().len(), 10);
+        assert_eq!(log.events()[0], (0, Event::Clock(10)));
+        assert_eq!(
+            log.events()[1],
+            (
+                2,
+                Event::Native {
+                    popped: 1,
+                    pushed: vec![Value::Integer(101)]
+                }
+            )
+        );
+        assert_eq!(
+            log.events()[9],
+            (14, Event::Recover(Some(Value::Integer(-1))))
+        );
+
+        // no native, a different seed and the default clock
+        let log = Log::deserialize(&log.serialize().unwrap()).unwrap();
+        let mut replay = VmBuilder::new()
+            .replay(log.clone())
+            .rng_seed(8)
+            .build(chunk)
+            .unwrap();
+        assert_eq!(replay.execute_all().unwrap().instructions, 16);
+        assert_eq!(replay.stack(), original.stack());
+        assert_eq!(replay.locals(), original.locals());
+        let replayed = replay.take_log().unwrap().unwrap();
+        assert_eq!(replayed.chunk_hash(), log.chunk_hash());
+        assert_eq!(replayed.trace_hash(), log.trace_hash());
+        assert_eq!(replayed, log);
+    }
+
+    #[test]
+    fn test_wrong_chunk() {
+        let (_, log) = recorded(&inputs(0));
+        assert_eq!(log.chunk_hash(), Some(inputs(0).content_hash()));
+        let log = Log::deserialize(&log.serialize().unwrap()).unwrap();
+        assert_eq!(
+            VmBuilder::new().replay(log.clone()).build(inputs(1)).err(),
+            Some(VmError::HashMismatch)
+        );
+        let mut vm = VM::new(inputs(1));
+        assert_eq!(vm.replay(log), Err(VmError::HashMismatch));
+        assert!(vm.take_log().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_divergence() {
+        // the chunks differ on purpose, so the hash can't stop them
+        let (_, mut log) = recorded(&inputs(0));
+        log.set_chunk_hash(None);
+
+        let mut b = ChunkBuilder::new();
+        b.op(OpCode::Clock).op(Imm0);
+        let mut vm = VmBuilder::new()
+            .replay(log.clone())
+            .build(b.build().unwrap())
+            .unwrap();
+        assert_eq!(
+            vm.execute_all(),
+            Err(VmError::ReplayDiverged {
+                step: 1,
+                expected: Some(Imm1 as u8),
+                found: Imm0 as u8
+            })
+        );
+
+        let mut vm = VmBuilder::new().replay(log).build(inputs(1)).unwrap();
+        assert_eq!(
+            vm.execute_all(),
+            Err(VmError::ReplayDiverged {
+                step: 16,
+                expected: None,
+                found: Imm0 as u8
+            })
+        );
+    }
+
+    #[test]
+    fn test_allocations_are_traced() {
+        let chunk = ChunkBuilder::new()
+            .op(MapNew)
+            .op(Imm1)
+            .op(ArrayNew)
+            .build()
+            .unwrap();
+        let record = |host_objects| {
+            let mut vm = VmBuilder::new().record().build(chunk.clone()).unwrap();
+            for _ in 0..host_objects {
+                vm.alloc_object(1, Vec::new());
+            }
+            vm.execute_all().unwrap();
+            vm.take_log().unwrap().unwrap()
+        };
+        let log = record(0);
+        assert_eq!(log.allocations(), [(0, 0), (2, 1)]);
+        assert_eq!(Log::deserialize(&log.serialize().unwrap()), Ok(log.clone()));
+        assert_eq!(record(0).trace_hash(), log.trace_hash());
+        // the same steps, but the objects come later in the heap's order
+        let shifted = record(1);
+        assert_eq!(shifted.allocations()[1..], [(0, 1), (2, 2)]);
+        assert_ne!(shifted.trace_hash(), log.trace_hash());
+    }
+
+    #[test]
+    fn test_objects_are_unrecordable() {
+        let mut vm = VmBuilder::new()
+            .record()
+            .native(
+                "map",
+                Rc::new(|vm: &mut VM| {
+                    let ptr = vm.alloc_map();
+                    vm.push(Value::ObjectPtr(ptr));
+                    Ok(())
+                }),
+            )
+            .build(ChunkBuilder::new().call_native("map").build().unwrap())
+            .unwrap();
+        assert_eq!(vm.execute_all(), Err(VmError::UnrecordableValue));
+    }
+
+    #[test]
+    fn test_malformed() {
+        let bytes = Log::default().serialize().unwrap();
+        assert_eq!(Log::deserialize(b"ANDR"), Err(LogError::BadMagic));
+        assert_eq!(
+            Log::deserialize(&bytes[..bytes.len() - 1]),
+            Err(LogError::Truncated)
+        );
+        assert_eq!(
+            Log::deserialize(&[&bytes[..], &[0]].concat()),
+            Err(LogError::TrailingBytes(1))
+        );
+
+        // every count is checked, not only the sections': here a native's
+        let popped = u32::MAX as usize + 1;
+        let log = Log {
+            events: vec![(
+                0,
+                Event::Native {
+                    popped,
+                    pushed: Vec::new(),
+                },
+            )],
+            ..Log::default()
+        };
+        assert_eq!(log.serialize(), Err(LogError::TooLarge(popped as u64)));
+    }
+}
@@ -0,0 +1,267 @@
+use crate::error::VmError;
+use crate::value::Value;
+use crate::vm::{ExecutionOutcome, Status, VM};
+
+pub type TaskId = usize;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TaskState {
+    Ready,
+    // Stopped by a hook, breakpoint or watchpoint, until `unpark`.
+    Parked,
+    // `instructions` counts the whole run, across every slice.
+    Finished(Result<ExecutionOutcome, VmError>),
+}
+
+#[derive(Debug)]
+struct Task {
+    vm: VM,
+    state: TaskState,
+    instructions: u64,
+}
+
+// Runs many VMs on one thread, each for at most `slice` instructions at a
+// time, taking ready ones in turn. Slices are metered with fuel. A task's own
+// fuel, as set by the host or a policy's limit, is its budget across every
+// slice; a task that uses it up finishes with `FuelExhausted`.
+#[derive(Debug)]
+pub struct Scheduler {
+    tasks: Vec<Task>,
+    slice: u64,
+    next: usize,
+}
+
+impl Scheduler {
+    pub fn new(slice: u64) -> Self {
+        Self {
+            tasks: Vec::new(),
+            slice: slice.max(1),
+            next: 0,
+        }
+    }
+
+    pub fn spawn(&mut self, vm: VM) -> TaskId {
+        self.tasks.push(Task {
+            vm,
+            state: TaskState::Ready,
+            instructions: 0,
+        });
+        self.tasks.len() - 1
+    }
+
+    pub fn state(&self, id: TaskId) -> TaskState {
+        self.tasks[id].state.clone()
+    }
+
+    pub fn vm(&self, id: TaskId) -> &VM {
+        &self.tasks[id].vm
+    }
+
+    pub fn vm_mut(&mut self, id: TaskId) -> &mut VM {
+        &mut self.tasks[id].vm
+    }
+
+    // Makes a parked task ready again, first pushing the value it was
+    // waiting for, if any. Returns whether the task was parked.
+    pub fn unpark(&mut self, id: TaskId, value: Option<Value>) -> bool {
+        let task = &mut self.tasks[id];
+        if task.state != TaskState::Parked {
+            return false;
+        }
+        if let Some(value) = value {
+            task.vm.push(value);
+        }
+        task.state = TaskState::Ready;
+        true
+    }
+
+    // Runs one slice of the next ready task, returning which one ran, or
+    // `None` if no task is ready.
+    pub fn poll(&mut self) -> Option<TaskId> {
+        let len = self.tasks.len();
+        let id = (0..len)
+            .map(|i| (self.next + i) % len)
+            .find(|&id| self.tasks[id].state == TaskState::Ready)?;
+        self.next = (id + 1) % len;
+
+        // the VM gets back its own fuel less what the slice used
+        let task = &mut self.tasks[id];
+        let budget = task.vm.fuel();
+        let slice = budget.map_or(self.slice, |fuel| fuel.min(self.slice));
+        task.vm.refuel(Some(slice));
+        let result = task.vm.execute_all();
+        let used = slice - task.vm.fuel().unwrap_or(0);
+        task.instructions += used;
+        task.vm.refuel(budget.map(|fuel| fuel - used));
+        task.state = match result {
+            Err(VmError::FuelExhausted) if budget == Some(used) => {
+                TaskState::Finished(Err(VmError::FuelExhausted))
+            }
+            Err(VmError::FuelExhausted) => TaskState::Ready,
+            Ok(outcome) => match outcome.status {
+                Status::Finished(_) => TaskState::Finished(Ok(ExecutionOutcome {
+                    instructions: task.instructions,
+                    ..outcome
+                })),
+                Status::Paused | Status::Watchpoint(_) => TaskState::Parked,
+            },
+            Err(err) => TaskState::Finished(Err(err)),
+        };
+        Some(id)
+    }
+
+    // Polls until no task is ready, returning every task's state. Tasks
+    // still parked then are waiting on the host.
+    pub fn run_until_all_complete(&mut self) -> Vec<TaskState> {
+        while self.poll().is_some() {}
+        self.tasks.iter().map(|task| task.state.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::ChunkBuilder;
+    use crate::chunk::Chunk;
+    use crate::hook::{Hook, HookAction, VmView};
+    use crate::opcode::OpCode::{self, *};
+    use crate::policy::ExecutionPolicy;
+    use crate::workloads;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Logs which task ran each instruction.
+    struct Tracer(Rc<RefCell<Vec<TaskId>>>, TaskId);
+
+    impl Hook for Tracer {
+        fn before_instruction(&mut self, _vm: &VmView, _ip: usize, _op: OpCode) -> HookAction {
+            self.0.borrow_mut().push(self.1);
+            HookAction::Continue
+        }
+    }
+
+    fn traced(chunk: Chunk, log: &Rc<RefCell<Vec<TaskId>>>, id: TaskId) -> VM {
+        let mut vm = VM::new(chunk);
+        vm.add_hook(Box::new(Tracer(log.clone(), id))).unwrap();
+        vm
+    }
+
+    // Task ids in the order their slices ran, with consecutive slices of
+    // the same task merged.
+    fn slices(log: &[TaskId]) -> Vec<TaskId> {
+        let mut slices = log.to_vec();
+        slices.dedup();
+        slices
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut scheduler = Scheduler::new(10);
+        for (id, n) in [5, 10, 2].into_iter().enumerate() {
+            let spawned = scheduler.spawn(traced(workloads::countdown(n), &log, id));
+            assert_eq!(spawned, id);
+        }
+
+        let states = scheduler.run_until_all_complete();
+        let mut counts = Vec::new();
+        for (id, state) in states.into_iter().enumerate() {
+            let TaskState::Finished(Ok(outcome)) = state else {
+                panic!("task {id} didn't finish: {state:?}");
+            };
+            assert_eq!(outcome.value, Some(Value::Integer(0)));
+            let ran = log.borrow().iter().filter(|&&ran| ran == id).count();
+            assert_eq!(outcome.instructions, ran as u64);
+            counts.push(outcome.instructions);
+        }
+        assert_eq!(counts, [52, 97, 25]);
+        assert_eq!(
+            slices(&log.borrow()),
+            [0, 1, 2, 0, 1, 2, 0, 1, 2, 0, 1, 0, 1, 0, 1]
+        );
+    }
+
+    #[test]
+    fn test_trap_is_isolated() {
+        let mut b = ChunkBuilder::new();
+        b.imm_i(1).imm_i(0).op(DivI);
+        let mut scheduler = Scheduler::new(3);
+        let ok = scheduler.spawn(VM::new(workloads::countdown(4)));
+        let trapped = scheduler.spawn(VM::new(b.build().unwrap()));
+
+        scheduler.run_until_all_complete();
+        assert_eq!(
+            scheduler.state(trapped),
+            TaskState::Finished(Err(VmError::DivisionByZero))
+        );
+        let TaskState::Finished(Ok(outcome)) = scheduler.state(ok) else {
+            panic!("{:?}", scheduler.state(ok));
+        };
+        assert_eq!(outcome.value, Some(Value::Integer(0)));
+    }
+
+    #[test]
+    fn test_parked_tasks_are_skipped() {
+        // waits for the host to push a value, then adds one to it
+        let mut b = ChunkBuilder::new();
+        b.op(Nop).imm_i(1).op(AddI);
+        let mut waiting = VM::new(b.build().unwrap());
+        waiting.set_breakpoint(1).unwrap();
+
+        let mut scheduler = Scheduler::new(4);
+        let parked = scheduler.spawn(waiting);
+        let busy = scheduler.spawn(VM::new(workloads::countdown(3)));
+
+        assert_eq!(scheduler.poll(), Some(parked));
+        assert_eq!(scheduler.state(parked), TaskState::Parked);
+        assert!(!scheduler.unpark(busy, None));
+        while scheduler.poll() == Some(busy) {}
+        assert!(matches!(scheduler.state(busy), TaskState::Finished(Ok(_))));
+        assert_eq!(scheduler.poll(), None);
+
+        assert!(scheduler.unpark(parked, Some(Value::Integer(41))));
+        let states = scheduler.run_until_all_complete();
+        let TaskState::Finished(Ok(outcome)) = states[parked] else {
+            panic!("{:?}", states[parked]);
+        };
+        assert_eq!(outcome.value, Some(Value::Integer(42)));
+        assert_eq!(outcome.instructions, 3);
+    }
+
+    #[test]
+    fn test_fuel_spans_slices() {
+        let mut b = ChunkBuilder::new();
+        let head = b.label();
+        b.bind(head).goto(head);
+        let spin = b.build().unwrap();
+        let mut capped = VM::new(spin.clone());
+        let policy = ExecutionPolicy {
+            max_fuel: Some(100),
+            ..ExecutionPolicy::permissive()
+        };
+        capped.set_policy(Some(policy)).unwrap();
+        let mut fueled = VM::new(spin);
+        fueled.set_fuel(Some(25)).unwrap();
+
+        let mut scheduler = Scheduler::new(10);
+        let capped = scheduler.spawn(capped);
+        let fueled = scheduler.spawn(fueled);
+        let ok = scheduler.spawn(VM::new(workloads::countdown(2)));
+        let states = scheduler.run_until_all_complete();
+        assert_eq!(
+            states[capped],
+            TaskState::Finished(Err(VmError::FuelExhausted))
+        );
+        assert_eq!(
+            states[fueled],
+            TaskState::Finished(Err(VmError::FuelExhausted))
+        );
+        assert!(matches!(states[ok], TaskState::Finished(Ok(_))));
+        assert_eq!(scheduler.vm(capped).fuel(), Some(0));
+        assert_eq!(scheduler.vm(ok).fuel(), None);
+
+        // a task out of fuel stays finished, whatever fuel is added later
+        scheduler.vm_mut(fueled).set_fuel(Some(5)).unwrap();
+        assert_eq!(scheduler.poll(), None);
+    }
+}
@@ -0,0 +1,479 @@
+use crate::chunk::{self, feature, Chunk, Constant, Function, Image, MAX_LEN};
+use crate::error::ChunkError;
+use crate::hash;
+
+const MAGIC: &[u8; 4] = b"ANDR";
+const VERSION: u8 = 7;
+
+mod constant_tag {
+    pub const INTEGER: u8 = 0;
+    pub const WORD: u8 = 1;
+    pub const FLOAT: u8 = 2;
+    pub const CHAR: u8 = 3;
+    pub const STR: u8 = 4;
+}
+
+// Layout, all integers big-endian:
+//   magic, version: u8
+//   features: u32 mask of `feature` bits
+//   max_locals: u8 flag, then a u16 when the flag is set
+//   content hash: u8 flag, then the 32 bytes of `content_hash` when set,
+//     which deserializing checks
+//   code: u32 length, bytes
+//   constants: u32 count, each a tag byte and its payload
+//   functions: u32 count, each a u32 entry, a u8 arity, a u8 flag followed
+//     by a u8 return count when set, a u8 flag followed by the name when
+//     set, and the local names: a u32 count of u16 slots each followed by a
+//     name
+//   exports: u32 count, each a name and a u16 function index
+//   imports: u32 count of names
+//   names: u32 length, UTF-8 bytes
+impl Chunk {
+    // Panics if any length or offset is past `chunk::MAX_LEN`, which its
+    // u32 can't hold.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        out.extend(self.features().to_be_bytes());
+        match self.max_locals() {
+            Some(max) => {
+                out.push(1);
+                out.extend(max.to_be_bytes());
+            }
+            None => out.push(0),
+        }
+        out.push(1);
+        out.extend(self.content_hash());
+
+        put_len(&mut out, self.len());
+        out.extend(self.public_code().iter());
+
+        put_len(&mut out, self.constants().len());
+        for constant in self.constants() {
+            put_constant(&mut out, constant);
+        }
+
+        put_len(&mut out, self.functions().len());
+        for function in self.functions() {
+            put_len(&mut out, function.entry);
+            out.push(function.arity);
+            match function.returns {
+                Some(count) => out.extend([1, count]),
+                None => out.push(0),
+            }
+            match &function.name {
+                Some(name) => {
+                    out.push(1);
+                    put_str(&mut out, name);
+                }
+                None => out.push(0),
+            }
+            put_len(&mut out, function.locals.len());
+            for (slot, name) in &function.locals {
+                out.extend(slot.to_be_bytes());
+                put_str(&mut out, name);
+            }
+        }
+
+        put_len(&mut out, self.exports().len());
+        for (name, function) in self.exports() {
+            put_str(&mut out, name);
+            out.extend(function.to_be_bytes());
+        }
+        put_len(&mut out, self.imports().len());
+        for name in self.imports() {
+            put_str(&mut out, name);
+        }
+        out
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, ChunkError> {
+        Ok(Self::deserialize_borrowed(bytes)?.into_owned())
+    }
+
+    // Validates the whole of `bytes` but leaves the code where it is; only
+    // the constants and function table are materialized.
+    pub fn deserialize_borrowed(bytes: &[u8]) -> Result<ChunkRef<'_>, ChunkError> {
+        let mut r = Reader(bytes);
+        if r.take(MAGIC.len())? != MAGIC {
+            return Err(ChunkError::BadMagic);
+        }
+        let version = r.u8()?;
+        if version != VERSION {
+            return Err(ChunkError::UnsupportedVersion(version));
+        }
+        let features = u32::from_be_bytes(r.array()?);
+        if features & !feature::SUPPORTED != 0 {
+            return Err(ChunkError::UnsupportedFeature(
+                features & !feature::SUPPORTED,
+            ));
+        }
+        let max_locals = match r.u8()? {
+            0 => None,
+            _ => Some(u16::from_be_bytes(r.array()?)),
+        };
+        let hash: Option<[u8; 32]> = match r.u8()? {
+            0 => None,
+            _ => Some(r.array()?),
+        };
+
+        let len = r.len()?;
+        let code = r.take(len)?;
+        // only the optimizer may put these in, after the chunk is loaded
+        if let Some((ip, _)) = chunk::opcodes(code).find(|(_, op)| op.is_internal()) {
+            return Err(ChunkError::InternalOpcode(ip));
+        }
+
+        let count = r.len()?;
+        let mut constants = Vec::with_capacity(count.min(r.0.len()));
+        for _ in 0..count {
+            constants.push(match r.u8()? {
+                constant_tag::INTEGER => Constant::Integer(i64::from_be_bytes(r.array()?)),
+                constant_tag::WORD => Constant::Word(u64::from_be_bytes(r.array()?)),
+                constant_tag::FLOAT => {
+                    Constant::Float(f64::from_bits(u64::from_be_bytes(r.array()?)))
+                }
+                constant_tag::CHAR => {
+                    let c = u32::from_be_bytes(r.array()?);
+                    Constant::Char(char::from_u32(c).ok_or(ChunkError::InvalidChar(c))?)
+                }
+                constant_tag::STR => Constant::Str(r.str()?),
+                tag => return Err(ChunkError::InvalidConstant(tag)),
+            });
+        }
+
+        let count = r.len()?;
+        let mut functions = Vec::with_capacity(count.min(r.0.len()));
+        for _ in 0..count {
+            let entry = r.len()?;
+            let arity = r.u8()?;
+            let returns = match r.u8()? {
+                0 => None,
+                _ => Some(r.u8()?),
+            };
+            let name = match r.u8()? {
+                0 => None,
+                _ => Some(r.str()?),
+            };
+            let count = r.len()?;
+            let mut locals = Vec::with_capacity(count.min(r.0.len()));
+            for _ in 0..count {
+                let slot = u16::from_be_bytes(r.array()?);
+                locals.push((slot, r.str()?));
+            }
+            functions.push(Function {
+                entry,
+                arity,
+                returns,
+                name,
+                locals,
+            });
+        }
+
+        let count = r.len()?;
+        let mut exports = Vec::with_capacity(count.min(r.0.len()));
+        for _ in 0..count {
+            let name = r.str()?;
+            exports.push((name, u16::from_be_bytes(r.array()?)));
+        }
+        let count = r.len()?;
+        let mut imports = Vec::with_capacity(count.min(r.0.len()));
+        for _ in 0..count {
+            imports.push(r.str()?);
+        }
+
+        if !r.0.is_empty() {
+            return Err(ChunkError::TrailingBytes(r.0.len()));
+        }
+        if hash.is_some_and(|hash| {
+            hash != hash::content_hash(features, max_locals, code, &constants, &functions)
+        }) {
+            return Err(ChunkError::HashMismatch);
+        }
+        Ok(ChunkRef {
+            code,
+            max_locals,
+            constants,
+            functions,
+            exports,
+            imports,
+            features,
+        })
+    }
+
+    // A chunk executing from the image it was serialized into.
+    pub fn deserialize_image(image: Image) -> Result<Self, ChunkError> {
+        let bytes = (*image).as_ref();
+        let chunk = Self::deserialize_borrowed(bytes)?;
+        let start = chunk.code.as_ptr() as usize - bytes.as_ptr() as usize;
+        let range = start..start + chunk.code.len();
+        Ok(chunk.into_chunk(Chunk::from_image(image.clone(), range)))
+    }
+}
+
+// A deserialized chunk whose code is still in the serialized bytes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkRef<'a> {
+    code: &'a [u8],
+    max_locals: Option<u16>,
+    constants: Vec<Constant>,
+    functions: Vec<Function>,
+    exports: Vec<(String, u16)>,
+    imports: Vec<String>,
+    features: u32,
+}
+
+impl<'a> ChunkRef<'a> {
+    pub fn code(&self) -> &'a [u8] {
+        self.code
+    }
+
+    // Copies the code.
+    pub fn into_owned(self) -> Chunk {
+        let code = Chunk::new(self.code.to_vec());
+        self.into_chunk(code)
+    }
+
+    fn into_chunk(self, code: Chunk) -> Chunk {
+        let chunk = code
+            .with_constants(self.constants)
+            .with_functions(self.functions)
+            .with_exports(self.exports)
+            .with_imports(self.imports)
+            .with_features(self.features);
+        match self.max_locals {
+            Some(max) => chunk.with_max_locals(max),
+            None => chunk,
+        }
+    }
+}
+
+// No copy is made: the chunk executes from the original bytes.
+impl From<ChunkRef<'static>> for Chunk {
+    fn from(chunk: ChunkRef<'static>) -> Self {
+        let code = Chunk::from_static(chunk.code);
+        chunk.into_chunk(code)
+    }
+}
+
+pub(crate) fn put_constant(out: &mut Vec<u8>, constant: &Constant) {
+    match constant {
+        Constant::Integer(i) => {
+            out.push(constant_tag::INTEGER);
+            out.extend(i.to_be_bytes());
+        }
+        Constant::Word(w) => {
+            out.push(constant_tag::WORD);
+            out.extend(w.to_be_bytes());
+        }
+        Constant::Float(f) => {
+            out.push(constant_tag::FLOAT);
+            out.extend(f.to_bits().to_be_bytes());
+        }
+        Constant::Char(c) => {
+            out.push(constant_tag::CHAR);
+            out.extend((*c as u32).to_be_bytes());
+        }
+        Constant::Str(s) => {
+            out.push(constant_tag::STR);
+            put_str(out, s);
+        }
+    }
+}
+
+fn put_len(out: &mut Vec<u8>, len: usize) {
+    assert!(len <= MAX_LEN, "{len} is too large to serialize");
+    out.extend((len as u32).to_be_bytes());
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) {
+    put_len(out, s.len());
+    out.extend(s.as_bytes());
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], ChunkError> {
+        if n > self.0.len() {
+            return Err(ChunkError::Truncated);
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], ChunkError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, ChunkError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn len(&mut self) -> Result<usize, ChunkError> {
+        Ok(u32::from_be_bytes(self.array()?) as usize)
+    }
+
+    fn str(&mut self) -> Result<String, ChunkError> {
+        let len = self.len()?;
+        let s = std::str::from_utf8(self.take(len)?).map_err(|_| ChunkError::InvalidUtf8)?;
+        Ok(s.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder;
+    use crate::instruction::{self, Instruction::*};
+    use crate::opcode::OpCode;
+    use crate::value::Value;
+    use crate::vm::VM;
+
+    #[test]
+    fn test_round_trip() {
+        let chunk = builder::tests::factorial(5)
+            .max_locals(2)
+            .string("héllo")
+            .load_const(Constant::Float(1.5))
+            .load_const(Constant::Char('λ'))
+            .build()
+            .unwrap()
+            .with_functions(vec![Function {
+                entry: 0,
+                arity: 2,
+                returns: Some(1),
+                name: Some("fact".to_string()),
+                locals: vec![(0, "n".to_string()), (3, "λ".to_string())],
+            }])
+            .with_exports(vec![("fact".to_string(), 0)])
+            .with_imports(vec!["print".to_string(), "λ".to_string()]);
+        assert_eq!(chunk.features(), feature::OBJECTS);
+        assert_eq!(Chunk::deserialize(&chunk.serialize()), Ok(chunk));
+    }
+
+    #[test]
+    fn test_known_bytes() {
+        let chunk = Chunk::new(vec![0x2a])
+            .with_max_locals(0x0102)
+            .with_constants(vec![Constant::Integer(-2), Constant::Float(1.5)]);
+        #[rustfmt::skip]
+        let (header, rest) = ([
+            b'A', b'N', b'D', b'R', VERSION,
+            0, 0, 0, 0,
+            1, 0x01, 0x02,
+            1,
+        ], [
+            0, 0, 0, 1, 0x2a,
+            0, 0, 0, 2,
+            constant_tag::INTEGER, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe,
+            constant_tag::FLOAT, 0x3f, 0xf8, 0, 0, 0, 0, 0, 0,
+            0, 0, 0, 0,
+            0, 0, 0, 0,
+            0, 0, 0, 0,
+        ]);
+        let hash = chunk.content_hash();
+        assert_eq!(chunk.serialize(), [&header[..], &hash, &rest].concat());
+        // the hash is optional
+        let unhashed = [&header[..header.len() - 1], &[0], &rest].concat();
+        assert_eq!(Chunk::deserialize(&unhashed), Ok(chunk));
+    }
+
+    #[test]
+    fn test_hash_mismatch() {
+        let chunk = Chunk::new(vec![OpCode::ImmI8 as u8, 7]);
+        let mut bytes = chunk.serialize();
+        // the immediate, before the four empty tables
+        let last = bytes.len() - 1 - 16;
+        assert_eq!(bytes[last], 7);
+        bytes[last] = 8;
+        assert_eq!(Chunk::deserialize(&bytes), Err(ChunkError::HashMismatch));
+        assert_eq!(
+            Chunk::deserialize_borrowed(&bytes),
+            Err(ChunkError::HashMismatch)
+        );
+    }
+
+    #[test]
+    fn test_internal_opcode() {
+        let mut bytes = Chunk::new(vec![OpCode::Nop as u8, OpCode::ArrayGet as u8]).serialize();
+        let last = bytes.len() - 1 - 16;
+        assert_eq!(bytes[last], OpCode::ArrayGet as u8);
+        bytes[last] = OpCode::ArrayGetUnchecked as u8;
+        assert_eq!(
+            Chunk::deserialize(&bytes),
+            Err(ChunkError::InternalOpcode(1))
+        );
+        assert_eq!(
+            Chunk::deserialize_borrowed(&bytes),
+            Err(ChunkError::InternalOpcode(1))
+        );
+
+        // as an operand the byte is only a number
+        let operand = Chunk::new(vec![OpCode::ImmI8 as u8, OpCode::ArraySetUnchecked as u8]);
+        assert_eq!(Chunk::deserialize(&operand.serialize()), Ok(operand));
+    }
+
+    // Every section is checked, not only the code: here a function's entry.
+    #[test]
+    #[should_panic(expected = "4294967296 is too large to serialize")]
+    fn test_too_long() {
+        let function = Function::new(MAX_LEN + 1, 0);
+        Chunk::new(vec![0])
+            .with_functions(vec![function])
+            .serialize();
+    }
+
+    #[test]
+    fn test_malformed() {
+        let bytes = Chunk::new(vec![0]).serialize();
+        assert_eq!(Chunk::deserialize(b"ANDX"), Err(ChunkError::BadMagic));
+        assert_eq!(
+            Chunk::deserialize(&bytes[..bytes.len() - 1]),
+            Err(ChunkError::Truncated)
+        );
+        assert_eq!(
+            Chunk::deserialize(&[&bytes[..], &[0]].concat()),
+            Err(ChunkError::TrailingBytes(1))
+        );
+
+        for bits in [feature::WIDE_JUMPS, 1 << 31] {
+            let flagged = Chunk::new(vec![0]).with_features(feature::OBJECTS | bits);
+            assert_eq!(
+                Chunk::deserialize(&flagged.serialize()),
+                Err(ChunkError::UnsupportedFeature(bits))
+            );
+        }
+
+        let mut bytes = bytes;
+        bytes[4] = 9;
+        assert_eq!(
+            Chunk::deserialize(&bytes),
+            Err(ChunkError::UnsupportedVersion(9))
+        );
+    }
+
+    #[test]
+    fn test_version_5_operand_order() {
+        // before version 6 the top of the stack was the left operand, so old
+        // chunks are refused rather than run with their operands exchanged
+        let old = [
+            MAGIC.as_slice(),
+            &[5],
+            &Chunk::new(vec![0]).serialize()[5..],
+        ]
+        .concat();
+        assert_eq!(
+            Chunk::deserialize(&old),
+            Err(ChunkError::UnsupportedVersion(5))
+        );
+
+        // porting one swaps the pushes of each binary operator's operands:
+        // what was `ImmI(2) ImmI(7) SubI`, 7 - 2, is now written this way
+        let ported = instruction::encode(&[ImmI(7), ImmI(2), SubI]);
+        let chunk = Chunk::deserialize(&ported.serialize()).unwrap();
+        let outcome = VM::new(chunk).execute_all().unwrap();
+        assert_eq!(outcome.value, Some(Value::Integer(5)));
+    }
+}
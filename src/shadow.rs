@@ -0,0 +1,194 @@
+use crate::error::VmError;
+use crate::opcode::OpCode;
+use crate::value::Value;
+
+// What an opcode pops or pushes. Objects aren't told apart: whether one is
+// a string, array or map is left to the opcode itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Integer,
+    Word,
+    Float,
+    Char,
+    Object,
+    Any,
+}
+
+impl Kind {
+    pub const fn matches(self, val: Value) -> bool {
+        matches!(
+            (self, val),
+            (Self::Any, _)
+                | (Self::Integer, Value::Integer(_))
+                | (Self::Word, Value::Word(_))
+                | (Self::Float, Value::Float(_))
+                | (Self::Char, Value::Char(_))
+                | (Self::Object, Value::ObjectPtr(_))
+        )
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Integer => "integer",
+            Self::Word => "word",
+            Self::Float => "float",
+            Self::Char => "char",
+            Self::Object => "object",
+            Self::Any => "any value",
+        }
+    }
+}
+
+// The kinds an opcode pops, top of the stack first, and then the kinds it
+// pushes, in the order it pushes them. Opcodes whose effect depends on a
+// callee or on the values themselves have none.
+pub const fn signature(op: OpCode) -> Option<(&'static [Kind], &'static [Kind])> {
+    use Kind::*;
+    use OpCode::*;
+    Some(match op {
+        Return | ReturnN | Call | CallNative | IterNext | Unreachable => return None,
+        Nop | Goto | Gc => (&[], &[]),
+        GotoIf | GotoDyn => (&[Word], &[]),
+        TrapMsg => (&[Object], &[]),
+        Store | StoreArg => (&[Any], &[]),
+        AddToLocal | MulToLocal | MinToLocal | MaxToLocal => (&[Integer], &[]),
+        StoreIf => (&[Word, Any], &[]),
+        Load | LoadOrDefault | LoadArg | LoadConst => (&[], &[Any]),
+        ImmI | ImmI8 | ImmI16 | Imm0 | Imm1 | ImmNeg1 => (&[], &[Integer]),
+        ImmW | ImmW8 | ImmW16 => (&[], &[Word]),
+        ImmF => (&[], &[Float]),
+        HeapInfo | StackDepth | FrameDepth => (&[], &[Integer]),
+        PushIp | PushChunkLen | Clock | Rand | FuelRemaining => (&[], &[Word]),
+        MapNew => (&[], &[Object]),
+        AddI | SubI | MulI | DivI | ModI | DivFloorI | ModEuclidI => {
+            (&[Integer, Integer], &[Integer])
+        }
+        AddI32 | SubI32 | MulI32 | DivI32 => (&[Integer, Integer], &[Integer]),
+        CmpEqI | CmpGtI | CmpGeI | CmpLtI | CmpLeI => (&[Integer, Integer], &[Word]),
+        CmpEqW | CmpGtW | CmpGeW | CmpLtW | CmpLeW => (&[Word, Word], &[Word]),
+        AndW | OrW | XorW | ShlW | ShrW | RotlW | RotrW => (&[Word, Word], &[Word]),
+        ClzW | CtzW | PopcntW => (&[Word], &[Integer]),
+        F2Bits => (&[Float], &[Word]),
+        Bits2F => (&[Word], &[Float]),
+        I64toI32 => (&[Integer], &[Integer]),
+        IntToStr => (&[Integer], &[Object]),
+        FloatToStr => (&[Float], &[Object]),
+        FmtFloat => (&[Integer, Float], &[Object]),
+        ParseInt => (&[Object], &[Integer]),
+        ParseFloat => (&[Object], &[Float]),
+        ObjEq | StrEq => (&[Object, Object], &[Word]),
+        StrCmp => (&[Object, Object], &[Integer]),
+        StrLen | MapLen | ArrayLen => (&[Object], &[Integer]),
+        CharAt => (&[Integer, Object], &[Char]),
+        Substr => (&[Integer, Integer, Object], &[Object]),
+        ObjCloneShallow | ObjCloneDeep | Intern | NewWeak | IterNew => (&[Object], &[Object]),
+        WeakGet | GetField => (&[Object], &[Any]),
+        SetField => (&[Any, Object], &[]),
+        FieldCount => (&[Object], &[Integer]),
+        GetFieldDyn => (&[Integer, Object], &[Any]),
+        SetFieldDyn => (&[Any, Integer, Object], &[]),
+        MapGet => (&[Any, Object], &[Any]),
+        MapContains | MapDelete => (&[Any, Object], &[Word]),
+        MapSet => (&[Any, Any, Object], &[]),
+        ArrayNew => (&[Integer], &[Object]),
+        ArrayGet | ArrayGetUnchecked | ArrayGetRel => (&[Integer, Object], &[Any]),
+        ArraySet | ArraySetUnchecked | ArraySetRel => (&[Any, Integer, Object], &[]),
+        ArrayCopy => (&[Integer, Integer, Object, Integer, Object], &[]),
+        ArrayFill => (&[Any, Integer, Integer, Object], &[]),
+        ArraySlice => (&[Integer, Integer, Object], &[Object]),
+        ArrayPush => (&[Any, Object], &[]),
+        ArrayPop => (&[Object], &[Any]),
+        BufNew => (&[Integer], &[Object]),
+        BufLen => (&[Object], &[Integer]),
+        BufLoad8 | BufLoad16 | BufLoad32 | BufLoad64 => (&[Integer, Object], &[Integer]),
+        BufStore8 | BufStore16 | BufStore32 | BufStore64 => (&[Integer, Integer, Object], &[]),
+    })
+}
+
+// An instruction, by where it is and what it is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Site {
+    pub ip: usize,
+    pub op: OpCode,
+}
+
+// Which instruction pushed each value on the stack, or `None` for values
+// the host pushed. It follows the stack rather than trusting itself, so it
+// recovers from anything that changes the stack between instructions.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct ShadowStack(Vec<Option<Site>>);
+
+impl ShadowStack {
+    fn sync(&mut self, len: usize) {
+        self.0.resize(len, None);
+    }
+
+    // Checks the values `consumer` is about to pop against its signature.
+    pub(crate) fn check_pops(&mut self, stack: &[Value], consumer: Site) -> Result<(), VmError> {
+        self.sync(stack.len());
+        let Some((pops, _)) = signature(consumer.op) else {
+            return Ok(());
+        };
+        for (slot, &kind) in (0..stack.len()).rev().zip(pops) {
+            if !kind.matches(stack[slot]) {
+                return Err(VmError::ShadowMismatch {
+                    expected: kind.name(),
+                    found: stack[slot].type_name(),
+                    producer: self.0[slot],
+                    consumer: Some(consumer),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // Records `producer` as the source of whatever it left on the stack,
+    // which held `before` values until it ran, checking its pushes against
+    // its signature.
+    pub(crate) fn record_pushes(
+        &mut self,
+        stack: &[Value],
+        before: usize,
+        producer: Site,
+    ) -> Result<(), VmError> {
+        let signature = signature(producer.op);
+        let kept = match signature {
+            Some((pops, _)) => before.saturating_sub(pops.len()),
+            None => before,
+        };
+        let kept = kept.min(stack.len());
+        self.0.truncate(kept);
+        for (i, &val) in stack[kept..].iter().enumerate() {
+            if let Some(&kind) = signature.and_then(|(_, pushes)| pushes.get(i)) {
+                if !kind.matches(val) {
+                    return Err(VmError::ShadowMismatch {
+                        expected: kind.name(),
+                        found: val.type_name(),
+                        producer: Some(producer),
+                        consumer: None,
+                    });
+                }
+            }
+            self.0.push(Some(producer));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signatures_match_stack_effects() {
+        for op in (0..=u8::MAX).filter_map(|byte| OpCode::try_from(byte).ok()) {
+            let Some((pops, pushes)) = signature(op) else {
+                assert_eq!(op.stack_effect(), None, "{op:?}");
+                continue;
+            };
+            if let Some(effect) = op.stack_effect() {
+                assert_eq!(effect, (pops.len(), pushes.len()), "{op:?}");
+            }
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use crate::heap::Heap;
+use crate::value::Value;
+use crate::vm::VM;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::mem;
+
+// The live objects of each tag at one point in a run, for finding what a
+// guest program keeps hold of: take one, run on, take another and diff
+// them. Bytes are those of the objects' fields and buffer contents.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct HeapSnapshot {
+    tags: BTreeMap<u8, Tag>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct Tag {
+    bytes: usize,
+    ids: BTreeSet<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TagDelta {
+    pub tag: u8,
+    pub objects: i64,
+    pub bytes: i64,
+}
+
+// Every tag either snapshot has, those that grew the most first.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeapDiff(pub Vec<TagDelta>);
+
+impl VM {
+    // Collects first, so that only objects still reachable are counted.
+    pub fn heap_snapshot(&mut self) -> HeapSnapshot {
+        self.collect_garbage();
+        HeapSnapshot::new(self.heap())
+    }
+}
+
+impl HeapSnapshot {
+    pub(crate) fn new(heap: &Heap) -> Self {
+        let mut tags = BTreeMap::<u8, Tag>::new();
+        for ptr in heap.iter() {
+            let obj = heap.get(ptr);
+            let buffer = obj.as_buffer().map_or(0, <[u8]>::len);
+            let tag = tags.entry(obj.tag).or_default();
+            tag.bytes += obj.fields.len() * mem::size_of::<Value>() + buffer;
+            tag.ids.insert(ptr.id());
+        }
+        Self { tags }
+    }
+
+    pub fn objects(&self, tag: u8) -> usize {
+        self.tags.get(&tag).map_or(0, |tag| tag.ids.len())
+    }
+
+    pub fn bytes(&self, tag: u8) -> usize {
+        self.tags.get(&tag).map_or(0, |tag| tag.bytes)
+    }
+
+    // From this snapshot to `later`.
+    pub fn diff(&self, later: &Self) -> HeapDiff {
+        let tags: BTreeSet<_> = self.tags.keys().chain(later.tags.keys()).collect();
+        let mut deltas: Vec<_> = tags
+            .into_iter()
+            .map(|&tag| TagDelta {
+                tag,
+                objects: later.objects(tag) as i64 - self.objects(tag) as i64,
+                bytes: later.bytes(tag) as i64 - self.bytes(tag) as i64,
+            })
+            .collect();
+        deltas.sort_by_key(|delta| (Reverse(delta.bytes), Reverse(delta.objects), delta.tag));
+        HeapDiff(deltas)
+    }
+
+    // Ids of the objects of `tag` live in `later` but not in this snapshot,
+    // in allocation order. Both have to come from the same heap, without a
+    // reset in between.
+    pub fn new_ids(&self, later: &Self, tag: u8) -> Vec<u64> {
+        let Some(after) = later.tags.get(&tag) else {
+            return Vec::new();
+        };
+        match self.tags.get(&tag) {
+            Some(before) => after.ids.difference(&before.ids).copied().collect(),
+            None => after.ids.iter().copied().collect(),
+        }
+    }
+}
+
+impl fmt::Display for HeapDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:>5} {:>10} {:>12}", "tag", "objects", "bytes")?;
+        for delta in &self.0 {
+            write!(
+                f,
+                "\n{:>5} {:>+10} {:>+12}",
+                delta.tag, delta.objects, delta.bytes
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::ChunkBuilder;
+    use crate::heap::{tag, Object};
+    use crate::opcode::OpCode::*;
+    use crate::vm::Status;
+    use std::rc::Rc;
+
+    const LEAKED: u8 = 7;
+
+    // A cache that never evicts: each iteration links a new object of tag 7
+    // onto a list in local 0, and leaves a string in local 1 that the next
+    // one replaces. Pauses at the head of the loop.
+    fn leaky() -> VM {
+        let mut b = ChunkBuilder::new();
+        let (head, done) = (b.label(), b.label());
+        b.op(Imm0).store(0).imm_i(1000).store(2);
+        let head_ip = b.len();
+        b.bind(head).load(2).op(Imm0).op(CmpLeI).goto_if(done);
+        b.load(0).call_native("cache").store(0);
+        b.string("scratch").store(1);
+        b.load(2).op(Imm1).op(SubI).store(2).goto(head);
+        b.bind(done);
+        let mut vm = VM::new(b.build().unwrap());
+        vm.register_native(
+            "cache",
+            Rc::new(|vm: &mut VM| {
+                let next = vm.pop()?;
+                vm.hold(next);
+                let ptr = vm.alloc(Object::new(LEAKED, vec![next]));
+                vm.push(Value::ObjectPtr(ptr));
+                Ok(())
+            }),
+        )
+        .unwrap();
+        vm.set_breakpoint(head_ip).unwrap();
+        vm
+    }
+
+    #[test]
+    fn test_leak_shows_in_diff() {
+        let mut vm = leaky();
+        for _ in 0..3 {
+            assert_eq!(vm.execute_all().unwrap().status, Status::Paused);
+        }
+        let before = vm.heap_snapshot();
+        assert_eq!(before.objects(LEAKED), 2);
+        assert_eq!(before.objects(tag::STRING), 1);
+
+        const N: usize = 10;
+        for _ in 0..N {
+            assert_eq!(vm.execute_all().unwrap().status, Status::Paused);
+        }
+        let after = vm.heap_snapshot();
+        let diff = before.diff(&after);
+        let field = mem::size_of::<Value>() as i64;
+        assert_eq!(
+            diff.0,
+            [
+                TagDelta {
+                    tag: LEAKED,
+                    objects: N as i64,
+                    bytes: N as i64 * field
+                },
+                TagDelta {
+                    tag: tag::STRING,
+                    objects: 0,
+                    bytes: 0
+                },
+            ]
+        );
+        let report = diff.to_string();
+        assert_eq!(
+            report.lines().nth(1),
+            Some(&*format!("    7        +10 {:>+12}", 10 * field))
+        );
+
+        let new = before.new_ids(&after, LEAKED);
+        assert_eq!(new.len(), N);
+        assert!(new.windows(2).all(|pair| pair[0] < pair[1]));
+        let live: BTreeSet<_> = vm.heap().iter().map(|ptr| ptr.id()).collect();
+        assert!(new.iter().all(|id| live.contains(id)));
+        assert_eq!(before.new_ids(&after, tag::STRING).len(), 1);
+        assert!(after.new_ids(&before, LEAKED).is_empty());
+    }
+
+    #[test]
+    fn test_snapshot_collects_first() {
+        let mut vm = leaky();
+        for _ in 0..5 {
+            vm.execute_all().unwrap();
+        }
+        // the strings superseded so far are garbage, but may not be swept
+        let snapshot = vm.heap_snapshot();
+        assert_eq!(snapshot.objects(tag::STRING), 1);
+        assert_eq!(snapshot.bytes(tag::STRING), 7 * mem::size_of::<Value>());
+        assert_eq!(vm.heap().len(), 5);
+        assert_eq!(
+            HeapSnapshot::default().diff(&HeapSnapshot::default()),
+            HeapDiff::default()
+        );
+    }
+}
@@ -0,0 +1,188 @@
+use crate::builder::{ChunkBuilder, Label};
+use crate::chunk::Chunk;
+use crate::opcode::OpCode::{self, *};
+use std::sync::OnceLock;
+
+// The names `library` exports, sorted. Programs import them and are linked
+// with the library by `link::link`; the list only ever grows.
+pub const SYMBOLS: [&str; 5] = ["array_max", "array_min", "array_sum", "clamp", "int_abs"];
+
+// Guest routines shared by every front end, built with the public builder
+// the first time they are asked for. All of them take and return integers:
+// - `int_abs(x)`, wrapping as `i64::wrapping_abs` does
+// - `clamp(x, lo, hi)`, which is `lo` whenever `x < lo`, even if `lo > hi`
+// - `array_sum(a)`, wrapping on overflow
+// - `array_min(a)` and `array_max(a)`, which trap on an empty array
+pub fn library() -> Chunk {
+    static LIBRARY: OnceLock<Chunk> = OnceLock::new();
+    LIBRARY.get_or_init(build).clone()
+}
+
+fn build() -> Chunk {
+    let mut b = ChunkBuilder::new();
+    b.op(Return);
+    let functions = [
+        ("array_max", 1, array_extreme(&mut b, CmpGeI)),
+        ("array_min", 1, array_extreme(&mut b, CmpLeI)),
+        ("array_sum", 1, array_sum(&mut b)),
+        ("clamp", 3, clamp(&mut b)),
+        ("int_abs", 1, int_abs(&mut b)),
+    ];
+    for (name, arity, entry) in functions {
+        let function = b.function(entry, arity);
+        b.returns(function, 1)
+            .function_name(function, name)
+            .export(function, name);
+    }
+    b.build().unwrap()
+}
+
+fn int_abs(b: &mut ChunkBuilder) -> Label {
+    let (entry, negative) = (b.label(), b.label());
+    b.bind(entry).load(0).op(Imm0).op(CmpLtI).goto_if(negative);
+    b.load(0).op(Return);
+    b.bind(negative).op(Imm0).load(0).op(SubI).op(Return);
+    entry
+}
+
+fn clamp(b: &mut ChunkBuilder) -> Label {
+    let (entry, low, high) = (b.label(), b.label(), b.label());
+    b.bind(entry).load(0).load(1).op(CmpLtI).goto_if(low);
+    b.load(0).load(2).op(CmpGtI).goto_if(high);
+    b.load(0).op(Return);
+    b.bind(low).load(1).op(Return);
+    b.bind(high).load(2).op(Return);
+    entry
+}
+
+// locals: the array, the index and the sum
+fn array_sum(b: &mut ChunkBuilder) -> Label {
+    let (entry, head, done) = (b.label(), b.label(), b.label());
+    b.bind(entry).op(Imm0).store(1).op(Imm0).store(2);
+    b.bind(head)
+        .load(1)
+        .load(0)
+        .op(ArrayLen)
+        .op(CmpGeI)
+        .goto_if(done);
+    b.load(2).load(0).load(1).op(ArrayGet).op(AddI).store(2);
+    b.load(1).op(Imm1).op(AddI).store(1).goto(head);
+    b.bind(done).load(2).op(Return);
+    entry
+}
+
+// Keeps the best element found so far, skipping those for which
+// `best keep element` holds. locals: the array, the index and the best.
+fn array_extreme(b: &mut ChunkBuilder, keep: OpCode) -> Label {
+    let (entry, head, next, done) = (b.label(), b.label(), b.label(), b.label());
+    b.bind(entry).load(0).op(Imm0).op(ArrayGet).store(2);
+    b.op(Imm1).store(1);
+    b.bind(head)
+        .load(1)
+        .load(0)
+        .op(ArrayLen)
+        .op(CmpGeI)
+        .goto_if(done);
+    b.load(2)
+        .load(0)
+        .load(1)
+        .op(ArrayGet)
+        .op(keep)
+        .goto_if(next);
+    b.load(0).load(1).op(ArrayGet).store(2);
+    b.bind(next).load(1).op(Imm1).op(AddI).store(1).goto(head);
+    b.bind(done).load(2).op(Return);
+    entry
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::VmError;
+    use crate::link::link;
+    use crate::value::Value;
+    use crate::verifier;
+    use crate::vm::VM;
+
+    // Calls `name` on the integer arguments.
+    fn call(name: &str, args: &[i64]) -> Result<Option<Value>, VmError> {
+        let mut b = ChunkBuilder::new();
+        let function = b.import(name);
+        for &arg in args {
+            b.imm_i(arg);
+        }
+        b.call(function);
+        VM::new(link(&[b.build().unwrap(), library()]).unwrap())
+            .execute_all()
+            .map(|outcome| outcome.value)
+    }
+
+    // Calls `name` on an array of `elements`.
+    fn call_on_array(name: &str, elements: &[i64]) -> Result<Option<Value>, VmError> {
+        let mut b = ChunkBuilder::new();
+        let function = b.import(name);
+        b.imm_i(elements.len() as i64).op(ArrayNew).store(0);
+        for (index, &element) in elements.iter().enumerate() {
+            b.load(0).imm_i(index as i64).imm_i(element).op(ArraySet);
+        }
+        b.load(0).call(function);
+        VM::new(link(&[b.build().unwrap(), library()]).unwrap())
+            .execute_all()
+            .map(|outcome| outcome.value)
+    }
+
+    fn int(i: i64) -> Result<Option<Value>, VmError> {
+        Ok(Some(Value::Integer(i)))
+    }
+
+    #[test]
+    fn test_library() {
+        let library = library();
+        assert_eq!(verifier::verify_stack(&library), Ok(()));
+        let names: Vec<_> = library
+            .exports()
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(names, SYMBOLS);
+        assert!(library.imports().is_empty());
+    }
+
+    #[test]
+    fn test_int_abs() {
+        assert_eq!(call("int_abs", &[-5]), int(5));
+        assert_eq!(call("int_abs", &[5]), int(5));
+        assert_eq!(call("int_abs", &[0]), int(0));
+        assert_eq!(call("int_abs", &[i64::MIN]), int(i64::MIN));
+    }
+
+    #[test]
+    fn test_clamp() {
+        assert_eq!(call("clamp", &[5, 0, 10]), int(5));
+        assert_eq!(call("clamp", &[-5, 0, 10]), int(0));
+        assert_eq!(call("clamp", &[50, 0, 10]), int(10));
+        assert_eq!(call("clamp", &[10, 0, 10]), int(10));
+        assert_eq!(call("clamp", &[5, 8, 2]), int(8));
+        assert_eq!(call("clamp", &[9, 8, 2]), int(2));
+    }
+
+    #[test]
+    fn test_array_sum() {
+        assert_eq!(call_on_array("array_sum", &[]), int(0));
+        assert_eq!(call_on_array("array_sum", &[3, -1, 40]), int(42));
+        assert_eq!(call_on_array("array_sum", &[i64::MAX, 1]), int(i64::MIN));
+    }
+
+    #[test]
+    fn test_array_min_max() {
+        let elements = [4, -2, 9, 9, -7, 0];
+        assert_eq!(call_on_array("array_min", &elements), int(-7));
+        assert_eq!(call_on_array("array_max", &elements), int(9));
+        assert_eq!(call_on_array("array_min", &[3]), int(3));
+        assert_eq!(call_on_array("array_max", &[3]), int(3));
+        assert_eq!(
+            call_on_array("array_min", &[]),
+            Err(VmError::IndexOutOfBounds { index: 0, len: 0 })
+        );
+    }
+}
@@ -0,0 +1,337 @@
+use crate::chunk::{Chunk, Constant, Function};
+use crate::encode;
+use crate::opcode::OpCode::{self, *};
+
+// Locals 0..VARS hold program variables; loop counters live above them, one
+// per nesting level.
+const VARS: u16 = 4;
+const MAX_DEPTH: u16 = 3;
+pub(crate) const LOCALS: u16 = VARS + MAX_DEPTH + 1;
+
+// xorshift64*, so a failure reproduces from its seed alone.
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Self {
+        Self(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    pub(crate) fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    pub(crate) fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    pub(crate) fn chance(&mut self, percent: usize) -> bool {
+        self.below(100) < percent
+    }
+
+    pub(crate) fn pick<T: Copy>(&mut self, items: &[T]) -> T {
+        items[self.below(items.len())]
+    }
+
+    // Mostly small values, including the ones the optimizer narrows.
+    pub(crate) fn integer(&mut self) -> i64 {
+        match self.below(4) {
+            0 => self.pick(&[0, 1, -1]),
+            1 => self.next() as i64,
+            _ => self.below(201) as i64 - 100,
+        }
+    }
+}
+
+// Unlike the builder, immediates are always emitted in their wide form, so
+// the optimizer has something to do.
+#[derive(Default)]
+struct Asm {
+    code: Vec<u8>,
+    labels: Vec<Option<usize>>,
+    fixups: Vec<(usize, usize)>,
+}
+
+impl Asm {
+    fn op(&mut self, op: OpCode) {
+        self.code.push(op as u8);
+    }
+
+    fn op_u16(&mut self, op: OpCode, operand: u16) {
+        self.op(op);
+        encode::put_u16(&mut self.code, operand);
+    }
+
+    fn imm(&mut self, i: i64) {
+        self.op(ImmI);
+        encode::put_i64(&mut self.code, i);
+    }
+
+    fn label(&mut self) -> usize {
+        self.labels.push(None);
+        self.labels.len() - 1
+    }
+
+    fn bind(&mut self, label: usize) {
+        self.labels[label] = Some(self.code.len());
+    }
+
+    fn jump(&mut self, op: OpCode, label: usize) {
+        self.op(op);
+        self.fixups.push((self.code.len(), label));
+        self.code.extend([0, 0]);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        for (at, label) in self.fixups {
+            let target = self.labels[label].unwrap() as u16;
+            encode::patch_u16(&mut self.code, at, target);
+        }
+        self.code
+    }
+}
+
+struct Program<'a> {
+    rng: &'a mut Rng,
+    asm: Asm,
+}
+
+const ARITHMETIC: [OpCode; 11] = [
+    AddI, SubI, MulI, DivI, ModI, DivFloorI, ModEuclidI, AddI32, SubI32, MulI32, DivI32,
+];
+const COMPARISONS: [OpCode; 5] = [CmpEqI, CmpGtI, CmpGeI, CmpLtI, CmpLeI];
+
+impl Program<'_> {
+    fn expr(&mut self, depth: usize) {
+        if depth == 0 || self.rng.chance(30) {
+            match self.rng.below(2) {
+                0 => self.asm.imm(self.rng.integer()),
+                _ => self.asm.op_u16(Load, self.rng.below(VARS as usize) as u16),
+            }
+            return;
+        }
+        self.expr(depth - 1);
+        match self.rng.below(6) {
+            0 => self.asm.op(I64toI32),
+            1 => {
+                self.asm.op(IntToStr);
+                self.asm.op(ParseInt);
+            }
+            _ => {
+                self.expr(depth - 1);
+                self.asm.op(self.rng.pick(&ARITHMETIC));
+            }
+        }
+    }
+
+    fn block(&mut self, depth: u16) {
+        for _ in 0..=self.rng.below(4) {
+            match self.rng.below(4) {
+                0 if depth < MAX_DEPTH => self.branch(depth),
+                1 if depth < MAX_DEPTH => self.counted_loop(depth),
+                _ => {
+                    self.expr(3);
+                    self.asm.op_u16(Store, self.rng.below(VARS as usize) as u16);
+                }
+            }
+        }
+    }
+
+    fn branch(&mut self, depth: u16) {
+        let end = self.asm.label();
+        self.expr(2);
+        self.expr(2);
+        self.asm.op(self.rng.pick(&COMPARISONS));
+        self.asm.jump(GotoIf, end);
+        self.block(depth + 1);
+        self.asm.bind(end);
+    }
+
+    fn counted_loop(&mut self, depth: u16) {
+        let counter = VARS + depth;
+        let (head, end) = (self.asm.label(), self.asm.label());
+        self.asm.imm(self.rng.below(4) as i64);
+        self.asm.op_u16(Store, counter);
+        self.asm.bind(head);
+        self.asm.imm(0);
+        self.asm.op_u16(Load, counter);
+        self.asm.op(CmpGeI);
+        self.asm.jump(GotoIf, end);
+        self.block(depth + 1);
+        self.asm.op_u16(Load, counter);
+        self.asm.imm(1);
+        self.asm.op(SubI);
+        self.asm.op_u16(Store, counter);
+        self.asm.jump(Goto, head);
+        self.asm.bind(end);
+    }
+}
+
+// Well-formed programs: every statement leaves the stack as it found it,
+// loops count down from a small bound and the result, local 0, is pushed
+// last. Running one always terminates, though it may trap on division by
+// zero.
+pub(crate) fn program(rng: &mut Rng) -> Chunk {
+    let mut program = Program {
+        rng,
+        asm: Asm::default(),
+    };
+    for local in 0..VARS {
+        program.asm.imm(program.rng.integer());
+        program.asm.op_u16(Store, local);
+    }
+    program.block(0);
+    program.asm.op_u16(Load, 0);
+    Chunk::new(program.asm.finish()).with_max_locals(LOCALS)
+}
+
+// Arbitrary instruction sequences that still pass verification: operands
+// are in range and jumps land on boundaries, but stack effects and types are
+// unconstrained and loops may never end, so they need fuel to run. Argument
+// accesses are left out: they only verify in code reached from functions of
+// a high enough arity, and the top level reaches everything here. So are
+// the internal opcodes, which no chunk is written with.
+pub(crate) fn instructions(rng: &mut Rng, len: usize) -> Chunk {
+    let all: Vec<_> = (0..=u8::MAX)
+        .filter_map(|b| b.try_into().ok())
+        .filter(|op: &OpCode| !matches!(op, LoadArg | StoreArg) && !op.is_internal())
+        .collect();
+    let ops: Vec<OpCode> = (0..len).map(|_| rng.pick(&all)).collect();
+    let mut offsets = vec![0];
+    for op in &ops {
+        offsets.push(offsets.last().unwrap() + 1 + op.operand_len());
+    }
+
+    let constants = vec![
+        Constant::Integer(rng.integer()),
+        Constant::Word(rng.next()),
+        Constant::Float(rng.integer() as f64 / 4.0),
+        Constant::Char('a'),
+        Constant::Str("12".to_string()),
+        Constant::Str("native".to_string()),
+    ];
+    let functions: Vec<_> = (0..2)
+        .map(|_| Function::new(rng.pick(&offsets), rng.below(3) as u8))
+        .collect();
+
+    let mut code = Vec::new();
+    for op in ops {
+        code.push(op as u8);
+        let operand = match op {
+            _ if op.is_jump() => rng.pick(&offsets) as u64,
+            Load | Store | StoreIf | LoadOrDefault => rng.below(LOCALS as usize) as u64,
+            AddToLocal | MulToLocal | MinToLocal | MaxToLocal => rng.below(LOCALS as usize) as u64,
+            LoadConst => rng.below(constants.len()) as u64,
+            CallNative => rng.pick(&[4, 5]),
+            Call => rng.below(functions.len()) as u64,
+            GetField | SetField => rng.below(4) as u64,
+            ImmI | ImmI8 | ImmI16 => (rng.below(33) as i64 - 16) as u64,
+            ImmF => (rng.integer() as f64).to_bits(),
+            ImmW | ImmW8 | ImmW16 => rng.below(*offsets.last().unwrap() + 2) as u64,
+            _ => 0,
+        };
+        let bytes = operand.to_be_bytes();
+        code.extend(&bytes[8 - op.operand_len()..]);
+    }
+    Chunk::new(code)
+        .with_max_locals(LOCALS)
+        .with_constants(constants)
+        .with_functions(functions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::VmError;
+    use crate::optimizer;
+    use crate::verifier;
+    use crate::vm::{Status, Termination, VM};
+
+    const SEEDS: u64 = 500;
+
+    #[test]
+    fn test_programs_terminate() {
+        for seed in 0..SEEDS {
+            let chunk = program(&mut Rng::new(seed));
+            assert_eq!(verifier::verify(&chunk), Ok(()), "seed {seed}");
+            let mut vm = VM::new(chunk);
+            match vm.execute_all() {
+                Ok(outcome) => {
+                    let status = Status::Finished(Termination::EndOfChunk);
+                    assert_eq!(outcome.status, status, "seed {seed}");
+                    assert_eq!(vm.stack().len(), 1, "seed {seed}");
+                }
+                Err(err) => assert_eq!(err, VmError::DivisionByZero, "seed {seed}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_instructions_never_panic() {
+        for seed in 0..SEEDS {
+            let mut rng = Rng::new(seed);
+            let chunk = instructions(&mut rng, 48);
+            assert_eq!(verifier::verify(&chunk), Ok(()), "seed {seed}");
+            for stress in [false, true] {
+                let mut vm = VM::new(chunk.clone());
+                vm.set_gc_stress(stress).unwrap();
+                vm.set_fuel(Some(256)).unwrap();
+                if vm.execute_all() == Err(VmError::FuelExhausted) {
+                    assert_eq!(vm.fuel(), Some(0), "seed {seed}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_peephole_preserves_results() {
+        for seed in 0..SEEDS {
+            let chunk = program(&mut Rng::new(seed));
+            let optimized = optimizer::peephole(&chunk).unwrap();
+            assert!(optimized.len() <= chunk.len(), "seed {seed}");
+
+            // combining into locals in place runs fewer instructions
+            let mut before = VM::new(chunk);
+            let mut after = VM::new(optimized);
+            let result = before.execute_all().map(|o| (o.status, o.value));
+            let outcome = after.execute_all().map(|o| (o.status, o.value));
+            assert_eq!(outcome, result, "seed {seed}");
+            assert_eq!(after.stack(), before.stack(), "seed {seed}");
+            assert_eq!(after.locals(), before.locals(), "seed {seed}");
+        }
+    }
+
+    #[test]
+    fn test_optimize_preserves_results() {
+        for seed in 0..SEEDS {
+            let chunk = program(&mut Rng::new(seed));
+            let optimized = optimizer::optimize(&chunk).unwrap();
+            assert!(optimized.len() <= chunk.len(), "seed {seed}");
+
+            let mut before = VM::new(chunk);
+            let mut after = VM::new(optimized);
+            let result = before.execute_all().map(|outcome| outcome.value);
+            assert_eq!(after.execute_all().map(|o| o.value), result, "seed {seed}");
+            assert_eq!(after.stack(), before.stack(), "seed {seed}");
+            // a trap may strike before a dropped store is overwritten
+            if result.is_ok() {
+                assert_eq!(after.locals(), before.locals(), "seed {seed}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_serialize_round_trip() {
+        for seed in 0..SEEDS {
+            let mut rng = Rng::new(seed);
+            for chunk in [program(&mut rng), instructions(&mut rng, 48)] {
+                let bytes = chunk.serialize();
+                assert_eq!(Chunk::deserialize(&bytes), Ok(chunk), "seed {seed}");
+            }
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use crate::chunk::Chunk;
+use crate::error::DecodeError;
+use crate::instruction::Instruction;
+use crate::opcode::{effect, OpCode};
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+// How many of each table the report shows.
+const TOP: usize = 10;
+
+// Static opcode usage over a set of chunks, as data for choosing which
+// superinstructions and narrow encodings to add. Counts, pairs and triples
+// are sorted most common first, ties by opcode number.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Usage {
+    pub instructions: u64,
+    pub opcodes: Vec<(OpCode, u64)>,
+    // Integer immediates in any of their encodings, by value.
+    pub integers: Widths,
+    // Goto and GotoIf targets. They are encoded as u16, so none can be wider.
+    pub jump_targets: Widths,
+    // Runs of consecutive instructions that could be fused: none of them
+    // after the first is a jump target or a function entry, and none before
+    // the last is a jump, call or return.
+    pub pairs: Vec<([OpCode; 2], u64)>,
+    pub triples: Vec<([OpCode; 3], u64)>,
+}
+
+// Values that fit in 16 bits include those that fit in 8.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Widths {
+    pub total: u64,
+    pub fit8: u64,
+    pub fit16: u64,
+}
+
+impl Widths {
+    fn add(&mut self, fit8: bool, fit16: bool) {
+        self.total += 1;
+        self.fit8 += fit8 as u64;
+        self.fit16 += fit16 as u64;
+    }
+}
+
+pub fn analyze<'a>(chunks: impl IntoIterator<Item = &'a Chunk>) -> Result<Usage, DecodeError> {
+    let mut usage = Usage::default();
+    let mut opcodes = HashMap::new();
+    let mut pairs = HashMap::new();
+    let mut triples = HashMap::new();
+    for chunk in chunks {
+        let instructions = chunk.instructions().collect::<Result<Vec<_>, _>>()?;
+        let mut targets: BTreeSet<_> = chunk.functions().iter().map(|f| f.entry).collect();
+        for &(_, instruction) in &instructions {
+            if let Instruction::Goto(target) | Instruction::GotoIf(target) = instruction {
+                targets.insert(target as usize);
+                usage.jump_targets.add(target <= 0xff, true);
+            }
+            if let Some(i) = integer(instruction) {
+                usage
+                    .integers
+                    .add(i8::try_from(i).is_ok(), i16::try_from(i).is_ok());
+            }
+        }
+
+        // the opcodes since the last place a run has to start over
+        let mut run = Vec::new();
+        for &(offset, instruction) in &instructions {
+            let op = instruction.opcode();
+            usage.instructions += 1;
+            *opcodes.entry([op as u8]).or_insert(0) += 1;
+            if targets.contains(&offset) {
+                run.clear();
+            }
+            run.push(op as u8);
+            if let [.., a, b] = run[..] {
+                *pairs.entry([a, b]).or_insert(0) += 1;
+            }
+            if let [.., a, b, c] = run[..] {
+                *triples.entry([a, b, c]).or_insert(0) += 1;
+            }
+            if op.effects() & effect::CONTROL_FLOW != 0 {
+                run.clear();
+            }
+        }
+    }
+    usage.opcodes = sorted(opcodes)
+        .into_iter()
+        .map(|([op], count)| (op, count))
+        .collect();
+    usage.pairs = sorted(pairs);
+    usage.triples = sorted(triples);
+    Ok(usage)
+}
+
+fn integer(instruction: Instruction) -> Option<i64> {
+    match instruction {
+        Instruction::ImmI(i) => Some(i),
+        Instruction::ImmI16(i) => Some(i as i64),
+        Instruction::ImmI8(i) => Some(i as i64),
+        Instruction::Imm0 => Some(0),
+        Instruction::Imm1 => Some(1),
+        Instruction::ImmNeg1 => Some(-1),
+        _ => None,
+    }
+}
+
+// Keyed by opcode byte, since opcodes aren't hashable.
+fn sorted<const N: usize>(counts: HashMap<[u8; N], u64>) -> Vec<([OpCode; N], u64)> {
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_by_key(|&(ops, count)| (Reverse(count), ops));
+    counts
+        .into_iter()
+        .map(|(ops, count)| (ops.map(|byte| OpCode::try_from(byte).unwrap()), count))
+        .collect()
+}
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} instructions", self.instructions)?;
+        for (name, widths) in [
+            ("integer immediates", self.integers),
+            ("jump targets", self.jump_targets),
+        ] {
+            writeln!(
+                f,
+                "{} {name}, {} fit in 8 bits, {} in 16",
+                widths.total, widths.fit8, widths.fit16
+            )?;
+        }
+        writeln!(f, "\n{:>10}  opcode", "count")?;
+        for &(op, count) in self.opcodes.iter().take(TOP) {
+            writeln!(f, "{count:>10}  {op:?}")?;
+        }
+        writeln!(f, "\n{:>10}  pair", "count")?;
+        for &(ops, count) in self.pairs.iter().take(TOP) {
+            writeln!(f, "{count:>10}  {:?} {:?}", ops[0], ops[1])?;
+        }
+        writeln!(f, "\n{:>10}  triple", "count")?;
+        for &(ops, count) in self.triples.iter().take(TOP) {
+            writeln!(f, "{count:>10}  {:?} {:?} {:?}", ops[0], ops[1], ops[2])?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opcode::OpCode::*;
+    use crate::workloads;
+
+    #[test]
+    fn test_workload_usage() {
+        let corpus: Vec<_> = workloads::standard()
+            .into_iter()
+            .map(|workload| workload.chunk)
+            .collect();
+        let usage = analyze(&corpus).unwrap();
+        let decoded: usize = corpus
+            .iter()
+            .map(|chunk| chunk.instructions().count())
+            .sum();
+        assert_eq!(usage.instructions, decoded as u64);
+        let counted: u64 = usage.opcodes.iter().map(|&(_, count)| count).sum();
+        assert_eq!(counted, usage.instructions);
+        assert_eq!(usage.pairs[0], ([CmpGeI, GotoIf], 5));
+        assert!(usage.pairs.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+        assert!(usage.integers.fit8 <= usage.integers.fit16);
+        assert_eq!(usage.jump_targets.fit16, usage.jump_targets.total);
+
+        let report = usage.to_string();
+        assert!(report.starts_with(&format!("{} instructions\n", decoded)));
+        assert!(report.contains("\n         5  CmpGeI GotoIf\n"));
+    }
+
+    #[test]
+    fn test_runs_stop_at_labels() {
+        let usage = analyze([&workloads::countdown(1000)]).unwrap();
+        // the loop head and the exit are both jump targets
+        assert_eq!(usage.jump_targets.total, 2);
+        assert_eq!(usage.integers.total, 3);
+        assert_eq!(usage.integers.fit8, 2);
+        assert_eq!(usage.integers.fit16, 3);
+        let pairs: Vec<_> = usage.pairs.iter().map(|&(ops, _)| ops).collect();
+        assert!(pairs.contains(&[CmpGeI, GotoIf]));
+        assert!(pairs.contains(&[SubI, Store]));
+        assert!(!pairs.contains(&[Store, Imm0]));
+        assert!(!pairs.contains(&[Goto, Load]));
+        assert!(usage.pairs.iter().all(|&(_, count)| count == 1));
+        assert!(!pairs.contains(&[GotoIf, Load]));
+        assert_eq!(usage.triples.len(), 5);
+    }
+}
@@ -1,4 +1,6 @@
-use crate::heap::ObjectPtr;
+use crate::heap::{Heap, ObjectPtr};
+use std::collections::HashSet;
+use std::fmt;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Value {
@@ -7,6 +9,7 @@ pub enum Value {
     Word(u64),
     Float(f64),
     ObjectPtr(ObjectPtr),
+    Null,
 }
 
 impl Value {
@@ -17,4 +20,63 @@ impl Value {
             None
         }
     }
+
+    pub const fn type_name(&self) -> &'static str {
+        match self {
+            Self::Char(_) => "char",
+            Self::Integer(_) => "integer",
+            Self::Word(_) => "word",
+            Self::Float(_) => "float",
+            Self::ObjectPtr(_) => "object",
+            Self::Null => "null",
+        }
+    }
+
+    // Structural equality: objects compare equal when their tags match and
+    // their fields are pairwise deep-equal. Pairs already under comparison
+    // are assumed equal, which lets isomorphic cycles terminate.
+    pub fn deep_eq(&self, other: &Self, heap: &Heap) -> bool {
+        self.deep_eq_across(heap, other, heap)
+    }
+
+    // As `deep_eq`, for `self` in `heap` and `other` in `other_heap`.
+    pub fn deep_eq_across(&self, heap: &Heap, other: &Self, other_heap: &Heap) -> bool {
+        let mut assumed = HashSet::new();
+        let mut pending = vec![(*self, *other)];
+
+        while let Some((a, b)) = pending.pop() {
+            let (Self::ObjectPtr(a), Self::ObjectPtr(b)) = (a, b) else {
+                if a != b {
+                    return false;
+                }
+                continue;
+            };
+            if a == b || !assumed.insert((a, b)) {
+                continue;
+            }
+
+            let (x, y) = (heap.get(a), other_heap.get(b));
+            if x.tag != y.tag || x.fields.len() != y.fields.len() || x.buffer != y.buffer {
+                return false;
+            }
+            pending.extend(x.fields.iter().copied().zip(y.fields.iter().copied()));
+        }
+
+        true
+    }
+}
+
+// Objects are only named: their ids and contents live in the heap, see
+// `Value::display`, and their addresses differ from run to run.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Char(c) => write!(f, "{c:?}"),
+            Self::Integer(i) => write!(f, "{i}"),
+            Self::Word(w) => write!(f, "{w:#x}"),
+            Self::Float(x) => write!(f, "{x:?}"),
+            Self::ObjectPtr(_) => write!(f, "object"),
+            Self::Null => write!(f, "null"),
+        }
+    }
 }
@@ -0,0 +1,681 @@
+use crate::chunk::{feature, Chunk, Constant, MAX_UNDECLARED_LOCALS};
+use crate::error::VerifyError;
+use crate::instruction::Instruction;
+use crate::policy::ExecutionPolicy;
+use std::collections::{BTreeMap, BTreeSet};
+
+// Static checks over the whole chunk: every byte decodes, every jump lands on
+// an instruction boundary (or the end of the chunk) and every local index is
+// within the declared frame, or `MAX_UNDECLARED_LOCALS` without one.
+// Constant indices must refer to an entry of the chunk's constant pool, and
+// every call to an entry of its function table, whose entries must be
+// boundaries too, or to one of its imports. `GotoDyn` targets are only known
+// at run time, where the VM checks them against the same boundaries.
+// Argument indices must be within the arity of every function the access is
+// reachable from without a call.
+pub fn verify(chunk: &Chunk) -> Result<(), VerifyError> {
+    let instructions = chunk.instructions().collect::<Result<Vec<_>, _>>()?;
+    let mut boundaries: BTreeSet<_> = instructions.iter().map(|&(ip, _)| ip).collect();
+    boundaries.insert(chunk.len());
+
+    for (index, function) in chunk.functions().iter().enumerate() {
+        if !boundaries.contains(&function.entry) {
+            let index = index as u16;
+            let entry = function.entry;
+            return Err(VerifyError::InvalidEntry { index, entry });
+        }
+    }
+
+    for &(offset, instruction) in &instructions {
+        check_operands(chunk, offset, instruction, |target| {
+            boundaries.contains(&target)
+        })?;
+    }
+    if !accesses_args(&instructions) {
+        return Ok(());
+    }
+    let instructions: BTreeMap<_, _> = instructions.into_iter().collect();
+    let at = |offset| instructions.get(&offset).copied();
+    let top_level = std::iter::once((0, None, 0));
+    let functions = chunk
+        .functions()
+        .iter()
+        .enumerate()
+        .map(|(index, f)| (f.entry, Some(index as u16), f.arity));
+    for (entry, function, arity) in top_level.chain(functions) {
+        check_args(entry, function, arity, &at)?;
+    }
+    Ok(())
+}
+
+// A chunk `verify` accepted. Making one is the only way to get it, so code
+// holding one can rely on everything `verify` checks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifiedChunk(Chunk);
+
+impl VerifiedChunk {
+    pub fn new(chunk: Chunk) -> Result<Self, VerifyError> {
+        verify(&chunk)?;
+        Ok(Self(chunk))
+    }
+
+    pub fn chunk(&self) -> &Chunk {
+        &self.0
+    }
+
+    pub fn into_chunk(self) -> Chunk {
+        self.0
+    }
+}
+
+// As `verify`, for `code` about to be appended to a chunk that was verified
+// already, with offsets counted from the start of the chunk. The new code
+// runs as part of the top level and may jump anywhere in the chunk.
+pub fn verify_appended(chunk: &Chunk, code: &[u8]) -> Result<(), VerifyError> {
+    let start = chunk.len();
+    let appended = Chunk::new(code.to_vec());
+    let instructions = appended
+        .instructions()
+        .map(|decoded| decoded.map(|(offset, instruction)| (start + offset, instruction)))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| match err.into() {
+            VerifyError::InvalidOpcode { offset, byte } => VerifyError::InvalidOpcode {
+                offset: start + offset,
+                byte,
+            },
+            VerifyError::Truncated { offset } => VerifyError::Truncated {
+                offset: start + offset,
+            },
+            err => err,
+        })?;
+    let is_boundary = |target: usize| match target.checked_sub(start) {
+        Some(offset) => appended.is_boundary(offset),
+        None => chunk.is_boundary(target),
+    };
+
+    for &(offset, instruction) in &instructions {
+        check_operands(chunk, offset, instruction, is_boundary)?;
+    }
+    let at = |offset: usize| match offset.checked_sub(start) {
+        Some(offset) => Instruction::decode(code, offset).ok(),
+        None if chunk.is_boundary(offset) => Instruction::decode(chunk.code(), offset).ok(),
+        None => None,
+    };
+    // jumps back into the old code can reach argument accesses there
+    check_args(start, None, 0, &at)
+}
+
+// Everything about one instruction that can be checked on its own, given
+// where jumps may land.
+fn check_operands(
+    chunk: &Chunk,
+    offset: usize,
+    instruction: Instruction,
+    is_boundary: impl Fn(usize) -> bool,
+) -> Result<(), VerifyError> {
+    if let Some(index) = local_index(instruction) {
+        let max = chunk.max_locals().unwrap_or(MAX_UNDECLARED_LOCALS);
+        if index >= max {
+            return Err(VerifyError::LocalOutOfRange { offset, index, max });
+        }
+    }
+    match instruction {
+        Instruction::Goto(target) | Instruction::GotoIf(target) => {
+            let target = target as usize;
+            if !is_boundary(target) {
+                return Err(VerifyError::InvalidJump { offset, target });
+            }
+        }
+        Instruction::LoadConst(index) if index as usize >= chunk.constants().len() => {
+            return Err(VerifyError::ConstantOutOfRange { offset, index });
+        }
+        Instruction::CallNative(index) => {
+            native_name(chunk, offset, index)?;
+        }
+        Instruction::Call(index)
+            if index as usize >= chunk.functions().len() && chunk.import(index).is_none() =>
+        {
+            return Err(VerifyError::FunctionOutOfRange { offset, index });
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+// The local an instruction reads or writes, other than through an argument.
+fn local_index(instruction: Instruction) -> Option<u16> {
+    match instruction {
+        Instruction::Load(index)
+        | Instruction::Store(index)
+        | Instruction::StoreIf(index)
+        | Instruction::LoadOrDefault(index)
+        | Instruction::AddToLocal(index)
+        | Instruction::MulToLocal(index)
+        | Instruction::MinToLocal(index)
+        | Instruction::MaxToLocal(index) => Some(index),
+        _ => None,
+    }
+}
+
+// The highest local that code reachable from `offset` without following
+// calls can access, walking as `check_args` does. Once a `GotoDyn` is
+// reachable, the whole chunk could be. A trap handler can resume after a
+// `TrapMsg` or `Unreachable`, so both walks go on past them.
+pub fn max_local(chunk: &Chunk, offset: usize) -> Option<u16> {
+    let mut max = None;
+    let mut seen = BTreeSet::new();
+    let mut pending = vec![offset];
+    while let Some(offset) = pending.pop() {
+        if !chunk.is_boundary(offset) || !seen.insert(offset) {
+            continue;
+        }
+        let Ok(instruction) = Instruction::decode(chunk.code(), offset) else {
+            continue;
+        };
+        max = max.max(local_index(instruction));
+        let next = offset + instruction.encoded_len();
+        match instruction {
+            Instruction::GotoDyn => {
+                let instructions = chunk.instructions().map_while(Result::ok);
+                return instructions.filter_map(|(_, i)| local_index(i)).max();
+            }
+            Instruction::Return | Instruction::ReturnN(_) => {}
+            Instruction::Goto(target) => pending.push(target as usize),
+            Instruction::GotoIf(target) => pending.extend([target as usize, next]),
+            _ => pending.push(next),
+        }
+    }
+    max
+}
+
+fn accesses_args(instructions: &[(usize, Instruction)]) -> bool {
+    instructions.iter().any(|(_, instruction)| {
+        matches!(
+            instruction,
+            Instruction::LoadArg(_) | Instruction::StoreArg(_)
+        )
+    })
+}
+
+// Walks the code reachable from `entry` without following calls, checking
+// arguments against the arity in force there: the function's, or none for
+// the top level. Code only reached through `GotoDyn` is left to the VM.
+fn check_args(
+    entry: usize,
+    function: Option<u16>,
+    arity: u8,
+    at: &dyn Fn(usize) -> Option<Instruction>,
+) -> Result<(), VerifyError> {
+    let mut seen = BTreeSet::new();
+    let mut pending = vec![entry];
+    while let Some(offset) = pending.pop() {
+        let Some(instruction) = at(offset) else {
+            continue;
+        };
+        if !seen.insert(offset) {
+            continue;
+        }
+        let next = offset + instruction.encoded_len();
+        match instruction {
+            Instruction::LoadArg(index) | Instruction::StoreArg(index) if index >= arity => {
+                return Err(VerifyError::ArgOutOfRange {
+                    offset,
+                    index,
+                    function,
+                    arity,
+                });
+            }
+            Instruction::Return | Instruction::ReturnN(_) | Instruction::GotoDyn => {}
+            Instruction::Goto(target) => pending.push(target as usize),
+            Instruction::GotoIf(target) => pending.extend([target as usize, next]),
+            _ => pending.push(next),
+        }
+    }
+    Ok(())
+}
+
+// Stack-effect analysis over every path through the top-level code and each
+// function, tracking the stack height from where that code starts. No
+// instruction may pop more than there is, paths must agree on the height
+// where they join, and functions that declare a return count must return
+// exactly that many values. Following a call needs the callee's return
+// count; natives and computed jumps can't be followed at all. `IterNext`
+// pushes a different number of values when exhausted, so it can only be
+// followed when a `GotoIf` on its flag comes right after it.
+pub fn verify_stack(chunk: &Chunk) -> Result<(), VerifyError> {
+    verify(chunk)?;
+    let instructions = chunk
+        .instructions()
+        .collect::<Result<BTreeMap<_, _>, _>>()?;
+    let top_level = std::iter::once((0, None));
+    let functions = chunk.functions().iter().map(|f| (f.entry, f.returns));
+    for (entry, returns) in top_level.chain(functions) {
+        walk_stack(chunk, &instructions, entry, returns)?;
+    }
+    Ok(())
+}
+
+fn walk_stack(
+    chunk: &Chunk,
+    instructions: &BTreeMap<usize, Instruction>,
+    entry: usize,
+    returns: Option<u8>,
+) -> Result<(), VerifyError> {
+    let mut heights = BTreeMap::new();
+    let mut pending = vec![(entry, 0)];
+    while let Some((offset, height)) = pending.pop() {
+        if let Some(&expected) = heights.get(&offset) {
+            if expected != height {
+                let found = height;
+                return Err(VerifyError::InconsistentStack {
+                    offset,
+                    expected,
+                    found,
+                });
+            }
+            continue;
+        }
+        heights.insert(offset, height);
+        // running off the end of the chunk finishes the run
+        let Some(&instruction) = instructions.get(&offset) else {
+            continue;
+        };
+
+        let next = offset + instruction.encoded_len();
+        let underflow = VerifyError::StackUnderflow { offset };
+        let check_returns = |found: usize| match returns {
+            Some(expected) if expected as usize != found => Err(VerifyError::ReturnCountMismatch {
+                offset,
+                expected,
+                found,
+            }),
+            _ => Ok(()),
+        };
+        match instruction {
+            Instruction::Return => check_returns(height)?,
+            // never falls through, so any height will do
+            Instruction::Unreachable => {}
+            Instruction::TrapMsg => {
+                if height == 0 {
+                    return Err(underflow);
+                }
+            }
+            Instruction::ReturnN(count) => {
+                if height < count as usize {
+                    return Err(underflow);
+                }
+                check_returns(count as usize)?;
+            }
+            Instruction::Call(index) => {
+                // imports can't be followed until linked
+                let function = chunk
+                    .functions()
+                    .get(index as usize)
+                    .ok_or(VerifyError::UnknownStackEffect { offset })?;
+                let results = function
+                    .returns
+                    .ok_or(VerifyError::UnknownStackEffect { offset })?;
+                let height = height
+                    .checked_sub(function.arity as usize)
+                    .ok_or(underflow)?;
+                pending.push((next, height + results as usize));
+            }
+            Instruction::Goto(target) => pending.push((target as usize, height)),
+            Instruction::IterNext => {
+                let height = height.checked_sub(1).ok_or(underflow)?;
+                let Some(&Instruction::GotoIf(target)) = instructions.get(&next) else {
+                    return Err(VerifyError::UnknownStackEffect { offset });
+                };
+                pending.push((target as usize, height + 1));
+                pending.push((next + Instruction::GotoIf(0).encoded_len(), height));
+            }
+            _ => {
+                let (pops, pushes) = instruction
+                    .opcode()
+                    .stack_effect()
+                    .ok_or(VerifyError::UnknownStackEffect { offset })?;
+                let height = height.checked_sub(pops).ok_or(underflow)? + pushes;
+                if let Instruction::GotoIf(target) = instruction {
+                    pending.push((target as usize, height));
+                }
+                pending.push((next, height));
+            }
+        }
+    }
+    Ok(())
+}
+
+// The features mask the chunk should declare, from the instructions it
+// contains. Loading a string constant allocates, so it needs objects.
+pub fn required_features(chunk: &Chunk) -> Result<u32, VerifyError> {
+    let mut features = 0;
+    for decoded in chunk.instructions() {
+        let (_, instruction) = decoded?;
+        features |= match instruction {
+            Instruction::LoadConst(index) => match chunk.constants().get(index as usize) {
+                Some(Constant::Str(_)) => feature::OBJECTS,
+                _ => 0,
+            },
+            _ => instruction.opcode().features(),
+        };
+    }
+    Ok(features)
+}
+
+fn native_name(chunk: &Chunk, offset: usize, index: u16) -> Result<&str, VerifyError> {
+    match chunk.constants().get(index as usize) {
+        Some(Constant::Str(name)) => Ok(name),
+        Some(_) => Err(VerifyError::InvalidNativeName { offset, index }),
+        None => Err(VerifyError::ConstantOutOfRange { offset, index }),
+    }
+}
+
+// Also rejects chunks containing opcodes or calling natives that the policy
+// forbids. Limits on fuel, stack and heap can only be enforced at run time.
+pub fn verify_with_policy(chunk: &Chunk, policy: &ExecutionPolicy) -> Result<(), VerifyError> {
+    let max = policy.code_len_limit();
+    if chunk.len() > max {
+        let len = chunk.len();
+        return Err(VerifyError::ChunkTooLarge { len, max });
+    }
+    verify(chunk)?;
+    for decoded in chunk.instructions() {
+        let (offset, instruction) = decoded?;
+        if !policy.allows(instruction.opcode()) {
+            let byte = instruction.opcode() as u8;
+            return Err(VerifyError::ForbiddenOpcode { offset, byte });
+        }
+        if let Instruction::CallNative(index) = instruction {
+            if !policy.allows_native(native_name(chunk, offset, index)?) {
+                return Err(VerifyError::ForbiddenNative { offset, index });
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::{self, ChunkBuilder};
+    use crate::opcode::OpCode::*;
+    use crate::workloads;
+
+    #[test]
+    fn test_verified_chunk() {
+        let chunk = workloads::factorial(5);
+        let verified = VerifiedChunk::new(chunk.clone()).unwrap();
+        assert_eq!(verified.chunk(), &chunk);
+        assert_eq!(verified.into_chunk(), chunk);
+        let bad = Chunk::new(vec![Imm1 as u8, GotoIf as u8, 0, 9]);
+        assert_eq!(
+            VerifiedChunk::new(bad.clone()),
+            Err(verify(&bad).unwrap_err())
+        );
+    }
+
+    #[test]
+    fn test_verify_factorial() {
+        let chunk = builder::tests::factorial(5).build().unwrap();
+        assert_eq!(verify(&chunk), Ok(()));
+        assert_eq!(verify(&chunk.with_max_locals(2)), Ok(()));
+    }
+
+    #[test]
+    fn test_verify_locals() {
+        let chunk = builder::tests::factorial(5).build().unwrap();
+        assert_eq!(
+            verify(&chunk.with_max_locals(1)),
+            Err(VerifyError::LocalOutOfRange {
+                offset: 6,
+                index: 1,
+                max: 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_verify_args() {
+        let mut b = ChunkBuilder::new();
+        let (entry, skip) = (b.label(), b.label());
+        let f = b.function(entry, 2);
+        b.imm_i(1).imm_i(2).call(f).op(Return);
+        b.bind(entry)
+            .load_arg(1)
+            .goto_if(skip)
+            .load_arg(0)
+            .op(Return);
+        b.bind(skip).load_arg(2).op(Return);
+        let chunk = b.build().unwrap();
+        assert_eq!(
+            verify(&chunk),
+            Err(VerifyError::ArgOutOfRange {
+                offset: 15,
+                index: 2,
+                function: Some(0),
+                arity: 2
+            })
+        );
+        let message = verify(&chunk).unwrap_err().to_string();
+        assert!(message.contains("function 0 of arity 2"), "{message}");
+
+        let chunk = chunk.with_functions(vec![crate::chunk::Function::new(7, 3)]);
+        assert_eq!(verify(&chunk), Ok(()));
+
+        // arguments past a trap count, since a handler can resume there
+        let chunk = ChunkBuilder::new()
+            .string("no")
+            .op(TrapMsg)
+            .load_arg(1)
+            .build()
+            .unwrap();
+        assert!(matches!(
+            verify(&chunk),
+            Err(VerifyError::ArgOutOfRange { index: 1, .. })
+        ));
+
+        // the top level has no arguments
+        let chunk = ChunkBuilder::new().imm_i(0).store_arg(0).build().unwrap();
+        assert_eq!(
+            verify(&chunk),
+            Err(VerifyError::ArgOutOfRange {
+                offset: 1,
+                index: 0,
+                function: None,
+                arity: 0
+            })
+        );
+    }
+
+    #[test]
+    fn test_max_local() {
+        // a loop over local 1 after a store to local 5, and a call whose
+        // callee's locals don't count
+        let mut b = ChunkBuilder::new();
+        let (entry, head) = (b.label(), b.label());
+        let f = b.function(entry, 0);
+        b.op(Imm0).store(5);
+        let looping = b.len();
+        b.bind(head).load(1).goto_if(head).call(f).op(Return);
+        b.bind(entry).load(9).op(Return);
+        let chunk = b.build().unwrap();
+        assert_eq!(max_local(&chunk, 0), Some(5));
+        assert_eq!(max_local(&chunk, looping), Some(1));
+        assert_eq!(max_local(&chunk, chunk.functions()[0].entry), Some(9));
+        assert_eq!(max_local(&chunk, chunk.len()), None);
+
+        // any instruction could follow a computed jump
+        let chunk = ChunkBuilder::new()
+            .load(1)
+            .op(GotoDyn)
+            .load(7)
+            .build()
+            .unwrap();
+        assert_eq!(max_local(&chunk, 0), Some(7));
+    }
+
+    #[test]
+    fn test_verify_malformed() {
+        let chunk = Chunk::new(vec![Goto as u8, 0, 2, Return as u8]);
+        assert_eq!(
+            verify(&chunk),
+            Err(VerifyError::InvalidJump {
+                offset: 0,
+                target: 2
+            })
+        );
+
+        let chunk = Chunk::new(vec![Imm0 as u8, 0xff]);
+        assert_eq!(
+            verify(&chunk),
+            Err(VerifyError::InvalidOpcode {
+                offset: 1,
+                byte: 0xff
+            })
+        );
+
+        let chunk = Chunk::new(vec![Load as u8, 0]);
+        assert_eq!(verify(&chunk), Err(VerifyError::Truncated { offset: 0 }));
+    }
+
+    #[test]
+    fn test_verify_unreachable() {
+        // f(x) = match x { 0 => 10, 1 => 20 }, where the default arm leaves
+        // a value and an underflowing AddI behind it, which would fall into
+        // the first arm at the wrong height
+        let mut b = ChunkBuilder::new();
+        let (entry, zero, one) = (b.label(), b.label(), b.label());
+        let f = b.function(entry, 1);
+        b.returns(f, 1);
+        b.imm_i(1).call(f).op(Return);
+        b.bind(entry).load(0).op(Imm0).op(CmpEqI).goto_if(zero);
+        b.load(0).op(Imm1).op(CmpEqI).goto_if(one);
+        b.imm_i(7).op(Unreachable).op(AddI);
+        b.bind(zero).imm_i(10).op(Return);
+        b.bind(one).imm_i(20).op(Return);
+        let chunk = b.build().unwrap();
+        assert_eq!(verify_stack(&chunk), Ok(()));
+
+        // a trap handler can resume after it, so later locals still count
+        let chunk = ChunkBuilder::new().op(Unreachable).load(3).build().unwrap();
+        assert_eq!(max_local(&chunk, 0), Some(3));
+
+        // TrapMsg needs its message, but doesn't fall through either
+        let chunk = ChunkBuilder::new()
+            .string("no")
+            .op(TrapMsg)
+            .op(AddI)
+            .load(4)
+            .build()
+            .unwrap();
+        assert_eq!(verify_stack(&chunk), Ok(()));
+        assert_eq!(max_local(&chunk, 0), Some(4));
+        let chunk = ChunkBuilder::new().op(TrapMsg).build().unwrap();
+        assert_eq!(
+            verify_stack(&chunk),
+            Err(VerifyError::StackUnderflow { offset: 0 })
+        );
+    }
+
+    #[test]
+    fn test_verify_stack() {
+        // fact(n) = n <= 1 ? 1 : n * fact(n - 1)
+        let mut b = ChunkBuilder::new();
+        let (entry, base) = (b.label(), b.label());
+        let fact = b.function(entry, 1);
+        b.returns(fact, 1);
+        b.imm_i(5).call(fact).op(Return);
+        b.bind(entry).imm_i(1).load(0).op(CmpGeI).goto_if(base);
+        b.load(0).imm_i(1).op(SubI).call(fact);
+        b.load(0).op(MulI).op(Return);
+        b.bind(base).imm_i(1).ret_n(1);
+        assert_eq!(verify_stack(&b.build().unwrap()), Ok(()));
+
+        let mut b = ChunkBuilder::new();
+        let entry = b.label();
+        let pair = b.function(entry, 0);
+        b.returns(pair, 2);
+        b.call(pair).op(AddI).op(AddI).op(Return);
+        b.bind(entry).imm_i(1).imm_i(2).ret_n(2);
+        let chunk = b.build().unwrap();
+        let add = chunk.functions()[0].entry - 2;
+        assert_eq!(
+            verify_stack(&chunk),
+            Err(VerifyError::StackUnderflow { offset: add })
+        );
+
+        let mut b = ChunkBuilder::new();
+        let entry = b.label();
+        let pair = b.function(entry, 0);
+        b.returns(pair, 2);
+        b.call(pair).op(Return);
+        b.bind(entry).imm_i(1).op(Return);
+        let chunk = b.build().unwrap();
+        assert_eq!(
+            verify_stack(&chunk),
+            Err(VerifyError::ReturnCountMismatch {
+                offset: chunk.len() - 1,
+                expected: 2,
+                found: 1
+            })
+        );
+
+        // one branch pushes and the other doesn't
+        let mut b = ChunkBuilder::new();
+        let join = b.label();
+        b.imm_i(0).goto_if(join).imm_i(1).bind(join).op(Return);
+        assert_eq!(
+            verify_stack(&b.build().unwrap()),
+            Err(VerifyError::InconsistentStack {
+                offset: 5,
+                expected: 1,
+                found: 0
+            })
+        );
+
+        let chunk = ChunkBuilder::new().imm_i(1).call_native("print").build();
+        assert_eq!(
+            verify_stack(&chunk.unwrap()),
+            Err(VerifyError::UnknownStackEffect { offset: 1 })
+        );
+
+        // the flag `IterNext` pushes has to be branched on straight away
+        let mut b = ChunkBuilder::new();
+        let (head, body) = (b.label(), b.label());
+        b.imm_i(2).op(ArrayNew).op(IterNew).store(0);
+        b.bind(head).load(0).op(IterNext).goto_if(body).op(Return);
+        b.bind(body).store(1).goto(head);
+        assert_eq!(verify_stack(&b.build().unwrap()), Ok(()));
+
+        let mut b = ChunkBuilder::new();
+        b.imm_i(2).op(ArrayNew).op(IterNew).op(IterNext);
+        assert_eq!(
+            verify_stack(&b.build().unwrap()),
+            Err(VerifyError::UnknownStackEffect { offset: 4 })
+        );
+    }
+
+    #[test]
+    fn test_required_features() {
+        let chunk = builder::tests::factorial(5).build().unwrap();
+        assert_eq!(chunk.features(), 0);
+        assert_eq!(required_features(&chunk), Ok(0));
+
+        let mut b = ChunkBuilder::new();
+        let target = b.label();
+        b.load_const(Constant::Float(0.5))
+            .imm_label(target)
+            .op(GotoDyn);
+        b.bind(target).call_native("print");
+        let natives = feature::NATIVES | feature::DYNAMIC_JUMPS;
+        assert_eq!(b.build().unwrap().features(), natives);
+        b.string("s");
+        assert_eq!(b.build().unwrap().features(), natives | feature::OBJECTS);
+
+        let mut chunks: Vec<_> = workloads::standard().into_iter().map(|w| w.chunk).collect();
+        chunks.push(b.build().unwrap());
+        for chunk in chunks {
+            assert_eq!(required_features(&chunk), Ok(chunk.features()));
+        }
+    }
+}
@@ -1,285 +1,6602 @@
-use crate::heap::Heap;
+use crate::alloc_profile::{AllocationProfile, AllocationReport};
+use crate::checkpoint::{restored_locals, restored_values, Checkpoint, ObjectTable, SavedFrame};
+use crate::chunk::{Chunk, Constant, MAX_LEN, MAX_UNDECLARED_LOCALS};
+use crate::clock::{Clock, VmClock};
+use crate::encode;
+use crate::error::{
+    Backtrace, BacktraceFrame, ErrorWithBacktrace, ErrorWithState, Operand, VmError,
+};
+use crate::function_profile::{FunctionProfile, FunctionReport};
+use crate::heap::{
+    tag, Finalizer, GcObserver, GcReport, GcTrigger, Heap, HeapMode, HeapObject, HeapView, Object,
+    ObjectPtr, Observer,
+};
+use crate::hook::{
+    Fuel, Hook, HookAction, Hooks, ResumePoint, TrapDecision, TrapHandler, VmView, WatchpointHit,
+};
+use crate::instruction::Instruction;
+use crate::map::MapKey;
+use crate::native::{Native, Natives};
 use crate::opcode::OpCode;
+use crate::policy::ExecutionPolicy;
+#[cfg(feature = "profiler")]
+use crate::profile::Profiler;
+use crate::replay::{Event, Log, Tape};
+use crate::shadow::{ShadowStack, Site};
 use crate::value::Value;
+use crate::verifier;
+#[cfg(feature = "verified-fast")]
+use crate::verifier::VerifiedChunk;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::mem;
+use std::ops::Range;
+use std::time::Duration;
 
-#[derive(Debug, Default, Clone, PartialEq)]
+const MAX_FRAMES: usize = 4096;
+// The most decimal places `FmtFloat` formats.
+const MAX_PRECISION: i64 = 100;
+// Instructions between readings of the clock while a deadline is set.
+const DEADLINE_INTERVAL: u64 = 1024;
+
+// The quiet NaN every NaN becomes when NaNs are canonicalized.
+pub const CANONICAL_NAN: u64 = 0x7ff8_0000_0000_0000;
+
+#[derive(Debug, Default)]
 pub struct VM {
     chunk: Chunk,
     ip: usize,
     stack: Vec<Value>,
-    locals: Vec<Value>,
+    locals: Vec<Option<Value>>,
+    frames: Vec<Frame>,
+    // Index of the function the innermost frame is running, if any.
+    function: Option<u16>,
+    // Start of the instruction being executed, or last executed.
+    instruction_ip: usize,
     heap: Heap,
+    roots: Vec<ObjectPtr>,
+    // An error object allocated along with the trap handler, for recovering
+    // with one when there's no room for a new one.
+    spare_error: Option<ObjectPtr>,
+    // Values popped by the running instruction that it still needs; they
+    // are released once it finishes.
+    scratch: Vec<Value>,
+    hooks: Hooks,
+    watched_locals: BTreeSet<usize>,
+    watch_hit: Option<WatchpointHit>,
+    returned: bool,
+    incremental_gc: Option<IncrementalGc>,
+    gc_countdown: usize,
+    // What started the incremental cycle in progress.
+    gc_trigger: GcTrigger,
+    gc_observer: Observer,
+    shadow: Option<ShadowStack>,
+    canonical_nans: bool,
+    function_profile: Option<FunctionProfile>,
+    allocation_profile: Option<AllocationProfile>,
+    natives: Natives,
+    policy: Option<ExecutionPolicy>,
+    clock: VmClock,
+    // A reading of `clock` past which runs stop.
+    deadline: Option<u64>,
+    rng: u64,
+    // Set while a native runs, the one place guest execution hands the VM
+    // to host code.
+    in_native: bool,
+    // Indexed by byte offset, so the ip means the same thing in either
+    // mode; offsets that didn't decode are left to the bytecode path.
+    predecoded: Vec<Option<Instruction>>,
+    execution_mode: ExecutionMode,
+    // Set for a VM made from a `VerifiedChunk`, whose ip only ever rests on
+    // the instruction boundaries the verifier found, until host code moves
+    // it by hand.
+    verified: bool,
+    // Set by the host to skip the stack balance check at each `Return`,
+    // which only takes effect on the verified fast path.
+    unchecked_returns: bool,
+    tape: Option<Tape>,
+    #[cfg(feature = "profiler")]
+    profiler: Option<Profiler>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExecutionMode {
+    // Decodes each instruction from the code as it runs.
+    #[default]
+    Bytecode,
+    // Decodes the whole chunk once, up front.
+    Predecoded,
+}
+
+// Incremental marking traces `steps` gray objects every `interval`
+// instructions once a cycle has started, finishing with a stop-the-world
+// rescan of the roots before sweeping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IncrementalGc {
+    pub steps: usize,
+    pub interval: usize,
+}
+
+// Callers' locals are set aside while the callee runs. The value stack is
+// shared, so arguments and results are passed on it.
+#[derive(Debug, Clone, PartialEq)]
+struct Frame {
+    return_ip: usize,
+    locals: Vec<Option<Value>>,
+    function: Option<u16>,
+    // Stack height once the arguments were popped; the callee's results
+    // are the values above it.
+    base: usize,
 }
 
-pub type Chunk = Vec<u8>;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Termination {
+    // A `Return` with no caller to return to.
+    Return,
+    EndOfChunk,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Finished(Termination),
+    Paused,
+    Watchpoint(WatchpointHit),
+}
+
+// What a call to `execute_all` did. `value` is the top of the stack when it
+// stopped and `instructions` counts only the instructions run by this call.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExecutionOutcome {
+    pub status: Status,
+    pub value: Option<Value>,
+    pub instructions: u64,
+}
 
 impl VM {
     pub fn push(&mut self, val: Value) {
+        self.heap.check_value(val);
         self.stack.push(val)
-    } 
-    pub fn pop(&mut self) -> Value {
-        self.stack.pop().unwrap()
     }
 
-    pub fn get_bool(&mut self) -> bool {
-        let Value::Word(w) = self.pop() else {unreachable!()};
-        w != 0
+    pub fn pop(&mut self) -> Result<Value, VmError> {
+        self.stack.pop().ok_or(VmError::StackUnderflow)
+    }
+
+    pub fn peek(&self) -> Result<Value, VmError> {
+        self.stack.last().copied().ok_or(VmError::StackUnderflow)
+    }
+
+    pub fn get_bool(&mut self) -> Result<bool, VmError> {
+        Ok(self.get_word()? != 0)
+    }
+
+    pub fn get_integer(&mut self) -> Result<i64, VmError> {
+        match self.pop()? {
+            Value::Integer(i) => Ok(i),
+            val => Err(self.type_mismatch("integer", val)),
+        }
+    }
+
+    pub fn get_word(&mut self) -> Result<u64, VmError> {
+        match self.pop()? {
+            Value::Word(w) => Ok(w),
+            val => Err(self.type_mismatch("word", val)),
+        }
+    }
+
+    pub fn get_float(&mut self) -> Result<f64, VmError> {
+        match self.pop()? {
+            Value::Float(f) => Ok(f),
+            val => Err(self.type_mismatch("float", val)),
+        }
+    }
+
+    pub fn get_object(&mut self) -> Result<ObjectPtr, VmError> {
+        let val = self.pop()?;
+        val.get_object_ptr()
+            .ok_or_else(|| self.type_mismatch("object", val))
+    }
+
+    pub fn get_string(&mut self) -> Result<String, VmError> {
+        let val = self.pop()?;
+        val.get_object_ptr()
+            .and_then(|ptr| self.heap.object(ptr).as_string())
+            .ok_or_else(|| self.type_mismatch("string", val))
+    }
+
+    fn get_string_object(&mut self) -> Result<ObjectPtr, VmError> {
+        let val = self.pop()?;
+        val.get_object_ptr()
+            .filter(|&ptr| self.heap.object(ptr).tag == tag::STRING)
+            .ok_or_else(|| self.type_mismatch("string", val))
     }
 
-    pub fn get_integer(&mut self) -> i64 {
-        let Value::Integer(i) = self.pop() else {unreachable!()};
-        i
+    // For an operand popped by the instruction at `instruction_ip`, or by a
+    // native it called.
+    fn type_mismatch(&self, expected: &'static str, found: Value) -> VmError {
+        let code = self.chunk.code();
+        VmError::OperandMismatch {
+            expected,
+            found: Operand::of(found),
+            op: code
+                .get(self.instruction_ip)
+                .and_then(|&byte| OpCode::try_from(byte).ok()),
+            ip: self.instruction_ip,
+        }
     }
+}
 
-    pub fn get_word(&mut self) -> u64 {
-        let Value::Word(w) = self.pop() else {unreachable!()};
-        w
+// Rounds the quotient towards negative infinity.
+fn div_floor(x: i64, y: i64) -> i64 {
+    let q = x.wrapping_div(y);
+    if x.wrapping_rem(y) != 0 && (x < 0) != (y < 0) {
+        q - 1
+    } else {
+        q
     }
+}
+
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+// The `count` elements from `start` of something `len` long, trapping with
+// the start, or the end if only that is past the end, unless they all lie
+// within it.
+fn element_range(start: i64, count: i64, len: usize) -> Result<Range<usize>, VmError> {
+    let out_of_bounds = |index| VmError::IndexOutOfBounds { index, len };
+    let from = usize::try_from(start)
+        .ok()
+        .filter(|&from| from <= len)
+        .ok_or(out_of_bounds(start))?;
+    let to = usize::try_from(count)
+        .map_err(|_| VmError::InvalidLength(count))?
+        .checked_add(from)
+        .filter(|&to| to <= len)
+        .ok_or(out_of_bounds(start.saturating_add(count)))?;
+    Ok(from..to)
+}
 
-    pub fn get_float(&mut self) -> f64 {
-        let Value::Float(f) = self.pop() else {unreachable!()};
-        f
+fn check_len(chunk: &Chunk, max: usize) -> Result<(), VmError> {
+    match chunk.len() {
+        len if len > max => Err(VmError::ChunkTooLarge { len, max }),
+        _ => Ok(()),
     }
 }
 
 impl VM {
-    pub fn new(chunk: Chunk) -> Self {
-        Self {
+    // Panics if the chunk requires features this VM doesn't support or is
+    // longer than `chunk::MAX_LEN`; see `try_new`.
+    pub fn new(chunk: impl Into<Chunk>) -> Self {
+        Self::try_new(chunk).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    pub fn try_new(chunk: impl Into<Chunk>) -> Result<Self, VmError> {
+        let chunk = chunk.into();
+        match chunk.unsupported_features() {
+            0 => {}
+            bits => return Err(VmError::UnsupportedFeature(bits)),
+        }
+        check_len(&chunk, MAX_LEN)?;
+        let locals = vec![None; chunk.max_locals().unwrap_or(0) as usize];
+        Ok(Self {
             chunk,
+            locals,
             ..Default::default()
+        })
+    }
+
+    // Skips the checks the verifier already made: operands are fetched
+    // without bounds checks and static jump targets aren't looked up. Debug
+    // builds still assert both, to catch a verifier that let something
+    // through.
+    #[cfg(feature = "verified-fast")]
+    pub fn from_verified(chunk: VerifiedChunk) -> Result<Self, VmError> {
+        let mut vm = Self::try_new(chunk.into_chunk())?;
+        vm.verified = true;
+        Ok(vm)
+    }
+
+    // For chunks whose functions are known to leave the stack balanced, as
+    // `verifier::verify_stack` proves.
+    #[cfg(feature = "verified-fast")]
+    pub fn set_unchecked_returns(&mut self, unchecked: bool) -> Result<(), VmError> {
+        self.check_not_running()?;
+        self.unchecked_returns = unchecked;
+        Ok(())
+    }
+
+    // Whether the verified fast path is in use.
+    pub fn is_verified(&self) -> bool {
+        cfg!(feature = "verified-fast") && self.verified
+    }
+
+    // Refuses the same chunks as `try_new`.
+    pub fn with_heap_mode(chunk: impl Into<Chunk>, mode: HeapMode) -> Result<Self, VmError> {
+        let mut vm = Self::try_new(chunk)?;
+        vm.set_heap_mode(mode);
+        Ok(vm)
+    }
+
+    // Only for a VM that hasn't allocated yet.
+    pub(crate) fn set_heap_mode(&mut self, mode: HeapMode) {
+        self.heap = Heap::with_mode(mode);
+    }
+
+    pub(crate) fn set_execution_mode(&mut self, mode: ExecutionMode) {
+        self.execution_mode = mode;
+        self.predecoded = match mode {
+            ExecutionMode::Bytecode => Vec::new(),
+            ExecutionMode::Predecoded => {
+                let mut table = vec![None; self.chunk.len()];
+                for (offset, instruction) in self.chunk.instructions().map_while(Result::ok) {
+                    table[offset] = Some(instruction);
+                }
+                table
+            }
+        };
+    }
+
+    pub fn execution_mode(&self) -> ExecutionMode {
+        self.execution_mode
+    }
+
+    // Frees the whole heap and clears the stack, locals and roots, ready to
+    // run the chunk again from the start. Hooks, breakpoints and heap
+    // settings are kept.
+    pub fn reset(&mut self) -> Result<(), VmError> {
+        self.check_not_running()?;
+        self.ip = 0;
+        self.stack.clear();
+        if let Some(frame) = self.frames.drain(..).next() {
+            self.locals = frame.locals;
+        }
+        if let Some(profile) = &mut self.function_profile {
+            profile.unwind();
+        }
+        self.function = None;
+        self.locals.iter_mut().for_each(|local| *local = None);
+        self.roots.clear();
+        self.scratch.clear();
+        self.watch_hit = None;
+        self.returned = false;
+        self.heap.clear();
+        self.spare_error = None;
+        if self.hooks.trap.is_some() {
+            self.allocate_spare_error();
+        }
+        // ids start over with the heap
+        if let Some(profile) = &mut self.allocation_profile {
+            *profile = AllocationProfile::default();
+        }
+        Ok(())
+    }
+
+    pub fn stack(&self) -> &[Value] {
+        &self.stack
+    }
+
+    pub fn local(&self, index: usize) -> Option<Value> {
+        self.locals.get(index).copied().flatten()
+    }
+
+    // Locals of the innermost frame.
+    pub fn locals(&self) -> &[Option<Value>] {
+        &self.locals
+    }
+
+    // Initialized locals of the innermost frame, named when the function
+    // it's running names them.
+    pub fn frame_locals(&self) -> impl Iterator<Item = (Option<&str>, Value)> + '_ {
+        self.locals.iter().enumerate().filter_map(|(slot, local)| {
+            let name = self.local_name(slot as u16);
+            local.map(|val| (name, val))
+        })
+    }
+
+    fn local_name(&self, slot: u16) -> Option<&str> {
+        let function = self.chunk.functions().get(self.function? as usize)?;
+        function.local_name(slot)
+    }
+
+    // The slot the running function names `name`.
+    pub(crate) fn named_local(&self, name: &str) -> Option<u16> {
+        let function = self.chunk.functions().get(self.function? as usize)?;
+        let (slot, _) = function.locals.iter().find(|(_, named)| named == name)?;
+        Some(*slot)
+    }
+
+    // Arguments are passed to a chunk by storing them into its leading locals
+    // before execution starts.
+    pub fn set_local(&mut self, index: usize, val: Value) -> Result<(), VmError> {
+        self.heap.check_value(val);
+        self.check_local(index)?;
+        if index >= self.locals.len() {
+            self.locals.resize(index + 1, None);
+        }
+        self.locals[index] = Some(val);
+        Ok(())
+    }
+
+    fn check_local(&self, index: usize) -> Result<(), VmError> {
+        let max = self.chunk.max_locals().unwrap_or(MAX_UNDECLARED_LOCALS);
+        match index >= max as usize {
+            true => Err(VmError::LocalOutOfRange { index, max }),
+            false => Ok(()),
+        }
+    }
+
+    // Drops the top level's locals past the highest one the code it can
+    // still run accesses, returning how many are left. Callees can't see
+    // them, so this works while a call is running too. Code appended
+    // afterwards finds the dropped locals never stored.
+    pub fn compact_locals(&mut self) -> usize {
+        let (locals, ip) = match self.frames.first_mut() {
+            Some(frame) => (&mut frame.locals, frame.return_ip),
+            None => (&mut self.locals, self.ip),
+        };
+        let len = verifier::max_local(&self.chunk, ip).map_or(0, |max| max as usize + 1);
+        locals.truncate(len);
+        locals.shrink_to_fit();
+        locals.len()
+    }
+
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    // Shareable with other threads while this VM stays paused.
+    pub fn heap_view(&self) -> HeapView<'_> {
+        HeapView::new(&self.heap)
+    }
+
+    // Checks every value popped or pushed against the opcode's signature,
+    // at some cost, so that a mistyped operand is reported along with the
+    // instruction that produced it.
+    pub fn set_shadow_checking(&mut self, enabled: bool) -> Result<(), VmError> {
+        self.check_not_running()?;
+        self.shadow = enabled.then(ShadowStack::default);
+        Ok(())
+    }
+
+    pub fn shadow_checking(&self) -> bool {
+        self.shadow.is_some()
+    }
+
+    // Replaces every NaN an instruction pushes as a float with
+    // `CANONICAL_NAN`, so that NaN payloads, which can differ between
+    // platforms, are never observable.
+    pub fn set_canonical_nans(&mut self, enabled: bool) -> Result<(), VmError> {
+        self.check_not_running()?;
+        self.canonical_nans = enabled;
+        Ok(())
+    }
+
+    pub fn canonical_nans(&self) -> bool {
+        self.canonical_nans
+    }
+
+    // Counts the instructions each function runs from now on; see
+    // `FunctionProfile`. Disabling it discards the counts.
+    pub fn set_function_profiling(&mut self, enabled: bool) -> Result<(), VmError> {
+        self.check_not_running()?;
+        let callers = self.frames.iter().skip(1).map(|frame| frame.function);
+        let active = callers.chain([self.function]).flatten();
+        self.function_profile =
+            enabled.then(|| FunctionProfile::new(self.chunk.functions().len(), active));
+        Ok(())
+    }
+
+    pub fn function_profile(&self) -> Option<FunctionReport> {
+        let profile = self.function_profile.as_ref()?;
+        Some(profile.report(&self.chunk))
+    }
+
+    // Records where each object is allocated from now on; see
+    // `AllocationProfile`. Disabling it discards the sites.
+    pub fn set_allocation_profiling(&mut self, enabled: bool) -> Result<(), VmError> {
+        self.check_not_running()?;
+        self.allocation_profile = enabled.then(AllocationProfile::default);
+        Ok(())
+    }
+
+    pub fn allocation_profile(&self, lines: &[(usize, usize)]) -> Option<AllocationReport> {
+        let profile = self.allocation_profile.as_ref()?;
+        Some(profile.report(&self.chunk, &self.heap, lines))
+    }
+
+    pub fn set_gc_stress(&mut self, stress: bool) -> Result<(), VmError> {
+        self.check_not_running()?;
+        self.heap.set_stress(stress);
+        Ok(())
+    }
+
+    pub fn set_free_list_cap(&mut self, cap: usize) -> Result<(), VmError> {
+        self.check_not_running()?;
+        self.heap.set_free_list_cap(cap);
+        Ok(())
+    }
+
+    pub fn register_finalizer(&mut self, tag: u8, finalizer: Finalizer) -> Result<(), VmError> {
+        self.check_not_running()?;
+        self.heap.register_finalizer(tag, finalizer);
+        Ok(())
+    }
+
+    // Rooted objects survive collections until a matching `unroot`.
+    // Roots are counted, so rooting the same object twice needs two unroots.
+    pub fn root(&mut self, ptr: ObjectPtr) {
+        self.heap.check(ptr);
+        self.roots.push(ptr);
+    }
+
+    pub fn unroot(&mut self, ptr: ObjectPtr) -> bool {
+        if let Some(i) = self.roots.iter().rposition(|&root| root == ptr) {
+            self.roots.swap_remove(i);
+            true
+        } else {
+            false
         }
     }
 
-    pub fn mark_objects(&self) {
-        for val in &self.stack {
+    // Keeps a value the current instruction has popped alive until the
+    // instruction finishes. Handlers and natives that allocate after popping
+    // objects must hold them, since the allocation may collect.
+    pub fn hold(&mut self, val: Value) {
+        self.heap.check_value(val);
+        self.scratch.push(val);
+    }
+
+    // The stack, held values and the locals of every frame, including
+    // suspended callers.
+    fn frame_values(&self) -> impl Iterator<Item = &Value> {
+        let saved = self.frames.iter().flat_map(|frame| &frame.locals);
+        self.stack
+            .iter()
+            .chain(&self.scratch)
+            .chain(self.locals.iter().chain(saved).flatten())
+    }
+
+    fn mark_objects(&self) {
+        for val in self.frame_values() {
             if let Some(ptr) = val.get_object_ptr() {
-                ptr.mark();
+                ptr.node().mark();
             }
         }
+
+        for ptr in self.roots.iter().chain(&self.spare_error) {
+            ptr.node().mark();
+        }
     }
 
-    pub fn eof(&self) -> bool {
-        self.ip >= self.chunk.len()
+    pub fn collect_garbage(&mut self) {
+        self.collect(GcTrigger::Explicit);
     }
 
-    pub fn advance(&mut self) -> u8 {
-        let b = self.chunk[self.ip];
-        eprintln!("ip: {}", self.ip);
-        self.ip += 1;
-        b
+    // Objects already blackened by an unfinished incremental cycle wouldn't
+    // be traced again, so that cycle is finished instead.
+    fn collect(&mut self, trigger: GcTrigger) {
+        if self.heap.is_marking() {
+            self.finish_cycle(trigger);
+            return;
+        }
+        self.pause(trigger, |vm| {
+            vm.mark_objects();
+            vm.heap.sweep();
+        });
+    }
+
+    // Called after each collection, with the heap's stats already updated.
+    pub fn set_gc_observer(&mut self, observer: Option<GcObserver>) -> Result<(), VmError> {
+        self.check_not_running()?;
+        self.gc_observer = Observer(observer);
+        Ok(())
     }
 
-    pub fn advance2(&mut self) -> u16 {
-        u16::from_be_bytes([self.advance(), self.advance()])
+    // Runs the stop-the-world part of a collection and reports it.
+    fn pause<T>(&mut self, trigger: GcTrigger, collect: impl FnOnce(&mut Self) -> T) -> T {
+        let (objects_before, bytes_before) = (self.heap.len(), self.heap.bytes());
+        let started = self.gc_observer.0.is_some().then(|| self.clock.0.now());
+        let result = collect(self);
+        if let Some(profile) = &mut self.allocation_profile {
+            profile.retain(&self.heap);
+        }
+        if self.heap.mode() == HeapMode::Arena {
+            return result;
+        }
+        let nanos = started.map_or(0, |started| self.clock.0.now().saturating_sub(started));
+        let report = GcReport {
+            trigger,
+            nanos,
+            objects_before,
+            objects_after: self.heap.len(),
+            bytes_before,
+            bytes_after: self.heap.bytes(),
+        };
+        self.heap.record_collection(report);
+        debug!(
+            "collected {} of {objects_before} objects ({trigger:?}), next at {}",
+            objects_before - report.objects_after,
+            self.heap.threshold()
+        );
+        if let Some(observer) = &mut self.gc_observer.0 {
+            observer(&report);
+        }
+        result
     }
 
-    pub fn advance4(&mut self) -> u32 {
-        u32::from_be_bytes([
-            self.advance(),
-            self.advance(),
-            self.advance(),
-            self.advance(),
-        ])
+    // A full collection that also moves the survivors next to each other.
+    // Every pointer held by the VM is updated; pointers kept by the host
+    // must be looked up in the returned map, as the old ones are dangling.
+    pub fn compact(&mut self) -> HashMap<ObjectPtr, ObjectPtr> {
+        let forward = self.pause(GcTrigger::Explicit, |vm| {
+            if vm.heap.is_marking() {
+                vm.shade_roots();
+                vm.heap.mark_step(usize::MAX);
+            } else {
+                vm.mark_objects();
+            }
+            vm.heap.compact()
+        });
+
+        let stack = self.stack.iter_mut().chain(&mut self.scratch);
+        let saved = self.frames.iter_mut().flat_map(|frame| &mut frame.locals);
+        let locals = self.locals.iter_mut().chain(saved).flatten();
+        for val in stack.chain(locals) {
+            if let Value::ObjectPtr(ptr) = val {
+                *ptr = forward[ptr];
+            }
+        }
+        for ptr in self.roots.iter_mut().chain(&mut self.spare_error) {
+            *ptr = forward[ptr];
+        }
+        forward
     }
 
-    pub fn advance8(&mut self) -> u64 {
-        u64::from_be_bytes([
-            self.advance(),
-            self.advance(),
-            self.advance(),
-            self.advance(),
-            self.advance(),
-            self.advance(),
-            self.advance(),
-            self.advance(),
-        ])
+    // Like the policy, this can't be changed from a native.
+    pub fn set_incremental_gc(&mut self, config: Option<IncrementalGc>) -> Result<(), VmError> {
+        self.check_not_running()?;
+        self.incremental_gc = config;
+        self.gc_countdown = config.map_or(0, |config| config.interval);
+        Ok(())
     }
 
-    pub fn execute_all(&mut self) {
-        while !self.eof() {
-            self.execute();
+    fn shade_roots(&mut self) {
+        let saved = self.frames.iter().flat_map(|frame| &frame.locals);
+        let locals = self.locals.iter().chain(saved).flatten();
+        let values = self.stack.iter().chain(&self.scratch).chain(locals);
+        let values = values.filter_map(Value::get_object_ptr);
+        let roots = self.roots.iter().chain(&self.spare_error).copied();
+        for ptr in values.chain(roots) {
+            self.heap.shade(ptr);
         }
     }
 
-    pub fn execute(&mut self) {
-        use OpCode::*;
-        let op = self.advance().try_into().unwrap();
-        match op {
-            Return => self.ret(),
-            Goto => self.goto(),
-            GotoIf => self.goto_if(),
-            Load => self.load(),
-            Store => self.store(),
-            ImmI => self.imm_i(),
-            ImmF => self.imm_f(),
-            ImmW => self.imm_w(),
-            AddI => self.add_i(),
-            SubI => self.sub_i(),
-            MulI => self.mul_i(),
-            DivI => self.div_i(),
-            CmpEqI => self.cmpeq_i(),
-            CmpGtI => self.cmpgt_i(),
-            CmpGeI => self.cmpge_i(),
-            CmpLtI => self.cmplt_i(),
-            CmpLeI => self.cmple_i(),
+    pub fn start_gc_cycle(&mut self) {
+        self.start_cycle(GcTrigger::Explicit);
+    }
+
+    fn start_cycle(&mut self, trigger: GcTrigger) {
+        if !self.heap.is_marking() {
+            self.gc_trigger = trigger;
+            self.heap.start_marking();
+            self.shade_roots();
         }
     }
 
-    fn ret(&mut self) {
-        self.ip = self.chunk.len();
+    // Returns whether there is marking work left in the current cycle.
+    pub fn gc_step(&mut self, budget: usize) -> bool {
+        self.heap.mark_step(budget)
+    }
+
+    // The stack and locals aren't covered by the write barrier, so they
+    // are rescanned before the final trace.
+    pub fn finish_gc_cycle(&mut self) {
+        self.finish_cycle(GcTrigger::Explicit);
     }
-    
-    fn goto(&mut self) {
-        let index = self.advance2() as usize;
-        self.ip = index;
+
+    fn finish_cycle(&mut self, trigger: GcTrigger) {
+        self.pause(trigger, |vm| {
+            vm.shade_roots();
+            vm.heap.mark_step(usize::MAX);
+            vm.heap.sweep();
+        });
     }
 
-    fn goto_if(&mut self) {
-        let p = self.get_bool();
-        let index = self.advance2() as usize;
-        if p {
-                self.ip = index;
+    fn gc_tick(&mut self) {
+        let Some(config) = self.incremental_gc else {
+            return;
+        };
+        self.gc_countdown = self.gc_countdown.saturating_sub(1);
+        if self.gc_countdown == 0 {
+            self.gc_countdown = config.interval;
+            if !self.gc_step(config.steps) {
+                self.finish_cycle(self.gc_trigger);
+            }
         }
     }
 
-    fn load(&mut self) {
-        let index = self.advance2() as usize;
-        let variable = self.locals[index];
-        eprintln!("Loading {variable:?} from index {index}");
-        self.push(variable)
+    pub fn alloc(&mut self, obj: Object) -> ObjectPtr {
+        self.maybe_collect();
+        let ptr = self.heap.new_object(obj);
+        if let Some(tape) = &mut self.tape {
+            tape.allocated(ptr.id());
+        }
+        if let Some(profile) = &mut self.allocation_profile {
+            profile.record(ptr.id(), self.instruction_ip);
+        }
+        ptr
     }
 
-    fn store(&mut self) {
-        let index = self.advance2() as usize;
-        let value = self.pop();
-        eprintln!("Storing {value:?} at index {index}");
-        if self.locals.get(index).is_some() {
-            self.locals[index] = value;
-        } else {
-            self.locals.push(value);
+    fn maybe_collect(&mut self) {
+        if self.heap.should_collect() {
+            match self.incremental_gc {
+                _ if self.heap.is_stress() => self.collect(GcTrigger::Stress),
+                Some(_) => self.start_cycle(GcTrigger::Threshold),
+                None => self.collect(GcTrigger::Threshold),
+            }
         }
     }
 
-    fn imm_i(&mut self) {
-        let i = self.advance8() as i64;
-        self.stack.push(Value::Integer(i))
+    // The returned object is rooted on behalf of the host; call `unroot`
+    // once it is no longer needed.
+    pub fn alloc_object(&mut self, tag: u8, fields: Vec<Value>) -> ObjectPtr {
+        let ptr = self.alloc(Object::new(tag, fields));
+        self.root(ptr);
+        ptr
     }
 
-    fn imm_f(&mut self) {
-        let f = self.advance8() as f64;
-        self.stack.push(Value::Float(f))
+    pub fn alloc_string(&mut self, s: &str) -> ObjectPtr {
+        let ptr = self.alloc(Object::string(s));
+        self.root(ptr);
+        ptr
     }
 
-    fn imm_w(&mut self) {
-        let w = self.advance8();
-        self.stack.push(Value::Word(w))
+    // Rooted like `alloc_string`, whether or not the string was already
+    // interned.
+    pub fn intern_string(&mut self, s: &str) -> ObjectPtr {
+        let ptr = match self.heap.lookup_interned(s) {
+            Some(ptr) => ptr,
+            None => {
+                let ptr = self.alloc(Object::string(s));
+                self.heap.intern(ptr).unwrap()
+            }
+        };
+        self.root(ptr);
+        ptr
     }
 
-    fn add_i(&mut self) {
-        let x = self.get_integer();
-        let y = self.get_integer();
-        self.push(Value::Integer(x + y))
+    pub fn alloc_map(&mut self) -> ObjectPtr {
+        let ptr = self.alloc(Object::map());
+        self.root(ptr);
+        ptr
     }
 
-    fn sub_i(&mut self) {
-        let x = self.get_integer();
-        let y = self.get_integer();
-        eprintln!("Subtracting {x} - {y}");
-        self.push(Value::Integer(x - y))
+    pub fn alloc_buffer(&mut self, bytes: Vec<u8>) -> ObjectPtr {
+        let ptr = self.alloc(Object::buffer(bytes));
+        self.root(ptr);
+        ptr
     }
 
-    fn mul_i(&mut self) {
-        let x = self.get_integer();
-        let y = self.get_integer();
-        eprintln!("Multiplying {x} * {y}");
-        self.push(Value::Integer(x * y))
+    pub fn buffer_bytes(&self, ptr: ObjectPtr) -> Result<&[u8], VmError> {
+        self.heap.get(ptr).as_buffer().ok_or(VmError::TypeMismatch {
+            expected: "buffer",
+            found: "object",
+        })
     }
 
-    fn div_i(&mut self) {
-        let x = self.get_integer();
-        let y = self.get_integer();
-        self.push(Value::Integer(x / y))
+    pub fn buffer_bytes_mut(&mut self, ptr: ObjectPtr) -> Result<&mut [u8], VmError> {
+        self.heap.check(ptr);
+        self.heap
+            .get_mut(ptr)
+            .as_buffer_mut()
+            .ok_or(VmError::TypeMismatch {
+                expected: "buffer",
+                found: "object",
+            })
     }
 
-    fn cmpeq_i(&mut self) {
-        let x = self.get_integer();
-        let y = self.get_integer();
-        self.push(Value::Word((x == y) as u64))
+    pub fn map_get(&self, map: ObjectPtr, key: Value) -> Result<Option<Value>, VmError> {
+        let key = MapKey::new(key, &self.heap)?;
+        self.heap.get(map).map_get(&key)
     }
 
-    fn cmpgt_i(&mut self) {
-        let x = self.get_integer();
-        let y = self.get_integer();
-        eprintln!("Testing {x} > {y}");
-        self.push(Value::Word((x > y) as u64))
+    // String keys are interned, so that no key the map holds can change
+    // under its index; see `set_object_field`.
+    pub fn map_set(&mut self, map: ObjectPtr, key: Value, val: Value) -> Result<(), VmError> {
+        self.heap.check(map);
+        self.heap.check_value(val);
+        let map_key = MapKey::new(key, &self.heap)?;
+        let key = match key {
+            Value::ObjectPtr(ptr) => Value::ObjectPtr(self.heap.intern(ptr).unwrap()),
+            key => key,
+        };
+        self.heap.get_mut(map).map_set(map_key, key, val)?;
+        self.heap.write_barrier(map);
+        Ok(())
     }
 
-    fn cmpge_i(&mut self) {
-        let x = self.get_integer();
-        let y = self.get_integer();
-        self.push(Value::Word((x >= y) as u64))
+    pub fn object_field(&self, ptr: ObjectPtr, index: usize) -> Result<Value, VmError> {
+        let fields = &self.heap.get(ptr).fields;
+        fields.get(index).copied().ok_or(VmError::FieldOutOfBounds {
+            index,
+            len: fields.len(),
+        })
     }
 
-    fn cmplt_i(&mut self) {
-        let x = self.get_integer();
-        let y = self.get_integer();
-        self.push(Value::Word((x < y) as u64))
+    pub fn set_object_field(
+        &mut self,
+        ptr: ObjectPtr,
+        index: usize,
+        val: Value,
+    ) -> Result<(), VmError> {
+        self.heap.check_value(val);
+        if self.heap.get(ptr).is_map() {
+            return Err(VmError::TypeMismatch {
+                expected: "object",
+                found: "map",
+            });
+        }
+        // The intern table is keyed by contents, so interned strings can't
+        // change.
+        if ptr.node().is_interned() {
+            return Err(VmError::TypeMismatch {
+                expected: "object",
+                found: "interned string",
+            });
+        }
+        let fields = &mut self.heap.get_mut(ptr).fields;
+        let len = fields.len();
+        let field = fields
+            .get_mut(index)
+            .ok_or(VmError::FieldOutOfBounds { index, len })?;
+        *field = val;
+        self.heap.write_barrier(ptr);
+        Ok(())
     }
 
-    fn cmple_i(&mut self) {
-        let x = self.get_integer();
-        let y = self.get_integer();
-        self.push(Value::Word((x <= y) as u64))
+    pub fn ip(&self) -> usize {
+        self.ip
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use super::OpCode::*;
+    // Like the other settings below that bound or observe a run, hooks can't
+    // be changed by a native while it runs.
+    pub fn add_hook(&mut self, hook: Box<dyn Hook>) -> Result<(), VmError> {
+        self.check_not_running()?;
+        self.hooks.user.push(hook);
+        Ok(())
+    }
 
-    #[test]
-    fn test_factorial() {
-        #[rustfmt::skip]
-        let factorial = vec![
-            ImmI  as u8, 0, 0, 0, 0, 0, 0, 0, 5,
-            Store as u8, 0, 0,
+    // Fuel is capped by the policy's limit, if there is one.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) -> Result<(), VmError> {
+        self.check_not_running()?;
+        self.refuel(fuel);
+        Ok(())
+    }
 
-            // x = 1
-            ImmI  as u8, 0, 0, 0, 0, 0, 0, 0, 1,
-            Store as u8, 0, 1,
+    pub(crate) fn refuel(&mut self, fuel: Option<u64>) {
+        let max = self.policy.as_ref().and_then(|policy| policy.max_fuel);
+        let fuel = match (fuel, max) {
+            (Some(fuel), Some(max)) => Some(fuel.min(max)),
+            (fuel, max) => fuel.or(max),
+        };
+        self.hooks.fuel = fuel.map(Fuel);
+    }
 
-            // while n > 1 {
-            Load   as u8, 0, 0,
-            ImmI   as u8, 0, 0, 0, 0, 0, 0, 0, 1,
-            CmpGtI as u8,
-            GotoIf as u8, 0, 69,
+    pub fn fuel(&self) -> Option<u64> {
+        self.hooks.fuel.map(|Fuel(fuel)| fuel)
+    }
 
-            // x = x * n
-            Load  as u8, 0, 1,
-            Load  as u8, 0, 0,
-            MulI  as u8,
-            Store as u8, 0, 1,
+    // Runs stop with `DeadlineExceeded` once the clock reads past the
+    // deadline. The clock is only read every `DEADLINE_INTERVAL`
+    // instructions, and at the start of each run, so a run may overshoot.
+    pub fn set_deadline(&mut self, deadline: Option<u64>) -> Result<(), VmError> {
+        self.check_not_running()?;
+        self.deadline = deadline;
+        Ok(())
+    }
 
-            // n = n - 1
-            ImmI  as u8, 0, 0, 0, 0, 0, 0, 0, 1,
-            Load  as u8, 0, 0,
-            SubI  as u8,
-            Store as u8, 0, 0,
+    pub fn deadline(&self) -> Option<u64> {
+        self.deadline
+    }
 
-            // }
-            Goto  as u8, 0, 24,
+    // Sets the deadline `timeout` from now, by the VM's clock.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<(), VmError> {
+        self.check_not_running()?;
+        self.deadline = timeout.map(|timeout| {
+            let nanos = timeout.as_nanos().try_into().unwrap_or(u64::MAX);
+            self.clock.0.now().saturating_add(nanos)
+        });
+        Ok(())
+    }
 
-            // return x
-            Load   as u8, 0, 1,
-            Return as u8,
-        ];
+    pub fn set_trap_handler(&mut self, handler: TrapHandler) -> Result<(), VmError> {
+        self.check_not_running()?;
+        self.hooks.trap = Some(handler);
+        if self.spare_error.is_none() {
+            self.allocate_spare_error();
+        }
+        Ok(())
+    }
 
-        let mut vm = VM {
-            chunk: factorial,
-            ..Default::default()
-        };
-        vm.execute_all();
-        assert_eq!(vm.stack, vec![Value::Integer(120)]);
+    fn allocate_spare_error(&mut self) {
+        self.spare_error = Some(self.alloc(Object::error(0, 0, Value::Null)));
+    }
+
+    pub fn clear_trap_handler(&mut self) -> Result<(), VmError> {
+        self.check_not_running()?;
+        self.hooks.trap = None;
+        self.spare_error = None;
+        Ok(())
+    }
+
+    pub fn register_native(&mut self, name: &str, native: Native) -> Result<(), VmError> {
+        self.check_not_running()?;
+        self.natives.insert(name, native);
+        Ok(())
+    }
+
+    // Every VM starts from the same seed, so runs are reproducible unless
+    // seeded otherwise. The state is a single word: seeding with a value
+    // read from `rng_state` replays the sequence from that point.
+    pub fn seed_rng(&mut self, seed: u64) -> Result<(), VmError> {
+        self.check_not_running()?;
+        self.rng = seed;
+        Ok(())
+    }
+
+    pub fn rng_state(&self) -> u64 {
+        self.rng
+    }
+
+    // A native swapping the clock could stop the deadline from ever
+    // passing, so only the host can.
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) -> Result<(), VmError> {
+        self.check_not_running()?;
+        self.clock = VmClock(clock);
+        Ok(())
+    }
+
+    // Logs every step and every input the run takes from the host, until
+    // `take_log`.
+    pub fn record(&mut self) -> Result<(), VmError> {
+        self.check_not_running()?;
+        self.tape = Some(Tape::recording(self.chunk.content_hash()));
+        Ok(())
+    }
+
+    // Runs with the inputs of a recording of the same chunk instead of the
+    // clock, the rng, the natives and the trap handler, failing once the
+    // run stops matching it. The replay is recorded in turn. A log that
+    // names a different chunk by its hash is refused up front.
+    pub fn replay(&mut self, log: Log) -> Result<(), VmError> {
+        self.check_not_running()?;
+        let hash = self.chunk.content_hash();
+        if log.chunk_hash().is_some_and(|recorded| recorded != hash) {
+            return Err(VmError::HashMismatch);
+        }
+        self.tape = Some(Tape::replaying(log, hash));
+        Ok(())
+    }
+
+    pub fn take_log(&mut self) -> Result<Option<Log>, VmError> {
+        self.check_not_running()?;
+        Ok(self.tape.take().map(Tape::into_log))
+    }
+
+    // Only a run stopped between instructions can be checkpointed, as when
+    // fuel or a deadline runs out; a native can't take one of the run it's
+    // part of.
+    pub fn checkpoint(&self) -> Result<Checkpoint, VmError> {
+        self.check_not_running()?;
+        let table = ObjectTable::new(&self.heap, self.frame_values());
+        let frames = self.frames.iter().map(|frame| SavedFrame {
+            return_ip: frame.return_ip,
+            locals: table.save_locals(&frame.locals),
+            function: frame.function,
+            base: frame.base,
+        });
+        Ok(Checkpoint {
+            chunk_hash: self.chunk.content_hash(),
+            ip: self.ip,
+            instruction_ip: self.instruction_ip,
+            function: self.function,
+            rng: self.rng,
+            stack: table.save_values(&self.stack),
+            locals: table.save_locals(&self.locals),
+            frames: frames.collect(),
+            objects: table.objects(&self.heap),
+        })
+    }
+
+    // Picks up the run a checkpoint was taken of in place of this VM's own,
+    // which is reset first. Natives, hooks, fuel and heap settings stay this
+    // VM's, so the host sets them up again as it did for the original. A
+    // checkpoint of a different chunk is refused.
+    pub fn restore(&mut self, checkpoint: &Checkpoint) -> Result<(), VmError> {
+        self.check_not_running()?;
+        if checkpoint.chunk_hash != self.chunk.content_hash() {
+            return Err(VmError::HashMismatch);
+        }
+        checkpoint
+            .check_frames(&self.chunk)
+            .map_err(VmError::InvalidCheckpoint)?;
+        self.reset()?;
+        let ptrs = checkpoint.restore_objects(&mut self.heap);
+        self.stack = restored_values(&ptrs, &checkpoint.stack);
+        self.locals = restored_locals(&ptrs, &checkpoint.locals);
+        self.frames = checkpoint
+            .frames
+            .iter()
+            .map(|frame| Frame {
+                return_ip: frame.return_ip,
+                locals: restored_locals(&ptrs, &frame.locals),
+                function: frame.function,
+                base: frame.base,
+            })
+            .collect();
+        self.ip = checkpoint.ip;
+        self.instruction_ip = checkpoint.instruction_ip;
+        self.function = checkpoint.function;
+        self.rng = checkpoint.rng;
+        // the ip comes from outside, as when the host reads operands itself
+        self.verified = false;
+        Ok(())
+    }
+
+    #[cfg(feature = "profiler")]
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) -> Result<(), VmError> {
+        self.check_not_running()?;
+        self.profiler = profiler;
+        Ok(())
+    }
+
+    #[cfg(feature = "profiler")]
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    // Refused while a native is running, so that it can't lift the limits
+    // of the code that called it.
+    pub fn set_policy(&mut self, policy: Option<ExecutionPolicy>) -> Result<(), VmError> {
+        self.check_not_running()?;
+        if let Some(policy) = &policy {
+            check_len(&self.chunk, policy.code_len_limit())?;
+        }
+        self.policy = policy;
+        self.refuel(self.fuel());
+        Ok(())
+    }
+
+    fn check_not_running(&self) -> Result<(), VmError> {
+        match self.in_native {
+            true => Err(VmError::ReconfiguredWhileRunning),
+            false => Ok(()),
+        }
+    }
+
+    pub fn policy(&self) -> Option<&ExecutionPolicy> {
+        self.policy.as_ref()
+    }
+
+    pub fn set_breakpoint(&mut self, ip: usize) -> Result<bool, VmError> {
+        self.check_not_running()?;
+        Ok(self.hooks.breakpoints.insert(ip))
+    }
+
+    pub fn clear_breakpoint(&mut self, ip: usize) -> Result<bool, VmError> {
+        self.check_not_running()?;
+        Ok(self.hooks.breakpoints.remove(ip))
     }
-}
\ No newline at end of file
+
+    // Execution pauses right after any instruction that writes a watched
+    // local, reporting the value it held beforehand.
+    pub fn watch_local(&mut self, index: usize) -> bool {
+        self.watched_locals.insert(index)
+    }
+
+    pub fn unwatch_local(&mut self, index: usize) -> bool {
+        self.watched_locals.remove(&index)
+    }
+
+    // Everything relevant to a trap: the code around the ip, the stack from
+    // bottom to top, the locals, the suspended frames and the heap.
+    pub fn dump_state(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        writeln!(out, "ip={} of {}", self.ip, self.chunk.len())?;
+        let mut offset = self.ip;
+        for _ in 0..4 {
+            if offset >= self.chunk.len() {
+                break;
+            }
+            let marker = if offset == self.ip { '>' } else { ' ' };
+            match Instruction::decode(self.chunk.code(), offset) {
+                Ok(instruction) => {
+                    write!(out, "  {marker} {offset:>5}: {instruction:?}")?;
+                    if let Instruction::Load(slot)
+                    | Instruction::Store(slot)
+                    | Instruction::StoreIf(slot)
+                    | Instruction::LoadOrDefault(slot)
+                    | Instruction::AddToLocal(slot)
+                    | Instruction::MulToLocal(slot)
+                    | Instruction::MinToLocal(slot)
+                    | Instruction::MaxToLocal(slot) = instruction
+                    {
+                        if let Some(name) = self.local_name(slot) {
+                            write!(out, "  ; {name}")?;
+                        }
+                    }
+                    writeln!(out)?;
+                    offset += instruction.encoded_len();
+                }
+                Err(err) => {
+                    writeln!(out, "  {marker} {offset:>5}: {err}")?;
+                    break;
+                }
+            }
+        }
+
+        writeln!(out, "stack ({}):", self.stack.len())?;
+        for (index, val) in self.stack.iter().enumerate() {
+            writeln!(out, "  [{index}] {}", self.describe(val))?;
+        }
+        writeln!(out, "locals ({}):", self.locals.len())?;
+        for (index, local) in self.locals.iter().enumerate() {
+            match local {
+                Some(val) => writeln!(out, "  [{index}] {}", self.describe(val))?,
+                None => writeln!(out, "  [{index}] uninitialized")?,
+            }
+        }
+        if !self.frames.is_empty() {
+            writeln!(out, "frames ({}):", self.frames.len())?;
+            for (depth, frame) in self.frames.iter().enumerate().rev() {
+                write!(out, "  #{depth} returns to {}, locals:", frame.return_ip)?;
+                for local in &frame.locals {
+                    match local {
+                        Some(val) => write!(out, " {}", self.describe(val))?,
+                        None => write!(out, " uninitialized")?,
+                    }
+                }
+                writeln!(out)?;
+            }
+        }
+
+        let stats = self.heap.stats();
+        write!(
+            out,
+            "heap: {} objects, {} bytes, {} collections, {} freed",
+            self.heap.len(),
+            self.heap.bytes(),
+            stats.collections,
+            stats.freed,
+        )
+    }
+
+    pub fn dump_heap(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        self.heap.dump(out)
+    }
+
+    fn describe(&self, val: &Value) -> String {
+        match val {
+            Value::ObjectPtr(_) => format!("{val} {:#}", val.display(&self.heap)),
+            val => val.to_string(),
+        }
+    }
+
+    pub fn eof(&self) -> bool {
+        self.ip >= self.chunk.len()
+    }
+
+    // Host code reading operands itself leaves the ip wherever it likes, so
+    // the advance functions give up the verified fast path.
+    pub fn advance(&mut self) -> Result<u8, VmError> {
+        self.verified = false;
+        let b = *self
+            .chunk
+            .code()
+            .get(self.ip)
+            .ok_or(VmError::UnexpectedEof)?;
+        self.ip += 1;
+        Ok(b)
+    }
+
+    pub fn advance2(&mut self) -> Result<u16, VmError> {
+        self.advance_by(2, encode::read_u16)
+    }
+
+    pub fn advance4(&mut self) -> Result<u32, VmError> {
+        self.advance_by(4, encode::read_u32)
+    }
+
+    pub fn advance8(&mut self) -> Result<u64, VmError> {
+        self.advance_by(8, encode::read_u64)
+    }
+
+    fn advance_by<T>(&mut self, len: usize, read: fn(&[u8]) -> Option<T>) -> Result<T, VmError> {
+        self.verified = false;
+        let code = self.chunk.code().get(self.ip..).unwrap_or_default();
+        let v = read(code).ok_or(VmError::UnexpectedEof)?;
+        self.ip += len;
+        Ok(v)
+    }
+
+    // Appends `instructions` to the chunk and runs them to completion, as a
+    // REPL does, keeping the stack, the top level's locals and the heap from
+    // earlier runs. Only the new code is verified; it runs as part of the top
+    // level and may jump back into the old code. A run left inside a call by
+    // a trap is abandoned first.
+    pub fn append_and_run(
+        &mut self,
+        instructions: &[Instruction],
+    ) -> Result<ExecutionOutcome, VmError> {
+        self.check_not_running()?;
+        let mut code = Vec::new();
+        for instruction in instructions {
+            instruction.encode_into(&mut code);
+        }
+        let max = self
+            .policy
+            .as_ref()
+            .map_or(MAX_LEN, ExecutionPolicy::code_len_limit);
+        let len = self.chunk.len().saturating_add(code.len());
+        if len > max {
+            return Err(VmError::ChunkTooLarge { len, max });
+        }
+        verifier::verify_appended(&self.chunk, &code).map_err(VmError::InvalidChunk)?;
+
+        let start = self.chunk.len();
+        self.chunk.append(&code);
+        if self.execution_mode == ExecutionMode::Predecoded {
+            self.predecoded.resize(len, None);
+            for (offset, instruction) in Chunk::new(code).instructions().map_while(Result::ok) {
+                self.predecoded[start + offset] = Some(instruction);
+            }
+        }
+        if let Some(frame) = self.frames.drain(..).next() {
+            self.locals = frame.locals;
+        }
+        if let Some(profile) = &mut self.function_profile {
+            profile.unwind();
+        }
+        self.function = None;
+        self.ip = start;
+        self.returned = false;
+        self.execute_all()
+    }
+
+    pub fn execute_all(&mut self) -> Result<ExecutionOutcome, VmError> {
+        let mut instructions = 0;
+        let status = self.run(&mut instructions)?;
+        Ok(ExecutionOutcome {
+            status,
+            value: self.stack.last().copied(),
+            instructions,
+        })
+    }
+
+    fn run(&mut self, instructions: &mut u64) -> Result<Status, VmError> {
+        if self.hooks.is_empty() && self.watched_locals.is_empty() {
+            while !self.eof() {
+                self.check_deadline(*instructions)?;
+                self.step()?;
+                *instructions += 1;
+            }
+            return Ok(self.finished());
+        }
+
+        while !self.eof() {
+            self.check_deadline(*instructions)?;
+            let ip = self.ip;
+            let byte = self.chunk.code()[ip];
+            let op = OpCode::try_from(byte).map_err(VmError::InvalidOpcode)?;
+
+            let view = VmView {
+                stack: &self.stack,
+                locals: &self.locals,
+                heap: &self.heap,
+            };
+            let action = self.hooks.before(&view, ip, op);
+            match action {
+                HookAction::Continue => {}
+                HookAction::Pause => return Ok(Status::Paused),
+                HookAction::Abort(err) => return Err(err),
+            }
+
+            self.step()?;
+            *instructions += 1;
+
+            let view = VmView {
+                stack: &self.stack,
+                locals: &self.locals,
+                heap: &self.heap,
+            };
+            let action = self.hooks.after(&view, ip, op);
+            match action {
+                HookAction::Continue => {}
+                HookAction::Pause => return Ok(Status::Paused),
+                HookAction::Abort(err) => return Err(err),
+            }
+
+            if let Some(hit) = self.watch_hit.take() {
+                return Ok(Status::Watchpoint(hit));
+            }
+        }
+        Ok(self.finished())
+    }
+
+    // Stops before the instruction, so the run can be resumed with a later
+    // deadline.
+    fn check_deadline(&mut self, instructions: u64) -> Result<(), VmError> {
+        match self.deadline {
+            Some(deadline)
+                if instructions.is_multiple_of(DEADLINE_INTERVAL)
+                    && self.clock.0.now() > deadline =>
+            {
+                Err(VmError::DeadlineExceeded)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn finished(&self) -> Status {
+        Status::Finished(match self.returned {
+            true => Termination::Return,
+            false => Termination::EndOfChunk,
+        })
+    }
+
+    // Executes one instruction, giving the trap handler a chance to recover
+    // if it fails.
+    fn step(&mut self) -> Result<(), VmError> {
+        let ip = self.ip;
+        let err = match self.execute() {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+        let recoverable = !matches!(
+            err,
+            VmError::ForbiddenOpcode(_)
+                | VmError::ForbiddenNative(_)
+                | VmError::HeapExhausted
+                | VmError::StackOverflow
+                | VmError::FuelExhausted
+                | VmError::ReplayDiverged { .. }
+                | VmError::ReplayEventMissing { .. }
+                | VmError::UnrecordableValue
+        );
+        if !recoverable {
+            return Err(err);
+        }
+        // A replay recovers where the recording did, without the handler.
+        let decision = match self.tape.as_mut().filter(|tape| tape.is_replaying()) {
+            Some(tape) => match tape.replayed_recovery() {
+                Some(push) => TrapDecision::Recover {
+                    push,
+                    resume_at: ResumePoint::NextInstruction,
+                },
+                None => TrapDecision::Propagate,
+            },
+            None => {
+                let Some(handler) = self.hooks.trap.as_mut() else {
+                    return Err(err);
+                };
+                let view = VmView {
+                    stack: &self.stack,
+                    locals: &self.locals,
+                    heap: &self.heap,
+                };
+                handler(&err, &view)
+            }
+        };
+        let (push, resume_at) = match decision {
+            TrapDecision::Propagate => return Err(err),
+            TrapDecision::Recover { push, resume_at } => (push, resume_at),
+            TrapDecision::RecoverWithError { resume_at } => {
+                let error = self.error_object(&err, ip);
+                (Some(Value::ObjectPtr(error)), resume_at)
+            }
+        };
+
+        // An instruction that doesn't decode has no next instruction, and a
+        // pushed object has to be one the heap knows about.
+        let next = match resume_at {
+            ResumePoint::NextInstruction => match Instruction::decode(self.chunk.code(), ip) {
+                Ok(instruction) => ip + instruction.encoded_len(),
+                Err(_) => return Err(err),
+            },
+        };
+        if let Some(val) = push {
+            if val
+                .get_object_ptr()
+                .is_some_and(|ptr| !self.heap.contains(ptr))
+            {
+                return Err(err);
+            }
+        }
+        if let Some(tape) = &mut self.tape {
+            tape.record(Event::Recover(push))?;
+        }
+        if let Some(val) = push {
+            self.stack.push(val);
+        }
+        warn!("recovered from {err} at {ip}");
+        self.ip = next;
+        self.check_limits()
+    }
+
+    // Executes one instruction, from the predecoded table when there is one
+    // and it covers the ip, and otherwise by decoding it from the code.
+    pub fn execute(&mut self) -> Result<(), VmError> {
+        self.instruction_ip = self.ip;
+        let instruction = match self.predecoded.get(self.ip) {
+            Some(&Some(instruction)) => {
+                self.check_allowed(instruction.opcode())?;
+                self.ip += instruction.encoded_len();
+                instruction
+            }
+            _ => self.decode()?,
+        };
+        trace!("{} {instruction:?}", self.instruction_ip);
+        let site = Site {
+            ip: self.instruction_ip,
+            op: instruction.opcode(),
+        };
+        if let Some(shadow) = &mut self.shadow {
+            shadow.check_pops(&self.stack, site)?;
+        }
+        if let Some(tape) = &mut self.tape {
+            tape.step(instruction.opcode())?;
+        }
+        if let Some(profile) = &mut self.function_profile {
+            profile.step();
+        }
+        let before = self.stack.len();
+        #[cfg(feature = "profiler")]
+        let started = self.profiler.as_mut().and_then(Profiler::start);
+        let result = self.dispatch(instruction);
+        #[cfg(feature = "profiler")]
+        if let (Some(profiler), Some(started)) = (&mut self.profiler, started) {
+            profiler.finish(instruction.opcode(), started);
+        }
+        let result = match &mut self.shadow {
+            Some(shadow) if result.is_ok() => shadow.record_pushes(&self.stack, before, site),
+            _ => result,
+        };
+
+        self.scratch.clear();
+        if self.heap.is_marking() {
+            self.gc_tick();
+        }
+        if result.is_ok() && self.policy.is_some() {
+            return self.check_limits();
+        }
+        result
+    }
+
+    fn decode(&mut self) -> Result<Instruction, VmError> {
+        if self.is_verified() {
+            return self.decode_verified();
+        }
+        let byte = self.advance()?;
+        let op = byte.try_into().map_err(VmError::InvalidOpcode)?;
+        self.check_allowed(op)?;
+        let end = self
+            .ip
+            .checked_add(op.operand_len())
+            .ok_or(VmError::UnexpectedEof)?;
+        let operands = self
+            .chunk
+            .code()
+            .get(self.ip..end)
+            .ok_or(VmError::UnexpectedEof)?;
+        let instruction = Instruction::from_parts(op, operands);
+        self.ip = end;
+        Ok(instruction)
+    }
+
+    // The verifier decoded every instruction, so at a boundary short of the
+    // end there is a valid opcode and all of its operands.
+    fn decode_verified(&mut self) -> Result<Instruction, VmError> {
+        let code = self.chunk.code();
+        let byte = *code.get(self.ip).ok_or(VmError::UnexpectedEof)?;
+        debug_assert!(self.chunk.is_boundary(self.ip));
+        debug_assert!(OpCode::try_from(byte).is_ok());
+        // SAFETY: `OpCode` is a `repr(u8)` enum and `byte` one of its values.
+        let op = unsafe { mem::transmute::<u8, OpCode>(byte) };
+        self.check_allowed(op)?;
+        let start = self.ip + 1;
+        let end = start + op.operand_len();
+        debug_assert!(end <= code.len());
+        // SAFETY: the whole instruction lies within the code.
+        let operands = unsafe { code.get_unchecked(start..end) };
+        let instruction = Instruction::from_parts(op, operands);
+        self.ip = end;
+        Ok(instruction)
+    }
+
+    fn check_allowed(&self, op: OpCode) -> Result<(), VmError> {
+        match &self.policy {
+            Some(policy) if !policy.allows(op) => Err(VmError::ForbiddenOpcode(op as u8)),
+            _ => Ok(()),
+        }
+    }
+
+    fn dispatch(&mut self, instruction: Instruction) -> Result<(), VmError> {
+        use Instruction::*;
+        match instruction {
+            Return => self.ret(),
+            Unreachable => Err(VmError::UnreachableExecuted(self.instruction_ip)),
+            TrapMsg => Err(VmError::GuestPanic(self.get_string()?)),
+            ReturnN(count) => self.ret_n(count),
+            Nop => Ok(()),
+            Goto(target) => self.jump_static(target as usize),
+            GotoIf(target) => self.goto_if(target),
+            Load(index) => self.load(index),
+            Store(index) => self.store(index),
+            StoreIf(index) => self.store_if(index),
+            LoadOrDefault(index) => self.load_or_default(index),
+            AddToLocal(index) => self.accumulate(index, i64::wrapping_add),
+            MulToLocal(index) => self.accumulate(index, i64::wrapping_mul),
+            MinToLocal(index) => self.accumulate(index, i64::min),
+            MaxToLocal(index) => self.accumulate(index, i64::max),
+            LoadArg(index) => self.load_arg(index),
+            StoreArg(index) => self.store_arg(index),
+            ImmI(i) => self.imm(Value::Integer(i)),
+            ImmI8(i) => self.imm(Value::Integer(i.into())),
+            ImmI16(i) => self.imm(Value::Integer(i.into())),
+            ImmW8(w) => self.imm(Value::Word(w.into())),
+            ImmW16(w) => self.imm(Value::Word(w.into())),
+            ImmF(f) => self.imm(self.float(f)),
+            ImmW(w) => self.imm(Value::Word(w)),
+            AddI => self.add_i(),
+            SubI => self.sub_i(),
+            MulI => self.mul_i(),
+            DivI => self.div_op(i64::wrapping_div),
+            ModI => self.div_op(i64::wrapping_rem),
+            DivFloorI => self.div_op(div_floor),
+            ModEuclidI => self.div_op(i64::wrapping_rem_euclid),
+            CmpEqI => self.cmpeq_i(),
+            CmpGtI => self.cmpgt_i(),
+            CmpGeI => self.cmpge_i(),
+            CmpLtI => self.cmplt_i(),
+            CmpLeI => self.cmple_i(),
+            CmpEqW => self.compare_w(u64::eq),
+            CmpGtW => self.compare_w(u64::gt),
+            CmpGeW => self.compare_w(u64::ge),
+            CmpLtW => self.compare_w(u64::lt),
+            CmpLeW => self.compare_w(u64::le),
+            GetField(index) => self.get_field(index),
+            SetField(index) => self.set_field(index),
+            FieldCount => self.field_count(),
+            GetFieldDyn => self.get_field_dyn(),
+            SetFieldDyn => self.set_field_dyn(),
+            ObjEq => self.obj_eq(),
+            ObjCloneShallow => self.obj_clone_shallow(),
+            ObjCloneDeep => self.obj_clone_deep(),
+            Imm0 => self.imm(Value::Integer(0)),
+            Imm1 => self.imm(Value::Integer(1)),
+            ImmNeg1 => self.imm(Value::Integer(-1)),
+            AndW => self.binary_w(|x, y| x & y),
+            OrW => self.binary_w(|x, y| x | y),
+            XorW => self.binary_w(|x, y| x ^ y),
+            ShlW => self.binary_w(|x, y| x << (y & 63)),
+            ShrW => self.binary_w(|x, y| x >> (y & 63)),
+            RotlW => self.binary_w(|x, y| x.rotate_left((y & 63) as u32)),
+            RotrW => self.binary_w(|x, y| x.rotate_right((y & 63) as u32)),
+            ClzW => self.count_w(u64::leading_zeros),
+            CtzW => self.count_w(u64::trailing_zeros),
+            PopcntW => self.count_w(u64::count_ones),
+            F2Bits => self.f2bits(),
+            Bits2F => self.bits2f(),
+            AddI32 => self.binary_i32(|x, y| Ok(x.wrapping_add(y))),
+            SubI32 => self.binary_i32(|x, y| Ok(x.wrapping_sub(y))),
+            MulI32 => self.binary_i32(|x, y| Ok(x.wrapping_mul(y))),
+            DivI32 => self.binary_i32(|x, y| match y {
+                0 => Err(VmError::DivisionByZero),
+                _ => Ok(x.wrapping_div(y)),
+            }),
+            I64toI32 => self.i64_to_i32(),
+            ParseInt => self.parse_int(),
+            ParseFloat => self.parse_float(),
+            IntToStr => self.int_to_str(),
+            FloatToStr => self.float_to_str(),
+            FmtFloat => self.fmt_float(),
+            MapNew => self.map_new(),
+            MapGet => self.map_get_op(),
+            MapSet => self.map_set_op(),
+            MapContains => self.map_contains(),
+            MapLen => self.map_len(),
+            MapDelete => self.map_delete(),
+            LoadConst(index) => self.load_const(index),
+            Intern => self.intern(),
+            StrEq => self.str_eq(),
+            StrCmp => self.str_cmp(),
+            StrLen => self.str_len(),
+            CharAt => self.char_at(),
+            Substr => self.substr(),
+            NewWeak => self.new_weak(),
+            WeakGet => self.weak_get(),
+            Gc => self.gc(),
+            HeapInfo => self.heap_info(),
+            PushIp => self.push_ip(),
+            PushChunkLen => self.push_chunk_len(),
+            StackDepth => self.stack_depth(),
+            FrameDepth => self.frame_depth(),
+            FuelRemaining => self.fuel_remaining(),
+            Clock => self.clock(),
+            Rand => self.rand(),
+            GotoDyn => self.goto_dyn(),
+            CallNative(index) => self.call_native(index),
+            Call(index) => self.call(index),
+            ArrayNew => self.array_new(),
+            ArrayGet => self.array_get(),
+            ArrayGetRel => self.array_get_rel(),
+            ArraySetRel => self.array_set_rel(),
+            ArraySet => self.array_set(),
+            ArrayGetUnchecked => self.array_get_unchecked(),
+            ArraySetUnchecked => self.array_set_unchecked(),
+            ArrayCopy => self.array_copy(),
+            ArrayFill => self.array_fill(),
+            ArraySlice => self.array_slice(),
+            ArrayPush => self.array_push(),
+            ArrayPop => self.array_pop(),
+            BufNew => self.buf_new(),
+            BufLoad8 => self.buf_load(1),
+            BufLoad16 => self.buf_load(2),
+            BufLoad32 => self.buf_load(4),
+            BufLoad64 => self.buf_load(8),
+            BufStore8 => self.buf_store(1),
+            BufStore16 => self.buf_store(2),
+            BufStore32 => self.buf_store(4),
+            BufStore64 => self.buf_store(8),
+            BufLen => self.buf_len(),
+            ArrayLen => self.array_len(),
+            IterNew => self.iter_new(),
+            IterNext => self.iter_next(),
+        }
+    }
+
+    fn check_limits(&mut self) -> Result<(), VmError> {
+        let Some(policy) = &self.policy else {
+            return Ok(());
+        };
+        if policy.max_stack.is_some_and(|max| self.stack.len() > max) {
+            return Err(VmError::StackOverflow);
+        }
+        if let Some(max) = policy.max_heap_bytes.filter(|&max| self.heap.bytes() > max) {
+            self.collect(GcTrigger::Threshold);
+            if self.heap.bytes() > max {
+                return Err(VmError::HeapExhausted);
+            }
+        }
+        Ok(())
+    }
+
+    // A new error object for `err`, or the spare one with its fields
+    // overwritten and no message when a new one would take the heap past
+    // the policy's limit even after collecting.
+    fn error_object(&mut self, err: &VmError, ip: usize) -> ObjectPtr {
+        let message = Object::string(&err.to_string());
+        let bytes =
+            HeapObject::size(&message) + HeapObject::size(&Object::error(0, 0, Value::Null));
+        let limit = self
+            .policy
+            .as_ref()
+            .and_then(|policy| policy.max_heap_bytes);
+        let fits = |heap: &Heap| limit.is_none_or(|max| heap.bytes() + bytes <= max);
+        if !fits(&self.heap) {
+            self.collect(GcTrigger::Threshold);
+        }
+        match self.spare_error {
+            Some(spare) if !fits(&self.heap) => {
+                let fields = [
+                    Value::Integer(err.kind()),
+                    Value::Word(ip as u64),
+                    Value::Null,
+                ];
+                self.heap.get_mut(spare).fields.copy_from_slice(&fields);
+                spare
+            }
+            _ => {
+                // the message stays on the stack while the error is allocated
+                let message = self.alloc(message);
+                self.stack.push(Value::ObjectPtr(message));
+                let error = self.alloc(Object::error(err.kind(), ip, Value::ObjectPtr(message)));
+                self.stack.pop();
+                error
+            }
+        }
+    }
+
+    // Returning from the outermost frame finishes execution.
+    fn ret(&mut self) -> Result<(), VmError> {
+        if !(self.unchecked_returns && self.is_verified()) {
+            self.check_balance()?;
+        }
+        self.leave()
+    }
+
+    // A function must leave exactly its declared results above its frame's
+    // base, and one that declares none mustn't have eaten into its caller's
+    // values below it.
+    fn check_balance(&self) -> Result<(), VmError> {
+        let (Some(frame), Some(function)) = (self.frames.last(), self.function) else {
+            return Ok(());
+        };
+        let returns = self
+            .chunk
+            .functions()
+            .get(function as usize)
+            .and_then(|function| function.returns);
+        let actual = self.stack.len();
+        let expected = frame.base + returns.unwrap_or(0) as usize;
+        let balanced = match returns {
+            Some(_) => actual == expected,
+            None => actual >= expected,
+        };
+        if balanced {
+            Ok(())
+        } else {
+            Err(VmError::StackImbalance {
+                expected,
+                actual,
+                function,
+            })
+        }
+    }
+
+    // Keeps the top `count` values of the callee's and discards the rest.
+    fn ret_n(&mut self, count: u8) -> Result<(), VmError> {
+        let count = count as usize;
+        let base = self.frame_base();
+        let start = self
+            .stack
+            .len()
+            .checked_sub(count)
+            .filter(|&start| start >= base)
+            .ok_or(VmError::StackUnderflow)?;
+        self.check_returns(count)?;
+        self.stack.drain(base..start);
+        self.leave()
+    }
+
+    fn frame_base(&self) -> usize {
+        self.frames.last().map_or(0, |frame| frame.base)
+    }
+
+    // Only functions that declare a return count are checked.
+    fn check_returns(&self, found: usize) -> Result<(), VmError> {
+        let expected = self
+            .function
+            .and_then(|index| self.chunk.functions().get(index as usize))
+            .and_then(|function| function.returns);
+        match expected {
+            Some(expected) if expected as usize != found => {
+                Err(VmError::ReturnCountMismatch { expected, found })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn leave(&mut self) -> Result<(), VmError> {
+        match self.frames.pop() {
+            Some(frame) => {
+                self.locals = frame.locals;
+                self.function = frame.function;
+                self.ip = frame.return_ip;
+                if let Some(profile) = &mut self.function_profile {
+                    profile.leave();
+                }
+            }
+            None => {
+                self.ip = self.chunk.len();
+                self.returned = true;
+            }
+        }
+        Ok(())
+    }
+
+    fn call(&mut self, index: u16) -> Result<(), VmError> {
+        let (entry, arity) = self
+            .chunk
+            .functions()
+            .get(index as usize)
+            .map(|function| (function.entry, function.arity))
+            .ok_or(VmError::UnknownFunction(index))?;
+        if self.frames.len() >= MAX_FRAMES {
+            return Err(VmError::StackOverflow);
+        }
+        let base = self
+            .stack
+            .len()
+            .checked_sub(arity as usize)
+            .ok_or(VmError::StackUnderflow)?;
+
+        let return_ip = self.ip;
+        self.jump_static(entry)?;
+        let args = self.stack.drain(base..).map(Some).collect();
+        let locals = mem::replace(&mut self.locals, args);
+        self.frames.push(Frame {
+            return_ip,
+            locals,
+            function: self.function.replace(index),
+            base,
+        });
+        if let Some(profile) = &mut self.function_profile {
+            profile.enter(index);
+        }
+        Ok(())
+    }
+
+    // Static targets are normally checked by the verifier, but chunks
+    // aren't required to be verified, so every jump is checked here too.
+    fn jump(&mut self, target: usize) -> Result<(), VmError> {
+        if !self.chunk.is_boundary(target) {
+            return Err(VmError::InvalidJump(target));
+        }
+        self.ip = target;
+        Ok(())
+    }
+
+    // For targets in the code or the function table, which the verifier
+    // checked if the fast path is in use.
+    fn jump_static(&mut self, target: usize) -> Result<(), VmError> {
+        if !self.is_verified() {
+            return self.jump(target);
+        }
+        debug_assert!(self.chunk.is_boundary(target));
+        self.ip = target;
+        Ok(())
+    }
+
+    fn goto_if(&mut self, target: u16) -> Result<(), VmError> {
+        if self.get_bool()? {
+            self.jump_static(target as usize)?;
+        }
+        Ok(())
+    }
+
+    fn call_native(&mut self, index: u16) -> Result<(), VmError> {
+        let Some(Constant::Str(name)) = self.chunk.constants().get(index as usize) else {
+            return Err(VmError::UnknownNative(index));
+        };
+        if self
+            .policy
+            .as_ref()
+            .is_some_and(|policy| !policy.allows_native(name))
+        {
+            return Err(VmError::ForbiddenNative(index));
+        }
+        let native = self.natives.get(name);
+        if let Some(event) = self.replayed()? {
+            return self.replay_native(event);
+        }
+        let native = native.ok_or(VmError::UnknownNative(index))?;
+        // natives can leave the stack in any state, so recording one keeps
+        // what it started from
+        let before = self.tape.as_ref().map(|_| self.stack.clone());
+        let outer = mem::replace(&mut self.in_native, true);
+        let result = native(self);
+        self.in_native = outer;
+        if let Some(before) = before {
+            let kept = before
+                .iter()
+                .zip(&self.stack)
+                .take_while(|(a, b)| a == b)
+                .count();
+            let event = match result {
+                Ok(()) => Event::Native {
+                    popped: before.len() - kept,
+                    pushed: self.stack[kept..].to_vec(),
+                },
+                Err(_) => Event::NativeFailed,
+            };
+            self.record_event(event)?;
+        }
+        result
+    }
+
+    fn replay_native(&mut self, event: Event) -> Result<(), VmError> {
+        match event {
+            Event::Native { popped, pushed } => {
+                let kept = self
+                    .stack
+                    .len()
+                    .checked_sub(popped)
+                    .ok_or(VmError::StackUnderflow)?;
+                self.stack.truncate(kept);
+                self.stack.extend(&pushed);
+                self.record_event(Event::Native { popped, pushed })
+            }
+            Event::NativeFailed => {
+                self.record_event(Event::NativeFailed)?;
+                Err(VmError::ReplayedNativeFailure)
+            }
+            _ => Err(self.missing_event()),
+        }
+    }
+
+    // Takes this step's input from the recording being replayed, if any.
+    fn replayed(&mut self) -> Result<Option<Event>, VmError> {
+        match &mut self.tape {
+            Some(tape) => tape.replayed(),
+            None => Ok(None),
+        }
+    }
+
+    fn record_event(&mut self, event: Event) -> Result<(), VmError> {
+        match &mut self.tape {
+            Some(tape) => tape.record(event),
+            None => Ok(()),
+        }
+    }
+
+    fn missing_event(&self) -> VmError {
+        let step = self.tape.as_ref().map_or(0, Tape::current_step);
+        VmError::ReplayEventMissing { step }
+    }
+
+    fn clock(&mut self) -> Result<(), VmError> {
+        let now = match self.replayed()? {
+            Some(Event::Clock(now)) => now,
+            Some(_) => return Err(self.missing_event()),
+            None => self.clock.0.now(),
+        };
+        self.record_event(Event::Clock(now))?;
+        self.stack.push(Value::Word(now));
+        Ok(())
+    }
+
+    fn rand(&mut self) -> Result<(), VmError> {
+        let r = match self.replayed()? {
+            Some(Event::Rand(r)) => r,
+            Some(_) => return Err(self.missing_event()),
+            None => splitmix64(&mut self.rng),
+        };
+        self.record_event(Event::Rand(r))?;
+        self.stack.push(Value::Word(r));
+        Ok(())
+    }
+
+    fn push_ip(&mut self) -> Result<(), VmError> {
+        self.stack.push(Value::Word(self.ip as u64));
+        Ok(())
+    }
+
+    fn push_chunk_len(&mut self) -> Result<(), VmError> {
+        self.stack.push(Value::Word(self.chunk.len() as u64));
+        Ok(())
+    }
+
+    // The whole value stack, which frames share, before the push.
+    fn stack_depth(&mut self) -> Result<(), VmError> {
+        self.stack.push(Value::Integer(self.stack.len() as i64));
+        Ok(())
+    }
+
+    // Zero at the top level, one inside a function called from there.
+    fn frame_depth(&mut self) -> Result<(), VmError> {
+        self.stack.push(Value::Integer(self.frames.len() as i64));
+        Ok(())
+    }
+
+    // Fuel is charged once an instruction completes, so this includes the
+    // `FuelRemaining` itself. Without a fuel limit it's `u64::MAX`.
+    fn fuel_remaining(&mut self) -> Result<(), VmError> {
+        self.stack
+            .push(Value::Word(self.fuel().unwrap_or(u64::MAX)));
+        Ok(())
+    }
+
+    fn goto_dyn(&mut self) -> Result<(), VmError> {
+        let target = self.get_word()?;
+        self.jump(usize::try_from(target).unwrap_or(usize::MAX))
+    }
+
+    fn load(&mut self, index: u16) -> Result<(), VmError> {
+        let index = index as usize;
+        self.check_local(index)?;
+        let variable = self
+            .local(index)
+            .ok_or(VmError::UninitializedLocal(index))?;
+        self.stack.push(variable);
+        Ok(())
+    }
+
+    fn store(&mut self, index: u16) -> Result<(), VmError> {
+        let value = self.pop()?;
+        self.write_local(self.instruction_ip, index as usize, value)
+    }
+
+    // Pops the condition, then the value, which is dropped unless the
+    // condition holds.
+    fn store_if(&mut self, index: u16) -> Result<(), VmError> {
+        let cond = self.get_bool()?;
+        let value = self.pop()?;
+        if !cond {
+            return self.check_local(index as usize);
+        }
+        self.write_local(self.instruction_ip, index as usize, value)
+    }
+
+    // Pushes null for a local that was never stored to.
+    fn load_or_default(&mut self, index: u16) -> Result<(), VmError> {
+        let index = index as usize;
+        self.check_local(index)?;
+        self.stack.push(self.local(index).unwrap_or(Value::Null));
+        Ok(())
+    }
+
+    // Combines the popped integer into an integer local without going
+    // through the stack. The local is read first, as `Load`, the operation
+    // and `Store` would.
+    fn accumulate(&mut self, index: u16, combine: fn(i64, i64) -> i64) -> Result<(), VmError> {
+        let index = index as usize;
+        self.check_local(index)?;
+        let acc = match self.local(index) {
+            Some(Value::Integer(acc)) => acc,
+            Some(val) => return Err(self.type_mismatch("integer", val)),
+            None => return Err(VmError::UninitializedLocal(index)),
+        };
+        let operand = self.get_integer()?;
+        self.write_local(
+            self.instruction_ip,
+            index,
+            Value::Integer(combine(acc, operand)),
+        )
+    }
+
+    // Arguments are the first locals of a call's frame. These reach only
+    // that far, so an argument can't be confused with any other local.
+    fn arg(&self, index: u8) -> Result<u16, VmError> {
+        let arity = self
+            .function
+            .and_then(|function| self.chunk.functions().get(function as usize))
+            .map_or(0, |function| function.arity);
+        if index >= arity {
+            return Err(VmError::ArgOutOfRange { index, arity });
+        }
+        Ok(index.into())
+    }
+
+    fn load_arg(&mut self, index: u8) -> Result<(), VmError> {
+        let index = self.arg(index)?;
+        self.load(index)
+    }
+
+    fn store_arg(&mut self, index: u8) -> Result<(), VmError> {
+        let index = self.arg(index)?;
+        self.store(index)
+    }
+
+    fn write_local(&mut self, ip: usize, index: usize, new: Value) -> Result<(), VmError> {
+        let old = self.local(index);
+        self.set_local(index, new)?;
+        if self.watched_locals.contains(&index) {
+            self.watch_hit = Some(WatchpointHit {
+                index,
+                old,
+                new,
+                ip,
+            });
+        }
+        Ok(())
+    }
+
+    fn float(&self, f: f64) -> Value {
+        if self.canonical_nans && f.is_nan() {
+            Value::Float(f64::from_bits(CANONICAL_NAN))
+        } else {
+            Value::Float(f)
+        }
+    }
+
+    fn imm(&mut self, val: Value) -> Result<(), VmError> {
+        self.stack.push(val);
+        Ok(())
+    }
+
+    fn add_i(&mut self) -> Result<(), VmError> {
+        let y = self.get_integer()?;
+        let x = self.get_integer()?;
+        self.stack.push(Value::Integer(x.wrapping_add(y)));
+        Ok(())
+    }
+
+    fn sub_i(&mut self) -> Result<(), VmError> {
+        let y = self.get_integer()?;
+        let x = self.get_integer()?;
+        self.stack.push(Value::Integer(x.wrapping_sub(y)));
+        Ok(())
+    }
+
+    fn mul_i(&mut self) -> Result<(), VmError> {
+        let y = self.get_integer()?;
+        let x = self.get_integer()?;
+        self.stack.push(Value::Integer(x.wrapping_mul(y)));
+        Ok(())
+    }
+
+    // `i64::MIN` divided by -1 wraps like the other integer operations.
+    fn div_op(&mut self, f: impl FnOnce(i64, i64) -> i64) -> Result<(), VmError> {
+        let y = self.get_integer()?;
+        let x = self.get_integer()?;
+        if y == 0 {
+            return Err(VmError::DivisionByZero);
+        }
+        self.stack.push(Value::Integer(f(x, y)));
+        Ok(())
+    }
+
+    fn cmpeq_i(&mut self) -> Result<(), VmError> {
+        let y = self.get_integer()?;
+        let x = self.get_integer()?;
+        self.stack.push(Value::Word((x == y) as u64));
+        Ok(())
+    }
+
+    fn cmpgt_i(&mut self) -> Result<(), VmError> {
+        let y = self.get_integer()?;
+        let x = self.get_integer()?;
+        self.stack.push(Value::Word((x > y) as u64));
+        Ok(())
+    }
+
+    fn cmpge_i(&mut self) -> Result<(), VmError> {
+        let y = self.get_integer()?;
+        let x = self.get_integer()?;
+        self.stack.push(Value::Word((x >= y) as u64));
+        Ok(())
+    }
+
+    fn cmplt_i(&mut self) -> Result<(), VmError> {
+        let y = self.get_integer()?;
+        let x = self.get_integer()?;
+        self.stack.push(Value::Word((x < y) as u64));
+        Ok(())
+    }
+
+    fn cmple_i(&mut self) -> Result<(), VmError> {
+        let y = self.get_integer()?;
+        let x = self.get_integer()?;
+        self.stack.push(Value::Word((x <= y) as u64));
+        Ok(())
+    }
+
+    // Unsigned, unlike the integer comparisons.
+    fn compare_w(&mut self, f: impl FnOnce(&u64, &u64) -> bool) -> Result<(), VmError> {
+        let y = self.get_word()?;
+        let x = self.get_word()?;
+        self.stack.push(Value::Word(f(&x, &y) as u64));
+        Ok(())
+    }
+
+    fn binary_w(&mut self, f: impl FnOnce(u64, u64) -> u64) -> Result<(), VmError> {
+        let y = self.get_word()?;
+        let x = self.get_word()?;
+        self.stack.push(Value::Word(f(x, y)));
+        Ok(())
+    }
+
+    fn count_w(&mut self, f: impl FnOnce(u64) -> u32) -> Result<(), VmError> {
+        let w = self.get_word()?;
+        self.stack.push(Value::Integer(f(w) as i64));
+        Ok(())
+    }
+
+    // 32-bit arithmetic operates on the low halves of its operands and
+    // sign-extends the wrapped result back to 64 bits.
+    fn binary_i32(
+        &mut self,
+        f: impl FnOnce(i32, i32) -> Result<i32, VmError>,
+    ) -> Result<(), VmError> {
+        let y = self.get_integer()? as i32;
+        let x = self.get_integer()? as i32;
+        self.stack.push(Value::Integer(f(x, y)? as i64));
+        Ok(())
+    }
+
+    fn i64_to_i32(&mut self) -> Result<(), VmError> {
+        let i = self.get_integer()?;
+        self.stack.push(Value::Integer(i as i32 as i64));
+        Ok(())
+    }
+
+    // Parsing is strict: surrounding whitespace is rejected, a leading `+` is
+    // accepted, and integers outside the i64 range are errors rather than
+    // saturating.
+    fn parse_int(&mut self) -> Result<(), VmError> {
+        let s = self.get_string()?;
+        let i = s.parse().map_err(|_| VmError::InvalidNumber)?;
+        self.stack.push(Value::Integer(i));
+        Ok(())
+    }
+
+    fn parse_float(&mut self) -> Result<(), VmError> {
+        let s = self.get_string()?;
+        let f = s.parse().map_err(|_| VmError::InvalidNumber)?;
+        self.stack.push(self.float(f));
+        Ok(())
+    }
+
+    fn int_to_str(&mut self) -> Result<(), VmError> {
+        let i = self.get_integer()?;
+        let ptr = self.alloc(Object::string(&i.to_string()));
+        self.stack.push(Value::ObjectPtr(ptr));
+        Ok(())
+    }
+
+    // Display for f64 is the shortest representation that parses back to
+    // the same value.
+    fn float_to_str(&mut self) -> Result<(), VmError> {
+        let f = self.get_float()?;
+        let ptr = self.alloc(Object::string(&f.to_string()));
+        self.stack.push(Value::ObjectPtr(ptr));
+        Ok(())
+    }
+
+    // `precision` decimal places, rounding ties to even as `format!` does.
+    fn fmt_float(&mut self) -> Result<(), VmError> {
+        let precision = self.get_integer()?;
+        let f = self.get_float()?;
+        if !(0..=MAX_PRECISION).contains(&precision) {
+            return Err(VmError::InvalidPrecision(precision));
+        }
+        let s = format!("{f:.*}", precision as usize);
+        let ptr = self.alloc(Object::string(&s));
+        self.stack.push(Value::ObjectPtr(ptr));
+        Ok(())
+    }
+
+    fn map_new(&mut self) -> Result<(), VmError> {
+        let ptr = self.alloc(Object::map());
+        self.stack.push(Value::ObjectPtr(ptr));
+        Ok(())
+    }
+
+    fn get_map_key(&mut self) -> Result<(ObjectPtr, MapKey), VmError> {
+        let key = self.pop()?;
+        let key = MapKey::new(key, &self.heap)?;
+        Ok((self.get_object()?, key))
+    }
+
+    fn map_get_op(&mut self) -> Result<(), VmError> {
+        let (map, key) = self.get_map_key()?;
+        let val = self.heap.object(map).map_get(&key)?;
+        self.stack.push(val.ok_or(VmError::KeyNotFound)?);
+        Ok(())
+    }
+
+    fn map_set_op(&mut self) -> Result<(), VmError> {
+        let val = self.pop()?;
+        let key = self.pop()?;
+        let map = self.get_object()?;
+        self.map_set(map, key, val)
+    }
+
+    fn map_contains(&mut self) -> Result<(), VmError> {
+        let (map, key) = self.get_map_key()?;
+        let found = self.heap.object(map).map_get(&key)?.is_some();
+        self.stack.push(Value::Word(found as u64));
+        Ok(())
+    }
+
+    fn map_len(&mut self) -> Result<(), VmError> {
+        let map = self.get_object()?;
+        let len = self.heap.object(map).map_len()?;
+        self.stack.push(Value::Integer(len as i64));
+        Ok(())
+    }
+
+    fn map_delete(&mut self) -> Result<(), VmError> {
+        let (map, key) = self.get_map_key()?;
+        let found = self.heap.get_mut(map).map_delete(&key)?;
+        self.stack.push(Value::Word(found as u64));
+        Ok(())
+    }
+
+    fn get_array(&mut self) -> Result<ObjectPtr, VmError> {
+        let val = self.pop()?;
+        val.get_object_ptr()
+            .filter(|&ptr| self.heap.object(ptr).tag == tag::ARRAY)
+            .ok_or_else(|| self.type_mismatch("array", val))
+    }
+
+    fn get_array_index(&mut self) -> Result<(ObjectPtr, usize), VmError> {
+        let index = self.get_integer()?;
+        let array = self.get_array()?;
+        let len = self.heap.object(array).fields.len();
+        match usize::try_from(index) {
+            Ok(i) if i < len => Ok((array, i)),
+            _ => Err(VmError::IndexOutOfBounds { index, len }),
+        }
+    }
+
+    // As `get_array_index`, with a negative index counting back from the
+    // end: -1 is the last element and -len the first.
+    fn get_array_index_rel(&mut self) -> Result<(ObjectPtr, usize), VmError> {
+        let index = self.get_integer()?;
+        let array = self.get_array()?;
+        let len = self.heap.object(array).fields.len();
+        let i = match usize::try_from(index) {
+            Ok(i) => Some(i),
+            Err(_) => usize::try_from(index.unsigned_abs())
+                .ok()
+                .and_then(|back| len.checked_sub(back)),
+        };
+        match i {
+            Some(i) if i < len => Ok((array, i)),
+            _ => Err(VmError::IndexOutOfBounds { index, len }),
+        }
+    }
+
+    fn array_new(&mut self) -> Result<(), VmError> {
+        let len = self.get_integer()?;
+        let len = usize::try_from(len).map_err(|_| VmError::InvalidLength(len))?;
+        // The heap limit is normally checked after the instruction, which is
+        // too late for a single huge allocation.
+        let bytes = len.saturating_mul(mem::size_of::<Value>());
+        let limit = self
+            .policy
+            .as_ref()
+            .and_then(|policy| policy.max_heap_bytes);
+        if limit.is_some_and(|max| bytes > max) {
+            return Err(VmError::HeapExhausted);
+        }
+        let mut fields = Vec::new();
+        fields
+            .try_reserve_exact(len)
+            .map_err(|_| VmError::HeapExhausted)?;
+        fields.resize(len, Value::Null);
+        let ptr = self.alloc(Object::new(tag::ARRAY, fields));
+        self.stack.push(Value::ObjectPtr(ptr));
+        Ok(())
+    }
+
+    fn array_get(&mut self) -> Result<(), VmError> {
+        let (array, index) = self.get_array_index()?;
+        self.stack.push(self.heap.object(array).fields[index]);
+        Ok(())
+    }
+
+    fn array_set(&mut self) -> Result<(), VmError> {
+        let val = self.pop()?;
+        let (array, index) = self.get_array_index()?;
+        self.heap.get_mut(array).fields[index] = val;
+        self.heap.write_barrier(array);
+        Ok(())
+    }
+
+    fn array_get_rel(&mut self) -> Result<(), VmError> {
+        let (array, index) = self.get_array_index_rel()?;
+        self.stack.push(self.heap.object(array).fields[index]);
+        Ok(())
+    }
+
+    fn array_set_rel(&mut self) -> Result<(), VmError> {
+        let val = self.pop()?;
+        let (array, index) = self.get_array_index_rel()?;
+        self.heap.get_mut(array).fields[index] = val;
+        self.heap.write_barrier(array);
+        Ok(())
+    }
+
+    // The loop head `optimizer::eliminate_range_checks` found has just
+    // compared the index with the length of this very array, so the index
+    // isn't checked again beyond the slice's own bounds. The tag still is:
+    // any chunk can use these opcodes, and a string or map written as an
+    // array would corrupt the intern table or a map's index. Any operands
+    // the loop didn't promise, as when the host changes a local mid-loop,
+    // go to the checked handler for its errors.
+    fn array_get_unchecked(&mut self) -> Result<(), VmError> {
+        if let [.., Value::ObjectPtr(array), Value::Integer(index)] = self.stack[..] {
+            let obj = self.heap.object(array);
+            if let Some(&val) = obj
+                .fields
+                .get(index as usize)
+                .filter(|_| obj.tag == tag::ARRAY)
+            {
+                self.stack.truncate(self.stack.len() - 2);
+                self.stack.push(val);
+                return Ok(());
+            }
+        }
+        self.array_get()
+    }
+
+    fn array_set_unchecked(&mut self) -> Result<(), VmError> {
+        if let [.., Value::ObjectPtr(array), Value::Integer(index), val] = self.stack[..] {
+            let obj = self.heap.get_mut(array);
+            if let Some(field) = obj
+                .fields
+                .get_mut(index as usize)
+                .filter(|_| obj.tag == tag::ARRAY)
+            {
+                *field = val;
+                self.stack.truncate(self.stack.len() - 3);
+                self.heap.write_barrier(array);
+                return Ok(());
+            }
+        }
+        self.array_set()
+    }
+
+    fn array_len(&mut self) -> Result<(), VmError> {
+        let array = self.get_array()?;
+        let len = self.heap.object(array).fields.len();
+        self.stack.push(Value::Integer(len as i64));
+        Ok(())
+    }
+
+    // Copies `len` elements of `src` from `src_start` over those of `dst`
+    // from `dst_start`. The two may be the same array, with the ranges
+    // overlapping either way.
+    fn array_copy(&mut self) -> Result<(), VmError> {
+        let len = self.get_integer()?;
+        let src_start = self.get_integer()?;
+        let src = self.get_array()?;
+        let dst_start = self.get_integer()?;
+        let dst = self.get_array()?;
+        let from = element_range(src_start, len, self.heap.object(src).fields.len())?;
+        let to = element_range(dst_start, len, self.heap.object(dst).fields.len())?;
+        if src == dst {
+            self.heap.get_mut(dst).fields.copy_within(from, to.start);
+        } else {
+            let values = self.heap.object(src).fields[from].to_vec();
+            self.heap.get_mut(dst).fields[to].copy_from_slice(&values);
+        }
+        self.heap.write_barrier(dst);
+        Ok(())
+    }
+
+    fn array_fill(&mut self) -> Result<(), VmError> {
+        let val = self.pop()?;
+        let len = self.get_integer()?;
+        let start = self.get_integer()?;
+        let array = self.get_array()?;
+        let range = element_range(start, len, self.heap.object(array).fields.len())?;
+        self.heap.get_mut(array).fields[range].fill(val);
+        self.heap.write_barrier(array);
+        Ok(())
+    }
+
+    fn array_slice(&mut self) -> Result<(), VmError> {
+        let len = self.get_integer()?;
+        let start = self.get_integer()?;
+        let source = self.get_array()?;
+        self.hold(Value::ObjectPtr(source));
+
+        let elements = &self.heap.object(source).fields;
+        let fields = elements[element_range(start, len, elements.len())?].to_vec();
+        let ptr = self.alloc(Object::new(tag::ARRAY, fields));
+        self.stack.push(Value::ObjectPtr(ptr));
+        Ok(())
+    }
+
+    // Growing an array is treated like an allocation, so it can be what
+    // triggers a collection.
+    fn array_push(&mut self) -> Result<(), VmError> {
+        let val = self.pop()?;
+        let array = self.get_array()?;
+        let fields = &mut self.heap.get_mut(array).fields;
+        fields.try_reserve(1).map_err(|_| VmError::HeapExhausted)?;
+        fields.push(val);
+        self.heap.recharge(array);
+        self.heap.write_barrier(array);
+        self.hold(Value::ObjectPtr(array));
+        self.maybe_collect();
+        Ok(())
+    }
+
+    // Popping an empty array traps, as reading its last element would.
+    fn array_pop(&mut self) -> Result<(), VmError> {
+        let array = self.get_array()?;
+        let val = self.heap.get_mut(array).fields.pop();
+        let val = val.ok_or(VmError::IndexOutOfBounds { index: -1, len: 0 })?;
+        self.heap.recharge(array);
+        self.stack.push(val);
+        Ok(())
+    }
+
+    // Buffers start out zeroed. Like arrays, they're checked against the
+    // heap limit before anything is allocated.
+    fn buf_new(&mut self) -> Result<(), VmError> {
+        let len = self.get_integer()?;
+        let len = usize::try_from(len).map_err(|_| VmError::InvalidLength(len))?;
+        let limit = self
+            .policy
+            .as_ref()
+            .and_then(|policy| policy.max_heap_bytes);
+        if limit.is_some_and(|max| len > max) {
+            return Err(VmError::HeapExhausted);
+        }
+        let mut bytes = Vec::new();
+        bytes
+            .try_reserve_exact(len)
+            .map_err(|_| VmError::HeapExhausted)?;
+        bytes.resize(len, 0);
+        let ptr = self.alloc(Object::buffer(bytes));
+        self.stack.push(Value::ObjectPtr(ptr));
+        Ok(())
+    }
+
+    fn get_buffer(&mut self) -> Result<ObjectPtr, VmError> {
+        let val = self.pop()?;
+        val.get_object_ptr()
+            .filter(|&ptr| self.heap.object(ptr).as_buffer().is_some())
+            .ok_or_else(|| self.type_mismatch("buffer", val))
+    }
+
+    fn buf_load(&mut self, width: usize) -> Result<(), VmError> {
+        let offset = self.get_integer()?;
+        let buffer = self.get_buffer()?;
+        let val = self.heap.object(buffer).buffer_load(offset, width)?;
+        self.stack.push(Value::Integer(val as i64));
+        Ok(())
+    }
+
+    fn buf_store(&mut self, width: usize) -> Result<(), VmError> {
+        let val = self.get_integer()?;
+        let offset = self.get_integer()?;
+        let buffer = self.get_buffer()?;
+        self.heap
+            .get_mut(buffer)
+            .buffer_store(offset, width, val as u64)
+    }
+
+    fn buf_len(&mut self) -> Result<(), VmError> {
+        let buffer = self.get_buffer()?;
+        let len = self.heap.object(buffer).as_buffer().unwrap().len();
+        self.stack.push(Value::Integer(len as i64));
+        Ok(())
+    }
+
+    // Iterators are objects holding the container, a cursor and, for maps,
+    // the map's version when the iterator was made. Arrays yield their
+    // elements as they are when reached, and maps their keys in insertion
+    // order. Updating a map's values is fine, but adding or removing keys
+    // invalidates its iterators, since entries move.
+    fn iter_new(&mut self) -> Result<(), VmError> {
+        let val = self.pop()?;
+        let container = val
+            .get_object_ptr()
+            .filter(|&ptr| {
+                let obj = self.heap.object(ptr);
+                obj.tag == tag::ARRAY || obj.is_map()
+            })
+            .ok_or_else(|| self.type_mismatch("array or map", val))?;
+        self.hold(val);
+        let version = self.heap.object(container).map_version().unwrap_or(0);
+        let fields = vec![val, Value::Integer(0), Value::Word(version)];
+        let iter = self.alloc(Object::new(tag::ITERATOR, fields));
+        self.stack.push(Value::ObjectPtr(iter));
+        Ok(())
+    }
+
+    // Pushes the next item and then true, or just false once the container
+    // is exhausted.
+    fn iter_next(&mut self) -> Result<(), VmError> {
+        let val = self.pop()?;
+        let iter = val
+            .get_object_ptr()
+            .filter(|&ptr| self.heap.object(ptr).tag == tag::ITERATOR)
+            .ok_or_else(|| self.type_mismatch("iterator", val))?;
+        let [Value::ObjectPtr(container), Value::Integer(cursor), Value::Word(version)] =
+            self.heap.object(iter).fields[..]
+        else {
+            return Err(VmError::TypeMismatch {
+                expected: "iterator",
+                found: "object",
+            });
+        };
+
+        let obj = self.heap.object(container);
+        let cursor = usize::try_from(cursor).unwrap_or(usize::MAX);
+        let item = if obj.is_map() {
+            if obj.map_version()? != version {
+                return Err(VmError::IteratorInvalidated);
+            }
+            obj.fields.get(cursor.saturating_mul(2)).copied()
+        } else {
+            obj.fields.get(cursor).copied()
+        };
+        match item {
+            Some(item) => {
+                self.heap.get_mut(iter).fields[1] = Value::Integer(cursor as i64 + 1);
+                self.stack.push(item);
+                self.stack.push(Value::Word(1));
+            }
+            None => self.stack.push(Value::Word(0)),
+        }
+        Ok(())
+    }
+
+    fn load_const(&mut self, index: u16) -> Result<(), VmError> {
+        let index = index as usize;
+        let constant = self.chunk.constants().get(index).cloned();
+        let val = match constant.ok_or(VmError::ConstantOutOfRange(index))? {
+            Constant::Integer(i) => Value::Integer(i),
+            Constant::Word(w) => Value::Word(w),
+            Constant::Float(f) => self.float(f),
+            Constant::Char(c) => Value::Char(c),
+            Constant::Str(s) => Value::ObjectPtr(self.alloc(Object::string(&s))),
+        };
+        self.stack.push(val);
+        Ok(())
+    }
+
+    fn intern(&mut self) -> Result<(), VmError> {
+        let val = self.pop()?;
+        let ptr = val
+            .get_object_ptr()
+            .and_then(|ptr| self.heap.intern(ptr))
+            .ok_or_else(|| self.type_mismatch("string", val))?;
+        self.stack.push(Value::ObjectPtr(ptr));
+        Ok(())
+    }
+
+    // Distinct interned strings never have the same contents, so two of
+    // them are compared by pointer alone.
+    fn str_eq(&mut self) -> Result<(), VmError> {
+        let y = self.get_string_object()?;
+        let x = self.get_string_object()?;
+        let eq = x == y
+            || !(x.node().is_interned() && y.node().is_interned())
+                && self.heap.object(x).fields == self.heap.object(y).fields;
+        self.stack.push(Value::Word(eq as u64));
+        Ok(())
+    }
+
+    // Orders by code point, with a prefix before any longer string.
+    fn str_cmp(&mut self) -> Result<(), VmError> {
+        let y = self.get_string_object()?;
+        let x = self.get_string_object()?;
+        let chars = |ptr| {
+            self.heap
+                .object(ptr)
+                .fields
+                .iter()
+                .map(|field| match field {
+                    Value::Char(c) => Some(*c),
+                    _ => None,
+                })
+        };
+        let ordering = chars(x).cmp(chars(y));
+        self.stack.push(Value::Integer(ordering as i64));
+        Ok(())
+    }
+
+    fn str_len(&mut self) -> Result<(), VmError> {
+        let s = self.get_string_object()?;
+        let len = self.heap.object(s).fields.len();
+        self.stack.push(Value::Integer(len as i64));
+        Ok(())
+    }
+
+    fn char_at(&mut self) -> Result<(), VmError> {
+        let index = self.get_integer()?;
+        let s = self.get_string_object()?;
+        let chars = &self.heap.object(s).fields;
+        let c = usize::try_from(index)
+            .ok()
+            .and_then(|i| chars.get(i).copied())
+            .ok_or(VmError::IndexOutOfBounds {
+                index,
+                len: chars.len(),
+            })?;
+        self.stack.push(c);
+        Ok(())
+    }
+
+    // Takes `len` characters from `start`, trapping unless they all lie
+    // within the string.
+    fn substr(&mut self) -> Result<(), VmError> {
+        let len = self.get_integer()?;
+        let start = self.get_integer()?;
+        let source = self.get_string_object()?;
+        self.hold(Value::ObjectPtr(source));
+
+        let chars = &self.heap.object(source).fields;
+        let fields = chars[element_range(start, len, chars.len())?].to_vec();
+        let ptr = self.alloc(Object::new(tag::STRING, fields));
+        self.stack.push(Value::ObjectPtr(ptr));
+        Ok(())
+    }
+
+    fn new_weak(&mut self) -> Result<(), VmError> {
+        let target = self.get_object()?;
+        self.hold(Value::ObjectPtr(target));
+        let weak = self.alloc(Object::weak(target));
+        self.stack.push(Value::ObjectPtr(weak));
+        Ok(())
+    }
+
+    fn weak_get(&mut self) -> Result<(), VmError> {
+        let val = self.pop()?;
+        let target = val
+            .get_object_ptr()
+            .and_then(|ptr| self.heap.object(ptr).weak_target())
+            .ok_or_else(|| self.type_mismatch("weak reference", val))?;
+        self.stack
+            .push(target.map_or(Value::Null, Value::ObjectPtr));
+        Ok(())
+    }
+
+    // Everything on the stack and in the locals is a root, so a collection
+    // is safe between any two instructions.
+    fn gc(&mut self) -> Result<(), VmError> {
+        self.collect_garbage();
+        Ok(())
+    }
+
+    fn heap_info(&mut self) -> Result<(), VmError> {
+        self.stack.push(Value::Integer(self.heap.len() as i64));
+        Ok(())
+    }
+
+    fn f2bits(&mut self) -> Result<(), VmError> {
+        let f = self.get_float()?;
+        self.stack.push(Value::Word(f.to_bits()));
+        Ok(())
+    }
+
+    fn bits2f(&mut self) -> Result<(), VmError> {
+        let w = self.get_word()?;
+        self.stack.push(self.float(f64::from_bits(w)));
+        Ok(())
+    }
+
+    fn get_field(&mut self, index: u16) -> Result<(), VmError> {
+        let obj = self.get_object()?;
+        let field = self.object_field(obj, index as usize)?;
+        self.stack.push(field);
+        Ok(())
+    }
+
+    fn set_field(&mut self, index: u16) -> Result<(), VmError> {
+        let val = self.pop()?;
+        let obj = self.get_object()?;
+        self.set_object_field(obj, index as usize, val)
+    }
+
+    // Counts the fields GetField can reach, whatever kind the object is.
+    fn field_count(&mut self) -> Result<(), VmError> {
+        let obj = self.get_object()?;
+        let len = self.heap.object(obj).fields.len();
+        self.stack.push(Value::Integer(len as i64));
+        Ok(())
+    }
+
+    // As GetField and SetField, with the index popped from below the value,
+    // if any. A negative index traps as it would for an array.
+    fn field_index(&mut self) -> Result<(ObjectPtr, usize), VmError> {
+        let index = self.get_integer()?;
+        let obj = self.get_object()?;
+        let index = usize::try_from(index).map_err(|_| VmError::IndexOutOfBounds {
+            index,
+            len: self.heap.object(obj).fields.len(),
+        })?;
+        Ok((obj, index))
+    }
+
+    fn get_field_dyn(&mut self) -> Result<(), VmError> {
+        let (obj, index) = self.field_index()?;
+        let field = self.object_field(obj, index)?;
+        self.stack.push(field);
+        Ok(())
+    }
+
+    fn set_field_dyn(&mut self) -> Result<(), VmError> {
+        let val = self.pop()?;
+        let (obj, index) = self.field_index()?;
+        self.set_object_field(obj, index, val)
+    }
+
+    fn obj_eq(&mut self) -> Result<(), VmError> {
+        let x = self.get_object()?;
+        let y = self.get_object()?;
+        let eq = Value::ObjectPtr(x).deep_eq(&Value::ObjectPtr(y), &self.heap);
+        self.stack.push(Value::Word(eq as u64));
+        Ok(())
+    }
+
+    fn obj_clone_shallow(&mut self) -> Result<(), VmError> {
+        let src = self.get_object()?;
+        self.hold(Value::ObjectPtr(src));
+        let copy = self.alloc(self.heap.object(src).clone());
+        self.stack.push(Value::ObjectPtr(copy));
+        Ok(())
+    }
+
+    // Copies are held as soon as they are allocated: until the clone is
+    // complete they are only referenced from `copies`, which the collector
+    // can't see.
+    fn obj_clone_deep(&mut self) -> Result<(), VmError> {
+        let src = self.get_object()?;
+        self.hold(Value::ObjectPtr(src));
+
+        let mut copies = HashMap::new();
+        let root = self.alloc(self.heap.object(src).clone());
+        self.hold(Value::ObjectPtr(root));
+        copies.insert(src, root);
+
+        let mut pending = vec![root];
+        while let Some(copy) = pending.pop() {
+            for index in 0..self.heap.object(copy).fields.len() {
+                let Some(orig) = self.heap.object(copy).fields[index].get_object_ptr() else {
+                    continue;
+                };
+                let field = match copies.get(&orig) {
+                    Some(&field) => field,
+                    None => {
+                        let field = self.alloc(self.heap.object(orig).clone());
+                        self.hold(Value::ObjectPtr(field));
+                        copies.insert(orig, field);
+                        pending.push(field);
+                        field
+                    }
+                };
+                self.set_object_field(copy, index, Value::ObjectPtr(field))?;
+            }
+        }
+
+        self.stack.push(Value::ObjectPtr(root));
+        Ok(())
+    }
+}
+
+impl VM {
+    // Frames of the current call stack. Call sites are found from the
+    // return addresses, which always follow a `Call`.
+    pub fn backtrace(&self) -> Backtrace {
+        let call_len = Instruction::Call(0).encoded_len();
+        let callers = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| (frame.function, frame.return_ip - call_len));
+        let frames = [(self.function, self.instruction_ip)]
+            .into_iter()
+            .chain(callers)
+            .map(|(function, ip)| {
+                let entry = function.and_then(|index| self.chunk.functions().get(index as usize));
+                BacktraceFrame {
+                    function,
+                    name: entry.and_then(|entry| entry.name.clone()),
+                    entry: entry.map(|entry| entry.entry),
+                    ip,
+                }
+            });
+        Backtrace(frames.collect())
+    }
+}
+
+impl VmError {
+    pub fn with_backtrace(self, vm: &VM) -> ErrorWithBacktrace {
+        ErrorWithBacktrace {
+            error: self,
+            backtrace: vm.backtrace(),
+        }
+    }
+
+    pub fn with_state(self, vm: &VM) -> ErrorWithState {
+        let mut state = String::new();
+        // the operand is no longer rooted, so it's only shown if it hasn't
+        // been collected since
+        if let Self::OperandMismatch {
+            found: Operand::Object(id),
+            ..
+        } = self
+        {
+            if let Some(ptr) = vm.heap.iter().find(|ptr| ptr.id() == id) {
+                state = format!("operand: {:#}\n", Value::ObjectPtr(ptr).display(&vm.heap));
+            }
+        }
+        vm.dump_state(&mut state).unwrap();
+        ErrorWithState { error: self, state }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::OpCode::*;
+    use super::*;
+    use crate::builder::ChunkBuilder;
+    use crate::chunk::feature;
+    use crate::config::VmBuilder;
+    use crate::error::{err_kind, VerifyError};
+    use crate::eval::eval;
+    use crate::fuzz;
+    use crate::heap::{Color, HeapObject};
+    use crate::verifier;
+    use std::cell::Cell;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    pub(crate) fn factorial() -> Chunk {
+        #[rustfmt::skip]
+        let factorial = vec![
+            ImmI  as u8, 0, 0, 0, 0, 0, 0, 0, 5,
+            Store as u8, 0, 0,
+
+            // x = 1
+            ImmI  as u8, 0, 0, 0, 0, 0, 0, 0, 1,
+            Store as u8, 0, 1,
+
+            // while n > 1 {
+            ImmI   as u8, 0, 0, 0, 0, 0, 0, 0, 1,
+            Load   as u8, 0, 0,
+            CmpGtI as u8,
+            GotoIf as u8, 0, 69,
+
+            // x = x * n
+            Load  as u8, 0, 1,
+            Load  as u8, 0, 0,
+            MulI  as u8,
+            Store as u8, 0, 1,
+
+            // n = n - 1
+            Load  as u8, 0, 0,
+            ImmI  as u8, 0, 0, 0, 0, 0, 0, 0, 1,
+            SubI  as u8,
+            Store as u8, 0, 0,
+
+            // }
+            Goto  as u8, 0, 24,
+
+            // return x
+            Load   as u8, 0, 1,
+            Return as u8,
+        ];
+        factorial.into()
+    }
+
+    // An operand mismatch, with the type of the value found in place of
+    // the value.
+    fn mismatch<T: fmt::Debug>(
+        result: Result<T, VmError>,
+    ) -> (&'static str, &'static str, OpCode, usize) {
+        match result {
+            Err(VmError::OperandMismatch {
+                expected,
+                found,
+                op: Some(op),
+                ip,
+            }) => (expected, found.type_name(), op, ip),
+            other => panic!("not an operand mismatch: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_factorial() {
+        let chunk = factorial();
+        let instructions: Vec<_> = chunk
+            .instructions()
+            .map(|decoded| decoded.unwrap().1)
+            .collect();
+        assert_eq!(eval(&instructions, &[]), Ok(Some(Value::Integer(120))));
+
+        let outcome = VM::new(factorial()).execute_all().unwrap();
+        assert_eq!(outcome.status, Status::Finished(Termination::Return));
+        assert_eq!(outcome.instructions, 75);
+    }
+
+    #[test]
+    fn test_unsupported_features() {
+        for bits in [feature::WIDE_JUMPS, 1 << 31] {
+            let chunk = factorial().with_features(bits);
+            assert_eq!(
+                VM::try_new(chunk.clone()).err(),
+                Some(VmError::UnsupportedFeature(bits))
+            );
+            assert_eq!(
+                VM::with_heap_mode(chunk.clone(), HeapMode::Arena).err(),
+                Some(VmError::UnsupportedFeature(bits))
+            );
+            assert_eq!(
+                fuzz::run_chunk(chunk),
+                Err(VmError::UnsupportedFeature(bits))
+            );
+        }
+
+        let chunk = ChunkBuilder::new().string("ok").op(StrLen).build().unwrap();
+        assert_eq!(chunk.features(), feature::OBJECTS);
+        let mut vm = VM::try_new(chunk).unwrap();
+        let outcome = vm.execute_all().unwrap();
+        assert_eq!(outcome.value, Some(Value::Integer(2)));
+    }
+
+    #[test]
+    fn test_termination() {
+        let mut b = ChunkBuilder::new();
+        b.imm_i(7).imm_i(9);
+        let mut vm = VM::new(b.build().unwrap());
+        let outcome = vm.execute_all().unwrap();
+        assert_eq!(outcome.status, Status::Finished(Termination::EndOfChunk));
+        assert_eq!(outcome.value, Some(Value::Integer(9)));
+        assert_eq!(outcome.instructions, 2);
+
+        b.op(Return).imm_i(11);
+        let mut vm = VM::new(b.build().unwrap());
+        let outcome = vm.execute_all().unwrap();
+        assert_eq!(outcome.status, Status::Finished(Termination::Return));
+        assert_eq!(outcome.value, Some(Value::Integer(9)));
+        assert_eq!(outcome.instructions, 3);
+
+        // a Return from a call doesn't end the run
+        let mut b = ChunkBuilder::new();
+        let entry = b.label();
+        let f = b.function(entry, 0);
+        let end = b.label();
+        b.call(f).goto(end);
+        b.bind(entry).imm_i(1).op(Return);
+        b.bind(end);
+        let mut vm = VM::new(b.build().unwrap());
+        let outcome = vm.execute_all().unwrap();
+        assert_eq!(outcome.status, Status::Finished(Termination::EndOfChunk));
+        assert_eq!(outcome.value, Some(Value::Integer(1)));
+
+        vm.reset().unwrap();
+        let outcome = vm.execute_all().unwrap();
+        assert_eq!(outcome.status, Status::Finished(Termination::EndOfChunk));
+        let mut vm = VM::new(Chunk::default());
+        assert_eq!(vm.execute_all().unwrap().value, None);
+    }
+
+    #[test]
+    fn test_subroutine_return_address() {
+        let mut b = ChunkBuilder::new();
+        let (sub, ret1, ret2, end) = (b.label(), b.label(), b.label(), b.label());
+        b.imm_i(3).store(0);
+        b.imm_label(ret1).store(9).goto(sub);
+        b.bind(ret1).imm_label(ret2).store(9).goto(sub);
+        b.bind(ret2).load(0).goto(end);
+
+        // x = x + x, then return to the caller
+        b.bind(sub).load(0).load(0).op(AddI).store(0);
+        b.load(9).op(GotoDyn);
+        b.bind(end);
+
+        let mut vm = VM::new(b.build().unwrap());
+        vm.execute_all().unwrap();
+        assert_eq!(vm.stack, [Value::Integer(12)]);
+    }
+
+    #[test]
+    fn test_call_frames() {
+        let mut b = ChunkBuilder::new();
+        let entry = b.label();
+        let sub = b.function(entry, 2);
+        b.imm_i(7).store(0);
+        b.imm_i(10).imm_i(3).call(sub).load(0).op(Return);
+
+        // a - b, with locals of its own
+        b.bind(entry).load(0).load(1).op(SubI).store(2);
+        b.load(2).op(Return);
+
+        let chunk = b.build().unwrap();
+        assert_eq!(verifier::verify(&chunk), Ok(()));
+        let mut vm = VM::new(chunk);
+        vm.execute_all().unwrap();
+        assert_eq!(vm.stack, [Value::Integer(7), Value::Integer(7)]);
+        assert_eq!(vm.local(2), None);
+        assert!(vm.frames.is_empty());
+    }
+
+    #[test]
+    fn test_unreachable() {
+        // only the default arm of the match on local 0 is unreachable
+        let mut b = ChunkBuilder::new();
+        let (zero, one) = (b.label(), b.label());
+        b.load(0).op(Imm0).op(CmpEqI).goto_if(zero);
+        b.load(0).op(Imm1).op(CmpEqI).goto_if(one);
+        let default = b.len();
+        b.op(Unreachable);
+        b.bind(zero).imm_i(10).op(Return);
+        b.bind(one).imm_i(20).op(Return);
+        let chunk = b.build().unwrap();
+
+        let run = |arg| crate::eval::eval_chunk(chunk.clone(), &[Value::Integer(arg)]);
+        assert_eq!(run(1).unwrap(), Some(Value::Integer(20)));
+        let err = run(2).unwrap_err();
+        assert_eq!(err.error, VmError::UnreachableExecuted(default));
+        assert_eq!(err.backtrace.0[0].ip, default);
+
+        let mut vm = VM::new(chunk);
+        vm.set_local(0, Value::Integer(2)).unwrap();
+        vm.set_breakpoint(default).unwrap();
+        assert_eq!(vm.execute_all().unwrap().status, Status::Paused);
+        let mut dump = String::new();
+        vm.dump_state(&mut dump).unwrap();
+        assert!(
+            dump.contains(&format!("> {default:>5}: Unreachable\n")),
+            "{dump}"
+        );
+    }
+
+    #[test]
+    fn test_trap_msg() {
+        // assert(x > 0, "x must be positive"); x * 2
+        let mut b = ChunkBuilder::new();
+        let ok = b.label();
+        b.load(0).op(Imm0).op(CmpGtI).goto_if(ok);
+        b.string("x must be positive").op(TrapMsg);
+        b.bind(ok).load(0).imm_i(2).op(MulI);
+        let chunk = b.build().unwrap();
+
+        let run = |arg| crate::eval::eval_chunk(chunk.clone(), &[Value::Integer(arg)]);
+        assert_eq!(run(4).unwrap(), Some(Value::Integer(8)));
+        // the VM and its heap are gone by now
+        let err = run(-1).unwrap_err().error;
+        assert_eq!(err, VmError::GuestPanic("x must be positive".to_string()));
+        assert_eq!(err.to_string(), "guest panicked: x must be positive");
+
+        // a handler catches it, and execution goes on after the TrapMsg with
+        // whatever the handler pushed
+        let mut vm = VM::new(chunk);
+        vm.set_local(0, Value::Integer(-1)).unwrap();
+        let caught = Rc::new(RefCell::new(Vec::new()));
+        let seen = caught.clone();
+        vm.set_trap_handler(Box::new(move |err, _| {
+            let VmError::GuestPanic(message) = err else {
+                return TrapDecision::Propagate;
+            };
+            seen.borrow_mut().push(message.clone());
+            TrapDecision::Recover {
+                push: Some(Value::Integer(5)),
+                resume_at: ResumePoint::NextInstruction,
+            }
+        }))
+        .unwrap();
+        let outcome = vm.execute_all().unwrap();
+        assert_eq!(outcome.value, Some(Value::Integer(-2)));
+        assert_eq!(vm.stack(), [Value::Integer(5), Value::Integer(-2)]);
+        assert_eq!(*caught.borrow(), ["x must be positive"]);
+
+        let err = eval(&[Instruction::Imm1, Instruction::TrapMsg], &[]).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                VmError::OperandMismatch {
+                    expected: "string",
+                    found: Operand::Integer(1),
+                    op: Some(TrapMsg),
+                    ip: 1,
+                }
+            ),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn test_frame_locals() {
+        // sum(n): acc = 0; while n > 0 { acc += n; n -= 1 }
+        let mut b = ChunkBuilder::new();
+        let (entry, head, end) = (b.label(), b.label(), b.label());
+        let sum = b.function(entry, 1);
+        b.local_name(sum, 0, "n").local_name(sum, 1, "acc");
+        b.imm_i(7).store(0).imm_i(5).call(sum).op(Return);
+        b.bind(entry).imm_i(0).store(1);
+        b.bind(head).load(0).imm_i(0).op(CmpLeI).goto_if(end);
+        b.load(0).load(1).op(AddI).store(1);
+        b.load(0).imm_i(1).op(SubI).store(0);
+        b.goto(head);
+        b.bind(end).load(1).op(Return);
+        let chunk = b.build().unwrap();
+
+        let mut vm = VM::new(chunk.clone());
+        let head = chunk.functions()[0].entry + 4;
+        vm.set_breakpoint(head).unwrap();
+        let int = Value::Integer;
+        assert_eq!(vm.execute_all().unwrap().status, Status::Paused);
+        let locals: Vec<_> = vm.frame_locals().collect();
+        assert_eq!(locals, [(Some("n"), int(5)), (Some("acc"), int(0))]);
+        let mut dump = String::new();
+        vm.dump_state(&mut dump).unwrap();
+        assert!(
+            dump.contains(&format!("> {head:>5}: Load(0)  ; n\n")),
+            "{dump}"
+        );
+
+        assert_eq!(vm.execute_all().unwrap().status, Status::Paused);
+        let locals: Vec<_> = vm.frame_locals().collect();
+        assert_eq!(locals, [(Some("n"), int(4)), (Some("acc"), int(5))]);
+
+        vm.clear_breakpoint(head).unwrap();
+        vm.execute_all().unwrap();
+        assert_eq!(vm.frame_locals().collect::<Vec<_>>(), [(None, int(7))]);
+        assert_eq!(vm.stack, [int(15)]);
+    }
+
+    #[test]
+    fn test_backtrace() {
+        // main calls f, which calls g, which divides by zero
+        let mut b = ChunkBuilder::new();
+        let (f_entry, g_entry) = (b.label(), b.label());
+        let f = b.function(f_entry, 0);
+        let g = b.function(g_entry, 1);
+        b.function_name(g, "g");
+        b.op(Nop).call(f).op(Return);
+        b.bind(f_entry).imm_i(0).call(g).op(Return);
+        b.bind(g_entry).imm_i(1).load(0).op(DivI).op(Return);
+        let chunk = b.build().unwrap();
+
+        let mut vm = VM::new(chunk.clone());
+        let err = vm.execute_all().unwrap_err().with_backtrace(&vm);
+        assert_eq!(err.error, VmError::DivisionByZero);
+        let (f_entry, g_entry) = (chunk.functions()[0].entry, chunk.functions()[1].entry);
+        let frames: Vec<_> = err
+            .backtrace
+            .0
+            .iter()
+            .map(|frame| (frame.function, frame.ip))
+            .collect();
+        assert_eq!(
+            frames,
+            [(Some(g), g_entry + 4), (Some(f), f_entry + 1), (None, 1)]
+        );
+        assert_eq!(chunk.code()[f_entry + 1], Call as u8);
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "division by zero\n  #0 g at {}\n  #1 <function at {f_entry}> at {}\n  #2 <top level> at 1",
+                g_entry + 4,
+                f_entry + 1
+            )
+        );
+    }
+
+    #[test]
+    fn test_caller_locals_are_roots() {
+        let mut b = ChunkBuilder::new();
+        let entry = b.label();
+        let sub = b.function(entry, 0);
+        b.string("kept").store(0).call(sub).load(0).op(Return);
+        b.bind(entry).op(Gc).op(Return);
+
+        let mut vm = VM::new(b.build().unwrap());
+        vm.execute_all().unwrap();
+        assert_eq!(vm.get_string(), Ok("kept".to_string()));
+    }
+
+    #[test]
+    fn test_call_errors() {
+        let chunk = Chunk::new(vec![Call as u8, 0, 0]);
+        assert_eq!(
+            VM::new(chunk).execute_all(),
+            Err(VmError::UnknownFunction(0))
+        );
+
+        let mut b = ChunkBuilder::new();
+        let entry = b.label();
+        let sub = b.function(entry, 1);
+        b.call(sub).bind(entry);
+        assert_eq!(
+            VM::new(b.build().unwrap()).execute_all(),
+            Err(VmError::StackUnderflow)
+        );
+
+        let mut b = ChunkBuilder::new();
+        let entry = b.label();
+        let sub = b.function(entry, 0);
+        b.bind(entry).call(sub);
+        assert_eq!(
+            VM::new(b.build().unwrap()).execute_all(),
+            Err(VmError::StackOverflow)
+        );
+    }
+
+    #[test]
+    fn test_args() {
+        // add(a, b) = (a + 10) + b, changing a in its own frame
+        let mut b = ChunkBuilder::new();
+        let entry = b.label();
+        let add = b.function(entry, 2);
+        b.returns(add, 1);
+        b.imm_i(100).store(0);
+        b.imm_i(5).imm_i(1).imm_i(2).call(add).load(0).op(Return);
+        b.bind(entry).load_arg(0).imm_i(10).op(AddI).store_arg(0);
+        b.load_arg(0).load_arg(1).op(AddI).op(Return);
+        let chunk = b.build().unwrap();
+        assert_eq!(verifier::verify_stack(&chunk), Ok(()));
+        let mut vm = VM::new(chunk);
+        vm.execute_all().unwrap();
+        assert_eq!(
+            vm.stack,
+            [Value::Integer(5), Value::Integer(13), Value::Integer(100)]
+        );
+
+        // only indices 0 and 1 are arguments of a function of arity 2,
+        // whatever else the frame holds
+        let mut b = ChunkBuilder::new();
+        let entry = b.label();
+        let f = b.function(entry, 2);
+        b.imm_i(1).imm_i(2).call(f).op(Return);
+        b.bind(entry).imm_i(3).store(2).load_arg(0).load_arg(2);
+        let chunk = b.build().unwrap();
+        assert!(verifier::verify(&chunk).is_err());
+        let mut vm = VM::new(chunk);
+        let err = vm.execute_all().unwrap_err();
+        assert_eq!(err, VmError::ArgOutOfRange { index: 2, arity: 2 });
+        assert_eq!(vm.stack, [Value::Integer(1)]);
+        assert_eq!(
+            err.to_string(),
+            "argument 2 out of range for a function of arity 2"
+        );
+
+        let chunk = ChunkBuilder::new().load_arg(0).build().unwrap();
+        assert_eq!(
+            VM::new(chunk).execute_all(),
+            Err(VmError::ArgOutOfRange { index: 0, arity: 0 })
+        );
+    }
+
+    #[test]
+    fn test_arrays() {
+        let mut b = ChunkBuilder::new();
+        b.imm_i(3).op(ArrayNew).store(0);
+        b.load(0).imm_i(2).imm_i(5).op(ArraySet);
+        b.load(0).imm_i(2).op(ArrayGet);
+        b.load(0).imm_i(0).op(ArrayGet);
+        b.load(0).op(ArrayLen);
+        let mut vm = VM::new(b.build().unwrap());
+        vm.execute_all().unwrap();
+        assert_eq!(
+            vm.stack,
+            [Value::Integer(5), Value::Null, Value::Integer(3)]
+        );
+
+        for index in [3, -1] {
+            let mut b = ChunkBuilder::new();
+            b.imm_i(3).op(ArrayNew).imm_i(index).op(ArrayGet);
+            assert_eq!(
+                VM::new(b.build().unwrap()).execute_all(),
+                Err(VmError::IndexOutOfBounds { index, len: 3 })
+            );
+        }
+
+        let chunk = ChunkBuilder::new().imm_i(-2).op(ArrayNew).build().unwrap();
+        assert_eq!(
+            VM::new(chunk).execute_all(),
+            Err(VmError::InvalidLength(-2))
+        );
+
+        let chunk = ChunkBuilder::new().op(MapNew).op(ArrayLen).build().unwrap();
+        assert_eq!(
+            mismatch(VM::new(chunk).execute_all()),
+            ("array", "object", ArrayLen, 1)
+        );
+    }
+
+    #[test]
+    fn test_relative_array_index() {
+        // [10, 20, 30]
+        let array = |b: &mut ChunkBuilder, len: i64| {
+            b.imm_i(len).op(ArrayNew).store(0);
+            for i in 0..len {
+                b.load(0).imm_i(i).imm_i(10 * (i + 1)).op(ArraySet);
+            }
+        };
+        let get = |len: i64, index: i64| {
+            let mut b = ChunkBuilder::new();
+            array(&mut b, len);
+            b.load(0).imm_i(index).op(ArrayGetRel);
+            VM::new(b.build().unwrap()).execute_all().map(|o| o.value)
+        };
+        let int = |i| Ok(Some(Value::Integer(i)));
+        assert_eq!(get(3, -1), int(30));
+        assert_eq!(get(3, -3), int(10));
+        assert_eq!(get(3, 0), int(10));
+        assert_eq!(get(3, 2), int(30));
+        for index in [-4, 3, i64::MIN] {
+            let err = Err(VmError::IndexOutOfBounds { index, len: 3 });
+            assert_eq!(get(3, index), err);
+        }
+        for index in [-1, 0, 1, i64::MIN] {
+            let err = Err(VmError::IndexOutOfBounds { index, len: 0 });
+            assert_eq!(get(0, index), err);
+        }
+
+        let mut b = ChunkBuilder::new();
+        array(&mut b, 3);
+        b.load(0).imm_i(-1).imm_i(7).op(ArraySetRel);
+        b.load(0).imm_i(-3).imm_i(8).op(ArraySetRel);
+        b.load(0)
+            .imm_i(2)
+            .op(ArrayGet)
+            .load(0)
+            .imm_i(0)
+            .op(ArrayGet);
+        let mut vm = VM::new(b.build().unwrap());
+        vm.execute_all().unwrap();
+        assert_eq!(vm.stack, [Value::Integer(7), Value::Integer(8)]);
+
+        let mut b = ChunkBuilder::new();
+        array(&mut b, 3);
+        b.load(0).imm_i(-4).imm_i(7).op(ArraySetRel);
+        assert_eq!(
+            VM::new(b.build().unwrap()).execute_all(),
+            Err(VmError::IndexOutOfBounds { index: -4, len: 3 })
+        );
+    }
+
+    #[test]
+    fn test_unchecked_array_access() {
+        let mut b = ChunkBuilder::new();
+        b.imm_i(3).op(ArrayNew).store(0);
+        b.load(0).imm_i(2).imm_i(5).op(ArraySetUnchecked);
+        b.load(0).imm_i(2).op(ArrayGetUnchecked);
+        let mut vm = VM::new(b.build().unwrap());
+        vm.execute_all().unwrap();
+        assert_eq!(vm.stack, [Value::Integer(5)]);
+
+        // what the loop would have ruled out traps as the checked opcodes do
+        for index in [3, -1] {
+            let mut b = ChunkBuilder::new();
+            b.imm_i(3).op(ArrayNew).imm_i(index).op(ArrayGetUnchecked);
+            assert_eq!(
+                VM::new(b.build().unwrap()).execute_all(),
+                Err(VmError::IndexOutOfBounds { index, len: 3 })
+            );
+            let mut b = ChunkBuilder::new();
+            b.imm_i(3).op(ArrayNew).imm_i(index).op(Imm0);
+            b.op(ArraySetUnchecked);
+            assert_eq!(
+                VM::new(b.build().unwrap()).execute_all(),
+                Err(VmError::IndexOutOfBounds { index, len: 3 })
+            );
+        }
+        let mut b = ChunkBuilder::new();
+        b.imm_w(1).op(Imm0).op(ArrayGetUnchecked);
+        let result = VM::new(b.build().unwrap()).execute_all();
+        assert_eq!(mismatch(result), ("array", "word", ArrayGetUnchecked, 3));
+
+        // nor can they write to objects that aren't arrays
+        let mut b = ChunkBuilder::new();
+        b.interned("a").store(0);
+        b.load(0).op(Imm0).load_const(Constant::Char('b'));
+        b.op(ArraySetUnchecked);
+        let result = VM::new(b.build().unwrap()).execute_all();
+        assert_eq!(mismatch(result), ("array", "object", ArraySetUnchecked, 14));
+        let mut b = ChunkBuilder::new();
+        b.string("a").op(Imm0).op(ArrayGetUnchecked);
+        let result = VM::new(b.build().unwrap()).execute_all();
+        assert_eq!(mismatch(result), ("array", "object", ArrayGetUnchecked, 4));
+    }
+
+    #[test]
+    fn test_array_ranges() {
+        // runs `body` with [0, 1, 2, 3, 4, 5] in local 0, returning local 0
+        let run = |body: fn(&mut ChunkBuilder)| -> Result<Vec<Value>, VmError> {
+            let mut b = ChunkBuilder::new();
+            b.imm_i(6).op(ArrayNew).store(0);
+            for i in 0..6 {
+                b.load(0).imm_i(i).imm_i(i).op(ArraySet);
+            }
+            body(&mut b);
+            let mut vm = VM::new(b.load(0).build().unwrap());
+            vm.set_gc_stress(true).unwrap();
+            let array = vm.execute_all()?.value.unwrap().get_object_ptr().unwrap();
+            Ok(vm.heap.get(array).fields.clone())
+        };
+        let ints = |ints: &[i64]| Ok(ints.iter().copied().map(Value::Integer).collect());
+        let out_of_bounds = |index| Err(VmError::IndexOutOfBounds { index, len: 6 });
+
+        // dst, dst_start, src, src_start, len
+        let forward = |b: &mut ChunkBuilder| {
+            b.load(0).imm_i(2).load(0).imm_i(0).imm_i(3).op(ArrayCopy);
+        };
+        assert_eq!(run(forward), ints(&[0, 1, 0, 1, 2, 5]));
+        let backward = |b: &mut ChunkBuilder| {
+            b.load(0).imm_i(0).load(0).imm_i(2).imm_i(4).op(ArrayCopy);
+        };
+        assert_eq!(run(backward), ints(&[2, 3, 4, 5, 4, 5]));
+        let other = |b: &mut ChunkBuilder| {
+            b.imm_i(2).op(ArrayNew).store(1);
+            b.load(1).imm_i(0).load(0).imm_i(4).imm_i(2).op(ArrayCopy);
+            b.load(0).imm_i(1).load(1).imm_i(0).imm_i(2).op(ArrayCopy);
+        };
+        assert_eq!(run(other), ints(&[0, 4, 5, 3, 4, 5]));
+        let empty = |b: &mut ChunkBuilder| {
+            b.load(0).imm_i(6).load(0).imm_i(0).imm_i(0).op(ArrayCopy);
+        };
+        assert_eq!(run(empty), ints(&[0, 1, 2, 3, 4, 5]));
+        let past_end = |b: &mut ChunkBuilder| {
+            b.load(0).imm_i(4).load(0).imm_i(0).imm_i(3).op(ArrayCopy);
+        };
+        assert_eq!(run(past_end), out_of_bounds(7));
+        let before_start = |b: &mut ChunkBuilder| {
+            b.load(0).imm_i(0).load(0).imm_i(-1).imm_i(1).op(ArrayCopy);
+        };
+        assert_eq!(run(before_start), out_of_bounds(-1));
+
+        // array, start, len, value
+        let fill = |b: &mut ChunkBuilder| {
+            b.load(0).imm_i(1).imm_i(3).op(ImmNeg1).op(ArrayFill);
+        };
+        assert_eq!(run(fill), ints(&[0, -1, -1, -1, 4, 5]));
+        let empty = |b: &mut ChunkBuilder| {
+            b.load(0).imm_i(6).imm_i(0).op(ImmNeg1).op(ArrayFill);
+        };
+        assert_eq!(run(empty), ints(&[0, 1, 2, 3, 4, 5]));
+        let past_end = |b: &mut ChunkBuilder| {
+            b.load(0).imm_i(7).imm_i(0).op(ImmNeg1).op(ArrayFill);
+        };
+        assert_eq!(run(past_end), out_of_bounds(7));
+        let negative = |b: &mut ChunkBuilder| {
+            b.load(0).imm_i(0).op(ImmNeg1).op(ImmNeg1).op(ArrayFill);
+        };
+        assert_eq!(run(negative), Err(VmError::InvalidLength(-1)));
+
+        // array, start, len
+        let slice = |b: &mut ChunkBuilder| {
+            b.load(0).imm_i(1).imm_i(3).op(ArraySlice).store(0);
+        };
+        assert_eq!(run(slice), ints(&[1, 2, 3]));
+        let empty = |b: &mut ChunkBuilder| {
+            b.load(0).imm_i(6).imm_i(0).op(ArraySlice).store(0);
+        };
+        assert_eq!(run(empty), ints(&[]));
+        let past_end = |b: &mut ChunkBuilder| {
+            b.load(0).imm_i(5).imm_i(2).op(ArraySlice).store(0);
+        };
+        assert_eq!(run(past_end), out_of_bounds(7));
+
+        // the source is only on the stack while the slice is allocated, and
+        // the strings in it only in the source
+        let mut b = ChunkBuilder::new();
+        b.imm_i(3).op(ArrayNew).store(0);
+        for (i, s) in ["a", "b", "c"].into_iter().enumerate() {
+            b.load(0).imm_i(i as i64).string(s).op(ArraySet);
+        }
+        b.load(0).op(Imm0).store(0);
+        b.imm_i(1).imm_i(2).op(ArraySlice);
+        let mut vm = VM::new(b.build().unwrap());
+        vm.set_gc_stress(true).unwrap();
+        let slice = vm.execute_all().unwrap().value.unwrap();
+        let strings: Vec<_> = vm.heap.get(slice.get_object_ptr().unwrap()).fields[..]
+            .iter()
+            .map(|s| {
+                vm.heap
+                    .get(s.get_object_ptr().unwrap())
+                    .as_string()
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(strings, ["b", "c"]);
+    }
+
+    #[test]
+    fn test_array_push_pop() {
+        const N: i64 = 10_000;
+        let mut b = ChunkBuilder::new();
+        let (push, drain, done) = (b.label(), b.label(), b.label());
+        b.op(Imm0).op(ArrayNew).store(0).op(Imm0).store(1);
+        // a garbage array per element keeps the collector busy
+        b.bind(push).load(1).imm_i(N).op(CmpGeI).goto_if(drain);
+        b.load(0).load(1).op(ArrayPush);
+        b.op(Imm1).op(ArrayNew).store(2);
+        b.load(1).op(Imm1).op(AddI).store(1).goto(push);
+
+        // popped in reverse, or the result is -1
+        b.bind(drain).load(1).op(Imm0).op(CmpLeI).goto_if(done);
+        b.load(1).op(Imm1).op(SubI).store(1);
+        b.load(0).op(ArrayPop).load(1).op(CmpEqI).goto_if(drain);
+        b.op(ImmNeg1).op(Return);
+        b.bind(done).load(0).op(ArrayLen).op(Return);
+        let chunk = b.build().unwrap();
+
+        let mut vm = VM::new(chunk.clone());
+        vm.set_gc_stress(true).unwrap();
+        let outcome = vm.execute_all().unwrap();
+        assert_eq!(outcome.value, Some(Value::Integer(0)));
+        assert!(vm.heap.stats().collections as i64 > N);
+
+        // the elements count towards the heap limit while they're there
+        let element = mem::size_of::<Value>();
+        let policy = |max_heap_bytes| ExecutionPolicy {
+            max_heap_bytes: Some(max_heap_bytes),
+            ..ExecutionPolicy::permissive()
+        };
+        let mut vm = VM::new(chunk.clone());
+        vm.set_policy(Some(policy(element * N as usize / 2)))
+            .unwrap();
+        assert_eq!(vm.execute_all(), Err(VmError::HeapExhausted));
+        let mut vm = VM::new(chunk);
+        vm.set_policy(Some(policy(element * N as usize * 2)))
+            .unwrap();
+        assert_eq!(vm.execute_all().unwrap().value, Some(Value::Integer(0)));
+        let bytes = |b: &mut ChunkBuilder| {
+            let mut vm = VM::new(b.build().unwrap());
+            vm.execute_all().unwrap();
+            vm.heap.bytes()
+        };
+        let mut b = ChunkBuilder::new();
+        b.op(Imm0)
+            .op(ArrayNew)
+            .store(0)
+            .load(0)
+            .op(Imm1)
+            .op(ArrayPush);
+        let pushed = bytes(&mut b);
+        assert_eq!(bytes(b.load(0).op(ArrayPop)), pushed - element);
+
+        // pushed objects are traced like any other field
+        let mut b = ChunkBuilder::new();
+        b.op(Imm0).op(ArrayNew).store(0);
+        b.load(0).string("kept").op(ArrayPush);
+        b.op(Imm1).op(ArrayNew).op(Gc).load(0).op(ArrayPop);
+        let mut vm = VM::new(b.build().unwrap());
+        vm.set_gc_stress(true).unwrap();
+        let val = vm.execute_all().unwrap().value.unwrap();
+        let kept = vm.heap.get(val.get_object_ptr().unwrap()).as_string();
+        assert_eq!(kept.as_deref(), Some("kept"));
+
+        let chunk = ChunkBuilder::new()
+            .op(Imm0)
+            .op(ArrayNew)
+            .op(ArrayPop)
+            .build();
+        assert_eq!(
+            VM::new(chunk.unwrap()).execute_all(),
+            Err(VmError::IndexOutOfBounds { index: -1, len: 0 })
+        );
+    }
+
+    #[cfg(feature = "verified-fast")]
+    #[test]
+    fn test_unchecked_returns() {
+        use crate::verifier::VerifiedChunk;
+        // f leaves a stray value under its result
+        let mut b = ChunkBuilder::new();
+        let entry = b.label();
+        let f = b.function(entry, 0);
+        b.returns(f, 1);
+        b.call(f).op(Return);
+        b.bind(entry).op(Imm0).op(Imm1).op(Return);
+        let chunk = b.build().unwrap();
+
+        let mut vm = VM::new(chunk.clone());
+        vm.set_unchecked_returns(true).unwrap();
+        assert!(matches!(
+            vm.execute_all(),
+            Err(VmError::StackImbalance { .. })
+        ));
+        let mut vm = VM::from_verified(VerifiedChunk::new(chunk).unwrap()).unwrap();
+        vm.set_unchecked_returns(true).unwrap();
+        assert_eq!(vm.execute_all().unwrap().value, Some(Value::Integer(1)));
+        assert_eq!(vm.stack(), [Value::Integer(0), Value::Integer(1)]);
+    }
+
+    #[cfg(feature = "verified-fast")]
+    #[test]
+    fn test_from_verified() {
+        use crate::verifier::VerifiedChunk;
+        let verified = VerifiedChunk::new(factorial()).unwrap();
+        let mut vm = VM::from_verified(verified.clone()).unwrap();
+        assert!(vm.is_verified());
+        assert_eq!(vm.execute_all().unwrap().value, Some(Value::Integer(120)));
+        assert!(!VM::new(factorial()).is_verified());
+
+        // reading operands by hand can leave the ip off a boundary
+        let mut vm = VM::from_verified(verified).unwrap();
+        assert_eq!(vm.advance(), Ok(ImmI as u8));
+        assert!(!vm.is_verified());
+        // the immediate's top byte, zero, decodes as a Return
+        assert_eq!(vm.execute(), Ok(()));
+        assert!(vm.eof());
+    }
+
+    #[test]
+    fn test_accumulate() {
+        let mut b = ChunkBuilder::new();
+        b.imm_i(i64::MAX).store(0).imm_i(3).store(1);
+        b.imm_i(0).store(2).imm_i(0).store(3);
+        for i in [4, -9, 2] {
+            b.imm_i(i).min_to_local(2).imm_i(i).max_to_local(3);
+        }
+        b.op(Imm1).add_to_local(0).imm_i(-2).mul_to_local(1);
+        let mut vm = VM::new(b.build().unwrap());
+        vm.execute_all().unwrap();
+        assert!(vm.stack().is_empty());
+        assert_eq!(
+            vm.locals(),
+            [i64::MIN, -6, -9, 4].map(|i| Some(Value::Integer(i)))
+        );
+
+        // the local is read before the operand is popped
+        let chunk = ChunkBuilder::new().op(Imm1).add_to_local(0).build();
+        let mut vm = VM::new(chunk.unwrap());
+        assert_eq!(vm.execute_all(), Err(VmError::UninitializedLocal(0)));
+        assert_eq!(vm.stack(), [Value::Integer(1)]);
+
+        let mut b = ChunkBuilder::new();
+        b.imm_w(1).store(0).op(Imm1).max_to_local(0);
+        let result = VM::new(b.build().unwrap()).execute_all();
+        assert_eq!(mismatch(result), ("integer", "word", MaxToLocal, 6));
+        let mut b = ChunkBuilder::new();
+        b.op(Imm1).store(0).imm_f(1.0).mul_to_local(0);
+        let result = VM::new(b.build().unwrap()).execute_all();
+        assert_eq!(mismatch(result), ("integer", "float", MulToLocal, 13));
+    }
+
+    #[test]
+    fn test_field_reflection() {
+        // sums the fields of whatever object is in local 0 and doubles
+        // each in place, knowing nothing of its tag or size
+        let mut b = ChunkBuilder::new();
+        let (head, done) = (b.label(), b.label());
+        b.op(Imm0).store(1).op(Imm0).store(2);
+        b.bind(head)
+            .load(1)
+            .load(0)
+            .op(FieldCount)
+            .op(CmpGeI)
+            .goto_if(done);
+        b.load(0).load(1).op(GetFieldDyn).load(2).op(AddI).store(2);
+        b.load(0)
+            .load(1)
+            .load(0)
+            .load(1)
+            .op(GetFieldDyn)
+            .imm_i(2)
+            .op(MulI);
+        b.op(SetFieldDyn);
+        b.load(1).op(Imm1).op(AddI).store(1).goto(head);
+        b.bind(done).load(2).op(Return);
+        let chunk = b.build().unwrap();
+
+        for len in [0, 3, 50] {
+            let mut vm = VM::new(chunk.clone());
+            let fields: Vec<_> = (1..=len).map(Value::Integer).collect();
+            let obj = vm.alloc_object(7, fields);
+            vm.set_local(0, Value::ObjectPtr(obj)).unwrap();
+            let sum = len * (len + 1) / 2;
+            assert_eq!(vm.execute_all().unwrap().value, Some(Value::Integer(sum)));
+            let doubled: Vec<_> = (1..=len).map(|i| Value::Integer(2 * i)).collect();
+            assert_eq!(vm.heap.get(obj).fields, doubled);
+        }
+
+        let run = |index: fn(&mut ChunkBuilder) -> &mut ChunkBuilder| {
+            let mut b = ChunkBuilder::new();
+            index(b.imm_i(3).op(ArrayNew)).op(GetFieldDyn);
+            VM::new(b.build().unwrap()).execute_all()
+        };
+        assert_eq!(
+            run(|b| b.op(ImmNeg1)),
+            Err(VmError::IndexOutOfBounds { index: -1, len: 3 })
+        );
+        assert_eq!(
+            run(|b| b.imm_i(3)),
+            Err(VmError::FieldOutOfBounds { index: 3, len: 3 })
+        );
+        assert_eq!(
+            mismatch(run(|b| b.imm_w(1))),
+            ("integer", "word", GetFieldDyn, 5)
+        );
+    }
+
+    #[test]
+    fn test_buffers() {
+        // a 16-byte header: u32 magic, u16 version, u8 flags, u8 padding
+        // and a u64 length
+        let mut b = ChunkBuilder::new();
+        b.imm_i(16).op(BufNew).store(0);
+        b.load(0).imm_i(0).imm_i(0xcafe_f00d).op(BufStore32);
+        b.load(0).imm_i(4).imm_i(0x0102).op(BufStore16);
+        b.load(0).imm_i(6).imm_i(0x1ff).op(BufStore8);
+        b.load(0).imm_i(8).imm_i(-2).op(BufStore64);
+        b.load(0).imm_i(3).op(BufLoad32);
+        b.load(0).imm_i(8).op(BufLoad64);
+        b.load(0).op(BufLen);
+        b.load(0);
+        let mut vm = VM::new(b.build().unwrap());
+        vm.set_gc_stress(true).unwrap();
+        let buffer = vm.execute_all().unwrap().value.unwrap();
+        let buffer = buffer.get_object_ptr().unwrap();
+        #[rustfmt::skip]
+        assert_eq!(vm.buffer_bytes(buffer).unwrap(), [
+            0xca, 0xfe, 0xf0, 0x0d,
+            0x01, 0x02,
+            0xff,
+            0x00,
+            0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe,
+        ]);
+        assert_eq!(
+            vm.stack[..3],
+            [
+                Value::Integer(0x0d01_02ff),
+                Value::Integer(-2),
+                Value::Integer(16)
+            ]
+        );
+
+        vm.buffer_bytes_mut(buffer).unwrap()[7] = 0x80;
+        assert_eq!(vm.buffer_bytes(buffer).unwrap()[6..8], [0xff, 0x80]);
+        let map = vm.alloc_map();
+        assert_eq!(
+            vm.buffer_bytes(map),
+            Err(VmError::TypeMismatch {
+                expected: "buffer",
+                found: "object"
+            })
+        );
+
+        let run = |b: &mut ChunkBuilder| VM::new(b.build().unwrap()).execute_all();
+        let out_of_bounds = |offset, width| {
+            Err(VmError::BufferOutOfBounds {
+                offset,
+                width,
+                len: 16,
+            })
+        };
+        let new = || {
+            let mut b = ChunkBuilder::new();
+            b.imm_i(16).op(BufNew);
+            b
+        };
+        assert_eq!(run(new().imm_i(9).op(BufLoad64)), out_of_bounds(9, 8));
+        assert_eq!(run(new().imm_i(16).op(BufLoad8)), out_of_bounds(16, 1));
+        assert_eq!(
+            run(new().imm_i(-1).imm_i(0).op(BufStore16)),
+            out_of_bounds(-1, 2)
+        );
+        assert_eq!(
+            run(ChunkBuilder::new().op(ImmNeg1).op(BufNew)),
+            Err(VmError::InvalidLength(-1))
+        );
+        assert_eq!(
+            mismatch(run(ChunkBuilder::new().imm_i(1).op(ArrayNew).op(BufLen))),
+            ("buffer", "object", BufLen, 2)
+        );
+    }
+
+    #[test]
+    fn test_canonical_nans() {
+        let zero = std::hint::black_box(0.0);
+        let quotient = zero / zero;
+        let signaling = 0x7ff0_0000_0000_0001;
+        let negative = 0xfff8_0000_0000_0000;
+        let bits = |b: &mut ChunkBuilder, canonical| {
+            let mut vm = VM::new(b.op(F2Bits).build().unwrap());
+            vm.set_canonical_nans(canonical).unwrap();
+            vm.execute_all().unwrap().value.unwrap()
+        };
+        let new = ChunkBuilder::new;
+
+        for canonical in [false, true] {
+            let expect = |raw: u64| Value::Word(if canonical { CANONICAL_NAN } else { raw });
+            assert_eq!(
+                bits(new().imm_f(quotient), canonical),
+                expect(quotient.to_bits())
+            );
+            for raw in [signaling, negative] {
+                assert_eq!(bits(new().imm_w(raw).op(Bits2F), canonical), expect(raw));
+                let nan = Constant::Float(f64::from_bits(raw));
+                assert_eq!(bits(new().load_const(nan), canonical), expect(raw));
+            }
+            // other floats are left alone
+            assert_eq!(bits(new().imm_f(-0.0), canonical), Value::Word(1 << 63));
+        }
+        assert!(!VM::default().canonical_nans());
+    }
+
+    #[test]
+    fn test_append_and_run() {
+        use Instruction as I;
+        for mode in [ExecutionMode::Bytecode, ExecutionMode::Predecoded] {
+            let mut vm = VM::new(Chunk::new(vec![]));
+            vm.set_execution_mode(mode);
+            let outcome = vm
+                .append_and_run(&[I::ImmI(7), I::Store(0), I::Return])
+                .unwrap();
+            assert_eq!(outcome.status, Status::Finished(Termination::Return));
+
+            // the map in local 2 stands in for a global
+            let second = [
+                I::Load(0),
+                I::ImmI(6),
+                I::MulI,
+                I::Store(1),
+                I::MapNew,
+                I::Store(2),
+                I::Load(2),
+                I::Imm1,
+                I::Load(1),
+                I::MapSet,
+            ];
+            let outcome = vm.append_and_run(&second).unwrap();
+            let status = Status::Finished(Termination::EndOfChunk);
+            assert_eq!(outcome.status, status, "{mode:?}");
+            assert_eq!(outcome.instructions, 10);
+            assert_eq!(vm.local(1), Some(Value::Integer(42)));
+
+            let outcome = vm
+                .append_and_run(&[I::Gc, I::Load(2), I::Imm1, I::MapGet])
+                .unwrap();
+            assert_eq!(outcome.value, Some(Value::Integer(42)), "{mode:?}");
+            assert_eq!(vm.stack, [Value::Integer(42)]);
+
+            // jumps into the old code are allowed, but must land on it
+            let outcome = vm.append_and_run(&[I::Goto(0)]).unwrap();
+            assert_eq!(outcome.status, Status::Finished(Termination::Return));
+            assert_eq!(outcome.instructions, 4);
+            let len = vm.chunk.len();
+            assert_eq!(
+                vm.append_and_run(&[I::Nop, I::Goto(1)]),
+                Err(VmError::InvalidChunk(VerifyError::InvalidJump {
+                    offset: len + 1,
+                    target: 1
+                }))
+            );
+            assert_eq!(vm.chunk.len(), len);
+        }
+
+        // a trap inside a call is abandoned before the next run
+        let mut b = ChunkBuilder::new();
+        let entry = b.label();
+        let f = b.function(entry, 0);
+        b.imm_i(5).store(0).call(f).op(Return);
+        b.bind(entry).imm_i(1).store(0).op(Imm1).op(Imm0).op(DivI);
+        let mut vm = VM::new(b.build().unwrap());
+        assert_eq!(vm.execute_all(), Err(VmError::DivisionByZero));
+        let outcome = vm.append_and_run(&[Instruction::Load(0)]).unwrap();
+        assert_eq!(outcome.value, Some(Value::Integer(5)));
+        assert!(vm.frames.is_empty());
+    }
+
+    #[test]
+    fn test_dump_state() {
+        let mut b = ChunkBuilder::new();
+        let entry = b.label();
+        let div = b.function(entry, 1);
+        b.string("text").store(0).imm_i(7).call(div).op(Return);
+        b.bind(entry).imm_i(5).load(0).imm_i(0).op(DivI).op(Return);
+        let mut vm = VM::new(b.build().unwrap());
+
+        let err = vm.execute_all().unwrap_err();
+        assert_eq!(err, VmError::DivisionByZero);
+        let report = err.with_state(&vm).to_string();
+        assert!(report.starts_with("division by zero\n"));
+        assert!(report.contains(&format!("ip={}", vm.ip())));
+        assert!(report.contains("Return"));
+
+        let section = |name: &str| {
+            let start = report.find(&format!("{name} (")).unwrap();
+            let lines = report[start..].lines().skip(1);
+            lines.take_while(|line| line.starts_with("  ")).count()
+        };
+        assert_eq!(section("stack"), 1);
+        assert_eq!(section("locals"), 1);
+        assert_eq!(section("frames"), 1);
+        assert!(report.contains("[0] 5"));
+        assert!(report.contains("\"text\""));
+        assert!(report.contains("heap: 1 objects"));
+
+        // an object operand is shown as well as named
+        let mut b = ChunkBuilder::new();
+        b.imm_i(2).op(ArrayNew).imm_i(1).op(AddI);
+        let mut vm = VM::new(b.build().unwrap());
+        let report = vm.execute_all().unwrap_err().with_state(&vm).to_string();
+        assert!(
+            report.contains("\noperand: {tag 252: [null, null]}\n"),
+            "{report}"
+        );
+        assert!(!report.contains("fields)"), "{report}");
+    }
+
+    #[test]
+    fn test_division_semantics() {
+        let run = |op: OpCode, x: i64, y: i64| {
+            let op = Instruction::from_parts(op, &[]);
+            eval(&[Instruction::ImmI(x), Instruction::ImmI(y), op], &[])
+        };
+        let int = |i: i64| Ok(Some(Value::Integer(i)));
+        assert_eq!(run(DivI, -7, 2), int(-3));
+        assert_eq!(run(ModI, -7, 2), int(-1));
+        assert_eq!(run(DivFloorI, -7, 2), int(-4));
+        assert_eq!(run(ModEuclidI, -7, 2), int(1));
+        assert_eq!(run(ModEuclidI, -7, -2), int(1));
+        assert_eq!(run(ModEuclidI, 7, -2), int(1));
+
+        // floor(x / y) = floor(-x / -y), and the euclidean division by a
+        // positive divisor is the floor
+        let floor = |x: i128, y: i128| match y > 0 {
+            true => x.div_euclid(y),
+            false => (-x).div_euclid(-y),
+        };
+        let operands = [0, 1, -1, 2, -2, 7, -7, i64::MAX, i64::MIN];
+        for x in operands {
+            for y in operands {
+                if y == 0 {
+                    for op in [DivI, ModI, DivFloorI, ModEuclidI] {
+                        assert_eq!(run(op, x, y), Err(VmError::DivisionByZero));
+                    }
+                    continue;
+                }
+                let (wide_x, wide_y) = (x as i128, y as i128);
+                assert_eq!(run(DivI, x, y), int((wide_x / wide_y) as i64), "{x} / {y}");
+                assert_eq!(run(ModI, x, y), int((wide_x % wide_y) as i64), "{x} % {y}");
+                let expected = floor(wide_x, wide_y) as i64;
+                assert_eq!(run(DivFloorI, x, y), int(expected), "{x} // {y}");
+                let expected = wide_x.rem_euclid(wide_y) as i64;
+                assert_eq!(run(ModEuclidI, x, y), int(expected), "{x} mod {y}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_narrow_immediates_sign_extend() {
+        let mut vm = VM::new(vec![
+            ImmI8 as u8,
+            0xff,
+            ImmI8 as u8,
+            0x80,
+            ImmI8 as u8,
+            0x7f,
+            ImmI16 as u8,
+            0xff,
+            0xff,
+            ImmI16 as u8,
+            0x80,
+            0x00,
+            ImmI16 as u8,
+            0x7f,
+            0xff,
+        ]);
+        vm.execute_all().unwrap();
+        let expected = [-1, -128, 127, -1, -32768, 32767].map(Value::Integer);
+        assert_eq!(vm.stack, expected);
+
+        let mut vm = VM::new(vec![ImmI16 as u8, 0x80]);
+        assert_eq!(vm.execute_all(), Err(VmError::UnexpectedEof));
+    }
+
+    #[test]
+    fn test_narrow_word_immediates_zero_extend() {
+        let mut vm = VM::new(vec![
+            ImmI8 as u8,
+            0xff,
+            ImmW8 as u8,
+            0xff,
+            ImmI16 as u8,
+            0xff,
+            0xff,
+            ImmW16 as u8,
+            0xff,
+            0xff,
+        ]);
+        vm.execute_all().unwrap();
+        assert_eq!(
+            vm.stack,
+            [
+                Value::Integer(-1),
+                Value::Word(255),
+                Value::Integer(-1),
+                Value::Word(65535)
+            ]
+        );
+
+        let mut vm = VM::new(vec![ImmW16 as u8, 0xff]);
+        assert_eq!(vm.execute_all(), Err(VmError::UnexpectedEof));
+    }
+
+    // q, r = divmod(17, 5); the callee leaves a stray value under its
+    // results, which `ReturnN` discards
+    fn divmod(ret: impl FnOnce(&mut ChunkBuilder)) -> Chunk {
+        let mut b = ChunkBuilder::new();
+        let entry = b.label();
+        let divmod = b.function(entry, 2);
+        b.returns(divmod, 2);
+        b.imm_i(17).imm_i(5).call(divmod).store(1).store(0);
+        b.load(0).load(1).op(Return);
+        b.bind(entry).imm_i(-1);
+        b.load(0).load(1).op(DivI).load(0).load(1).op(ModI);
+        ret(&mut b);
+        b.build().unwrap()
+    }
+
+    #[test]
+    fn test_multiple_returns() {
+        let chunk = divmod(|b| {
+            b.ret_n(2);
+        });
+        assert_eq!(verifier::verify_stack(&chunk), Ok(()));
+        let mut vm = VM::new(chunk);
+        vm.execute_all().unwrap();
+        assert_eq!(vm.stack, [Value::Integer(3), Value::Integer(2)]);
+
+        // a plain return counts everything the callee left
+        let chunk = divmod(|b| {
+            b.op(Return);
+        });
+        let imbalance = VmError::StackImbalance {
+            expected: 2,
+            actual: 3,
+            function: 0,
+        };
+        assert_eq!(VM::new(chunk).execute_all(), Err(imbalance));
+
+        let chunk = divmod(|b| {
+            b.ret_n(1);
+        });
+        let mismatch = VmError::ReturnCountMismatch {
+            expected: 2,
+            found: 1,
+        };
+        assert_eq!(VM::new(chunk).execute_all(), Err(mismatch));
+
+        // the caller's values below the arguments can't be returned
+        let mut b = ChunkBuilder::new();
+        let entry = b.label();
+        let f = b.function(entry, 1);
+        b.imm_i(1).imm_i(2).call(f).op(Return);
+        b.bind(entry).ret_n(2);
+        let mut vm = VM::new(b.build().unwrap());
+        assert_eq!(vm.execute_all(), Err(VmError::StackUnderflow));
+    }
+
+    #[test]
+    fn test_stack_imbalance() {
+        // 1 + f(2), where f may leave a stray value or eat the caller's 1
+        let chunk = |body: fn(&mut ChunkBuilder), returns: Option<u8>| {
+            let mut b = ChunkBuilder::new();
+            let entry = b.label();
+            let f = b.function(entry, 1);
+            b.function_name(f, "f");
+            if let Some(count) = returns {
+                b.returns(f, count);
+            }
+            b.imm_i(1).imm_i(2).call(f).op(AddI).op(Return);
+            b.bind(entry).load(0);
+            body(&mut b);
+            let ret = b.len();
+            b.op(Return);
+            (b.build().unwrap(), ret)
+        };
+
+        for returns in [Some(1), None] {
+            let (correct, _) = chunk(|_| {}, returns);
+            let outcome = VM::new(correct).execute_all().unwrap();
+            assert_eq!(outcome.value, Some(Value::Integer(3)));
+        }
+
+        let (extra, ret) = chunk(
+            |b| {
+                b.op(Imm0);
+            },
+            Some(1),
+        );
+        let mut vm = VM::new(extra);
+        let err = vm.execute_all().unwrap_err();
+        assert_eq!(
+            err,
+            VmError::StackImbalance {
+                expected: 2,
+                actual: 3,
+                function: 0
+            }
+        );
+        assert_eq!(vm.backtrace().0[0].ip, ret);
+        let message = err.with_backtrace(&vm).to_string();
+        assert!(message.contains("#0 f at"), "{message}");
+
+        let (eats, _) = chunk(
+            |b| {
+                b.op(AddI).store(0);
+            },
+            None,
+        );
+        assert_eq!(
+            VM::new(eats).execute_all(),
+            Err(VmError::StackImbalance {
+                expected: 1,
+                actual: 0,
+                function: 0
+            })
+        );
+    }
+
+    #[test]
+    fn test_introspection() {
+        let mut b = ChunkBuilder::new();
+        let entry = b.label();
+        let f = b.function(entry, 1);
+        b.imm_i(5).imm_i(6).op(StackDepth).op(FrameDepth);
+        b.imm_i(9).call(f).op(FuelRemaining).op(Return);
+        // the argument has been popped; depths are absolute
+        b.bind(entry).op(StackDepth).op(FrameDepth).op(Return);
+        let chunk = b.build().unwrap();
+
+        let int = Value::Integer;
+        let mut vm = VM::new(chunk.clone());
+        vm.set_fuel(Some(100)).unwrap();
+        vm.execute_all().unwrap();
+        let expected = [int(5), int(6), int(2), int(0), int(4), int(1)];
+        // nine instructions ran before it
+        assert_eq!(vm.stack, [&expected[..], &[Value::Word(91)]].concat());
+
+        let mut vm = VM::new(chunk);
+        vm.execute_all().unwrap();
+        assert_eq!(vm.stack.last(), Some(&Value::Word(u64::MAX)));
+    }
+
+    #[test]
+    fn test_push_chunk_len() {
+        // jumps to the end of the chunk, skipping the push after it
+        let chunk = ChunkBuilder::new()
+            .op(PushChunkLen)
+            .op(GotoDyn)
+            .op(Imm1)
+            .build()
+            .unwrap();
+        let mut vm = VM::new(chunk);
+        let outcome = vm.execute_all().unwrap();
+        assert_eq!(outcome.status, Status::Finished(Termination::EndOfChunk));
+        assert_eq!(vm.stack, []);
+
+        let mut vm = VM::new(vec![Imm0 as u8, PushChunkLen as u8, Nop as u8]);
+        vm.execute_all().unwrap();
+        assert_eq!(vm.stack, [Value::Integer(0), Value::Word(3)]);
+    }
+
+    // Reads a time advanced by the `Tick` hook.
+    struct FakeClock(Rc<Cell<u64>>);
+
+    impl crate::clock::Clock for FakeClock {
+        fn now(&mut self) -> u64 {
+            self.0.get()
+        }
+    }
+
+    struct Tick(Rc<Cell<u64>>, u64);
+
+    impl Hook for Tick {
+        fn after_instruction(&mut self, _vm: &VmView, _ip: usize, _op: OpCode) -> HookAction {
+            self.0.set(self.0.get() + self.1);
+            HookAction::Continue
+        }
+    }
+
+    #[test]
+    fn test_fake_clock() {
+        let mut b = ChunkBuilder::new();
+        b.op(OpCode::Clock).store(0);
+        b.imm_i(1).imm_i(2).op(AddI).store(1);
+        b.op(OpCode::Clock).load(0);
+        let time = Rc::new(Cell::new(1_000));
+        let mut vm = VM::new(b.build().unwrap());
+        vm.set_clock(Box::new(FakeClock(time.clone()))).unwrap();
+        vm.add_hook(Box::new(Tick(time.clone(), 10))).unwrap();
+        vm.execute_all().unwrap();
+
+        // the first reading and the five instructions after it take 10ns each
+        let [Value::Word(end), Value::Word(start)] = vm.stack[..] else {
+            panic!("expected two readings, got {:?}", vm.stack);
+        };
+        assert_eq!((start, end - start), (1_000, 60));
+    }
+
+    #[test]
+    fn test_monotonic_clock() {
+        let mut b = ChunkBuilder::new();
+        for _ in 0..100 {
+            b.op(OpCode::Clock);
+        }
+        let mut vm = VM::new(b.build().unwrap());
+        vm.execute_all().unwrap();
+        let readings: Vec<_> = vm
+            .stack
+            .iter()
+            .map(|val| match val {
+                Value::Word(w) => *w,
+                _ => panic!("expected a word, got {val:?}"),
+            })
+            .collect();
+        assert!(readings.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    // Moves the time past any deadline after `n` instructions.
+    struct Jump(Rc<Cell<u64>>, u64);
+
+    impl Hook for Jump {
+        fn after_instruction(&mut self, _vm: &VmView, _ip: usize, _op: OpCode) -> HookAction {
+            self.1 = self.1.saturating_sub(1);
+            if self.1 == 0 {
+                self.0.set(u64::MAX);
+            }
+            HookAction::Continue
+        }
+    }
+
+    // Counts local 0 up forever.
+    fn endless() -> Chunk {
+        let mut b = ChunkBuilder::new();
+        let top = b.label();
+        b.op(Imm0).store(0);
+        b.bind(top).load(0).op(Imm1).op(AddI).store(0).goto(top);
+        b.build().unwrap()
+    }
+
+    #[test]
+    fn test_deadline() {
+        let time = Rc::new(Cell::new(0));
+        let mut vm = VM::new(endless());
+        vm.set_clock(Box::new(FakeClock(time.clone()))).unwrap();
+        vm.add_hook(Box::new(Jump(time.clone(), 3000))).unwrap();
+        vm.set_deadline(Some(1_000_000)).unwrap();
+        vm.set_fuel(Some(10_000)).unwrap();
+        assert_eq!(vm.execute_all(), Err(VmError::DeadlineExceeded));
+        // the jump is seen at the next multiple of the interval
+        assert_eq!(vm.fuel(), Some(10_000 - 3 * DEADLINE_INTERVAL));
+
+        // stopped between instructions, so the run picks up where it was
+        let ip = vm.ip;
+        let local = vm.locals[0];
+        vm.set_deadline(None).unwrap();
+        assert_eq!(vm.execute_all(), Err(VmError::FuelExhausted));
+        assert_ne!(vm.ip, ip);
+        let (Some(Value::Integer(before)), Some(Value::Integer(after))) = (local, vm.locals[0])
+        else {
+            panic!("local 0 isn't a count: {:?}", vm.locals[0]);
+        };
+        assert!(after > before);
+
+        // fuel that runs out first wins
+        time.set(0);
+        let mut vm = VM::new(endless());
+        vm.set_clock(Box::new(FakeClock(time.clone()))).unwrap();
+        vm.add_hook(Box::new(Jump(time.clone(), 3000))).unwrap();
+        vm.set_deadline(Some(1_000_000)).unwrap();
+        vm.set_fuel(Some(2000)).unwrap();
+        assert_eq!(vm.execute_all(), Err(VmError::FuelExhausted));
+        assert_eq!(time.get(), 0);
+    }
+
+    #[test]
+    fn test_timeout() {
+        // each reading of the clock is a nanosecond later
+        struct Counting(u64);
+
+        impl crate::clock::Clock for Counting {
+            fn now(&mut self) -> u64 {
+                self.0 += 1;
+                self.0 - 1
+            }
+        }
+
+        let mut vm = crate::config::VmBuilder::new()
+            .clock(Box::new(Counting(0)))
+            .timeout(Duration::from_nanos(5))
+            .build(endless())
+            .unwrap();
+        assert_eq!(vm.deadline(), Some(5));
+        assert_eq!(vm.execute_all(), Err(VmError::DeadlineExceeded));
+        // stopped at the sixth check, 5 * 1024 instructions in: two set
+        // up, then 1023 iterations of five and the first three of the next
+        assert_eq!(vm.locals[0], Some(Value::Integer(1023)));
+        assert_eq!(vm.stack, [Value::Integer(1024)]);
+
+        // a deadline already passed stops the run before it starts
+        let ip = vm.ip;
+        assert_eq!(vm.execute_all(), Err(VmError::DeadlineExceeded));
+        assert_eq!(vm.ip, ip);
+
+        // a native can't stop the clock to outrun the deadline
+        struct Stopped;
+
+        impl crate::clock::Clock for Stopped {
+            fn now(&mut self) -> u64 {
+                0
+            }
+        }
+
+        let mut b = ChunkBuilder::new();
+        let top = b.label();
+        b.bind(top).call_native("stop").goto(top);
+        let mut vm = crate::config::VmBuilder::new()
+            .clock(Box::new(Counting(0)))
+            .timeout(Duration::from_nanos(5))
+            .fuel(1 << 20)
+            .build(b.build().unwrap())
+            .unwrap();
+        vm.register_native(
+            "stop",
+            Rc::new(|vm| {
+                let refused = vm.set_clock(Box::new(Stopped));
+                assert_eq!(refused, Err(VmError::ReconfiguredWhileRunning));
+                Ok(())
+            }),
+        )
+        .unwrap();
+        assert_eq!(vm.execute_all(), Err(VmError::DeadlineExceeded));
+    }
+
+    #[test]
+    fn test_rand() {
+        let mut b = ChunkBuilder::new();
+        for _ in 0..8 {
+            b.op(Rand);
+        }
+        let chunk = b.build().unwrap();
+        let run = |seed: Option<u64>| {
+            let mut vm = VM::new(chunk.clone());
+            if let Some(seed) = seed {
+                vm.seed_rng(seed).unwrap();
+            }
+            vm.execute_all().unwrap();
+            vm.stack
+        };
+        assert_eq!(run(None), run(None));
+        assert_eq!(run(Some(42)), run(Some(42)));
+        assert_ne!(run(Some(42)), run(Some(43)));
+        assert_ne!(run(None), run(Some(42)));
+
+        let mut vm = VM::new(chunk.clone());
+        vm.seed_rng(42).unwrap();
+        vm.execute_all().unwrap();
+        let saved = vm.rng_state();
+        vm.reset().unwrap();
+        vm.execute_all().unwrap();
+        let first = vm.stack.clone();
+        vm.reset().unwrap();
+        vm.seed_rng(saved).unwrap();
+        vm.execute_all().unwrap();
+        assert_eq!(vm.stack, first);
+        assert_ne!(first, run(Some(42)));
+    }
+
+    // total += 60 / i for i from 3 down to -3
+    fn divide_down() -> Chunk {
+        let mut b = ChunkBuilder::new();
+        let (head, end) = (b.label(), b.label());
+        b.imm_i(0).store(0).imm_i(3).store(1);
+        b.bind(head).load(1).imm_i(-3).op(CmpLtI).goto_if(end);
+        b.imm_i(60).load(1).op(DivI).load(0).op(AddI).store(0);
+        b.load(1).imm_i(1).op(SubI).store(1);
+        b.goto(head);
+        b.bind(end).load(0);
+        b.build().unwrap()
+    }
+
+    #[test]
+    fn test_trap_handler_recovers() {
+        let mut vm = VM::new(divide_down());
+        assert_eq!(vm.execute_all(), Err(VmError::DivisionByZero));
+
+        let traps = Rc::new(Cell::new(0));
+        let mut vm = VM::new(divide_down());
+        let seen = traps.clone();
+        vm.set_trap_handler(Box::new(move |err, view| {
+            assert_eq!(*err, VmError::DivisionByZero);
+            assert_eq!(view.local(1), Some(Value::Integer(0)));
+            seen.set(seen.get() + 1);
+            TrapDecision::Recover {
+                push: Some(Value::Integer(0)),
+                resume_at: ResumePoint::NextInstruction,
+            }
+        }))
+        .unwrap();
+        // the terms for i and -i cancel out, leaving the substituted 0
+        let outcome = vm.execute_all().unwrap();
+        assert_eq!(outcome.value, Some(Value::Integer(0)));
+        assert_eq!(vm.local(1), Some(Value::Integer(-4)));
+        assert_eq!(traps.get(), 1);
+
+        vm.reset().unwrap();
+        vm.set_trap_handler(Box::new(|_, _| TrapDecision::Propagate))
+            .unwrap();
+        assert_eq!(vm.execute_all(), Err(VmError::DivisionByZero));
+        vm.reset().unwrap();
+        vm.clear_trap_handler().unwrap();
+        assert_eq!(vm.execute_all(), Err(VmError::DivisionByZero));
+    }
+
+    #[test]
+    fn test_trap_handler_limits() {
+        // policy violations aren't offered to the handler
+        let mut vm = VM::new(divide_down());
+        vm.set_policy(Some(ExecutionPolicy {
+            allowed: [DivI].into_iter().collect::<crate::policy::OpSet>(),
+            ..ExecutionPolicy::permissive()
+        }))
+        .unwrap();
+        vm.set_trap_handler(Box::new(|err, _| panic!("offered {err:?}")))
+            .unwrap();
+        assert!(matches!(vm.execute_all(), Err(VmError::ForbiddenOpcode(_))));
+
+        // nor can it resume past an instruction that didn't decode
+        let mut vm = VM::new(vec![Imm0 as u8, 0xff]);
+        vm.set_trap_handler(Box::new(|_, _| TrapDecision::Recover {
+            push: None,
+            resume_at: ResumePoint::NextInstruction,
+        }))
+        .unwrap();
+        assert_eq!(vm.execute_all(), Err(VmError::InvalidOpcode(0xff)));
+    }
+
+    #[test]
+    fn test_recover_with_error() {
+        // keeps an array of 10 in local 3, then computes x / y into local 2
+        // and, if that gave an error, whether it was a division by zero
+        let mut b = ChunkBuilder::new();
+        let (matched, end) = (b.label(), b.label());
+        b.imm_i(10).op(ArrayNew).store(3);
+        let division = b.len() + 6;
+        b.load(0).load(1).op(DivI).store(2);
+        b.load(2)
+            .get_field(0)
+            .imm_i(err_kind::DIVISION_BY_ZERO)
+            .op(CmpEqI);
+        b.goto_if(matched).imm_i(-1).goto(end);
+        b.bind(matched).op(Imm0);
+        b.bind(end);
+        let chunk = b.build().unwrap();
+        let setup = |vm: &mut VM| {
+            vm.set_local(0, Value::Integer(7)).unwrap();
+            vm.set_local(1, Value::Integer(0)).unwrap();
+            vm.set_trap_handler(Box::new(|_, _| TrapDecision::RecoverWithError {
+                resume_at: ResumePoint::NextInstruction,
+            }))
+            .unwrap();
+        };
+
+        let mut vm = VM::new(chunk.clone());
+        setup(&mut vm);
+        assert_eq!(vm.execute_all().unwrap().value, Some(Value::Integer(0)));
+        let error = vm.local(2).unwrap().get_object_ptr().unwrap();
+        assert_ne!(Some(error), vm.spare_error);
+        let fields = &vm.heap.get(error).fields;
+        assert_eq!(vm.heap.get(error).tag, tag::ERROR);
+        assert_eq!(
+            fields[..2],
+            [Value::Integer(9), Value::Word(division as u64)]
+        );
+        let message = vm.heap.get(fields[2].get_object_ptr().unwrap());
+        assert_eq!(message.as_string().unwrap(), "division by zero");
+        assert_eq!(VmError::DivisionByZero.kind(), err_kind::DIVISION_BY_ZERO);
+
+        // with the heap limit leaving no room for a new error, the spare one
+        // allocated with the handler stands in
+        let mut vm = VM::new(chunk);
+        setup(&mut vm);
+        let limit = vm.heap.bytes() + HeapObject::size(&Object::array(10)) + 8;
+        vm.set_policy(Some(ExecutionPolicy {
+            max_heap_bytes: Some(limit),
+            ..ExecutionPolicy::permissive()
+        }))
+        .unwrap();
+        assert_eq!(vm.execute_all().unwrap().value, Some(Value::Integer(0)));
+        let error = vm.local(2).unwrap().get_object_ptr().unwrap();
+        assert_eq!(Some(error), vm.spare_error);
+        assert_eq!(
+            vm.heap.get(error).fields,
+            [
+                Value::Integer(err_kind::DIVISION_BY_ZERO),
+                Value::Word(division as u64),
+                Value::Null
+            ]
+        );
+        assert_eq!(vm.heap.len(), 2);
+
+        // the spare is kept across collections and made again on a reset
+        vm.compact();
+        assert_eq!(vm.heap.get(vm.spare_error.unwrap()).tag, tag::ERROR);
+        vm.reset().unwrap();
+        assert_eq!(vm.heap.len(), 1);
+        vm.clear_trap_handler().unwrap();
+        vm.collect_garbage();
+        assert!(vm.heap.is_empty());
+    }
+
+    #[test]
+    fn test_push_ip_loop() {
+        let mut b = ChunkBuilder::new();
+        let end = b.label();
+        b.imm_i(0).store(2).imm_i(3).store(0);
+        // the captured address is the store, which consumes it again on
+        // every jump back
+        b.op(PushIp).store(1);
+        b.load(2).imm_i(1).op(AddI).store(2);
+        b.load(0).imm_i(1).op(SubI).store(0);
+        b.imm_i(0).load(0).op(CmpGeI).goto_if(end);
+        b.load(1).load(1).op(GotoDyn);
+        b.bind(end).load(2);
+
+        let chunk = b.build().unwrap();
+        assert_eq!(verifier::verify(&chunk), Ok(()));
+        let mut vm = VM::new(chunk);
+        vm.execute_all().unwrap();
+        assert_eq!(vm.stack, [Value::Integer(3)]);
+        assert_eq!(vm.local(1), Some(Value::Word(10)));
+    }
+
+    #[test]
+    fn test_jump_targets_checked_at_runtime() {
+        // verification can't see where a computed jump goes
+        let chunk = ChunkBuilder::new().imm_w(1).op(GotoDyn).build().unwrap();
+        assert_eq!(verifier::verify(&chunk), Ok(()));
+        assert_eq!(VM::new(chunk).execute_all(), Err(VmError::InvalidJump(1)));
+
+        let chunk = ChunkBuilder::new()
+            .imm_w(u64::MAX)
+            .op(GotoDyn)
+            .build()
+            .unwrap();
+        assert_eq!(
+            VM::new(chunk).execute_all(),
+            Err(VmError::InvalidJump(usize::MAX))
+        );
+
+        let chunk = ChunkBuilder::new().imm_i(1).op(GotoDyn).build().unwrap();
+        assert_eq!(
+            mismatch(VM::new(chunk).execute_all()),
+            ("word", "integer", GotoDyn, 1)
+        );
+
+        let mut vm = VM::new(vec![Goto as u8, 0, 2, Return as u8]);
+        assert_eq!(vm.execute_all(), Err(VmError::InvalidJump(2)));
+
+        // the end of the chunk is a valid target, but nothing past it
+        let mut vm = VM::new(vec![Goto as u8, 0, 4, Return as u8]);
+        assert_eq!(vm.execute_all().map(|outcome| outcome.instructions), Ok(1));
+        let mut vm = VM::new(vec![Goto as u8, 0, 5, Return as u8]);
+        assert_eq!(vm.execute_all(), Err(VmError::InvalidJump(5)));
+        let mut vm = VM::new(vec![Nop as u8, ImmI as u8, 0, 0]);
+        assert_eq!(vm.execute_all(), Err(VmError::UnexpectedEof));
+    }
+
+    #[test]
+    fn test_declared_locals() {
+        let chunk = factorial().with_max_locals(2);
+        let mut vm = VM::new(chunk);
+        assert_eq!(vm.locals.len(), 2);
+        vm.execute_all().unwrap();
+        assert_eq!(vm.stack, vec![Value::Integer(120)]);
+        assert_eq!(vm.locals.len(), 2);
+
+        let chunk = Chunk::from(vec![Imm0 as u8, Store as u8, 0, 2]).with_max_locals(2);
+        let mut vm = VM::new(chunk);
+        assert_eq!(
+            vm.execute_all(),
+            Err(VmError::LocalOutOfRange { index: 2, max: 2 })
+        );
+        assert_eq!(vm.locals.len(), 2);
+
+        let chunk = Chunk::from(vec![Load as u8, 0xff, 0xff]).with_max_locals(2);
+        let mut vm = VM::new(chunk);
+        assert_eq!(
+            vm.execute_all(),
+            Err(VmError::LocalOutOfRange {
+                index: 0xffff,
+                max: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_undeclared_frame_limit() {
+        // Store 60000
+        let chunk = Chunk::from(vec![Imm0 as u8, Store as u8, 0xea, 0x60]);
+        let max = MAX_UNDECLARED_LOCALS;
+        let mut vm = VM::new(chunk.clone());
+        assert_eq!(
+            vm.execute_all(),
+            Err(VmError::LocalOutOfRange { index: 60000, max })
+        );
+        assert_eq!(vm.locals.capacity(), 0);
+        assert_eq!(
+            verifier::verify(&chunk),
+            Err(VerifyError::LocalOutOfRange {
+                offset: 1,
+                index: 60000,
+                max
+            })
+        );
+
+        let mut vm = VM::new(chunk.with_max_locals(60001));
+        vm.execute_all().unwrap();
+        assert_eq!(vm.local(60000), Some(Value::Integer(0)));
+
+        let mut b = ChunkBuilder::new();
+        b.op(Imm1).store(max - 1);
+        let mut vm = VM::new(b.build().unwrap());
+        vm.execute_all().unwrap();
+        assert_eq!(vm.locals.len(), max as usize);
+    }
+
+    #[test]
+    fn test_callee_locals_are_dropped() {
+        // f(n) stores n in its local 40 and recurses down to 0
+        let mut b = ChunkBuilder::new();
+        let (entry, base) = (b.label(), b.label());
+        let f = b.function(entry, 1);
+        b.op(Imm1).store(0).imm_i(3).call(f).op(Return);
+        b.bind(entry).load(0).store(40);
+        b.load(0).op(Imm0).op(CmpLeI).goto_if(base);
+        b.load(0).op(Imm1).op(SubI).call(f).op(Return);
+        let base_ip = b.len();
+        b.bind(base).load(0).op(Return);
+
+        let mut vm = VM::new(b.build().unwrap());
+        vm.set_breakpoint(base_ip).unwrap();
+        assert_eq!(vm.execute_all().unwrap().status, Status::Paused);
+        assert_eq!(vm.frames.len(), 4);
+        assert_eq!(vm.locals.len(), 41);
+        assert_eq!(vm.frames[0].locals.len(), 1);
+
+        vm.execute_all().unwrap();
+        assert_eq!(vm.stack, [Value::Integer(0)]);
+        assert!(vm.frames.is_empty());
+        assert_eq!(vm.locals.len(), 1);
+        assert!(vm.locals.capacity() < 41);
+    }
+
+    #[test]
+    fn test_compact_locals() {
+        // ten locals, of which only 0 and 2 are read again
+        let mut b = ChunkBuilder::new();
+        for local in 0..10 {
+            b.imm_i(local).store(local as u16);
+        }
+        let pause = b.len();
+        b.load(0).load(2).op(AddI).op(Return);
+        let mut vm = VM::new(b.build().unwrap());
+        vm.set_breakpoint(pause).unwrap();
+        assert_eq!(vm.execute_all().unwrap().status, Status::Paused);
+        assert_eq!(vm.locals.len(), 10);
+        assert_eq!(vm.compact_locals(), 3);
+        assert_eq!(vm.locals.capacity(), 3);
+        vm.execute_all().unwrap();
+        assert_eq!(vm.stack, [Value::Integer(2)]);
+        // nothing is left to run
+        assert_eq!(vm.compact_locals(), 0);
+
+        // inside a call, the top level's locals are the ones compacted
+        let mut b = ChunkBuilder::new();
+        let entry = b.label();
+        let g = b.function(entry, 0);
+        for local in 0..10 {
+            b.imm_i(local).store(local as u16);
+        }
+        b.call(g).load(1).op(AddI).op(Return);
+        let pause = b.len();
+        b.bind(entry).imm_i(5).store(20).load(20).op(Return);
+        let mut vm = VM::new(b.build().unwrap());
+        vm.set_breakpoint(pause).unwrap();
+        assert_eq!(vm.execute_all().unwrap().status, Status::Paused);
+        assert_eq!(vm.compact_locals(), 2);
+        assert_eq!(vm.frames[0].locals.len(), 2);
+        assert!(vm.locals.is_empty());
+        vm.execute_all().unwrap();
+        assert_eq!(vm.stack, [Value::Integer(6)]);
+
+        // a local read after a trap the handler recovers from is kept
+        let mut b = ChunkBuilder::new();
+        b.imm_i(7).store(0);
+        let pause = b.len();
+        b.string("boom").op(TrapMsg).load(0);
+        let mut vm = VM::new(b.build().unwrap());
+        vm.set_trap_handler(Box::new(|_, _| TrapDecision::Recover {
+            push: None,
+            resume_at: ResumePoint::NextInstruction,
+        }))
+        .unwrap();
+        vm.set_breakpoint(pause).unwrap();
+        assert_eq!(vm.execute_all().unwrap().status, Status::Paused);
+        assert_eq!(vm.compact_locals(), 1);
+        vm.execute_all().unwrap();
+        assert_eq!(vm.stack, [Value::Integer(7)]);
+    }
+
+    #[test]
+    fn test_bit_counts() {
+        let cases = [
+            (0, [64, 64, 0]),
+            (1, [63, 0, 1]),
+            (0x8000_0000_0000_0000, [0, 63, 1]),
+            (0xf0, [56, 4, 4]),
+        ];
+        for (w, expected) in cases {
+            for (op, expected) in [Instruction::ClzW, Instruction::CtzW, Instruction::PopcntW]
+                .into_iter()
+                .zip(expected)
+            {
+                let count = eval(&[Instruction::Load(0), op], &[Value::Word(w)]);
+                assert_eq!(count, Ok(Some(Value::Integer(expected))), "{op:?} {w:#x}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_rotates() {
+        let x = 0x0123_4567_89ab_cdef;
+        let rotate = |by, op| {
+            eval(
+                &[Instruction::Load(0), Instruction::Load(1), op],
+                &[Value::Word(x), Value::Word(by)],
+            )
+        };
+        let cases = [
+            (4, Instruction::RotlW, x.rotate_left(4)),
+            (4, Instruction::RotrW, x.rotate_right(4)),
+            (68, Instruction::RotlW, x.rotate_left(4)),
+            (0, Instruction::RotrW, x),
+        ];
+        for (by, op, expected) in cases {
+            assert_eq!(
+                rotate(by, op),
+                Ok(Some(Value::Word(expected))),
+                "{op:?} {by}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_store_if() {
+        let run = |cond: u64| {
+            let mut b = ChunkBuilder::new();
+            b.imm_i(1).store(0);
+            b.imm_i(2).imm_w(cond).store_if(0);
+            b.imm_i(3).imm_w(cond).store_if(1);
+            let mut vm = VM::new(b.build().unwrap());
+            vm.execute_all().unwrap();
+            assert_eq!(vm.stack, []);
+            (vm.local(0), vm.local(1))
+        };
+        let int = |i| Some(Value::Integer(i));
+        assert_eq!(run(0), (int(1), None));
+        assert_eq!(run(1), (int(2), int(3)));
+        assert_eq!(run(u64::MAX), (int(2), int(3)));
+
+        // the index is checked whether or not the store happens
+        let mut b = ChunkBuilder::new();
+        b.max_locals(1).op(Imm0).imm_w(0).store_if(1);
+        assert_eq!(
+            VM::new(b.build().unwrap()).execute_all(),
+            Err(VmError::LocalOutOfRange { index: 1, max: 1 })
+        );
+    }
+
+    #[test]
+    fn test_load_or_default() {
+        let mut b = ChunkBuilder::new();
+        b.load_or_default(0).imm_i(5).store(0).load_or_default(0);
+        let mut vm = VM::new(b.build().unwrap());
+        vm.execute_all().unwrap();
+        assert_eq!(vm.stack, [Value::Null, Value::Integer(5)]);
+
+        let mut b = ChunkBuilder::new();
+        b.max_locals(2).load_or_default(2);
+        assert_eq!(
+            VM::new(b.build().unwrap()).execute_all(),
+            Err(VmError::LocalOutOfRange { index: 2, max: 2 })
+        );
+    }
+
+    #[test]
+    fn test_store_if_matches_branch() {
+        // local 0 = max(local 0, local 1), with and without a branch
+        let mut branchy = ChunkBuilder::new();
+        let skip = branchy.label();
+        branchy.load(0).load(1).op(CmpGeI).goto_if(skip);
+        branchy.load(1).store(0).bind(skip);
+        let mut select = ChunkBuilder::new();
+        select.load(1).load(0).load(1).op(CmpLtI).store_if(0);
+        let (branchy, select) = (branchy.build().unwrap(), select.build().unwrap());
+
+        let mut rng = crate::testing::Rng::new(7);
+        for _ in 0..200 {
+            let (x, y) = (rng.integer(), rng.integer());
+            let results = [&branchy, &select].map(|chunk| {
+                let mut vm = VM::new(chunk.clone());
+                vm.set_local(0, Value::Integer(x)).unwrap();
+                vm.set_local(1, Value::Integer(y)).unwrap();
+                vm.execute_all().unwrap();
+                (vm.local(0), vm.stack.clone())
+            });
+            assert_eq!(results[0], results[1], "{x} {y}");
+            assert_eq!(results[0].0, Some(Value::Integer(x.max(y))));
+        }
+    }
+
+    #[test]
+    fn test_operand_mismatch_reports_context() {
+        let mut b = ChunkBuilder::new();
+        b.imm_f(1.5).imm_f(2.5).op(AddI);
+        let err = VM::new(b.build().unwrap()).execute_all().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "type mismatch: expected integer, found float 2.5 for AddI at 18"
+        );
+
+        let mut b = ChunkBuilder::new();
+        let end = b.label();
+        b.op(Nop).imm_i(7).goto_if(end).bind(end);
+        let mut vm = VM::new(b.build().unwrap());
+        vm.set_execution_mode(ExecutionMode::Predecoded);
+        let err = vm.execute_all().unwrap_err();
+        assert_eq!(
+            mismatch(Err::<(), _>(err.clone())),
+            ("word", "integer", GotoIf, 3)
+        );
+        assert_eq!(
+            err.to_string(),
+            "type mismatch: expected word, found integer 7 for GotoIf at 3"
+        );
+
+        // natives report the call they were made from
+        let mut b = ChunkBuilder::new();
+        b.op(Imm0).imm_f(0.5).call_native("inc");
+        let mut vm = VM::new(b.build().unwrap());
+        vm.register_native(
+            "inc",
+            Rc::new(|vm: &mut VM| {
+                let i = vm.get_integer()?;
+                vm.push(Value::Integer(i + 1));
+                Ok(())
+            }),
+        )
+        .unwrap();
+        assert_eq!(
+            mismatch(vm.execute_all()),
+            ("integer", "float", CallNative, 10)
+        );
+
+        // an object operand is named by its id, which outlives the heap
+        let mut b = ChunkBuilder::new();
+        b.op(MapNew).op(ArrayLen);
+        let err = VM::new(b.build().unwrap()).execute_all().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "type mismatch: expected array, found object object #0 for ArrayLen at 1"
+        );
+    }
+
+    #[test]
+    fn test_shadow_checking() {
+        let shadow = |b: &mut ChunkBuilder| {
+            let mut vm = VM::new(b.build().unwrap());
+            vm.set_shadow_checking(true).unwrap();
+            vm.execute_all()
+        };
+
+        let mut b = ChunkBuilder::new();
+        b.imm_i(1).imm_f(1.5).op(AddI);
+        let err = shadow(&mut b).unwrap_err();
+        assert_eq!(
+            err,
+            VmError::ShadowMismatch {
+                expected: "integer",
+                found: "float",
+                producer: Some(Site { ip: 1, op: ImmF }),
+                consumer: Some(Site { ip: 10, op: AddI }),
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "shadow check: expected integer, found float pushed by ImmF at 1 and popped by AddI at 10"
+        );
+        // without it, only the consumer is known
+        assert_eq!(
+            mismatch(VM::new(b.build().unwrap()).execute_all()),
+            ("integer", "float", AddI, 10)
+        );
+
+        // the float sat under another instruction's result until it was used
+        let mut b = ChunkBuilder::new();
+        b.imm_f(2.5).imm_i(3).imm_i(4).op(AddI).op(AddI);
+        assert_eq!(
+            shadow(&mut b),
+            Err(VmError::ShadowMismatch {
+                expected: "integer",
+                found: "float",
+                producer: Some(Site { ip: 0, op: ImmF }),
+                consumer: Some(Site { ip: 14, op: AddI }),
+            })
+        );
+
+        let mut b = ChunkBuilder::new();
+        b.imm_i(1).op(AddI);
+        let mut vm = VM::new(b.build().unwrap());
+        vm.set_shadow_checking(true).unwrap();
+        vm.push(Value::Char('x'));
+        let err = vm.execute_all().unwrap_err();
+        assert_eq!(
+            err,
+            VmError::ShadowMismatch {
+                expected: "integer",
+                found: "char",
+                producer: None,
+                consumer: Some(Site { ip: 1, op: AddI }),
+            }
+        );
+        assert!(err.to_string().contains("pushed by the host"), "{err}");
+
+        // well-typed code runs as it would otherwise
+        let mut b = ChunkBuilder::new();
+        b.string("a,b").op(StrLen).imm_i(2).op(MulI).op(IntToStr);
+        assert_eq!(shadow(&mut b).map(|outcome| outcome.instructions), Ok(5));
+        let mut vm = VmBuilder::new()
+            .shadow_checking(true)
+            .build(factorial())
+            .unwrap();
+        assert!(vm.shadow_checking());
+        let outcome = vm.execute_all().unwrap();
+        assert_eq!(outcome.value, Some(Value::Integer(120)));
+        assert_eq!(outcome.instructions, 75);
+    }
+
+    #[test]
+    fn test_word_comparisons_are_unsigned() {
+        let high = 0x8000_0000_0000_0000u64;
+        let mut b = ChunkBuilder::new();
+        b.imm_w(high).imm_w(1).op(CmpGtW);
+        b.imm_i(high as i64).imm_i(1).op(CmpGtI);
+        let mut vm = VM::new(b.build().unwrap());
+        vm.execute_all().unwrap();
+        assert_eq!(vm.stack, [Value::Word(1), Value::Word(0)]);
+
+        let cases = [
+            (CmpEqW, 0),
+            (CmpGtW, 1),
+            (CmpGeW, 1),
+            (CmpLtW, 0),
+            (CmpLeW, 0),
+        ];
+        for (op, expected) in cases {
+            let mut b = ChunkBuilder::new();
+            b.imm_w(high).imm_w(1).op(op);
+            b.imm_w(7).imm_w(7).op(op);
+            let mut vm = VM::new(b.build().unwrap());
+            vm.execute_all().unwrap();
+            let equal = matches!(op, CmpEqW | CmpGeW | CmpLeW) as u64;
+            assert_eq!(
+                vm.stack,
+                [Value::Word(expected), Value::Word(equal)],
+                "{op:?}"
+            );
+        }
+
+        let mut vm = VM::new(
+            ChunkBuilder::new()
+                .imm_w(1)
+                .imm_i(1)
+                .op(CmpLtW)
+                .build()
+                .unwrap(),
+        );
+        assert_eq!(mismatch(vm.execute_all()), ("word", "integer", CmpLtW, 3));
+    }
+
+    #[test]
+    fn test_xorshift_step() {
+        // x ^= x << 13; x ^= x >> 7; x ^= x << 17; then push rotl(x, 23)
+        // and popcnt(x)
+        let mut b = ChunkBuilder::new();
+        for (shift, op) in [(13, ShlW), (7, ShrW), (17, ShlW)] {
+            b.load(0).imm_w(shift).op(op).load(0).op(XorW).store(0);
+        }
+        b.load(0).imm_w(23).op(RotlW);
+        b.load(0).op(PopcntW);
+
+        let seed = 0x9e37_79b9_7f4a_7c15u64;
+        let mut vm = VM::new(b.build().unwrap());
+        vm.set_local(0, Value::Word(seed)).unwrap();
+        vm.execute_all().unwrap();
+
+        let mut x = seed;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        assert_eq!(vm.local(0), Some(Value::Word(x)));
+        assert_eq!(
+            vm.stack,
+            [
+                Value::Word(x.rotate_left(23)),
+                Value::Integer(x.count_ones() as i64),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_float_bits_round_trip() {
+        let payload_nan = 0x7ff8_0000_dead_beef;
+        let floats = [-0.0, f64::from_bits(payload_nan), f64::INFINITY, 1.5];
+
+        let mut b = ChunkBuilder::new();
+        for f in floats {
+            b.imm_f(f).op(F2Bits);
+            b.imm_w(f.to_bits()).op(Bits2F).op(F2Bits).op(Bits2F);
+        }
+        let mut vm = VM::new(b.build().unwrap());
+        vm.execute_all().unwrap();
+
+        for (i, f) in floats.into_iter().enumerate() {
+            assert_eq!(vm.stack[2 * i], Value::Word(f.to_bits()));
+            let Value::Float(g) = vm.stack[2 * i + 1] else {
+                panic!("expected a float");
+            };
+            assert_eq!(g.to_bits(), f.to_bits());
+        }
+        assert_eq!(floats[1].to_bits(), payload_nan);
+    }
+
+    #[test]
+    fn test_i32_arithmetic() {
+        let run = |x: i64, y: i64, op| {
+            let op = Instruction::from_parts(op, &[]);
+            eval(&[Instruction::ImmI(x), Instruction::ImmI(y), op], &[])
+        };
+        let int = |i| Ok(Some(Value::Integer(i)));
+        let (min, max) = (i32::MIN as i64, i32::MAX as i64);
+
+        assert_eq!(run(0x7fff_ffff, 1, AddI32), int(-2147483648));
+        assert_eq!(run(min, 1, SubI32), int(max));
+        assert_eq!(run(0x1_0000, 0x1_0000, MulI32), int(0));
+        assert_eq!(run(max, 2, MulI32), int(-2));
+        assert_eq!(run(-7, 2, DivI32), int(-3));
+        assert_eq!(run(min, -1, DivI32), int(min));
+        assert_eq!(run(1, 0, DivI32), Err(VmError::DivisionByZero));
+        // operands are truncated to their low 32 bits first
+        assert_eq!(run(0x1_0000_0002, 3, AddI32), int(5));
+    }
+
+    #[test]
+    fn test_i64_to_i32() {
+        let cases = [
+            (0x1_0000_0005, 5),
+            (0xffff_ffff, -1),
+            (-1, -1),
+            (0x8000_0000, i32::MIN as i64),
+        ];
+        for (i, expected) in cases {
+            let truncated = eval(&[Instruction::ImmI(i), Instruction::I64toI32], &[]);
+            assert_eq!(truncated, Ok(Some(Value::Integer(expected))), "{i:#x}");
+        }
+    }
+
+    fn run_on_string(s: &str, op: OpCode) -> Result<Value, VmError> {
+        let mut vm = VM::new(ChunkBuilder::new().load(0).op(op).build().unwrap());
+        vm.set_gc_stress(true).unwrap();
+        let ptr = vm.alloc_string(s);
+        vm.set_local(0, Value::ObjectPtr(ptr)).unwrap();
+        vm.execute_all().map(|_| vm.stack[0])
+    }
+
+    #[test]
+    fn test_parse_int() {
+        let int = |i| Ok(Value::Integer(i));
+        assert_eq!(run_on_string("42", ParseInt), int(42));
+        assert_eq!(run_on_string("+5", ParseInt), int(5));
+        assert_eq!(
+            run_on_string("-9223372036854775808", ParseInt),
+            int(i64::MIN)
+        );
+
+        for bad in ["", " 5", "5 ", "9223372036854775808", "1.0", "NaN", "0x10"] {
+            assert_eq!(
+                run_on_string(bad, ParseInt),
+                Err(VmError::InvalidNumber),
+                "{bad:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_float() {
+        let parse = |s| match run_on_string(s, ParseFloat) {
+            Ok(Value::Float(f)) => Ok(f),
+            other => Err(other),
+        };
+        assert_eq!(parse("+2.5"), Ok(2.5));
+        assert_eq!(parse("inf"), Ok(f64::INFINITY));
+        assert_eq!(parse("-inf"), Ok(f64::NEG_INFINITY));
+        assert!(parse("NaN").unwrap().is_nan());
+        assert_eq!(parse("-0").map(f64::to_bits), Ok((-0.0f64).to_bits()));
+        assert_eq!(parse(" 1"), Err(Err(VmError::InvalidNumber)));
+        assert_eq!(parse("1e"), Err(Err(VmError::InvalidNumber)));
+    }
+
+    #[test]
+    fn test_fmt_float() {
+        let fmt = |f: f64, precision: i64| {
+            let mut b = ChunkBuilder::new();
+            b.imm_f(f).imm_i(precision).op(FmtFloat);
+            let mut vm = VM::new(b.build().unwrap());
+            vm.set_gc_stress(true).unwrap();
+            vm.execute_all()?;
+            let ptr = vm.stack[0].get_object_ptr().unwrap();
+            Ok(vm.heap.get(ptr).as_string().unwrap().to_string())
+        };
+        let cases = [
+            (0.125, 2, "0.12"),
+            (0.375, 2, "0.38"),
+            (2.5, 0, "2"),
+            (-0.0, 1, "-0.0"),
+            (-0.04, 1, "-0.0"),
+            (1.0 / 3.0, 5, "0.33333"),
+            (1e21, 0, "1000000000000000000000"),
+            (f64::NAN, 2, "NaN"),
+            (f64::NEG_INFINITY, 1, "-inf"),
+        ];
+        for (f, precision, expected) in cases {
+            assert_eq!(
+                fmt(f, precision).as_deref(),
+                Ok(expected),
+                "{f} {precision}"
+            );
+        }
+        assert_eq!(fmt(0.5, 100).map(|s| s.len()), Ok(102));
+        assert_eq!(fmt(0.5, -1), Err(VmError::InvalidPrecision(-1)));
+        assert_eq!(fmt(0.5, 101), Err(VmError::InvalidPrecision(101)));
+    }
+
+    #[test]
+    fn test_number_to_string() {
+        let mut b = ChunkBuilder::new();
+        b.imm_i(-17).op(IntToStr);
+        b.imm_i(i64::MIN).op(IntToStr);
+        for f in [-0.0, 0.1, 1e300, 1.0 / 3.0, f64::NAN, f64::NEG_INFINITY] {
+            b.imm_f(f).op(FloatToStr);
+        }
+        let mut vm = VM::new(b.build().unwrap());
+        vm.set_gc_stress(true).unwrap();
+        vm.execute_all().unwrap();
+
+        let strings: Vec<_> = vm
+            .stack
+            .iter()
+            .map(|val| {
+                vm.heap
+                    .get(val.get_object_ptr().unwrap())
+                    .as_string()
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(strings[..3], ["-17", "-9223372036854775808", "-0"]);
+        assert_eq!(strings[3].parse::<f64>(), Ok(0.1));
+        assert_eq!(strings[4].parse::<f64>(), Ok(1e300));
+        assert_eq!(strings[5].parse::<f64>(), Ok(1.0 / 3.0));
+        assert_eq!(strings[6..], ["NaN", "-inf"]);
+    }
+
+    #[test]
+    fn test_string_operand_types() {
+        let mut vm = VM::new(ChunkBuilder::new().load(0).op(ParseInt).build().unwrap());
+        let ptr = vm.alloc_object(3, vec![Value::Char('1')]);
+        vm.set_local(0, Value::ObjectPtr(ptr)).unwrap();
+        assert_eq!(
+            mismatch(vm.execute_all()),
+            ("string", "object", ParseInt, 3)
+        );
+    }
+
+    #[test]
+    fn test_interned_literals() {
+        let mut b = ChunkBuilder::new();
+        b.interned("tag")
+            .interned("tag")
+            .string("tag")
+            .string("tag");
+        let chunk = b.build().unwrap();
+        assert_eq!(chunk.constants(), [Constant::Str("tag".to_string())]);
+
+        let mut vm = VM::new(chunk);
+        vm.set_gc_stress(true).unwrap();
+        vm.execute_all().unwrap();
+
+        let [a, b, c, d] = vm.stack[..] else {
+            panic!("expected four strings");
+        };
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert_ne!(c, d);
+        assert_eq!(vm.heap.interned_len(), 1);
+        assert_eq!(vm.heap.lookup_interned("tag"), a.get_object_ptr());
+    }
+
+    #[test]
+    fn test_intern_is_weak() {
+        let mut vm = VM::new(ChunkBuilder::new().interned("x").build().unwrap());
+        vm.execute_all().unwrap();
+        vm.pop().unwrap();
+        vm.collect_garbage();
+        assert_eq!(vm.heap.interned_len(), 0);
+        assert!(vm.heap.is_empty());
+
+        // a plain string interned later becomes the new canonical copy
+        let ptr = vm.alloc_string("x");
+        vm.push(Value::ObjectPtr(ptr));
+        vm.chunk = ChunkBuilder::new()
+            .op(Intern)
+            .interned("x")
+            .build()
+            .unwrap();
+        vm.ip = 0;
+        vm.execute_all().unwrap();
+        assert_eq!(vm.stack, [Value::ObjectPtr(ptr); 2]);
+
+        vm.stack.clear();
+        vm.collect_garbage();
+        assert_eq!(vm.heap.lookup_interned("x"), Some(ptr));
+        assert_eq!(vm.intern_string("x"), ptr);
+        assert!(vm.unroot(ptr) && vm.unroot(ptr));
+        vm.collect_garbage();
+        assert_eq!(vm.heap.lookup_interned("x"), None);
+    }
+
+    #[test]
+    fn test_str_eq() {
+        let str_eq = |b: &mut ChunkBuilder| {
+            let mut vm = VM::new(b.op(StrEq).build().unwrap());
+            vm.set_gc_stress(true).unwrap();
+            vm.execute_all().map(|_| vm.stack[0])
+        };
+        let word = |b| Ok(Value::Word(b as u64));
+        let b = ChunkBuilder::new;
+
+        assert_eq!(str_eq(b().interned("ab").interned("ab")), word(true));
+        assert_eq!(str_eq(b().interned("ab").interned("ba")), word(false));
+        assert_eq!(str_eq(b().interned("ab").string("ab")), word(true));
+        assert_eq!(str_eq(b().string("ab").string("ab")), word(true));
+        assert_eq!(str_eq(b().string("ab").string("abc")), word(false));
+        assert_eq!(str_eq(b().string("abc").string("ab")), word(false));
+        assert_eq!(
+            mismatch(str_eq(b().string("1").imm_i(1))),
+            ("string", "integer", StrEq, 4)
+        );
+
+        // two interned strings are never read: with the contents of one
+        // forced to match the other's, they still differ
+        let pair = |interned: bool| {
+            let mut vm = VM::new(b().op(StrEq).build().unwrap());
+            let [x, y] = ["ab", "ac"].map(|s| match interned {
+                true => vm.intern_string(s),
+                false => vm.alloc_string(s),
+            });
+            vm.heap.get_mut(y).fields[1] = Value::Char('b');
+            vm.push(Value::ObjectPtr(x));
+            vm.push(Value::ObjectPtr(y));
+            vm.execute_all().map(|_| vm.stack[0])
+        };
+        assert_eq!(pair(true), word(false));
+        assert_eq!(pair(false), word(true));
+    }
+
+    #[test]
+    fn test_str_cmp() {
+        let str_cmp = |b: &mut ChunkBuilder| {
+            let mut vm = VM::new(b.op(StrCmp).build().unwrap());
+            vm.execute_all().map(|outcome| outcome.value.unwrap())
+        };
+        let int = |i| Ok(Value::Integer(i));
+        let b = ChunkBuilder::new;
+
+        // the string below the top is the left-hand side
+        assert_eq!(str_cmp(b().string("b").string("a")), int(1));
+        assert_eq!(str_cmp(b().string("a").string("b")), int(-1));
+        assert_eq!(str_cmp(b().string("ab").interned("ab")), int(0));
+        assert_eq!(str_cmp(b().string("abc").string("ab")), int(1));
+        assert_eq!(str_cmp(b().string("").string("")), int(0));
+        assert_eq!(str_cmp(b().string("z").string("é")), int(-1));
+        assert_eq!(
+            mismatch(str_cmp(b().string("a").imm_i(1).op(ArrayNew))),
+            ("string", "object", StrCmp, 5)
+        );
+    }
+
+    #[test]
+    fn test_string_slicing() {
+        let run = |b: &mut ChunkBuilder| {
+            let mut vm = VM::new(b.build().unwrap());
+            vm.set_gc_stress(true).unwrap();
+            let result = vm.execute_all().map(|outcome| outcome.value.unwrap());
+            (result, vm)
+        };
+        let substr = |s: &str, start: i64, len: i64| {
+            let mut b = ChunkBuilder::new();
+            b.string(s).imm_i(start).imm_i(len).op(Substr);
+            let (result, vm) = run(&mut b);
+            result.map(|val| {
+                vm.heap
+                    .get(val.get_object_ptr().unwrap())
+                    .as_string()
+                    .unwrap()
+            })
+        };
+        let out_of_bounds = |index, len| VmError::IndexOutOfBounds { index, len };
+
+        assert_eq!(substr("naïve", 1, 3).as_deref(), Ok("aïv"));
+        assert_eq!(substr("naïve", 0, 5).as_deref(), Ok("naïve"));
+        assert_eq!(substr("naïve", 5, 0).as_deref(), Ok(""));
+        assert_eq!(substr("", 0, 0).as_deref(), Ok(""));
+        assert_eq!(substr("naïve", 6, 0), Err(out_of_bounds(6, 5)));
+        assert_eq!(substr("naïve", -1, 2), Err(out_of_bounds(-1, 5)));
+        assert_eq!(substr("naïve", 3, 3), Err(out_of_bounds(6, 5)));
+        assert_eq!(substr("naïve", 0, -1), Err(VmError::InvalidLength(-1)));
+
+        let char_at =
+            |s: &str, index: i64| run(ChunkBuilder::new().string(s).imm_i(index).op(CharAt)).0;
+        assert_eq!(char_at("naïve", 2), Ok(Value::Char('ï')));
+        assert_eq!(char_at("naïve", 4), Ok(Value::Char('e')));
+        assert_eq!(char_at("naïve", 5), Err(out_of_bounds(5, 5)));
+        assert_eq!(char_at("", 0), Err(out_of_bounds(0, 0)));
+        assert_eq!(char_at("naïve", -1), Err(out_of_bounds(-1, 5)));
+
+        let str_len = |s: &str| run(ChunkBuilder::new().string(s).op(StrLen)).0;
+        assert_eq!(str_len("naïve"), Ok(Value::Integer(5)));
+        assert_eq!(str_len(""), Ok(Value::Integer(0)));
+        assert_eq!(
+            mismatch(run(ChunkBuilder::new().imm_i(1).op(StrLen)).0),
+            ("string", "integer", StrLen, 1)
+        );
+    }
+
+    #[test]
+    fn test_string_bubble_sort() {
+        let words = ["pear", "éclair", "fig", "apple"];
+        let n = words.len() as i64;
+        let mut b = ChunkBuilder::new();
+        let (outer, inner, no_swap, next_pass, done) =
+            (b.label(), b.label(), b.label(), b.label(), b.label());
+        b.imm_i(n).op(ArrayNew).store(0);
+        for (i, word) in words.iter().enumerate() {
+            b.load(0).imm_i(i as i64).string(word).op(ArraySet);
+        }
+
+        // for pass in (1..n).rev(), for j in 0..n - 1
+        b.imm_i(n - 1).store(1);
+        b.bind(outer).imm_i(0).load(1).op(CmpGeI).goto_if(done);
+        b.imm_i(0).store(2);
+        b.bind(inner)
+            .load(2)
+            .imm_i(n - 1)
+            .op(CmpGeI)
+            .goto_if(next_pass);
+
+        // if a[j] > a[j + 1], swap them
+        b.load(0).load(2).op(ArrayGet);
+        b.load(0).imm_i(1).load(2).op(AddI).op(ArrayGet);
+        b.op(StrCmp).imm_i(1).op(CmpLtI).goto_if(no_swap);
+        b.load(0).load(2).op(ArrayGet).store(3);
+        b.load(0).load(2);
+        b.load(0).imm_i(1).load(2).op(AddI).op(ArrayGet);
+        b.op(ArraySet);
+        b.load(0).imm_i(1).load(2).op(AddI).load(3).op(ArraySet);
+
+        b.bind(no_swap)
+            .imm_i(1)
+            .load(2)
+            .op(AddI)
+            .store(2)
+            .goto(inner);
+        b.bind(next_pass)
+            .load(1)
+            .imm_i(1)
+            .op(SubI)
+            .store(1)
+            .goto(outer);
+        b.bind(done).load(0);
+
+        let mut vm = VM::new(b.build().unwrap());
+        vm.set_gc_stress(true).unwrap();
+        let outcome = vm.execute_all().unwrap();
+        let array = outcome.value.unwrap().get_object_ptr().unwrap();
+        let sorted: Vec<_> = vm
+            .heap
+            .get(array)
+            .fields
+            .iter()
+            .map(|word| {
+                vm.heap
+                    .get(word.get_object_ptr().unwrap())
+                    .as_string()
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(sorted, ["apple", "fig", "pear", "éclair"]);
+    }
+
+    #[test]
+    fn test_interned_strings_are_immutable() {
+        let mut vm = VM::default();
+        let ptr = vm.intern_string("abc");
+        assert_eq!(
+            vm.set_object_field(ptr, 0, Value::Char('x')),
+            Err(VmError::TypeMismatch {
+                expected: "object",
+                found: "interned string"
+            })
+        );
+
+        let chunk = ChunkBuilder::new()
+            .load(0)
+            .op(ObjCloneShallow)
+            .op(Intern)
+            .build();
+        vm = VM::new(chunk.unwrap());
+        let ptr = vm.intern_string("abc");
+        vm.set_local(0, Value::ObjectPtr(ptr)).unwrap();
+        vm.execute_all().unwrap();
+        assert_eq!(vm.stack, [Value::ObjectPtr(ptr)]);
+    }
+
+    #[test]
+    fn test_map_word_count() {
+        let words = ["the", "cat", "and", "the", "hat", "and", "the", "end"];
+
+        // locals: 0 = cons list of words, 1 = words left, 2 = map, 3 = word
+        let mut b = ChunkBuilder::new();
+        let (head, found, next, end) = (b.label(), b.label(), b.label(), b.label());
+        b.op(MapNew).store(2);
+        b.bind(head).imm_i(0).load(1).op(CmpGeI).goto_if(end);
+        b.load(0).get_field(0).store(3);
+        b.load(2).load(3).op(MapContains).goto_if(found);
+        b.load(2).load(3).imm_i(1).op(MapSet).goto(next);
+        b.bind(found).load(2).load(3);
+        b.load(2).load(3).op(MapGet).imm_i(1).op(AddI).op(MapSet);
+        b.bind(next).load(0).get_field(1).store(0);
+        b.load(1).imm_i(1).op(SubI).store(1);
+        b.goto(head);
+        b.bind(end).load(2);
+
+        let mut vm = VM::new(b.build().unwrap());
+        vm.set_gc_stress(true).unwrap();
+        let mut list = Value::Integer(0);
+        for word in words.iter().rev() {
+            let s = vm.alloc_string(word);
+            let cell = vm.alloc_object(1, vec![Value::ObjectPtr(s), list]);
+            vm.unroot(s);
+            if let Some(tail) = list.get_object_ptr() {
+                vm.unroot(tail);
+            }
+            list = Value::ObjectPtr(cell);
+        }
+        vm.unroot(list.get_object_ptr().unwrap());
+        vm.set_local(0, list).unwrap();
+        vm.set_local(1, Value::Integer(words.len() as i64)).unwrap();
+        vm.execute_all().unwrap();
+
+        let map = vm.stack[0].get_object_ptr().unwrap();
+        assert_eq!(vm.heap.get(map).map_len(), Ok(5));
+        for (word, count) in [("the", 3), ("and", 2), ("cat", 1), ("hat", 1), ("end", 1)] {
+            let key = vm.alloc_string(word);
+            let found = vm.map_get(map, Value::ObjectPtr(key));
+            assert_eq!(found, Ok(Some(Value::Integer(count))), "{word}");
+        }
+    }
+
+    #[test]
+    fn test_map_ops() {
+        let mut b = ChunkBuilder::new();
+        b.op(MapNew).store(0);
+        b.load(0).imm_i(1).imm_w(10).op(MapSet);
+        b.load(0).imm_w(1).imm_w(20).op(MapSet);
+        b.load(0).imm_i(2).imm_w(30).op(MapSet);
+        b.load(0).op(MapLen);
+        b.load(0).imm_i(1).op(MapDelete);
+        b.load(0).imm_i(1).op(MapDelete);
+        b.load(0).imm_i(1).op(MapContains);
+        b.load(0).imm_w(1).op(MapGet);
+        b.load(0).imm_i(2).op(MapGet);
+        b.load(0).op(MapLen);
+        b.load(0).imm_i(1).op(MapGet);
+        let mut vm = VM::new(b.build().unwrap());
+        vm.set_gc_stress(true).unwrap();
+
+        assert_eq!(vm.execute_all(), Err(VmError::KeyNotFound));
+        assert_eq!(
+            vm.stack,
+            [
+                Value::Integer(3),
+                Value::Word(1),
+                Value::Word(0),
+                Value::Word(0),
+                Value::Word(20),
+                Value::Word(30),
+                Value::Integer(2),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_map_keys() {
+        let mut vm = VM::new(vec![]);
+        let map = vm.alloc_map();
+        let obj = vm.alloc_object(1, vec![]);
+        let s = vm.alloc_string("x");
+        let same = vm.alloc_string("x");
+
+        vm.map_set(map, Value::ObjectPtr(s), Value::Char('a'))
+            .unwrap();
+        vm.map_set(map, Value::Char('x'), Value::Char('b')).unwrap();
+        assert_eq!(
+            vm.map_get(map, Value::ObjectPtr(same)),
+            Ok(Some(Value::Char('a')))
+        );
+        assert_eq!(
+            vm.map_set(map, Value::Float(1.0), Value::Integer(0)),
+            Err(VmError::InvalidKey("float"))
+        );
+        assert_eq!(
+            vm.map_get(map, Value::ObjectPtr(obj)),
+            Err(VmError::InvalidKey("object"))
+        );
+        assert!(vm.map_get(obj, Value::Integer(0)).is_err());
+        assert!(vm.set_object_field(map, 0, Value::Integer(0)).is_err());
+
+        // a string set as a key can't be changed afterwards, which would
+        // leave the index behind
+        assert_eq!(vm.heap.get(map).fields[0], Value::ObjectPtr(s));
+        assert!(vm.set_object_field(s, 0, Value::Char('y')).is_err());
+        assert_eq!(
+            vm.map_get(map, Value::ObjectPtr(same)),
+            Ok(Some(Value::Char('a')))
+        );
+
+        // as it can't by SetField
+        let mut b = ChunkBuilder::new();
+        b.op(MapNew).store(0).string("k").store(1);
+        b.load(0).load(1).op(Imm1).op(MapSet);
+        b.load(1).load_const(Constant::Char('j')).set_field(0);
+        let mut guest = VM::new(b.build().unwrap());
+        assert!(matches!(
+            guest.execute_all(),
+            Err(VmError::TypeMismatch {
+                found: "interned string",
+                ..
+            })
+        ));
+
+        // keys and values are traced through the map's fields
+        vm.unroot(s);
+        vm.collect_garbage();
+        let key = vm.heap.get(map).fields[0].get_object_ptr().unwrap();
+        assert_eq!(vm.heap.get(key).as_string().as_deref(), Some("x"));
+    }
+
+    #[test]
+    fn test_iterate_array() {
+        // sum = 0; for x in array { sum += x }
+        let mut b = ChunkBuilder::new();
+        let (head, body, end) = (b.label(), b.label(), b.label());
+        b.imm_i(10).op(ArrayNew).store(0);
+        for i in 0..10 {
+            b.load(0).imm_i(i).imm_i(i * i).op(ArraySet);
+        }
+        // the iterator alone keeps the array alive
+        b.load(0).op(IterNew).store(1).imm_i(0).store(0);
+        b.imm_i(0).store(2);
+        b.bind(head).load(1).op(IterNext).goto_if(body).goto(end);
+        b.bind(body).load(2).op(AddI).store(2).op(Gc).goto(head);
+        b.bind(end).load(2);
+        let chunk = b.build().unwrap();
+        assert_eq!(verifier::verify_stack(&chunk), Ok(()));
+        let mut vm = VM::new(chunk);
+        vm.set_gc_stress(true).unwrap();
+        vm.execute_all().unwrap();
+        assert_eq!(vm.stack, [Value::Integer(285)]);
+
+        // an item and true, then false alone
+        let mut b = ChunkBuilder::new();
+        b.imm_i(1).op(ArrayNew).op(IterNew).store(0);
+        b.load(0)
+            .op(IterNext)
+            .load(0)
+            .op(IterNext)
+            .load(0)
+            .op(IterNext);
+        let mut vm = VM::new(b.build().unwrap());
+        vm.execute_all().unwrap();
+        assert_eq!(
+            vm.stack,
+            [Value::Null, Value::Word(1), Value::Word(0), Value::Word(0)]
+        );
+
+        let chunk = ChunkBuilder::new().imm_i(3).op(IterNew).build().unwrap();
+        assert_eq!(
+            mismatch(VM::new(chunk).execute_all()),
+            ("array or map", "integer", IterNew, 2)
+        );
+        let mut b = ChunkBuilder::new();
+        b.imm_i(1).op(ArrayNew).op(IterNext);
+        assert_eq!(
+            mismatch(VM::new(b.build().unwrap()).execute_all()),
+            ("iterator", "object", IterNext, 2)
+        );
+    }
+
+    #[test]
+    fn test_iterate_map_keys() {
+        // keys[i++] = key for every key in the map
+        let mut b = ChunkBuilder::new();
+        let (head, body, end) = (b.label(), b.label(), b.label());
+        b.op(MapNew).store(0);
+        b.load(0).imm_i(7).imm_i(0).op(MapSet);
+        b.load(0).string("x").imm_i(0).op(MapSet);
+        b.load(0)
+            .load_const(Constant::Char('c'))
+            .imm_i(0)
+            .op(MapSet);
+        b.load(0).op(MapLen).op(ArrayNew).store(1);
+        b.imm_i(0).store(2).load(0).op(IterNew).store(3);
+        b.bind(head).load(3).op(IterNext).goto_if(body).goto(end);
+        b.bind(body).store(4).load(1).load(2).load(4).op(ArraySet);
+        b.imm_i(1).load(2).op(AddI).store(2).goto(head);
+        b.bind(end).load(1);
+        let chunk = b.build().unwrap();
+        assert_eq!(verifier::verify_stack(&chunk), Ok(()));
+        let mut vm = VM::new(chunk);
+        vm.set_gc_stress(true).unwrap();
+        vm.execute_all().unwrap();
+
+        let keys = vm.stack[0].get_object_ptr().unwrap();
+        let keys = &vm.heap.get(keys).fields;
+        assert_eq!(keys[0], Value::Integer(7));
+        let s = keys[1].get_object_ptr().unwrap();
+        assert_eq!(vm.heap.get(s).as_string().as_deref(), Some("x"));
+        assert_eq!(keys[2], Value::Char('c'));
+    }
+
+    #[test]
+    fn test_iterator_mutation() {
+        // values can change under an iterator, and are read when reached
+        let mut b = ChunkBuilder::new();
+        b.op(MapNew).store(0);
+        b.load(0).imm_i(1).imm_i(10).op(MapSet);
+        b.load(0).op(IterNew).store(1);
+        b.load(0).imm_i(1).imm_i(20).op(MapSet);
+        b.load(1).op(IterNext);
+        b.imm_i(2)
+            .op(ArrayNew)
+            .store(2)
+            .load(2)
+            .op(IterNew)
+            .store(3);
+        b.load(3).op(IterNext);
+        b.load(2).imm_i(1).imm_i(5).op(ArraySet);
+        b.load(3).op(IterNext);
+        let mut vm = VM::new(b.build().unwrap());
+        vm.execute_all().unwrap();
+        assert_eq!(
+            vm.stack,
+            [
+                Value::Integer(1),
+                Value::Word(1),
+                Value::Null,
+                Value::Word(1),
+                Value::Integer(5),
+                Value::Word(1)
+            ]
+        );
+
+        // but adding or removing keys traps the next step
+        for change in [MapSet, MapDelete] {
+            let mut b = ChunkBuilder::new();
+            b.op(MapNew).store(0);
+            b.load(0).imm_i(1).imm_i(10).op(MapSet);
+            b.load(0).op(IterNew).store(1);
+            match change {
+                MapSet => b.load(0).imm_i(2).imm_i(20).op(MapSet),
+                _ => b.load(0).imm_i(1).op(MapDelete),
+            };
+            b.load(1).op(IterNext);
+            assert_eq!(
+                VM::new(b.build().unwrap()).execute_all(),
+                Err(VmError::IteratorInvalidated)
+            );
+        }
+    }
+
+    #[test]
+    fn test_incremental_barrier() {
+        #[rustfmt::skip]
+        let chunk = vec![
+            // a.0 = c; b.0 = 0
+            Load     as u8, 0, 0,
+            Load     as u8, 0, 2,
+            SetField as u8, 0, 0,
+            Load     as u8, 0, 1,
+            Imm0     as u8,
+            SetField as u8, 0, 0,
+        ];
+
+        let mut vm = VM::new(chunk);
+        let c = vm.alloc_object(3, vec![]);
+        let b = vm.alloc_object(2, vec![Value::ObjectPtr(c)]);
+        let a = vm.alloc_object(1, vec![Value::ObjectPtr(b)]);
+        vm.unroot(b);
+        vm.unroot(c);
+
+        // a is traced (black) and b shaded (gray) before c has been seen
+        vm.start_gc_cycle();
+        assert!(vm.gc_step(1));
+        assert!(a.node().reachable());
+        assert_eq!(b.node().color.get(), Color::Gray);
+        assert_eq!(c.node().color.get(), Color::Unmarked);
+
+        // hide c behind a, then cut the only edge the marker would follow
+        vm.set_local(0, Value::ObjectPtr(a)).unwrap();
+        vm.set_local(1, Value::ObjectPtr(b)).unwrap();
+        vm.set_local(2, Value::ObjectPtr(c)).unwrap();
+        vm.execute_all().unwrap();
+        vm.locals.clear();
+
+        // b was already gray, so it floats until the next cycle
+        vm.finish_gc_cycle();
+        assert_eq!(vm.heap.len(), 3);
+        vm.collect_garbage();
+        assert_eq!(vm.heap.len(), 2);
+        assert_eq!(vm.object_field(a, 0), Ok(Value::ObjectPtr(c)));
+    }
+
+    #[test]
+    fn test_incremental_gc_run() {
+        // allocate 5000 short-lived strings while keeping one map alive
+        let mut b = ChunkBuilder::new();
+        let (head, end) = (b.label(), b.label());
+        b.op(MapNew).store(1).imm_i(5000).store(0);
+        b.bind(head).imm_i(0).load(0).op(CmpGeI).goto_if(end);
+        b.load(1).load(0).load(0).op(IntToStr).op(MapSet);
+        b.load(1).load(0).op(MapDelete).store(2);
+        b.load(0).imm_i(1).op(SubI).store(0);
+        b.goto(head);
+        b.bind(end).load(1).op(MapLen);
+
+        let mut vm = VM::new(b.build().unwrap());
+        vm.set_incremental_gc(Some(IncrementalGc {
+            steps: 4,
+            interval: 16,
+        }))
+        .unwrap();
+        vm.execute_all().unwrap();
+
+        assert_eq!(vm.stack[0], Value::Integer(0));
+        assert!(vm.heap.len() < 5000 / 2);
+        vm.collect_garbage();
+        assert_eq!(vm.heap.len(), 1);
+    }
+
+    #[test]
+    fn test_gc_opcode() {
+        let mut b = ChunkBuilder::new();
+        let (head, end) = (b.label(), b.label());
+        b.op(MapNew).store(1).imm_i(100).store(0);
+        b.bind(head).imm_i(0).load(0).op(CmpGeI).goto_if(end);
+        b.load(0).op(IntToStr).store(2);
+        b.load(0).imm_i(1).op(SubI).store(0);
+        b.goto(head);
+        b.bind(end).op(HeapInfo);
+        b.string("kept").op(Gc).op(HeapInfo);
+
+        let mut vm = VM::new(b.build().unwrap());
+        vm.execute_all().unwrap();
+
+        // the map, the last string in a local and the string on the stack
+        let [before, kept, after] = vm.stack[..] else {
+            panic!("expected three values");
+        };
+        assert_eq!(before, Value::Integer(101));
+        assert_eq!(
+            vm.heap.get(kept.get_object_ptr().unwrap()).as_string(),
+            Some("kept".into())
+        );
+        assert_eq!(after, Value::Integer(3));
+    }
+
+    #[test]
+    fn test_gc_observer() {
+        // each reading of the clock is 10ns after the last
+        struct Steady(u64);
+
+        impl crate::clock::Clock for Steady {
+            fn now(&mut self) -> u64 {
+                self.0 += 10;
+                self.0
+            }
+        }
+
+        // 2500 maps, one live at a time, then an explicit collection
+        let mut b = ChunkBuilder::new();
+        let (head, end) = (b.label(), b.label());
+        b.imm_i(2500).store(0);
+        b.bind(head).imm_i(0).load(0).op(CmpGeI).goto_if(end);
+        b.op(MapNew).store(1);
+        b.load(0).imm_i(1).op(SubI).store(0);
+        b.goto(head);
+        b.bind(end).op(Gc);
+
+        let reports = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = VM::new(b.build().unwrap());
+        vm.set_clock(Box::new(Steady(0))).unwrap();
+        let observed = reports.clone();
+        vm.set_gc_observer(Some(Box::new(move |report: &GcReport| {
+            observed.borrow_mut().push(*report)
+        })))
+        .unwrap();
+        vm.execute_all().unwrap();
+
+        let reports = reports.borrow();
+        let triggers: Vec<_> = reports.iter().map(|report| report.trigger).collect();
+        use GcTrigger::{Explicit, Threshold};
+        assert_eq!(triggers, [Threshold, Threshold, Explicit]);
+        for report in reports.iter() {
+            assert_eq!(report.nanos, 10);
+            assert_eq!(report.objects_after, 1);
+            assert!(report.objects_before > report.objects_after);
+            assert!(report.bytes_before > report.bytes_after);
+        }
+        assert_eq!(reports[0].objects_before, 1024);
+        assert_eq!(reports[2].objects_before, 2500 - 2048 + 2);
+
+        let stats = vm.heap.stats();
+        assert_eq!(stats.collections, 3);
+        assert_eq!(stats.freed, 2499);
+        assert_eq!(stats.pause_nanos, 30);
+        assert_eq!(stats.last_collection, reports.last().copied());
+        let freed_bytes = reports.iter().map(|r| r.bytes_before - r.bytes_after);
+        assert_eq!(stats.freed_bytes, freed_bytes.sum::<usize>() as u64);
+
+        // without an observer the clock isn't read
+        let mut vm = VM::new(ChunkBuilder::new().op(MapNew).op(Gc).build().unwrap());
+        vm.set_gc_stress(true).unwrap();
+        vm.set_clock(Box::new(Steady(0))).unwrap();
+        vm.execute_all().unwrap();
+        let last = vm.heap.stats().last_collection.unwrap();
+        assert_eq!((last.trigger, last.nanos), (Explicit, 0));
+        assert_eq!(vm.heap.stats().collections, 2);
+
+        let mut vm = VM::new(ChunkBuilder::new().op(MapNew).build().unwrap());
+        vm.set_gc_stress(true).unwrap();
+        vm.set_gc_observer(Some(Box::new(|report: &GcReport| {
+            assert_eq!(report.trigger, GcTrigger::Stress)
+        })))
+        .unwrap();
+        vm.execute_all().unwrap();
+        assert_eq!(vm.heap.stats().collections, 1);
+    }
+
+    #[test]
+    fn test_object_ids() {
+        let mut vm = VM::default();
+        let ptrs: Vec<_> = (0..4)
+            .map(|i| vm.alloc_object(1, vec![Value::Integer(i)]))
+            .collect();
+        let ids: Vec<_> = ptrs.iter().map(|ptr| ptr.id()).collect();
+        assert_eq!(ids, [0, 1, 2, 3]);
+        vm.unroot(ptrs[1]);
+        vm.unroot(ptrs[2]);
+        vm.collect_garbage();
+        // the freed nodes are reused, but not their ids
+        let fresh = vm.alloc_object(1, Vec::new());
+        assert_eq!(fresh.id(), 4);
+
+        let forward = vm.compact();
+        let moved = [ptrs[0], ptrs[3], fresh].map(|ptr| forward[&ptr].id());
+        assert_eq!(moved, [0, 3, 4]);
+        let val = Value::ObjectPtr(forward[&ptrs[3]]);
+        assert_eq!(val.display(vm.heap()).to_string(), "object #3");
+        assert_eq!(val.to_string(), "object");
+
+        vm.reset().unwrap();
+        assert_eq!(vm.alloc_object(1, Vec::new()).id(), 0);
+    }
+
+    #[test]
+    fn test_stale_pointers() {
+        let mut vm = VM::default();
+        let kept = vm.alloc_object(1, Vec::new());
+        let freed = vm.alloc_object(1, Vec::new());
+        vm.unroot(freed);
+        vm.collect_garbage();
+        assert!(vm.heap().contains(kept));
+        assert!(!vm.heap().contains(freed));
+
+        // compaction moves every object, leaving the old pointers behind
+        let forward = vm.compact();
+        assert!(!vm.heap().contains(kept));
+        assert!(vm.heap().contains(forward[&kept]));
+        assert!(!VM::default().heap().contains(forward[&kept]));
+        vm.reset().unwrap();
+        assert!(!vm.heap().contains(forward[&kept]));
+    }
+
+    #[test]
+    #[should_panic(expected = "object pointer isn't live in this heap")]
+    fn test_stale_pointer_access() {
+        let mut vm = VM::default();
+        let ptr = vm.alloc_object(1, vec![Value::Integer(1)]);
+        vm.unroot(ptr);
+        vm.collect_garbage();
+        _ = vm.object_field(ptr, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "object pointer isn't live in this heap")]
+    fn test_stale_pointer_push() {
+        let mut vm = VM::default();
+        let ptr = vm.alloc_object(1, Vec::new());
+        vm.unroot(ptr);
+        vm.collect_garbage();
+        vm.push(Value::ObjectPtr(ptr));
+    }
+
+    #[test]
+    fn test_dump_heap_is_deterministic() {
+        // a list of arrays, every other one dropped and collected, and a
+        // map from strings
+        let mut b = ChunkBuilder::new();
+        b.op(Imm0).store(0);
+        for i in 0..20 {
+            b.imm_i(2).op(ArrayNew).store(1);
+            b.load(1).op(Imm0).imm_i(i).op(ArraySet);
+            b.load(1).op(Imm1).load(0).op(ArraySet);
+            if i % 2 == 0 {
+                b.load(1).store(0);
+            }
+        }
+        b.op(Gc).op(MapNew).store(2);
+        b.load(2).string("key").load(0).op(MapSet);
+        let chunk = b.build().unwrap();
+
+        let dump = |stress| {
+            let mut vm = VM::new(chunk.clone());
+            vm.set_gc_stress(stress).unwrap();
+            vm.execute_all().unwrap();
+            vm.compact();
+            let mut out = String::new();
+            vm.dump_heap(&mut out).unwrap();
+            out
+        };
+        let dumped = dump(false);
+        assert_eq!(dump(false), dumped);
+        assert!(
+            dumped.starts_with("#0 tag 252: [0, 0]\n#2 tag 252: [2, object #0]\n"),
+            "{dumped}"
+        );
+        assert!(dumped.contains("#19 tag 252: [19, object #18]\n"));
+        // collecting at every allocation frees the same objects, only sooner
+        assert_eq!(dump(true), dumped);
+    }
+
+    #[test]
+    fn test_compaction() {
+        let finalized = Rc::new(Cell::new(0));
+        let mut vm = VM::default();
+        vm.set_gc_stress(true).unwrap();
+        let count = finalized.clone();
+        vm.register_finalizer(1, Box::new(move |_| count.set(count.get() + 1)))
+            .unwrap();
+
+        // a ring of nodes sharing one object, interleaved with garbage
+        let shared = vm.alloc_object(2, vec![Value::Char('s')]);
+        let mut nodes = Vec::new();
+        for i in 0..50 {
+            let node = vm.alloc_object(
+                1,
+                vec![Value::Integer(i), Value::Null, Value::ObjectPtr(shared)],
+            );
+            nodes.push(node);
+            vm.alloc(Object::new(3, vec![Value::Integer(i)]));
+        }
+        for (i, &node) in nodes.iter().enumerate() {
+            let next = Value::ObjectPtr(nodes[(i + 1) % nodes.len()]);
+            vm.set_object_field(node, 1, next).unwrap();
+        }
+        let map = vm.alloc_map();
+        let key = vm.intern_string("key");
+        vm.map_set(map, Value::ObjectPtr(key), Value::ObjectPtr(nodes[7]))
+            .unwrap();
+        vm.push(Value::ObjectPtr(map));
+        vm.set_local(0, Value::ObjectPtr(nodes[0])).unwrap();
+
+        vm.chunk = ChunkBuilder::new()
+            .load(0)
+            .get_field(1)
+            .op(NewWeak)
+            .op(MapNew)
+            .op(NewWeak)
+            .build()
+            .unwrap();
+        vm.execute_all().unwrap();
+        for ptr in [shared, map, key].into_iter().chain(nodes.drain(1..)) {
+            vm.unroot(ptr);
+        }
+
+        let forward = vm.compact();
+        let live = vm.heap.len();
+        // 50 nodes, the shared object, the map and its key, two weak refs
+        assert_eq!(live, 55);
+        assert_eq!(vm.heap.arena_len(), live);
+        assert_eq!(vm.heap.stats().live, live);
+        let addrs: Vec<_> = vm
+            .heap
+            .iter()
+            .map(|ptr| ptr.as_raw().as_ptr() as usize)
+            .collect();
+        assert!(addrs
+            .windows(2)
+            .all(|w| w[1] - w[0] == std::mem::size_of::<HeapObject>()));
+
+        let head = vm.local(0).unwrap();
+        assert_eq!(
+            Some(head),
+            forward.get(&nodes[0]).copied().map(Value::ObjectPtr)
+        );
+        let key = vm.heap.lookup_interned("key").unwrap();
+        let map = vm.stack[0].get_object_ptr().unwrap();
+        let mut node = head.get_object_ptr().unwrap();
+        for i in 0..50 {
+            assert_eq!(vm.object_field(node, 0), Ok(Value::Integer(i)));
+            let shared = vm.object_field(node, 2).unwrap().get_object_ptr().unwrap();
+            assert_eq!(vm.heap.get(shared).fields, [Value::Char('s')]);
+            if i == 7 {
+                assert_eq!(
+                    vm.map_get(map, Value::ObjectPtr(key)),
+                    Ok(Some(Value::ObjectPtr(node)))
+                );
+            }
+            node = vm.object_field(node, 1).unwrap().get_object_ptr().unwrap();
+        }
+        assert_eq!(Value::ObjectPtr(node), head);
+
+        let [_, weak_node, weak_map] = vm.stack[..] else {
+            panic!("expected the map and two weak refs");
+        };
+        let target = |weak: Value| vm.heap.get(weak.get_object_ptr().unwrap()).weak_target();
+        assert_eq!(target(weak_map), Some(None));
+        let node1 = vm.object_field(head.get_object_ptr().unwrap(), 1).unwrap();
+        assert_eq!(
+            target(weak_node).flatten().map(Value::ObjectPtr),
+            Some(node1)
+        );
+
+        // a second compaction replaces the arena, and dead arena slots
+        // are reused by later allocations
+        vm.stack.truncate(1);
+        vm.compact();
+        assert_eq!((vm.heap.len(), vm.heap.arena_len()), (53, 53));
+        // the host's root was forwarded along with everything else
+        let head = vm.local(0).unwrap().get_object_ptr().unwrap();
+        assert!(vm.unroot(head));
+        vm.set_local(0, Value::Null).unwrap();
+        vm.stack.clear();
+        vm.collect_garbage();
+        assert_eq!(finalized.get(), 50);
+        assert!(vm.heap.is_empty());
+
+        let recycled = vm.heap.stats().recycled;
+        let node = vm.alloc(Object::new(1, Vec::new()));
+        assert_eq!(vm.heap.stats().recycled, recycled + 1);
+        vm.push(Value::ObjectPtr(node));
+
+        drop(vm);
+        assert_eq!(finalized.get(), 51);
+    }
+
+    #[test]
+    fn test_arena_mode() {
+        let mut b = ChunkBuilder::new();
+        let (head, end) = (b.label(), b.label());
+        b.string("early").imm_i(5000).store(0);
+        b.bind(head).imm_i(0).load(0).op(CmpGeI).goto_if(end);
+        b.load(0).op(IntToStr).store(1);
+        b.load(0).imm_i(1).op(SubI).store(0);
+        b.goto(head);
+        b.bind(end).op(Gc);
+
+        let finalized = Rc::new(Cell::new(0));
+        let mut vm = VM::with_heap_mode(b.build().unwrap(), HeapMode::Arena).unwrap();
+        let count = finalized.clone();
+        vm.register_finalizer(tag::STRING, Box::new(move |_| count.set(count.get() + 1)))
+            .unwrap();
+        vm.set_gc_stress(true).unwrap();
+        vm.execute_all().unwrap();
+
+        // nothing is freed, not even the unreachable early string
+        let early = vm.pop().unwrap().get_object_ptr().unwrap();
+        vm.collect_garbage();
+        vm.compact();
+        assert_eq!(vm.heap.len(), 5001);
+        assert_eq!(vm.heap.stats().freed, 0);
+        assert_eq!(vm.heap.get(early).as_string(), Some("early".into()));
+        assert_eq!(finalized.get(), 0);
+
+        vm.reset().unwrap();
+        assert!(vm.heap.is_empty());
+        assert_eq!(finalized.get(), 5001);
+        assert_eq!(vm.heap.mode(), HeapMode::Arena);
+
+        vm.execute_all().unwrap();
+        drop(vm);
+        assert_eq!(finalized.get(), 2 * 5001);
+    }
+
+    #[test]
+    fn test_host_object_fields() {
+        #[rustfmt::skip]
+        let chunk = vec![
+            // arg.1 = arg.1 + 1
+            Load     as u8, 0, 0,
+            Load     as u8, 0, 0,
+            GetField as u8, 0, 1,
+            ImmI     as u8, 0, 0, 0, 0, 0, 0, 0, 1,
+            AddI     as u8,
+            SetField as u8, 0, 1,
+        ];
+
+        let mut vm = VM::new(chunk);
+        vm.set_gc_stress(true).unwrap();
+        let obj = vm.alloc_object(7, vec![Value::Char('a'), Value::Integer(41)]);
+        vm.set_local(0, Value::ObjectPtr(obj)).unwrap();
+        vm.execute_all().unwrap();
+
+        assert_eq!(vm.object_field(obj, 1), Ok(Value::Integer(42)));
+        assert_eq!(vm.object_field(obj, 0), Ok(Value::Char('a')));
+        assert_eq!(
+            vm.object_field(obj, 2),
+            Err(VmError::FieldOutOfBounds { index: 2, len: 2 })
+        );
+        assert_eq!(
+            vm.set_object_field(obj, 5, Value::Integer(0)),
+            Err(VmError::FieldOutOfBounds { index: 5, len: 2 })
+        );
+    }
+
+    #[test]
+    fn test_finalizers() {
+        let finalized = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = VM::default();
+        let log = finalized.clone();
+        vm.register_finalizer(
+            9,
+            Box::new(move |obj| {
+                if let Value::Integer(i) = obj.fields[0] {
+                    log.borrow_mut().push(i);
+                }
+            }),
+        )
+        .unwrap();
+        let sorted = || {
+            let mut seen = finalized.borrow().clone();
+            seen.sort();
+            seen
+        };
+
+        for i in 0..10 {
+            let ptr = vm.alloc(Object::new(9, vec![Value::Integer(i)]));
+            if i % 3 == 0 {
+                vm.root(ptr);
+            }
+            vm.alloc(Object::new(8, vec![Value::Integer(i)]));
+        }
+        vm.collect_garbage();
+        assert_eq!(sorted(), [1, 2, 4, 5, 7, 8]);
+
+        vm.collect_garbage();
+        assert_eq!(finalized.borrow().len(), 6);
+
+        drop(vm);
+        assert_eq!(sorted(), (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_weak_refs() {
+        let weak_get = |vm: &mut VM| {
+            vm.chunk = ChunkBuilder::new().load(1).op(WeakGet).build().unwrap();
+            vm.ip = 0;
+            vm.execute_all().unwrap();
+            vm.pop().unwrap()
+        };
+
+        let mut b = ChunkBuilder::new();
+        b.op(MapNew).store(0).load(0).op(NewWeak).store(1);
+        let mut vm = VM::new(b.build().unwrap());
+        vm.set_gc_stress(true).unwrap();
+        vm.execute_all().unwrap();
+        let target = vm.local(0).unwrap();
+
+        // a strong reference keeps the target alive
+        vm.collect_garbage();
+        assert_eq!(weak_get(&mut vm), target);
+        assert_eq!(vm.heap.len(), 2);
+
+        // with only the weak reference left it's cleared by the collection
+        vm.set_local(0, Value::Null).unwrap();
+        vm.collect_garbage();
+        assert_eq!(vm.heap.len(), 1);
+        assert_eq!(weak_get(&mut vm), Value::Null);
+
+        let map = vm.alloc_map();
+        vm.set_local(1, Value::ObjectPtr(map)).unwrap();
+        vm.ip = 0;
+        assert_eq!(
+            mismatch(vm.execute_all()),
+            ("weak reference", "object", WeakGet, 3)
+        );
+    }
+
+    #[test]
+    fn test_weak_refs_incremental() {
+        let mut vm = VM::new(
+            ChunkBuilder::new()
+                .load(0)
+                .op(NewWeak)
+                .store(1)
+                .build()
+                .unwrap(),
+        );
+        let target = vm.alloc_map();
+        vm.set_local(0, Value::ObjectPtr(target)).unwrap();
+        vm.unroot(target);
+        vm.execute_all().unwrap();
+
+        // the target is dropped mid-cycle, after the roots were shaded
+        vm.start_gc_cycle();
+        vm.set_local(0, Value::Null).unwrap();
+        vm.finish_gc_cycle();
+        assert_eq!(vm.heap.len(), 2);
+
+        vm.collect_garbage();
+        assert_eq!(vm.heap.len(), 1);
+        let weak = vm.local(1).unwrap().get_object_ptr().unwrap();
+        assert_eq!(vm.heap.get(weak).weak_target(), Some(None));
+    }
+
+    #[test]
+    fn test_host_objects_are_rooted() {
+        let mut vm = VM::new(vec![]);
+        let kept = vm.alloc_object(1, vec![Value::Integer(1)]);
+        let child = vm.alloc_object(2, vec![]);
+        vm.set_object_field(kept, 0, Value::ObjectPtr(child))
+            .unwrap();
+        assert!(vm.unroot(child));
+        let dropped = vm.alloc_object(3, vec![]);
+        assert!(vm.unroot(dropped));
+        assert!(!vm.unroot(dropped));
+
+        vm.collect_garbage();
+        assert_eq!(vm.heap().len(), 2);
+        assert_eq!(vm.object_field(kept, 0), Ok(Value::ObjectPtr(child)));
+
+        assert!(vm.unroot(kept));
+        vm.collect_garbage();
+        assert!(vm.heap().is_empty());
+    }
+
+    fn tree(vm: &mut VM, leaf: i64) -> Value {
+        let left = vm.alloc_object(1, vec![Value::Integer(leaf)]);
+        let right = vm.alloc_object(1, vec![Value::Char('r')]);
+        let root = vm.alloc_object(0, vec![Value::ObjectPtr(left), Value::ObjectPtr(right)]);
+        Value::ObjectPtr(root)
+    }
+
+    #[test]
+    fn test_obj_eq_trees() {
+        #[rustfmt::skip]
+        let chunk = vec![
+            Load  as u8, 0, 0,
+            Load  as u8, 0, 1,
+            ObjEq as u8,
+            Load  as u8, 0, 0,
+            Load  as u8, 0, 2,
+            ObjEq as u8,
+        ];
+
+        let mut vm = VM::new(chunk);
+        let (a, b, c) = (tree(&mut vm, 3), tree(&mut vm, 3), tree(&mut vm, 4));
+        vm.set_local(0, a).unwrap();
+        vm.set_local(1, b).unwrap();
+        vm.set_local(2, c).unwrap();
+        vm.execute_all().unwrap();
+
+        assert_ne!(a, b);
+        assert_eq!(vm.stack, vec![Value::Word(1), Value::Word(0)]);
+    }
+
+    #[test]
+    fn test_deep_eq_tags_and_fields() {
+        let mut vm = VM::new(vec![]);
+        let x = Value::ObjectPtr(vm.alloc_object(1, vec![Value::Integer(1)]));
+        let other_tag = Value::ObjectPtr(vm.alloc_object(2, vec![Value::Integer(1)]));
+        let other_field = Value::ObjectPtr(vm.alloc_object(1, vec![Value::Word(1)]));
+        let longer =
+            Value::ObjectPtr(vm.alloc_object(1, vec![Value::Integer(1), Value::Integer(1)]));
+
+        assert!(x.deep_eq(&x, vm.heap()));
+        assert!(!x.deep_eq(&other_tag, vm.heap()));
+        assert!(!x.deep_eq(&other_field, vm.heap()));
+        assert!(!x.deep_eq(&longer, vm.heap()));
+        assert!(!x.deep_eq(&Value::Integer(1), vm.heap()));
+    }
+
+    #[test]
+    fn test_deep_eq_cycles() {
+        let mut vm = VM::new(vec![]);
+        let cycle = |vm: &mut VM, len: usize| {
+            let nodes: Vec<_> = (0..len)
+                .map(|_| vm.alloc_object(5, vec![Value::Integer(0)]))
+                .collect();
+            for (i, &node) in nodes.iter().enumerate() {
+                let next = Value::ObjectPtr(nodes[(i + 1) % len]);
+                vm.set_object_field(node, 0, next).unwrap();
+            }
+            Value::ObjectPtr(nodes[0])
+        };
+
+        let selfref = cycle(&mut vm, 1);
+        let selfref2 = cycle(&mut vm, 1);
+        let pair = cycle(&mut vm, 2);
+
+        assert!(selfref.deep_eq(&selfref, vm.heap()));
+        assert!(selfref.deep_eq(&selfref2, vm.heap()));
+        assert!(selfref.deep_eq(&pair, vm.heap()));
+    }
+
+    #[test]
+    fn test_obj_clone_aliasing() {
+        #[rustfmt::skip]
+        let chunk = vec![
+            Load            as u8, 0, 0,
+            ObjCloneShallow as u8,
+            Load            as u8, 0, 0,
+            ObjCloneDeep    as u8,
+        ];
+
+        let mut vm = VM::new(chunk);
+        vm.set_gc_stress(true).unwrap();
+        let inner = vm.alloc_object(1, vec![Value::Integer(7)]);
+        let outer = vm.alloc_object(0, vec![Value::ObjectPtr(inner), Value::Word(3)]);
+        vm.unroot(inner);
+        vm.unroot(outer);
+        vm.set_local(0, Value::ObjectPtr(outer)).unwrap();
+        vm.execute_all().unwrap();
+
+        let shallow = vm.stack[0].get_object_ptr().unwrap();
+        let deep = vm.stack[1].get_object_ptr().unwrap();
+        assert_ne!(shallow, outer);
+        assert_ne!(deep, outer);
+        assert_eq!(vm.object_field(shallow, 0), Ok(Value::ObjectPtr(inner)));
+        assert_ne!(vm.object_field(deep, 0), Ok(Value::ObjectPtr(inner)));
+        assert!(vm.stack[0].deep_eq(&vm.stack[1], vm.heap()));
+
+        // outer, inner, the shallow copy and the two deep copies
+        vm.collect_garbage();
+        assert_eq!(vm.heap().len(), 5);
+    }
+
+    #[test]
+    fn test_obj_clone_deep_cycle() {
+        let mut vm = VM::new(vec![Load as u8, 0, 0, ObjCloneDeep as u8]);
+        vm.set_gc_stress(true).unwrap();
+
+        // a -> b -> a, with both nodes also sharing c
+        let c = vm.alloc_object(2, vec![]);
+        let a = vm.alloc_object(1, vec![Value::Integer(0), Value::ObjectPtr(c)]);
+        let b = vm.alloc_object(1, vec![Value::ObjectPtr(a), Value::ObjectPtr(c)]);
+        vm.set_object_field(a, 0, Value::ObjectPtr(b)).unwrap();
+        for ptr in [a, b, c] {
+            vm.unroot(ptr);
+        }
+        vm.set_local(0, Value::ObjectPtr(a)).unwrap();
+        vm.execute_all().unwrap();
+
+        let a2 = vm.stack[0].get_object_ptr().unwrap();
+        let Ok(Value::ObjectPtr(b2)) = vm.object_field(a2, 0) else {
+            panic!("expected an object field");
+        };
+        let Ok(Value::ObjectPtr(c2)) = vm.object_field(a2, 1) else {
+            panic!("expected an object field");
+        };
+        assert!(![a, b, c].contains(&a2));
+        assert!(![a, b, c].contains(&b2));
+        assert!(![a, b, c].contains(&c2));
+        assert_eq!(vm.object_field(b2, 0), Ok(Value::ObjectPtr(a2)));
+        assert_eq!(vm.object_field(b2, 1), Ok(Value::ObjectPtr(c2)));
+        assert!(vm.stack[0].deep_eq(&Value::ObjectPtr(a), vm.heap()));
+
+        vm.collect_garbage();
+        assert_eq!(vm.heap().len(), 6);
+    }
+
+    #[test]
+    fn test_allocating_opcodes_under_gc_stress() {
+        // each operand object is only reachable from the stack when the
+        // instruction pops it
+        let run = |b: &mut ChunkBuilder| {
+            let mut vm = VM::new(b.build().unwrap());
+            vm.set_gc_stress(true).unwrap();
+            vm.execute_all().unwrap();
+            vm.collect_garbage();
+            assert_eq!(vm.stack.len(), 1);
+            let val = vm.stack[0];
+            (vm, val)
+        };
+        let string = |vm: &VM, val: Value| vm.heap.get(val.get_object_ptr().unwrap()).as_string();
+
+        for (b, expected) in [
+            (ChunkBuilder::new().imm_i(-3).op(IntToStr), "-3"),
+            (ChunkBuilder::new().imm_f(0.5).op(FloatToStr), "0.5"),
+            (
+                ChunkBuilder::new().imm_f(0.125).imm_i(2).op(FmtFloat),
+                "0.12",
+            ),
+            (ChunkBuilder::new().string("héllo"), "héllo"),
+            (
+                ChunkBuilder::new()
+                    .string("héllo")
+                    .imm_i(1)
+                    .imm_i(3)
+                    .op(Substr),
+                "éll",
+            ),
+        ] {
+            let (vm, val) = run(b);
+            assert_eq!(string(&vm, val).as_deref(), Some(expected));
+        }
+
+        let (vm, val) = run(ChunkBuilder::new().op(MapNew).op(NewWeak).op(WeakGet));
+        assert_eq!(vm.heap.get(val.get_object_ptr().unwrap()).tag, tag::MAP);
+
+        let (_, val) = run(ChunkBuilder::new().imm_i(3).op(ArrayNew).op(ArrayLen));
+        assert_eq!(val, Value::Integer(3));
+
+        // [["x"]], cloned once its local has been overwritten
+        let mut b = ChunkBuilder::new();
+        b.imm_i(1)
+            .op(ArrayNew)
+            .store(0)
+            .imm_i(1)
+            .op(ArrayNew)
+            .store(1);
+        b.load(1).imm_i(0).string("x").op(ArraySet);
+        b.load(0).imm_i(0).load(1).op(ArraySet);
+        b.load(0).imm_i(0).store(0).imm_i(0).store(1);
+        for clone in [ObjCloneShallow, ObjCloneDeep] {
+            let mut b = b.clone();
+            b.op(clone).imm_i(0).op(ArrayGet).imm_i(0).op(ArrayGet);
+            let (vm, val) = run(&mut b);
+            assert_eq!(string(&vm, val).as_deref(), Some("x"), "{clone:?}");
+        }
+    }
+
+    #[test]
+    fn test_native_holds_popped_object() {
+        let chunk = ChunkBuilder::new()
+            .op(MapNew)
+            .call_native("alloc")
+            .build()
+            .unwrap();
+        for hold in [false, true] {
+            let live = Rc::new(Cell::new(0));
+            let mut vm = VM::new(chunk.clone());
+            vm.set_gc_stress(true).unwrap();
+            let seen = live.clone();
+            vm.register_native(
+                "alloc",
+                Rc::new(move |vm: &mut VM| {
+                    let map = vm.get_object()?;
+                    if hold {
+                        vm.hold(Value::ObjectPtr(map));
+                    }
+                    let s = vm.alloc(Object::string("new"));
+                    seen.set(vm.heap.len());
+                    vm.push(Value::ObjectPtr(s));
+                    Ok(())
+                }),
+            )
+            .unwrap();
+            vm.execute_all().unwrap();
+            assert_eq!(live.get(), if hold { 2 } else { 1 }, "hold {hold}");
+
+            // held values are released once the instruction finishes
+            vm.pop().unwrap();
+            vm.collect_garbage();
+            assert_eq!(vm.heap.len(), 0);
+        }
+    }
+}
@@ -0,0 +1,384 @@
+use crate::error::{VmError, WatchError};
+use crate::heap::tag;
+use crate::value::Value;
+use crate::vm::VM;
+
+// Watch expressions over the state of a paused VM, for a debugger to show:
+//   expr    := sum (("==" | "!=" | "<" | "<=" | ">" | ">=") sum)?
+//   sum     := product (("+" | "-") product)*
+//   product := unary (("*" | "/" | "%") unary)*
+//   unary   := "-" unary | postfix
+//   postfix := primary ("." field | "[" expr "]")*
+//   primary := integer | "local" "(" (slot | name) ")" | name | "(" expr ")"
+// Names are those the running function gives its locals. Operators take
+// integers and wrap, divide and compare as the `*I` opcodes do, comparisons
+// giving a word of 1 or 0. The whole expression is parsed before any of it
+// is evaluated, and evaluating only reads the VM.
+impl VM {
+    pub fn evaluate(&self, expression: &str) -> Result<Value, WatchError> {
+        let mut parser = Parser {
+            source: expression,
+            offset: 0,
+        };
+        let expr = parser.expr()?;
+        parser.skip_space();
+        if parser.offset < expression.len() {
+            return Err(parser.expected("an operator"));
+        }
+        expr.evaluate(self)
+    }
+}
+
+enum Expr {
+    Integer(i64),
+    Local(u16),
+    Named(String),
+    Field(Box<Expr>, usize),
+    Index(Box<Expr>, Box<Expr>),
+    Neg(Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+const COMPARISONS: [&str; 6] = ["==", "!=", "<=", ">=", "<", ">"];
+
+impl Expr {
+    fn evaluate(&self, vm: &VM) -> Result<Value, WatchError> {
+        Ok(match self {
+            Self::Integer(i) => Value::Integer(*i),
+            Self::Local(slot) => local(vm, *slot)?,
+            Self::Named(name) => {
+                let slot = vm
+                    .named_local(name)
+                    .ok_or_else(|| WatchError::UnknownLocal(name.clone()))?;
+                local(vm, slot)?
+            }
+            Self::Field(object, index) => {
+                let object = object.evaluate(vm)?;
+                let ptr = object
+                    .get_object_ptr()
+                    .ok_or_else(|| mismatch("object", object))?;
+                let fields = &vm.heap().get(ptr).fields;
+                *fields.get(*index).ok_or(VmError::FieldOutOfBounds {
+                    index: *index,
+                    len: fields.len(),
+                })?
+            }
+            Self::Index(array, index) => {
+                let array = array.evaluate(vm)?;
+                let index = integer(index.evaluate(vm)?)?;
+                let ptr = array
+                    .get_object_ptr()
+                    .filter(|&ptr| vm.heap().get(ptr).tag == tag::ARRAY)
+                    .ok_or_else(|| mismatch("array", array))?;
+                let fields = &vm.heap().get(ptr).fields;
+                *usize::try_from(index)
+                    .ok()
+                    .and_then(|i| fields.get(i))
+                    .ok_or(VmError::IndexOutOfBounds {
+                        index,
+                        len: fields.len(),
+                    })?
+            }
+            Self::Neg(operand) => Value::Integer(integer(operand.evaluate(vm)?)?.wrapping_neg()),
+            Self::Binary(op, x, y) => {
+                let x = integer(x.evaluate(vm)?)?;
+                let y = integer(y.evaluate(vm)?)?;
+                if matches!(*op, "/" | "%") && y == 0 {
+                    return Err(VmError::DivisionByZero.into());
+                }
+                match *op {
+                    "+" => Value::Integer(x.wrapping_add(y)),
+                    "-" => Value::Integer(x.wrapping_sub(y)),
+                    "*" => Value::Integer(x.wrapping_mul(y)),
+                    "/" => Value::Integer(x.wrapping_div(y)),
+                    "%" => Value::Integer(x.wrapping_rem(y)),
+                    "==" => Value::Word((x == y) as u64),
+                    "!=" => Value::Word((x != y) as u64),
+                    "<=" => Value::Word((x <= y) as u64),
+                    ">=" => Value::Word((x >= y) as u64),
+                    "<" => Value::Word((x < y) as u64),
+                    _ => Value::Word((x > y) as u64),
+                }
+            }
+        })
+    }
+}
+
+fn local(vm: &VM, slot: u16) -> Result<Value, WatchError> {
+    vm.local(slot as usize)
+        .ok_or(WatchError::Eval(VmError::UninitializedLocal(slot as usize)))
+}
+
+fn integer(val: Value) -> Result<i64, WatchError> {
+    match val {
+        Value::Integer(i) => Ok(i),
+        _ => Err(mismatch("integer", val)),
+    }
+}
+
+fn mismatch(expected: &'static str, found: Value) -> WatchError {
+    WatchError::Eval(VmError::TypeMismatch {
+        expected,
+        found: found.type_name(),
+    })
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    offset: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn expr(&mut self) -> Result<Expr, WatchError> {
+        let x = self.sum()?;
+        match COMPARISONS.into_iter().find(|op| self.eat(op)) {
+            Some(op) => Ok(Expr::Binary(op, Box::new(x), Box::new(self.sum()?))),
+            None => Ok(x),
+        }
+    }
+
+    fn sum(&mut self) -> Result<Expr, WatchError> {
+        let mut x = self.product()?;
+        while let Some(op) = ["+", "-"].into_iter().find(|op| self.eat(op)) {
+            x = Expr::Binary(op, Box::new(x), Box::new(self.product()?));
+        }
+        Ok(x)
+    }
+
+    fn product(&mut self) -> Result<Expr, WatchError> {
+        let mut x = self.unary()?;
+        while let Some(op) = ["*", "/", "%"].into_iter().find(|op| self.eat(op)) {
+            x = Expr::Binary(op, Box::new(x), Box::new(self.unary()?));
+        }
+        Ok(x)
+    }
+
+    fn unary(&mut self) -> Result<Expr, WatchError> {
+        if self.eat("-") {
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+        let mut x = self.primary()?;
+        loop {
+            if self.eat(".") {
+                x = Expr::Field(Box::new(x), self.number("a field index")?);
+            } else if self.eat("[") {
+                x = Expr::Index(Box::new(x), Box::new(self.expr()?));
+                self.expect("]")?;
+            } else {
+                return Ok(x);
+            }
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, WatchError> {
+        if self.eat("(") {
+            let x = self.expr()?;
+            self.expect(")")?;
+            return Ok(x);
+        }
+        if let Some(name) = self.name() {
+            if name != "local" || !self.eat("(") {
+                return Ok(Expr::Named(name.to_string()));
+            }
+            let x = match self.name() {
+                Some(name) => Expr::Named(name.to_string()),
+                None => Expr::Local(self.number("a local")?),
+            };
+            self.expect(")")?;
+            return Ok(x);
+        }
+        self.number("an expression").map(Expr::Integer)
+    }
+
+    fn skip_space(&mut self) {
+        let rest = &self.source[self.offset..];
+        self.offset += rest.len() - rest.trim_start().len();
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_space();
+        let found = self.source[self.offset..].starts_with(token);
+        if found {
+            self.offset += token.len();
+        }
+        found
+    }
+
+    fn expect(&mut self, token: &'static str) -> Result<(), WatchError> {
+        match self.eat(token) {
+            true => Ok(()),
+            false => Err(self.expected(token)),
+        }
+    }
+
+    fn expected(&self, expected: &'static str) -> WatchError {
+        WatchError::Syntax {
+            offset: self.offset,
+            expected,
+        }
+    }
+
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> &'a str {
+        self.skip_space();
+        let rest = &self.source[self.offset..];
+        let len = rest.find(|c| !f(c)).unwrap_or(rest.len());
+        self.offset += len;
+        &rest[..len]
+    }
+
+    fn name(&mut self) -> Option<&'a str> {
+        self.skip_space();
+        if !self.source[self.offset..].starts_with(|c: char| c.is_alphabetic() || c == '_') {
+            return None;
+        }
+        Some(self.take_while(|c| c.is_alphanumeric() || c == '_'))
+    }
+
+    fn number<T: std::str::FromStr>(&mut self, expected: &'static str) -> Result<T, WatchError> {
+        self.skip_space();
+        let start = self.offset;
+        let digits = self.take_while(|c| c.is_ascii_digit());
+        digits.parse().map_err(|_| {
+            self.offset = start;
+            self.expected(expected)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::ChunkBuilder;
+    use crate::opcode::OpCode::*;
+    use crate::vm::Status;
+
+    // fact(n): acc = 1; while n > 1 { acc *= n; n -= 1 }, paused at the top
+    // of the loop body with n = 3 and acc = 5 * 4
+    fn paused_in_factorial() -> VM {
+        let mut b = ChunkBuilder::new();
+        let (entry, head, body, end) = (b.label(), b.label(), b.label(), b.label());
+        let fact = b.function(entry, 1);
+        b.local_name(fact, 0, "n").local_name(fact, 1, "acc");
+        b.imm_i(5).call(fact).op(Return);
+        b.bind(entry).imm_i(1).store(1);
+        b.bind(head).load(0).imm_i(1).op(CmpLeI).goto_if(end);
+        b.bind(body).load(1).load(0).op(MulI).store(1);
+        b.load(0).imm_i(1).op(SubI).store(0).goto(head);
+        b.bind(end).load(1).op(Return);
+        let chunk = b.build().unwrap();
+        let mut vm = VM::new(chunk.clone());
+        vm.set_breakpoint(chunk.functions()[0].entry + 12).unwrap();
+        for _ in 0..3 {
+            assert_eq!(vm.execute_all().unwrap().status, Status::Paused);
+        }
+        vm
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let vm = paused_in_factorial();
+        let int = |i| Ok(Value::Integer(i));
+        assert_eq!(vm.evaluate("local(1) * local(0)"), int(60));
+        assert_eq!(vm.evaluate("acc * n"), int(60));
+        assert_eq!(vm.evaluate(" local( acc ) - -n*2 "), int(26));
+        assert_eq!(vm.evaluate("(acc + 1) % 7"), int(0));
+        assert_eq!(vm.evaluate("acc / n > n * 6"), Ok(Value::Word(0)));
+        assert_eq!(vm.evaluate("n != 4"), Ok(Value::Word(1)));
+    }
+
+    #[test]
+    fn test_evaluate_errors() {
+        let vm = paused_in_factorial();
+        assert_eq!(
+            vm.evaluate("local(2) + 1"),
+            Err(WatchError::Eval(VmError::UninitializedLocal(2)))
+        );
+        assert_eq!(
+            vm.evaluate("total"),
+            Err(WatchError::UnknownLocal("total".to_string()))
+        );
+        assert_eq!(
+            vm.evaluate("local(0).1"),
+            Err(WatchError::Eval(VmError::TypeMismatch {
+                expected: "object",
+                found: "integer"
+            }))
+        );
+        assert_eq!(
+            vm.evaluate("n / (acc - 20)"),
+            Err(WatchError::Eval(VmError::DivisionByZero))
+        );
+        // nothing is evaluated until the whole expression has parsed
+        assert_eq!(
+            vm.evaluate("local(2) + (n"),
+            Err(WatchError::Syntax {
+                offset: 13,
+                expected: ")"
+            })
+        );
+        assert_eq!(
+            vm.evaluate("n n"),
+            Err(WatchError::Syntax {
+                offset: 2,
+                expected: "an operator"
+            })
+        );
+        assert_eq!(
+            vm.evaluate("local(-1)"),
+            Err(WatchError::Syntax {
+                offset: 6,
+                expected: "a local"
+            })
+        );
+        assert_eq!(
+            vm.evaluate("n < 1 < 2").unwrap_err().to_string(),
+            "expected an operator at offset 6"
+        );
+    }
+
+    #[test]
+    fn test_evaluate_objects() {
+        let mut b = ChunkBuilder::new();
+        b.imm_i(3).op(ArrayNew).store(0);
+        b.load(0).imm_i(2).imm_i(42).op(ArraySet);
+        b.load(0).imm_i(0).load(0).op(ArraySet);
+        b.imm_i(1).op(BufNew).store(1);
+        let mut vm = VM::new(b.build().unwrap());
+        vm.execute_all().unwrap();
+        let before = (vm.stack().to_vec(), vm.locals().to_vec());
+
+        assert_eq!(vm.evaluate("local(0)[2] + 1"), Ok(Value::Integer(43)));
+        assert_eq!(vm.evaluate("local(0)[0][2]"), Ok(Value::Integer(42)));
+        assert_eq!(vm.evaluate("local(0).0.2"), Ok(Value::Integer(42)));
+        assert_eq!(vm.evaluate("local(0)[1]"), Ok(Value::Null));
+        assert_eq!(
+            vm.evaluate("local(0)[3]"),
+            Err(WatchError::Eval(VmError::IndexOutOfBounds {
+                index: 3,
+                len: 3
+            }))
+        );
+        assert_eq!(
+            vm.evaluate("local(1)[0]"),
+            Err(WatchError::Eval(VmError::TypeMismatch {
+                expected: "array",
+                found: "object"
+            }))
+        );
+        assert_eq!(
+            vm.evaluate("local(1).0"),
+            Err(WatchError::Eval(VmError::FieldOutOfBounds {
+                index: 0,
+                len: 0
+            }))
+        );
+        assert_eq!(
+            vm.evaluate("local(0) + 1"),
+            Err(WatchError::Eval(VmError::TypeMismatch {
+                expected: "integer",
+                found: "object"
+            }))
+        );
+        assert_eq!((vm.stack().to_vec(), vm.locals().to_vec()), before);
+    }
+}
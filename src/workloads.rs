@@ -0,0 +1,137 @@
+use crate::builder::ChunkBuilder;
+use crate::chunk::Chunk;
+use crate::opcode::OpCode::*;
+use crate::value::Value;
+
+// Representative programs shared by the benchmarks and the tests. Each one
+// finishes with its result on top of the stack.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Workload {
+    pub name: &'static str,
+    pub chunk: Chunk,
+    pub expected: Value,
+}
+
+pub fn standard() -> Vec<Workload> {
+    let workload = |name, chunk, expected| Workload {
+        name,
+        chunk,
+        expected: Value::Integer(expected),
+    };
+    vec![
+        workload("countdown", countdown(100_000), 0),
+        workload("factorial", factorial(20), (1..=20).product()),
+        workload("fibonacci", fibonacci(20), 6765),
+        workload("churn", object_churn(10_000), 10_000 * 10_001 / 2),
+        workload("array_sum", array_sum(10_000), 10_000 * 9_999 / 2),
+    ]
+}
+
+// Decrements a local until it reaches zero.
+pub fn countdown(n: i64) -> Chunk {
+    let mut b = ChunkBuilder::new();
+    let (head, end) = (b.label(), b.label());
+    b.imm_i(n).store(0);
+    b.bind(head).imm_i(0).load(0).op(CmpGeI).goto_if(end);
+    b.load(0).imm_i(1).op(SubI).store(0);
+    b.goto(head);
+    b.bind(end).load(0);
+    b.build().unwrap()
+}
+
+// Recursive, one frame per multiplication.
+pub fn factorial(n: i64) -> Chunk {
+    let mut b = ChunkBuilder::new();
+    let (entry, base) = (b.label(), b.label());
+    let fact = b.function(entry, 1);
+    b.imm_i(n).call(fact).op(Return);
+
+    b.bind(entry).imm_i(1).load(0).op(CmpGeI).goto_if(base);
+    b.load(0).imm_i(1).op(SubI).call(fact);
+    b.load(0).op(MulI).op(Return);
+    b.bind(base).imm_i(1).op(Return);
+    b.build().unwrap()
+}
+
+// Naive doubly recursive fibonacci, dominated by call overhead.
+pub fn fibonacci(n: i64) -> Chunk {
+    let mut b = ChunkBuilder::new();
+    let (entry, base) = (b.label(), b.label());
+    let fib = b.function(entry, 1);
+    b.imm_i(n).call(fib).op(Return);
+
+    b.bind(entry).imm_i(2).load(0).op(CmpGtI).goto_if(base);
+    b.load(0).imm_i(1).op(SubI).call(fib);
+    b.load(0).imm_i(2).op(SubI).call(fib);
+    b.op(AddI).op(Return);
+    b.bind(base).load(0).op(Return);
+    b.build().unwrap()
+}
+
+// Allocates a short-lived one-element array per iteration and sums n..=1
+// back out of them, so the collector runs repeatedly.
+pub fn object_churn(n: i64) -> Chunk {
+    let mut b = ChunkBuilder::new();
+    let (head, end) = (b.label(), b.label());
+    b.imm_i(n).store(0).imm_i(0).store(1);
+    b.bind(head).imm_i(0).load(0).op(CmpGeI).goto_if(end);
+    b.imm_i(1).op(ArrayNew).store(2);
+    b.load(2).imm_i(0).load(0).op(ArraySet);
+    b.load(2).imm_i(0).op(ArrayGet).load(1).op(AddI).store(1);
+    b.load(0).imm_i(1).op(SubI).store(0);
+    b.goto(head);
+    b.bind(end).load(1);
+    b.build().unwrap()
+}
+
+// Fills an array with 0..n, then sums it.
+pub fn array_sum(n: i64) -> Chunk {
+    let mut b = ChunkBuilder::new();
+    let (fill, summed, sum, end) = (b.label(), b.label(), b.label(), b.label());
+    b.imm_i(n).op(ArrayNew).store(0).imm_i(0).store(1);
+    b.bind(fill).load(1).imm_i(n).op(CmpGeI).goto_if(summed);
+    b.load(0).load(1).load(1).op(ArraySet);
+    b.imm_i(1).load(1).op(AddI).store(1);
+    b.goto(fill);
+
+    b.bind(summed).imm_i(0).store(1).imm_i(0).store(2);
+    b.bind(sum).load(1).load(0).op(ArrayLen);
+    b.op(CmpGeI).goto_if(end);
+    b.load(0).load(1).op(ArrayGet).load(2).op(AddI).store(2);
+    b.imm_i(1).load(1).op(AddI).store(1);
+    b.goto(sum);
+    b.bind(end).load(2);
+    b.build().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verifier;
+    use crate::vm::VM;
+
+    #[test]
+    fn test_standard_workloads() {
+        for workload in standard() {
+            assert_eq!(
+                verifier::verify(&workload.chunk),
+                Ok(()),
+                "{}",
+                workload.name
+            );
+            let mut vm = VM::new(workload.chunk);
+            let outcome = vm.execute_all().unwrap();
+            assert_eq!(outcome.value, Some(workload.expected), "{}", workload.name);
+        }
+    }
+
+    #[test]
+    fn test_workloads_under_gc_stress() {
+        for (chunk, expected) in [(object_churn(50), 1275), (array_sum(50), 1225)] {
+            let mut vm = VM::new(chunk);
+            vm.set_gc_stress(true).unwrap();
+            vm.execute_all().unwrap();
+            assert_eq!(vm.stack(), [Value::Integer(expected)]);
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use andrea::error::{FrontendError, VmError};
+use andrea::frontend::compile;
+use andrea::value::Value;
+use andrea::verifier;
+
+const ENV: [(&str, i64); 3] = [("x", 6), ("y", 7), ("zero", 0)];
+
+fn run(source: &str) -> Result<Value, FrontendError> {
+    compile(source)?.run(&ENV)
+}
+
+#[test]
+fn test_results() {
+    let int = |i| Ok(Value::Integer(i));
+    let boolean = |b: bool| Ok(Value::Word(b as u64));
+    let cases = [
+        ("42", int(42)),
+        ("1 + 2 * 3", int(7)),
+        ("(1 + 2) * 3", int(9)),
+        ("10 - 4 - 3", int(3)),
+        ("-x + --y", int(1)),
+        ("x * y", int(42)),
+        ("7 / 2 - -7 / 2", int(6)),
+        ("x < y", boolean(true)),
+        ("x >= y", boolean(false)),
+        ("x != y", boolean(true)),
+        ("x * 2 == 12", boolean(true)),
+        ("x < y ? x : y", int(6)),
+        ("x > y ? x : y", int(7)),
+        // right-associative, and only the branch taken runs
+        ("x > y ? 1 : x == y ? 2 : 3", int(3)),
+        ("zero == 0 ? x : x / zero", int(6)),
+        ("(x < y ? x < 1 : y < 1) ? 1 : 0", int(0)),
+        ("9223372036854775807 + 1", int(i64::MIN)),
+    ];
+    for (source, expected) in cases {
+        assert_eq!(run(source), expected, "{source}");
+        let program = compile(source).unwrap();
+        assert_eq!(verifier::verify_stack(program.chunk()), Ok(()), "{source}");
+    }
+}
+
+#[test]
+fn test_errors() {
+    let trap = |error, at| {
+        Err(FrontendError::Trap {
+            error,
+            at: Some(at),
+        })
+    };
+    let syntax = |at, expected| Err(FrontendError::Syntax { at, expected });
+    let mismatch = |expected, found, at| {
+        Err(FrontendError::TypeMismatch {
+            expected,
+            found,
+            at,
+        })
+    };
+    let cases = [
+        ("x / zero", trap(VmError::DivisionByZero, 2)),
+        ("x / y + y / (x - 6)", trap(VmError::DivisionByZero, 10)),
+        ("1 +", syntax(3, "an expression")),
+        ("(1 + 2", syntax(6, ")")),
+        ("x < 1 ? 2", syntax(9, ":")),
+        ("1 2", syntax(2, "an operator")),
+        ("99999999999999999999", syntax(0, "an integer")),
+        ("(x < y) + 1", mismatch("integer", "boolean", 0)),
+        ("x ? 1 : 2", mismatch("boolean", "integer", 0)),
+        ("x < y ? 1 : x < y", mismatch("integer", "boolean", 12)),
+        ("x + w", Err(FrontendError::Unbound("w".to_string()))),
+    ];
+    for (source, expected) in cases {
+        assert_eq!(run(source), expected, "{source}");
+    }
+    assert_eq!(
+        run("1 + 8 / zero").unwrap_err().to_string(),
+        "division by zero at 6"
+    );
+}
+
+#[test]
+fn test_variables_get_locals() {
+    let program = compile("b * a + b - c").unwrap();
+    assert_eq!(program.variables(), ["b", "a", "c"]);
+    assert_eq!(program.chunk().max_locals(), Some(3));
+    assert_eq!(
+        program.run(&[("a", 2), ("b", 10), ("c", 1)]),
+        Ok(Value::Integer(29))
+    );
+}
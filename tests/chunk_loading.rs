@@ -0,0 +1,54 @@
+use andrea::chunk::Chunk;
+use andrea::value::Value;
+use andrea::vm::VM;
+use andrea::workloads;
+use std::sync::Arc;
+
+// `workloads::countdown(1000)`, serialized.
+static IMAGE: &[u8] = include_bytes!("fixtures/countdown.chunk");
+
+fn contains(bytes: &[u8], inner: &[u8]) -> bool {
+    bytes.as_ptr_range().contains(&inner.as_ptr())
+        && inner.as_ptr_range().end <= bytes.as_ptr_range().end
+}
+
+#[test]
+fn test_static_image() {
+    let borrowed = Chunk::deserialize_borrowed(IMAGE).unwrap();
+    assert!(contains(IMAGE, borrowed.code()));
+    let chunk = Chunk::from(borrowed);
+    assert!(contains(IMAGE, chunk.code()));
+    assert_eq!(chunk, workloads::countdown(1000));
+
+    let mut vm = VM::new(chunk);
+    let outcome = vm.execute_all().unwrap();
+    assert_eq!(outcome.value, Some(Value::Integer(0)));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_mapped_file() {
+    use andrea::mmap::MappedFile;
+
+    let path = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/countdown.chunk"
+    );
+    let file = Arc::new(MappedFile::open(path).unwrap());
+    let chunk = Chunk::deserialize_image(file.clone()).unwrap();
+    assert!(contains((*file).as_ref(), chunk.code()));
+    assert_eq!(chunk, workloads::countdown(1000));
+
+    // patching copies the code rather than writing to the mapping
+    let mut patched = chunk.clone();
+    patched
+        .patch(0, andrea::instruction::Instruction::Imm1)
+        .unwrap();
+    assert!(!contains((*file).as_ref(), patched.code()));
+    assert!(contains((*file).as_ref(), chunk.code()));
+
+    drop(file);
+    let mut vm = VM::new(chunk);
+    let outcome = vm.execute_all().unwrap();
+    assert_eq!(outcome.value, Some(Value::Integer(0)));
+}
@@ -0,0 +1,392 @@
+use andrea::chunk::{feature, Chunk, Constant, Function};
+use andrea::config::VmBuilder;
+use andrea::encode;
+use andrea::eval::{FUEL, MAX_STACK};
+use andrea::opcode::OpCode;
+use andrea::value::Value;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+
+// The cases in tests/conformance are plain text, so that any implementation
+// of the VM can run them. Each is one program, an instruction per line as
+// the opcode's name and its operand, if it has one:
+//
+//   # a comment
+//   !input 5 0x2          values for the first locals, in order
+//   !function name 2      `name:` is the entry of a function of 2 arguments
+//   !result 120           the value left on top of the stack
+//   !result none          nothing left on the stack
+//   !error DivisionByZero @ line 7
+//   loop:                 a label, for Goto and GotoIf
+//       Load 0
+//       GotoIf loop
+//
+// An expected error names the `VmError` variant, and optionally the line
+// of the instruction that trapped. Results are compared as `{:#}` renders
+// them: 42 is an integer, 0x2a a word, 4.0 a float, 'c' a char, "s" a
+// string and `{tag 252: [1, 2]}` an array. The same literals are the
+// operands of LoadConst and CallNative, and the inputs; integer operands
+// may also be words in hex. Cases run with the limits `eval` uses, and
+// without being verified, so that they reach the VM's own checks.
+const DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/conformance");
+const MIN_CASES: usize = 30;
+
+struct Case {
+    chunk: Chunk,
+    inputs: Vec<Value>,
+    expected: Expected,
+    // The source line of the instruction at each offset.
+    lines: HashMap<usize, usize>,
+    opcodes: HashSet<u8>,
+}
+
+enum Expected {
+    Result(Option<String>),
+    Error {
+        variant: String,
+        line: Option<usize>,
+    },
+}
+
+fn mnemonics() -> HashMap<String, OpCode> {
+    (0..=u8::MAX)
+        .filter_map(|byte| OpCode::try_from(byte).ok())
+        .filter(|op| !op.is_internal())
+        .map(|op| (format!("{op:?}"), op))
+        .collect()
+}
+
+fn literal(token: &str) -> Result<Constant, String> {
+    if let Some(s) = token.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+        return Ok(Constant::Str(s.to_string()));
+    }
+    if let Some(c) = token.strip_prefix('\'').and_then(|c| c.strip_suffix('\'')) {
+        let mut chars = c.chars();
+        return match (chars.next(), chars.next()) {
+            (Some(c), None) => Ok(Constant::Char(c)),
+            _ => Err(format!("invalid char literal {token}")),
+        };
+    }
+    if let Some(hex) = token.strip_prefix("0x") {
+        return u64::from_str_radix(&hex.replace('_', ""), 16)
+            .map(Constant::Word)
+            .map_err(|_| format!("invalid word {token}"));
+    }
+    if let Ok(i) = token.parse() {
+        return Ok(Constant::Integer(i));
+    }
+    token
+        .parse()
+        .map(Constant::Float)
+        .map_err(|_| format!("invalid literal {token}"))
+}
+
+fn input(token: &str) -> Result<Value, String> {
+    Ok(match literal(token)? {
+        Constant::Integer(i) => Value::Integer(i),
+        Constant::Word(w) => Value::Word(w),
+        Constant::Float(f) => Value::Float(f),
+        Constant::Char(c) => Value::Char(c),
+        Constant::Str(_) => return Err("strings can't be inputs".to_string()),
+    })
+}
+
+// What follows `!error`.
+fn expected_error(rest: &str) -> Result<Expected, String> {
+    let (variant, line) = match rest.split_once('@') {
+        Some((variant, at)) => {
+            let line = at
+                .trim()
+                .strip_prefix("line")
+                .and_then(|line| line.trim().parse().ok())
+                .ok_or_else(|| format!("expected `@ line N`, found `@{at}`"))?;
+            (variant, Some(line))
+        }
+        None => (rest, None),
+    };
+    Ok(Expected::Error {
+        variant: variant.trim().to_string(),
+        line,
+    })
+}
+
+fn assemble(source: &str) -> Result<Case, String> {
+    let mnemonics = mnemonics();
+    let mut inputs = Vec::new();
+    let mut expected = None;
+    let mut declared = Vec::new();
+    let mut labels = HashMap::new();
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+
+    // the first pass finds where every label is
+    for (index, line) in source.lines().enumerate() {
+        let number = index + 1;
+        let at = |message: String| format!("line {number}: {message}");
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(directive) = line.strip_prefix('!') {
+            let (name, rest) = directive.split_once(' ').unwrap_or((directive, ""));
+            let rest = rest.trim();
+            match name {
+                "input" => {
+                    for token in rest.split_whitespace() {
+                        inputs.push(input(token).map_err(at)?);
+                    }
+                }
+                "function" => {
+                    let mut parts = rest.split_whitespace();
+                    let (Some(label), Some(arity), None) =
+                        (parts.next(), parts.next(), parts.next())
+                    else {
+                        return Err(at("expected `!function <label> <arity>`".to_string()));
+                    };
+                    let arity = arity
+                        .parse()
+                        .map_err(|_| at(format!("invalid arity {arity}")))?;
+                    declared.push((label.to_string(), arity));
+                }
+                "result" | "error" if expected.is_some() => {
+                    return Err(at("a case has one expected outcome".to_string()));
+                }
+                "result" if rest == "none" => expected = Some(Expected::Result(None)),
+                "result" => expected = Some(Expected::Result(Some(rest.to_string()))),
+                "error" => expected = Some(expected_error(rest).map_err(at)?),
+                _ => return Err(at(format!("unknown directive !{name}"))),
+            }
+            continue;
+        }
+        if let Some(label) = line.strip_suffix(':') {
+            if labels.insert(label.to_string(), offset).is_some() {
+                return Err(at(format!("label {label} defined twice")));
+            }
+            continue;
+        }
+        let (mnemonic, operand) = line.split_once(' ').unwrap_or((line, ""));
+        let op = *mnemonics
+            .get(mnemonic)
+            .ok_or_else(|| at(format!("unknown opcode {mnemonic}")))?;
+        let operand = operand.trim();
+        if (op.operand_len() > 0) == operand.is_empty() {
+            return Err(at(format!("wrong number of operands for {mnemonic}")));
+        }
+        instructions.push((number, offset, op, operand));
+        offset += 1 + op.operand_len();
+    }
+
+    let mut functions = Vec::new();
+    for (label, arity) in &declared {
+        let entry = *labels
+            .get(label)
+            .ok_or_else(|| format!("function {label} has no label"))?;
+        functions.push(Function {
+            name: Some(label.clone()),
+            ..Function::new(entry, *arity)
+        });
+    }
+    let mut constants = Vec::new();
+    let mut code = Vec::with_capacity(offset);
+    let mut features = 0;
+    let mut lines = HashMap::new();
+    let mut opcodes = HashSet::new();
+    for (number, offset, op, operand) in instructions {
+        let at = |message: String| format!("line {number}: {message}");
+        code.push(op as u8);
+        features |= op.features();
+        lines.insert(offset, number);
+        opcodes.insert(op as u8);
+        let mut constant = |constant: Constant| {
+            if matches!(constant, Constant::Str(_)) {
+                features |= feature::OBJECTS;
+            }
+            let index = constants.iter().position(|c| *c == constant);
+            index.unwrap_or_else(|| {
+                constants.push(constant);
+                constants.len() - 1
+            }) as u16
+        };
+        match op {
+            OpCode::Goto | OpCode::GotoIf => {
+                let target = labels
+                    .get(operand)
+                    .ok_or_else(|| at(format!("unknown label {operand}")))?;
+                encode::put_u16(&mut code, *target as u16);
+            }
+            OpCode::Call => {
+                let index = declared
+                    .iter()
+                    .position(|(label, _)| label == operand)
+                    .ok_or_else(|| at(format!("unknown function {operand}")))?;
+                encode::put_u16(&mut code, index as u16);
+            }
+            OpCode::LoadConst => {
+                let index = constant(literal(operand).map_err(at)?);
+                encode::put_u16(&mut code, index);
+            }
+            OpCode::CallNative => match literal(operand).map_err(at)? {
+                name @ Constant::Str(_) => encode::put_u16(&mut code, constant(name)),
+                _ => return Err(at("natives are named by strings".to_string())),
+            },
+            OpCode::ImmF => match literal(operand).map_err(at)? {
+                Constant::Float(f) => encode::put_f64_bits(&mut code, f),
+                Constant::Integer(i) => encode::put_f64_bits(&mut code, i as f64),
+                _ => return Err(at(format!("invalid float {operand}"))),
+            },
+            _ if op.operand_len() == 0 => {}
+            _ => {
+                let len = op.operand_len();
+                let signed = matches!(op, OpCode::ImmI | OpCode::ImmI8 | OpCode::ImmI16);
+                // the operand must survive truncation to its width, and hex
+                // gives its bits
+                let shift = 64 - 8 * len as u32;
+                let (bits, fits) = match literal(operand).map_err(at)? {
+                    Constant::Integer(i) if signed => (i as u64, i << shift >> shift == i),
+                    Constant::Integer(i) => (i as u64, i >= 0 && i >> (8 * len - 1) >> 1 == 0),
+                    Constant::Word(w) => (w, w << shift >> shift == w),
+                    _ => return Err(at(format!("invalid operand {operand}"))),
+                };
+                if !fits {
+                    return Err(at(format!("operand {operand} out of range for {op:?}")));
+                }
+                code.extend(&bits.to_be_bytes()[8 - len..]);
+            }
+        }
+    }
+
+    let chunk = Chunk::new(code)
+        .with_constants(constants)
+        .with_functions(functions)
+        .with_features(features);
+    Ok(Case {
+        chunk,
+        inputs,
+        expected: expected.ok_or("no !result or !error")?,
+        lines,
+        opcodes,
+    })
+}
+
+// The variant's name, without its fields.
+fn variant(debug: &str) -> &str {
+    let end = debug
+        .find(|c: char| !c.is_alphanumeric())
+        .unwrap_or(debug.len());
+    &debug[..end]
+}
+
+fn run(case: Case) -> Result<(), String> {
+    let mut vm = VmBuilder::new()
+        .fuel(FUEL)
+        .max_stack(MAX_STACK)
+        .build(case.chunk)
+        .map_err(|err| format!("VM refused the chunk: {err}"))?;
+    for (index, &val) in case.inputs.iter().enumerate() {
+        vm.set_local(index, val)
+            .map_err(|err| format!("input {index}: {err}"))?;
+    }
+    let result = vm.execute_all();
+    let ip = vm.backtrace().0[0].ip;
+    let line = case.lines.get(&ip).copied();
+    let site = match line {
+        Some(line) => format!("line {line}"),
+        None => format!("offset {ip}"),
+    };
+    let render = |val: Option<Value>| match val {
+        Some(val) => format!("{:#}", val.display(vm.heap())),
+        None => "none".to_string(),
+    };
+    match (result, case.expected) {
+        (Ok(outcome), Expected::Result(expected)) => {
+            let found = outcome.value.map(|val| render(Some(val)));
+            match found == expected {
+                true => Ok(()),
+                false => Err(format!(
+                    "expected result {}, found {}",
+                    expected.as_deref().unwrap_or("none"),
+                    render(outcome.value)
+                )),
+            }
+        }
+        (Ok(outcome), Expected::Error { variant, .. }) => Err(format!(
+            "expected error {variant}, but the case finished with {}",
+            render(outcome.value)
+        )),
+        (Err(err), Expected::Result(_)) => Err(format!("unexpected error {err:?} at {site}")),
+        (
+            Err(err),
+            Expected::Error {
+                variant: name,
+                line: expected,
+            },
+        ) => {
+            let debug = format!("{err:?}");
+            if variant(&debug) != name {
+                return Err(format!("expected error {name}, found {debug} at {site}"));
+            }
+            match expected {
+                Some(expected) if Some(expected) != line => Err(format!(
+                    "expected {name} at line {expected}, found it at {site}"
+                )),
+                _ => Ok(()),
+            }
+        }
+    }
+}
+
+// The cases by file name, in order.
+fn cases() -> Vec<(String, String)> {
+    let mut paths: Vec<_> = fs::read_dir(DIR)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "asm"))
+        .collect();
+    paths.sort();
+    paths
+        .iter()
+        .map(|path| {
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            (name, fs::read_to_string(path).unwrap())
+        })
+        .collect()
+}
+
+#[test]
+fn test_conformance() {
+    let cases = cases();
+    assert!(
+        cases.len() >= MIN_CASES,
+        "only {} conformance cases",
+        cases.len()
+    );
+    let failures: Vec<_> = cases
+        .iter()
+        .filter_map(|(name, source)| {
+            let message = assemble(source).and_then(run).err()?;
+            Some(format!("{name}: {message}"))
+        })
+        .collect();
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+// Every opcode has to appear in some case, so a new one can't be added
+// without one.
+#[test]
+fn test_every_opcode_covered() {
+    let covered: HashSet<_> = cases()
+        .iter()
+        .filter_map(|(_, source)| assemble(source).ok())
+        .flat_map(|case| case.opcodes)
+        .collect();
+    let mut missing: Vec<_> = mnemonics()
+        .into_values()
+        .filter(|&op| !covered.contains(&(op as u8)))
+        .map(|op| format!("{op:?}"))
+        .collect();
+    missing.sort();
+    assert!(
+        missing.is_empty(),
+        "opcodes without a conformance case: {missing:?}"
+    );
+}
@@ -0,0 +1,48 @@
+# a sum, a product, a minimum and a maximum of 3, -4 and 5, combined
+# into locals in place and then into one number
+!result 3399605
+Imm0
+Store 0
+Imm1
+Store 1
+ImmI 3
+Store 2
+ImmI 3
+Store 3
+ImmI 3
+AddToLocal 0
+ImmI 3
+MulToLocal 1
+ImmI 3
+MinToLocal 2
+ImmI 3
+MaxToLocal 3
+ImmI -4
+AddToLocal 0
+ImmI -4
+MulToLocal 1
+ImmI -4
+MinToLocal 2
+ImmI -4
+MaxToLocal 3
+ImmI 5
+AddToLocal 0
+ImmI 5
+MulToLocal 1
+ImmI 5
+MinToLocal 2
+ImmI 5
+MaxToLocal 3
+Load 0
+ImmI 100
+MulI
+Load 1
+AddI
+ImmI 100
+MulI
+Load 2
+AddI
+ImmI 100
+MulI
+Load 3
+AddI
@@ -0,0 +1,6 @@
+# the local being combined into holds a word
+!error OperandMismatch @ line 6
+ImmW 1
+Store 0
+Imm1
+AddToLocal 0
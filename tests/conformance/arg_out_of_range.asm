@@ -0,0 +1,7 @@
+!function f 1
+!error ArgOutOfRange @ line 7
+Imm0
+Call f
+Return
+f:
+LoadArg 1
@@ -0,0 +1,5 @@
+!error IndexOutOfBounds @ line 5
+ImmI 3
+ArrayNew
+ImmNeg1
+ArrayGet
@@ -0,0 +1,34 @@
+# sums the elements through an iterator
+!result 6
+ImmI 3
+ArrayNew
+Store 0
+Load 0
+Imm0
+Imm1
+ArraySet
+Load 0
+Imm1
+ImmI 2
+ArraySet
+Load 0
+ImmI 2
+ImmI 3
+ArraySet
+Imm0
+Store 1
+Load 0
+IterNew
+Store 2
+next:
+Load 2
+IterNext
+ImmW8 0
+CmpEqW
+GotoIf done
+Load 1
+AddI
+Store 1
+Goto next
+done:
+Load 1
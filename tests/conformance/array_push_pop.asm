@@ -0,0 +1,23 @@
+# push three, pop one, then the length is 2
+!result {tag 252: [1, 2]}
+Imm0
+ArrayNew
+Store 0
+Load 0
+Imm1
+ArrayPush
+Load 0
+ImmI 2
+ArrayPush
+Load 0
+ImmI 3
+ArrayPush
+Load 0
+ArrayPop
+ImmI 3
+CmpEqI
+GotoIf ok
+Imm0
+Return
+ok:
+Load 0
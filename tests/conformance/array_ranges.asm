@@ -0,0 +1,26 @@
+# fill [0, 6) with 1 and [1, 3) with 2, copy [0, 2) over [3, 5), then
+# slice [2, 6)
+!result {tag 252: [2, 1, 2, 1]}
+ImmI 6
+ArrayNew
+Store 0
+Load 0
+Imm0
+ImmI 6
+Imm1
+ArrayFill
+Load 0
+Imm1
+ImmI 2
+ImmI 2
+ArrayFill
+Load 0
+ImmI 3
+Load 0
+Imm0
+ImmI 2
+ArrayCopy
+Load 0
+ImmI 2
+ImmI 4
+ArraySlice
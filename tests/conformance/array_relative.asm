@@ -0,0 +1,16 @@
+# negative indices count back from the end of the array
+!result 7
+ImmI 3
+ArrayNew
+Store 0
+Load 0
+ImmNeg1
+ImmI 7
+ArraySetRel
+Load 0
+ImmI 2
+ArrayGetRel
+Load 0
+ImmI -3
+ArrayGetRel
+Store 1
@@ -0,0 +1,18 @@
+!result {tag 252: [10, 20, 3]}
+ImmI 3
+ArrayNew
+Store 0
+Load 0
+Imm0
+ImmI 10
+ArraySet
+Load 0
+Imm1
+ImmI 20
+ArraySet
+Load 0
+ImmI 2
+Load 0
+ArrayLen
+ArraySet
+Load 0
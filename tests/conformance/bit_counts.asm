@@ -0,0 +1,10 @@
+# clz 60 + ctz 4 + popcount 3 = 67
+!result 67
+ImmW8 0x8
+ClzW
+ImmW8 0x10
+CtzW
+AddI
+ImmW8 0x7
+PopcntW
+AddI
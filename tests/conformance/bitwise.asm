@@ -0,0 +1,29 @@
+!result 0xf000000000000000
+ImmW 0xff
+ImmW 0x0f
+AndW
+ImmW 0x30
+OrW
+ImmW 0x30
+XorW
+# 0x0f
+ImmW8 4
+ShlW
+ImmW8 4
+ShrW
+ImmW8 8
+RotrW
+ImmW8 4
+RotlW
+Store 0
+# shift counts are taken modulo 64
+ImmW8 1
+ImmW8 65
+ShlW
+ImmW8 2
+CmpEqW
+GotoIf ok
+ImmI 0
+Return
+ok:
+Load 0
@@ -0,0 +1,6 @@
+!error BufferOutOfBounds @ line 6
+ImmI 4
+BufNew
+Imm1
+ImmI 0
+BufStore32
@@ -0,0 +1,49 @@
+# big-endian stores of each width, read back
+!result 0x1
+ImmI 16
+BufNew
+Store 0
+Load 0
+Imm0
+ImmI 0x1122334455667788
+BufStore64
+Load 0
+ImmI 8
+ImmI 0xaabbccdd
+BufStore32
+Load 0
+ImmI 12
+ImmI 0x1eeff
+BufStore16
+Load 0
+ImmI 15
+ImmI 0x199
+BufStore8
+Load 0
+Imm0
+BufLoad8
+ImmI 0x11
+CmpEqI
+Load 0
+ImmI 3
+BufLoad16
+ImmI 0x4455
+CmpEqI
+AndW
+Load 0
+ImmI 7
+BufLoad32
+ImmI 0x88aabbcc
+CmpEqI
+AndW
+Load 0
+ImmI 8
+BufLoad64
+ImmI 0xaabbccddeeff0099
+CmpEqI
+AndW
+Load 0
+BufLen
+ImmI 16
+CmpEqI
+AndW
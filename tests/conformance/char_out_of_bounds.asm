@@ -0,0 +1,4 @@
+!error IndexOutOfBounds @ line 4
+LoadConst "abc"
+ImmI 3
+CharAt
@@ -0,0 +1,13 @@
+# neither is deterministic, but both are words
+!result 0x1
+Clock
+Store 0
+Rand
+Store 1
+Load 0
+Load 0
+CmpEqW
+Load 1
+Load 1
+CmpGeW
+AndW
@@ -0,0 +1,11 @@
+# StoreIf stores only when the condition holds
+!result 5
+ImmI 5
+ImmW8 1
+StoreIf 0
+ImmI 6
+ImmW8 0
+StoreIf 0
+LoadOrDefault 1
+Store 2
+Load 0
@@ -0,0 +1,7 @@
+!error DivisionByZero @ line 7
+ImmI 10
+ImmI 3
+ModI
+Imm0
+Imm0
+DivI
@@ -0,0 +1,22 @@
+# jumps through addresses pushed at run time, ending at the end
+!result 3
+Imm0
+Store 0
+PushIp
+Store 1
+Load 0
+Imm1
+AddI
+Store 0
+Load 0
+ImmI 3
+CmpLtI
+GotoIf again
+Load 0
+PushChunkLen
+GotoDyn
+again:
+# going back to the Store 1
+Load 1
+Load 1
+GotoDyn
@@ -0,0 +1,3 @@
+!result none
+Imm1
+Store 0
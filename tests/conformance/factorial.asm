@@ -0,0 +1,20 @@
+!input 5
+!result 120
+ImmI 1
+Store 1
+loop:
+Load 0
+ImmI 1
+CmpLeI
+GotoIf done
+Load 1
+Load 0
+MulI
+Store 1
+Load 0
+Imm1
+SubI
+Store 0
+Goto loop
+done:
+Load 1
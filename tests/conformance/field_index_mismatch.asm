@@ -0,0 +1,7 @@
+# field indices are integers, not words
+!error OperandMismatch @ line 7
+ImmI 2
+ArrayNew
+ImmW8 1
+Imm0
+SetFieldDyn
@@ -0,0 +1,4 @@
+!error FieldOutOfBounds @ line 4
+ImmI 2
+ArrayNew
+GetField 2
@@ -0,0 +1,11 @@
+!result 2.0
+ImmF 1.5
+F2Bits
+ImmW 0x3ff8000000000000
+CmpEqW
+GotoIf ok
+ImmF 0.0
+Return
+ok:
+ImmW 0x4000000000000000
+Bits2F
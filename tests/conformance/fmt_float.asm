@@ -0,0 +1,14 @@
+# fixed decimal places, rounding ties to even; negative zero keeps its sign
+!result "0.12"
+ImmF -0.0
+Imm1
+FmtFloat
+LoadConst "-0.0"
+StrEq
+GotoIf ok
+Imm0
+Return
+ok:
+ImmF 0.125
+ImmI 2
+FmtFloat
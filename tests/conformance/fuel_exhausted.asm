@@ -0,0 +1,4 @@
+!error FuelExhausted
+loop:
+Nop
+Goto loop
@@ -0,0 +1,36 @@
+# add3(1, 2, 3), which checks from a function it calls that the call
+# is two frames deep, and returns the sum below a 99
+!function add3 3
+!function depth 0
+!result 6
+Imm1
+ImmI 2
+ImmI 3
+Call add3
+ImmI 99
+CmpEqI
+GotoIf ok
+Imm0
+Return
+ok:
+Return
+add3:
+LoadArg 0
+LoadArg 1
+AddI
+StoreArg 0
+Call depth
+ImmI 2
+CmpEqI
+GotoIf done
+Imm0
+Return
+done:
+LoadArg 0
+LoadArg 2
+AddI
+ImmI 99
+ReturnN 2
+depth:
+FrameDepth
+Return
@@ -0,0 +1,3 @@
+!error HeapExhausted @ line 3
+ImmI 9223372036854775807
+ArrayNew
@@ -0,0 +1,22 @@
+# 32-bit results wrap and are sign-extended
+!result -2147483648
+ImmI 2147483647
+ImmI 1
+AddI32
+Store 0
+ImmI 0x100000005
+I64toI32
+ImmI 3
+SubI32
+ImmI 7
+MulI32
+ImmI 2
+DivI32
+# (5 - 3) * 7 / 2 = 7
+ImmI 7
+CmpEqI
+GotoIf ok
+Imm0
+Return
+ok:
+Load 0
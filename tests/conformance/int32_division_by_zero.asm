@@ -0,0 +1,5 @@
+!error DivisionByZero @ line 5
+# only the low 32 bits of the divisor count
+ImmI 1
+ImmI 0x100000000
+DivI32
@@ -0,0 +1,11 @@
+# (7 - 3) * 6 / 5 % 3, with the left operand pushed first
+!result 1
+ImmI 7
+ImmI 3
+SubI
+ImmI 6
+MulI
+ImmI 5
+DivI
+ImmI 3
+ModI
@@ -0,0 +1,28 @@
+# each comparison is true, so the conjunction is 1
+!result 0x1
+ImmI 2
+ImmI 2
+CmpEqI
+ImmI 3
+ImmI 2
+CmpGtI
+AndW
+ImmI 2
+ImmI 2
+CmpGeI
+AndW
+ImmI -5
+ImmI 2
+CmpLtI
+AndW
+ImmI 2
+ImmI 2
+CmpLeI
+AndW
+# and this one is false
+ImmI 2
+ImmI 3
+CmpGtI
+ImmW8 1
+XorW
+AndW
@@ -0,0 +1,17 @@
+# truncating and flooring division of -7 by 2: -3 + 1 - -4
+!result 2
+ImmI8 -7
+ImmI8 2
+DivFloorI
+Store 0
+ImmI16 -7
+ImmI16 2
+ModEuclidI
+Store 1
+ImmI -7
+ImmI 2
+DivI
+Load 1
+AddI
+Load 0
+SubI
@@ -0,0 +1,5 @@
+# arithmetic wraps around rather than trapping
+!result -9223372036854775808
+ImmI 9223372036854775807
+ImmI 1
+AddI
@@ -0,0 +1,7 @@
+# equal interned strings are the same object
+!result 0x1
+LoadConst "key"
+Intern
+LoadConst "key"
+Intern
+StrEq
@@ -0,0 +1,17 @@
+# two values below StackDepth, and fuel counting down
+!result 0x1
+ImmI 10
+ImmI 20
+StackDepth
+ImmI 2
+CmpEqI
+Store 0
+FuelRemaining
+FuelRemaining
+CmpGtW
+Load 0
+AndW
+FrameDepth
+Imm0
+CmpEqI
+AndW
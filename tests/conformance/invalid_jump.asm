@@ -0,0 +1,4 @@
+# the second byte of an instruction isn't a boundary
+!error InvalidJump @ line 4
+ImmW8 1
+GotoDyn
@@ -0,0 +1,6 @@
+!error InvalidLength @ line 6
+ImmI 4
+ArrayNew
+Imm0
+ImmNeg1
+ArraySlice
@@ -0,0 +1,4 @@
+!error InvalidNumber @ line 4
+# surrounding whitespace is rejected
+LoadConst " 12"
+ParseInt
@@ -0,0 +1,4 @@
+!error InvalidPrecision @ line 4
+ImmF 1.5
+ImmI 101
+FmtFloat
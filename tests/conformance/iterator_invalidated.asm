@@ -0,0 +1,15 @@
+# adding a key to a map invalidates its iterators
+!error IteratorInvalidated @ line 15
+MapNew
+Store 0
+Load 0
+Imm1
+Imm1
+MapSet
+Load 0
+IterNew
+Load 0
+ImmI 2
+Imm1
+MapSet
+IterNext
@@ -0,0 +1,4 @@
+!error KeyNotFound @ line 4
+MapNew
+LoadConst 'k'
+MapGet
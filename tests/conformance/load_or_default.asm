@@ -0,0 +1,2 @@
+!result null
+LoadOrDefault 3
@@ -0,0 +1,37 @@
+# set two keys, overwrite one, delete the other
+!result 2
+MapNew
+Store 0
+Load 0
+LoadConst "a"
+Imm1
+MapSet
+Load 0
+ImmI 5
+ImmI 50
+MapSet
+Load 0
+LoadConst "a"
+ImmI 2
+MapSet
+Load 0
+ImmI 5
+MapDelete
+Load 0
+ImmI 5
+MapContains
+ImmW8 1
+XorW
+AndW
+Load 0
+MapLen
+Imm1
+CmpEqI
+AndW
+GotoIf ok
+Imm0
+Return
+ok:
+Load 0
+LoadConst "a"
+MapGet
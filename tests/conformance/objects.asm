@@ -0,0 +1,38 @@
+# fields of a two-element array, cloned shallowly and deeply
+!result 0x1
+ImmI 2
+ArrayNew
+Store 0
+Load 0
+ImmI 7
+SetField 0
+Load 0
+Imm1
+ArrayNew
+SetField 1
+Load 0
+ObjCloneShallow
+Store 1
+Load 0
+ObjCloneDeep
+Store 2
+# the shallow copy shares the inner array, the deep one doesn't
+Load 1
+GetField 1
+Imm0
+ImmI 5
+ArraySet
+Load 0
+Load 1
+ObjEq
+Load 0
+Load 2
+ObjEq
+ImmW8 1
+XorW
+AndW
+Load 2
+GetField 0
+ImmI 7
+CmpEqI
+AndW
@@ -0,0 +1,4 @@
+!error OperandMismatch @ line 4
+ImmF 1.0
+Imm1
+AddI
@@ -0,0 +1,24 @@
+# "41" parsed, incremented and formatted; floats format as briefly as
+# they round-trip
+!result "42"
+LoadConst "41"
+ParseInt
+Imm1
+AddI
+IntToStr
+Store 0
+LoadConst "2.5"
+ParseFloat
+FloatToStr
+LoadConst "2.5"
+StrEq
+LoadConst 5.0
+FloatToStr
+LoadConst "5"
+StrEq
+AndW
+GotoIf ok
+Imm0
+Return
+ok:
+Load 0
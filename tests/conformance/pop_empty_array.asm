@@ -0,0 +1,4 @@
+!error IndexOutOfBounds @ line 4
+Imm0
+ArrayNew
+ArrayPop
@@ -0,0 +1,34 @@
+# counts, reads and writes the fields of an array by computed index
+!result {tag 252: [1, 5, 3]}
+ImmI 3
+ArrayNew
+Store 0
+Load 0
+FieldCount
+ImmI 3
+CmpEqI
+GotoIf ok
+Imm0
+Return
+ok:
+Load 0
+Imm0
+Imm1
+SetFieldDyn
+Load 0
+ImmI 2
+ImmI 3
+SetFieldDyn
+Load 0
+Imm1
+Load 0
+Imm0
+GetFieldDyn
+Load 0
+ImmI 2
+GetFieldDyn
+AddI
+Imm1
+AddI
+SetFieldDyn
+Load 0
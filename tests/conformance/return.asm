@@ -0,0 +1,5 @@
+# returning from the top level ends the program
+!result 1
+Imm1
+Return
+ImmI 2
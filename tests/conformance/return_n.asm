@@ -0,0 +1,11 @@
+# ReturnN keeps only the top values of the callee's
+!function pair 0
+!result 2
+Call pair
+StackDepth
+Return
+pair:
+ImmI 7
+ImmI 8
+ImmI 9
+ReturnN 2
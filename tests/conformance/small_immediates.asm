@@ -0,0 +1,14 @@
+# Imm0 + Imm1 + ImmNeg1 + 100 + -300 + 30000
+!result 29800
+Imm0
+Imm1
+AddI
+ImmNeg1
+AddI
+Nop
+ImmI8 100
+AddI
+ImmI16 -300
+AddI
+ImmI16 30000
+AddI
@@ -0,0 +1,10 @@
+# a function that declares no results pops one of its caller's values,
+# which traps when it returns
+!function eat 0
+!error StackImbalance @ line 10
+Imm1
+Call eat
+Return
+eat:
+Store 0
+Return
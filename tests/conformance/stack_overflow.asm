@@ -0,0 +1,4 @@
+!error StackOverflow @ line 3
+loop:
+Imm0
+Goto loop
@@ -0,0 +1,3 @@
+!error StackUnderflow @ line 3
+Imm1
+AddI
@@ -0,0 +1,28 @@
+# "conformance"[3..7] is "form", and 'f' is its first char
+!result 'f'
+LoadConst "conformance"
+ImmI 3
+ImmI 4
+Substr
+Store 0
+Load 0
+StrLen
+ImmI 4
+CmpEqI
+Load 0
+LoadConst "form"
+StrEq
+AndW
+Load 0
+LoadConst "formal"
+StrCmp
+ImmNeg1
+CmpEqI
+AndW
+GotoIf ok
+LoadConst 'x'
+Return
+ok:
+Load 0
+Imm0
+CharAt
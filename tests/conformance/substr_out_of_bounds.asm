@@ -0,0 +1,5 @@
+!error IndexOutOfBounds @ line 5
+LoadConst "abc"
+ImmI 1
+ImmI 3
+Substr
@@ -0,0 +1,4 @@
+!error GuestPanic @ line 3
+LoadConst "boom"
+TrapMsg
+ImmI 1
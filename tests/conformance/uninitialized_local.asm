@@ -0,0 +1,4 @@
+!error UninitializedLocal @ line 4
+Imm0
+Store 1
+Load 0
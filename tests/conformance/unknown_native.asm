@@ -0,0 +1,4 @@
+# no natives are registered for conformance cases
+!error UnknownNative @ line 4
+Imm1
+CallNative "missing"
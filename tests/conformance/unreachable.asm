@@ -0,0 +1,6 @@
+!error UnreachableExecuted @ line 5
+ImmW 0
+GotoIf never
+ImmI 7
+Unreachable
+never:
@@ -0,0 +1,29 @@
+# the target survives while referenced, and is collected after
+!result null
+Imm1
+ArrayNew
+Store 0
+Load 0
+NewWeak
+Store 1
+Gc
+Load 1
+WeakGet
+Load 0
+ObjEq
+GotoIf ok
+Imm0
+Return
+ok:
+HeapInfo
+ImmI 2
+CmpEqI
+GotoIf drop
+Imm0
+Return
+drop:
+Imm0
+Store 0
+Gc
+Load 1
+WeakGet
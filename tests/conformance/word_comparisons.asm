@@ -0,0 +1,21 @@
+# words compare unsigned: 0xffffffffffffffff is the largest
+!result 0x1
+ImmW 0xffffffffffffffff
+ImmW8 1
+CmpGtW
+ImmW16 300
+ImmW16 300
+CmpEqW
+AndW
+ImmW16 300
+ImmW8 7
+CmpGeW
+AndW
+ImmW8 7
+ImmW 0x8000000000000000
+CmpLtW
+AndW
+ImmW8 7
+ImmW8 7
+CmpLeW
+AndW
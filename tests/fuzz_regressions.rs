@@ -0,0 +1,61 @@
+use andrea::builder::ChunkBuilder;
+use andrea::error::VmError;
+use andrea::fuzz;
+use andrea::opcode::OpCode::*;
+use andrea::vm::VM;
+
+// Inputs that once panicked or aborted the interpreter, kept as found.
+const CRASHERS: &[&[u8]] = &[
+    // ArrayNew with a length too large to allocate
+    &[
+        5, 51, 75, 2, 56, 42, 16, 207, 216, 7, 20, 10, 139, 139, 228, 79, 23, 14, 72, 72, 5, 5, 46,
+        55, 147, 65, 37, 66, 18, 203, 63, 51, 51, 80, 99,
+    ],
+    &[
+        5, 12, 43, 44, 175, 34, 26, 11, 78, 63, 65, 48, 117, 5, 16, 29, 119, 52, 50, 1, 75, 35, 24,
+    ],
+    &[
+        78, 5, 18, 211, 147, 9, 85, 241, 74, 42, 63, 69, 23, 81, 165, 225, 71, 126, 50, 90, 76,
+        229, 17, 89, 10, 13, 3, 22, 166, 27, 28, 66,
+    ],
+];
+
+#[test]
+fn test_crashers() {
+    for (i, code) in CRASHERS.iter().enumerate() {
+        let result = fuzz::run_code(code);
+        assert!(result.is_err(), "crasher {i} ran to completion: {result:?}");
+    }
+}
+
+#[test]
+fn test_huge_array_without_policy() {
+    let mut vm = VM::new(
+        ChunkBuilder::new()
+            .imm_i(i64::MAX)
+            .op(ArrayNew)
+            .build()
+            .unwrap(),
+    );
+    assert_eq!(vm.execute_all(), Err(VmError::HeapExhausted));
+}
+
+#[test]
+fn test_long_object_chain() {
+    // x = [x], n times, then collect: marking must not recurse per link
+    let n = 200_000;
+    let mut b = ChunkBuilder::new();
+    let (head, end) = (b.label(), b.label());
+    b.imm_i(n).store(0).imm_i(0).store(1);
+    b.bind(head).imm_i(0).load(0).op(CmpGeI).goto_if(end);
+    b.imm_i(1).op(ArrayNew).store(2);
+    b.load(2).imm_i(0).load(1).op(ArraySet);
+    b.load(2).store(1);
+    b.load(0).imm_i(1).op(SubI).store(0);
+    b.goto(head);
+    b.bind(end).op(Gc).load(1);
+    let mut vm = VM::new(b.build().unwrap());
+    let outcome = vm.execute_all().unwrap();
+    assert!(outcome.value.is_some());
+    assert_eq!(vm.heap().len(), n as usize);
+}
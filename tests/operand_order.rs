@@ -0,0 +1,72 @@
+use andrea::builder::ChunkBuilder;
+use andrea::eval::eval;
+use andrea::instruction::Instruction::{self, *};
+use andrea::opcode::OpCode;
+use andrea::value::Value;
+use andrea::vm::VM;
+
+// Every binary opcode whose operands can't be exchanged, run on `x y op`:
+// `x` is pushed first and is the left operand, `y` is on top and is the
+// right one.
+fn int(x: i64, y: i64, op: Instruction) -> Value {
+    eval(&[ImmI(x), ImmI(y), op], &[]).unwrap().unwrap()
+}
+
+fn word(x: u64, y: u64, op: Instruction) -> Value {
+    eval(&[ImmW(x), ImmW(y), op], &[]).unwrap().unwrap()
+}
+
+fn str_cmp(x: &str, y: &str) -> Value {
+    let chunk = ChunkBuilder::new()
+        .string(x)
+        .string(y)
+        .op(OpCode::StrCmp)
+        .build()
+        .unwrap();
+    VM::new(chunk).execute_all().unwrap().value.unwrap()
+}
+
+#[test]
+fn test_integer_arithmetic() {
+    let i = Value::Integer;
+    assert_eq!(int(7, 2, SubI), i(5));
+    assert_eq!(int(7, 2, DivI), i(3));
+    assert_eq!(int(7, 2, ModI), i(1));
+    assert_eq!(int(-7, 2, DivFloorI), i(-4));
+    assert_eq!(int(-7, 2, ModEuclidI), i(1));
+    assert_eq!(int(7, 2, SubI32), i(5));
+    assert_eq!(int(7, 2, DivI32), i(3));
+}
+
+#[test]
+fn test_comparisons() {
+    let w = |b: bool| Value::Word(b as u64);
+    for (x, y) in [(1, 2), (2, 1), (2, 2)] {
+        assert_eq!(int(x, y, CmpGtI), w(x > y), "{x} > {y}");
+        assert_eq!(int(x, y, CmpGeI), w(x >= y), "{x} >= {y}");
+        assert_eq!(int(x, y, CmpLtI), w(x < y), "{x} < {y}");
+        assert_eq!(int(x, y, CmpLeI), w(x <= y), "{x} <= {y}");
+        let (x, y) = (x as u64, y as u64);
+        assert_eq!(word(x, y, CmpGtW), w(x > y), "{x} > {y}");
+        assert_eq!(word(x, y, CmpGeW), w(x >= y), "{x} >= {y}");
+        assert_eq!(word(x, y, CmpLtW), w(x < y), "{x} < {y}");
+        assert_eq!(word(x, y, CmpLeW), w(x <= y), "{x} <= {y}");
+    }
+}
+
+#[test]
+fn test_shifts() {
+    let w = Value::Word;
+    assert_eq!(word(1, 4, ShlW), w(16));
+    assert_eq!(word(16, 4, ShrW), w(1));
+    assert_eq!(word(1 << 63, 1, RotlW), w(1));
+    assert_eq!(word(1, 1, RotrW), w(1 << 63));
+}
+
+#[test]
+fn test_string_comparison() {
+    let i = Value::Integer;
+    assert_eq!(str_cmp("a", "b"), i(-1));
+    assert_eq!(str_cmp("b", "a"), i(1));
+    assert_eq!(str_cmp("ab", "abc"), i(-1));
+}
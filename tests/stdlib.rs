@@ -0,0 +1,27 @@
+use andrea::builder::ChunkBuilder;
+use andrea::link::link;
+use andrea::opcode::OpCode::*;
+use andrea::stdlib;
+use andrea::value::Value;
+use andrea::vm::VM;
+
+// clamp(array_sum(a), 0, 100) + int_abs(-7), for an array a of 1..=20
+#[test]
+fn test_link_with_stdlib() {
+    let mut b = ChunkBuilder::new();
+    let sum = b.import("array_sum");
+    let clamp = b.import("clamp");
+    let abs = b.import("int_abs");
+    b.imm_i(20).op(ArrayNew).store(0);
+    for index in 0..20 {
+        b.load(0).imm_i(index).imm_i(index + 1).op(ArraySet);
+    }
+    b.load(0).call(sum).imm_i(0).imm_i(100).call(clamp);
+    b.imm_i(-7).call(abs).op(AddI);
+
+    let linked = link(&[b.build().unwrap(), stdlib::library()]).unwrap();
+    let mut vm = VM::new(linked);
+    let outcome = vm.execute_all().unwrap();
+    assert_eq!(outcome.value, Some(Value::Integer(107)));
+    assert_eq!(vm.stack(), [Value::Integer(107)]);
+}